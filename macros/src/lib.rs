@@ -0,0 +1,159 @@
+//! Procedural macros re-exported by `mcpl-core`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, FnArg, ImplItem, ImplItemFn, ItemImpl, LitStr, Type};
+
+/// Generate a `router(self: Arc<Self>) -> mcpl_core::Router` method from the
+/// `#[mcpl_handler("method")]` methods of an impl block.
+///
+/// ```ignore
+/// #[mcpl_router]
+/// impl GameServer {
+///     #[mcpl_handler("state/rollback")]
+///     async fn rollback(&self, params: StateRollbackParams) -> HandlerResult<StateRollbackResult> {
+///         ...
+///     }
+/// }
+///
+/// Arc::new(GameServer::new()).router().serve(connection).await?;
+/// ```
+///
+/// Handlers are `async fn`s taking `&self` and then either nothing, the
+/// params, or a `RequestContext` (owned or borrowed) followed by the params.
+/// They return a `Result` whose error converts into `JsonRpcError`, such as
+/// `HandlerResult` or `Result<_, HandlerError>`.
+#[proc_macro_attribute]
+pub fn mcpl_router(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let err = syn::Error::new(Span::call_site(), "#[mcpl_router] takes no arguments");
+        return err.to_compile_error().into();
+    }
+    let mut item = parse_macro_input!(item as ItemImpl);
+    match expand_router(&mut item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Marks a method of an `#[mcpl_router]` impl block as the handler for a
+/// JSON-RPC method.
+#[proc_macro_attribute]
+pub fn mcpl_handler(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = proc_macro2::TokenStream::from(item);
+    let err = syn::Error::new(
+        item.span(),
+        "#[mcpl_handler] must be used on a method inside an #[mcpl_router] impl block",
+    );
+    let err = err.to_compile_error();
+    quote!(#err #item).into()
+}
+
+fn expand_router(item: &mut ItemImpl) -> syn::Result<proc_macro2::TokenStream> {
+    let mut registrations = Vec::new();
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(func) = impl_item else {
+            continue;
+        };
+        let Some(method) = take_handler_attr(func)? else {
+            continue;
+        };
+        registrations.push(registration(func, &method)?);
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics #self_ty #where_clause {
+            /// Router dispatching each `#[mcpl_handler]` method.
+            pub fn router(self: ::std::sync::Arc<Self>) -> ::mcpl_core::Router {
+                let mut router = ::mcpl_core::Router::new();
+                #(#registrations)*
+                router
+            }
+        }
+    })
+}
+
+/// Remove `#[mcpl_handler("...")]` from a method, returning the method name.
+fn take_handler_attr(func: &mut ImplItemFn) -> syn::Result<Option<LitStr>> {
+    let Some(index) = func
+        .attrs
+        .iter()
+        .position(|attr| attr.path().is_ident("mcpl_handler"))
+    else {
+        return Ok(None);
+    };
+    let attr = func.attrs.remove(index);
+    attr.parse_args::<LitStr>().map(Some)
+}
+
+fn registration(func: &ImplItemFn, method: &LitStr) -> syn::Result<proc_macro2::TokenStream> {
+    let sig = &func.sig;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            sig.fn_token.span(),
+            "handler must be an async fn",
+        ));
+    }
+    let mut inputs = sig.inputs.iter();
+    match inputs.next() {
+        Some(FnArg::Receiver(receiver))
+            if receiver.reference.is_some() && receiver.mutability.is_none() => {}
+        _ => return Err(syn::Error::new(sig.span(), "handler must take &self")),
+    }
+    let args = inputs
+        .map(|arg| match arg {
+            FnArg::Typed(pat) => Ok(&*pat.ty),
+            FnArg::Receiver(_) => unreachable!("receiver is always first"),
+        })
+        .collect::<syn::Result<Vec<&Type>>>()?;
+
+    let name = &sig.ident;
+    let (ctx, params, params_ty, call) = match args.as_slice() {
+        [] => (
+            quote!(_ctx),
+            quote!(_params),
+            quote!(::mcpl_core::__private::serde_json::Value),
+            quote!(this.#name()),
+        ),
+        [params_ty] => (
+            quote!(_ctx),
+            quote!(params),
+            quote!(#params_ty),
+            quote!(this.#name(params)),
+        ),
+        [ctx_ty, params_ty] => {
+            let ctx_arg = match ctx_ty {
+                Type::Reference(_) => quote!(&ctx),
+                _ => quote!(ctx),
+            };
+            (
+                quote!(ctx),
+                quote!(params),
+                quote!(#params_ty),
+                quote!(this.#name(#ctx_arg, params)),
+            )
+        }
+        _ => {
+            return Err(syn::Error::new(
+                sig.inputs.span(),
+                "handler takes at most a RequestContext and params after &self",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        {
+            let this = ::std::sync::Arc::clone(&self);
+            router.on(#method, move |#ctx: ::mcpl_core::RequestContext, #params: #params_ty| {
+                let this = ::std::sync::Arc::clone(&this);
+                async move { #call.await.map_err(::std::convert::Into::into) }
+            });
+        }
+    })
+}
@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+
+use crate::request::RequestContext;
+
+/// Check applied to every request a [`Router`](crate::Router) receives,
+/// before it reaches a handler or a concurrency limit.
+///
+/// The context carries what an authenticator usually needs: the request's
+/// `_meta` (e.g. a token), and the connection's [`McplSession`](crate::McplSession)
+/// with the peer's `clientInfo` and any identity the transport recorded.
+///
+/// ```ignore
+/// router.authenticate(|ctx: &RequestContext| match ctx.session().and_then(|s| s.identity()) {
+///     Some(_) => Ok(()),
+///     None => Err("anonymous connection".into()),
+/// });
+/// ```
+pub trait Authenticator: Send + Sync {
+    /// `Err` refuses the request; the reason is sent to the peer.
+    fn authenticate(&self, ctx: &RequestContext) -> Result<(), String>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(&RequestContext) -> Result<(), String> + Send + Sync,
+{
+    fn authenticate(&self, ctx: &RequestContext) -> Result<(), String> {
+        self(ctx)
+    }
+}
+
+/// Accepts requests carrying one of a set of tokens in a `_meta` field.
+#[derive(Debug, Clone)]
+pub struct MetaToken {
+    field: String,
+    tokens: HashSet<String>,
+}
+
+impl MetaToken {
+    /// Accept requests whose `_meta.<field>` is one of `tokens`.
+    pub fn new<I, T>(field: impl Into<String>, tokens: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        Self {
+            field: field.into(),
+            tokens: tokens.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Authenticator for MetaToken {
+    fn authenticate(&self, ctx: &RequestContext) -> Result<(), String> {
+        let token = ctx.meta_field(&self.field);
+        match token.as_ref().and_then(|token| token.as_str()) {
+            Some(token) if self.tokens.contains(token) => Ok(()),
+            Some(_) => Err(format!("invalid _meta.{}", self.field)),
+            None => Err(format!("missing _meta.{}", self.field)),
+        }
+    }
+}
@@ -1,12 +1,17 @@
 use serde::{Deserialize, Serialize};
 
-use crate::methods::FeatureSetDeclaration;
+use crate::methods::{method, FeatureSetDeclaration};
+use crate::version::{ParseVersionError, ProtocolVersion, SUPPORTED_VERSIONS};
 
 /// MCPL capability declaration, nested under `experimental.mcpl` in MCP's
 /// initialize request/response.
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct McplCapabilities {
+    /// Preferred MCPL version; in an `initialize` result, the negotiated one.
     pub version: String,
+    /// Every version the sender can speak, when more than `version`.
+    #[serde(rename = "supportedVersions", default, skip_serializing_if = "Option::is_none")]
+    pub supported_versions: Option<Vec<String>>,
     #[serde(rename = "pushEvents", default, skip_serializing_if = "Option::is_none")]
     pub push_events: Option<bool>,
     #[serde(rename = "contextHooks", default, skip_serializing_if = "Option::is_none")]
@@ -113,6 +118,23 @@ pub struct ImplementationInfo {
     pub version: String,
 }
 
+impl InitializeCapabilities {
+    /// Capabilities carrying only MCPL's, under `experimental.mcpl`.
+    pub fn with_mcpl(capabilities: McplCapabilities) -> Self {
+        Self {
+            experimental: Some(ExperimentalCapabilities {
+                mcpl: Some(capabilities),
+            }),
+            other: Default::default(),
+        }
+    }
+
+    /// The MCPL capabilities, if the sender speaks MCPL at all.
+    pub fn mcpl(&self) -> Option<&McplCapabilities> {
+        self.experimental.as_ref()?.mcpl.as_ref()
+    }
+}
+
 impl McplCapabilities {
     pub fn new(version: impl Into<String>) -> Self {
         Self {
@@ -152,4 +174,70 @@ impl McplCapabilities {
     pub fn has_scoped_access(&self) -> bool {
         self.scoped_access.unwrap_or(false)
     }
+
+    pub fn has_before_inference_hook(&self) -> bool {
+        self.context_hooks.as_ref().is_some_and(|c| c.before_inference)
+    }
+
+    pub fn has_after_inference_hook(&self) -> bool {
+        self.context_hooks.as_ref().is_some_and(|c| c.after_inference.is_some())
+    }
+
+    /// `version` as a typed version.
+    pub fn protocol_version(&self) -> Result<ProtocolVersion, ParseVersionError> {
+        self.version.parse()
+    }
+
+    /// Versions the sender can speak: `supportedVersions` if present,
+    /// otherwise just `version`. Unparseable entries are skipped.
+    pub fn versions(&self) -> Vec<ProtocolVersion> {
+        match &self.supported_versions {
+            Some(versions) => versions.iter().filter_map(|v| v.parse().ok()).collect(),
+            None => self.protocol_version().into_iter().collect(),
+        }
+    }
+
+    /// Versions to offer in a handshake: [`versions`](Self::versions), or
+    /// every version this crate supports if none are declared.
+    pub(crate) fn offered_versions(&self) -> Vec<ProtocolVersion> {
+        let versions = self.versions();
+        if versions.is_empty() {
+            SUPPORTED_VERSIONS.to_vec()
+        } else {
+            versions
+        }
+    }
+
+    /// Whether these capabilities cover `method`. Methods outside MCPL's
+    /// optional features (e.g. `initialize`, `ping`) are always covered, as
+    /// long as `version` (when it parses) defines them.
+    pub fn supports_method(&self, method: &str) -> bool {
+        if let Ok(version) = self.protocol_version() {
+            if !version.supports_method(method) {
+                return false;
+            }
+        }
+        match method {
+            method::PUSH_EVENT | method::PUSH_EVENT_BATCH => self.has_push_events(),
+            method::STATE_ROLLBACK => self.has_rollback(),
+            method::SCOPE_ELEVATE => self.has_scoped_access(),
+            method::CONTEXT_BEFORE_INFERENCE | method::CONTEXT_CANCEL => {
+                self.has_before_inference_hook()
+            }
+            method::CONTEXT_AFTER_INFERENCE => self.has_after_inference_hook(),
+            method::INFERENCE_REQUEST | method::INFERENCE_CANCEL => self.has_inference_request(),
+            method::INFERENCE_CHUNK => self.has_stream_observer(),
+            method::MODEL_INFO => self.has_model_info(),
+            method::CHANNELS_REGISTER
+            | method::CHANNELS_CHANGED
+            | method::CHANNELS_LIST
+            | method::CHANNELS_OPEN
+            | method::CHANNELS_CLOSE
+            | method::CHANNELS_OUTGOING_CHUNK
+            | method::CHANNELS_OUTGOING_COMPLETE
+            | method::CHANNELS_PUBLISH
+            | method::CHANNELS_INCOMING => self.has_channels(),
+            _ => true,
+        }
+    }
 }
@@ -0,0 +1,819 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::handler::HandlerError;
+use crate::methods::{StateCheckpoint, StateCheckpointsListParams, StateCheckpointsListResult};
+use crate::types::JsonRpcError;
+
+/// Where a server keeps the checkpoints `state/rollback` restores.
+///
+/// Each checkpoint holds its [`StateCheckpoint`] metadata and the feature
+/// set's state at that point. Give a store to
+/// [`McplServerBuilder::checkpoints`](crate::server::McplServerBuilder::checkpoints)
+/// to have `state/rollback` answered from it.
+///
+/// ```ignore
+/// let store = Arc::new(MemoryCheckpointStore::new());
+/// let saved = StoredCheckpoint::new("game", serde_json::to_value(&board)?).parent(last_id);
+/// store.create(saved.clone())?;
+/// ```
+pub trait CheckpointStore: Send + Sync {
+    /// Store a new checkpoint. Fails with [`CheckpointError::Duplicate`] if
+    /// the id is taken and [`CheckpointError::NotFound`] if its parent is not
+    /// stored.
+    fn create(&self, checkpoint: StoredCheckpoint) -> Result<(), CheckpointError>;
+
+    fn get(&self, id: &str) -> Result<Option<StoredCheckpoint>, CheckpointError>;
+
+    /// Checkpoints of `feature_set`, oldest first.
+    fn list(&self, feature_set: &str) -> Result<Vec<StateCheckpoint>, CheckpointError>;
+
+    /// Remove a checkpoint, returning whether it was stored. Its children
+    /// keep their `parent` link.
+    fn delete(&self, id: &str) -> Result<bool, CheckpointError>;
+
+    /// The checkpoint `id` was created from; `None` for a root.
+    fn parent(&self, id: &str) -> Result<Option<StateCheckpoint>, CheckpointError> {
+        let checkpoint = self
+            .get(id)?
+            .ok_or_else(|| CheckpointError::NotFound(id.to_string()))?;
+        let Some(parent) = checkpoint.checkpoint.parent else {
+            return Ok(None);
+        };
+        Ok(self.get(&parent)?.map(|stored| stored.checkpoint))
+    }
+
+    /// Checkpoints of `feature_set` labelled `label`, oldest first.
+    fn find_by_label(
+        &self,
+        feature_set: &str,
+        label: &str,
+    ) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        let mut found = self.list(feature_set)?;
+        found.retain(|checkpoint| checkpoint.label.as_deref() == Some(label));
+        Ok(found)
+    }
+
+    /// Checkpoints of `feature_set` taken at or after `since` and before
+    /// `until`, oldest first. Timestamps are compared as written by
+    /// [`StoredCheckpoint::new`].
+    fn between(
+        &self,
+        feature_set: &str,
+        since: SystemTime,
+        until: SystemTime,
+    ) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        let (since, until) = (rfc3339(since), rfc3339(until));
+        let mut found = self.list(feature_set)?;
+        found.retain(|checkpoint| since <= checkpoint.timestamp && checkpoint.timestamp < until);
+        Ok(found)
+    }
+}
+
+impl<T: CheckpointStore + ?Sized> CheckpointStore for Arc<T> {
+    fn create(&self, checkpoint: StoredCheckpoint) -> Result<(), CheckpointError> {
+        (**self).create(checkpoint)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<StoredCheckpoint>, CheckpointError> {
+        (**self).get(id)
+    }
+
+    fn list(&self, feature_set: &str) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        (**self).list(feature_set)
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, CheckpointError> {
+        (**self).delete(id)
+    }
+
+    fn parent(&self, id: &str) -> Result<Option<StateCheckpoint>, CheckpointError> {
+        (**self).parent(id)
+    }
+
+    fn find_by_label(
+        &self,
+        feature_set: &str,
+        label: &str,
+    ) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        (**self).find_by_label(feature_set, label)
+    }
+
+    fn between(
+        &self,
+        feature_set: &str,
+        since: SystemTime,
+        until: SystemTime,
+    ) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        (**self).between(feature_set, since, until)
+    }
+}
+
+/// A checkpoint and the state it captured.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredCheckpoint {
+    pub checkpoint: StateCheckpoint,
+    pub state: serde_json::Value,
+}
+
+impl StoredCheckpoint {
+    /// A root checkpoint of `feature_set` taken now, with a UUIDv7 id.
+    pub fn new(feature_set: impl Into<String>, state: serde_json::Value) -> Self {
+        Self {
+            checkpoint: StateCheckpoint {
+                id: uuid::Uuid::now_v7().to_string(),
+                feature_set: feature_set.into(),
+                timestamp: rfc3339(SystemTime::now()),
+                parent: None,
+                label: None,
+                metadata: None,
+            },
+            state,
+        }
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.checkpoint.id = id.into();
+        self
+    }
+
+    pub fn parent(mut self, parent: impl Into<String>) -> Self {
+        self.checkpoint.parent = Some(parent.into());
+        self
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.checkpoint.label = Some(label.into());
+        self
+    }
+
+    pub fn metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.checkpoint.metadata = Some(metadata);
+        self
+    }
+}
+
+/// Checkpoints linked by `parent` into a tree, which branches where a
+/// rollback was followed by new checkpoints.
+///
+/// ```ignore
+/// let page = client.state_checkpoints_list(&params).await?;
+/// let tree: CheckpointTree = page.checkpoints.into_iter().collect();
+/// let timeline = tree.path_to_root(&current);
+/// ```
+///
+/// Checkpoints whose parent is not in the tree count as roots.
+#[derive(Debug, Clone, Default)]
+pub struct CheckpointTree {
+    checkpoints: HashMap<String, StateCheckpoint>,
+    /// Child ids by parent id, oldest first.
+    children: HashMap<String, Vec<String>>,
+}
+
+impl CheckpointTree {
+    pub fn new(checkpoints: impl IntoIterator<Item = StateCheckpoint>) -> Self {
+        let checkpoints: HashMap<String, StateCheckpoint> = checkpoints
+            .into_iter()
+            .map(|checkpoint| (checkpoint.id.clone(), checkpoint))
+            .collect();
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for checkpoint in checkpoints.values() {
+            if let Some(parent) = &checkpoint.parent {
+                children
+                    .entry(parent.clone())
+                    .or_default()
+                    .push(checkpoint.id.clone());
+            }
+        }
+        for ids in children.values_mut() {
+            ids.sort_by(|a, b| {
+                let (a, b) = (&checkpoints[a], &checkpoints[b]);
+                (&a.timestamp, &a.id).cmp(&(&b.timestamp, &b.id))
+            });
+        }
+        Self {
+            checkpoints,
+            children,
+        }
+    }
+
+    /// The checkpoints of `feature_set` in `store`.
+    pub fn from_store(
+        store: &dyn CheckpointStore,
+        feature_set: &str,
+    ) -> Result<Self, CheckpointError> {
+        Ok(Self::new(store.list(feature_set)?))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&StateCheckpoint> {
+        self.checkpoints.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checkpoints.is_empty()
+    }
+
+    /// Checkpoints without a parent in the tree, oldest first.
+    pub fn roots(&self) -> Vec<&StateCheckpoint> {
+        let mut roots: Vec<&StateCheckpoint> = self
+            .checkpoints
+            .values()
+            .filter(|checkpoint| self.parent_of(checkpoint).is_none())
+            .collect();
+        roots.sort_by(|a, b| (&a.timestamp, &a.id).cmp(&(&b.timestamp, &b.id)));
+        roots
+    }
+
+    /// Checkpoints created from `id`, oldest first.
+    pub fn children(&self, id: &str) -> Vec<&StateCheckpoint> {
+        self.children
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|child| &self.checkpoints[child])
+            .collect()
+    }
+
+    /// The parent of `id`, its parent, and so on up to a root.
+    pub fn ancestors(&self, id: &str) -> Vec<&StateCheckpoint> {
+        let mut path = self.path_to_root(id);
+        if !path.is_empty() {
+            path.remove(0);
+        }
+        path
+    }
+
+    /// `id` followed by its ancestors; empty if `id` is not in the tree.
+    pub fn path_to_root(&self, id: &str) -> Vec<&StateCheckpoint> {
+        let mut path = Vec::new();
+        let mut next = self.checkpoints.get(id);
+        while let Some(checkpoint) = next {
+            // A cycle in corrupt data would otherwise never end
+            if path.len() == self.checkpoints.len() {
+                break;
+            }
+            path.push(checkpoint);
+            next = self.parent_of(checkpoint);
+        }
+        path
+    }
+
+    /// Every checkpoint created from `id`, directly or not, breadth first.
+    pub fn descendants(&self, id: &str) -> Vec<&StateCheckpoint> {
+        let mut found = Vec::new();
+        let mut seen = HashSet::from([id]);
+        let mut queue = VecDeque::from([id]);
+        while let Some(next) = queue.pop_front() {
+            for child in self.children(next) {
+                if seen.insert(&child.id) {
+                    found.push(child);
+                    queue.push_back(&child.id);
+                }
+            }
+        }
+        found
+    }
+
+    /// The newest checkpoint both `a` and `b` descend from, counting each as
+    /// descending from itself.
+    pub fn common_ancestor(&self, a: &str, b: &str) -> Option<&StateCheckpoint> {
+        let ancestors_of_a: HashSet<&str> = self
+            .path_to_root(a)
+            .into_iter()
+            .map(|checkpoint| checkpoint.id.as_str())
+            .collect();
+        self.path_to_root(b)
+            .into_iter()
+            .find(|checkpoint| ancestors_of_a.contains(checkpoint.id.as_str()))
+    }
+
+    /// Whether `descendant` was created from `ancestor`, directly or not.
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> bool {
+        self.ancestors(descendant)
+            .iter()
+            .any(|checkpoint| checkpoint.id == ancestor)
+    }
+
+    fn parent_of(&self, checkpoint: &StateCheckpoint) -> Option<&StateCheckpoint> {
+        checkpoint
+            .parent
+            .as_ref()
+            .and_then(|parent| self.checkpoints.get(parent))
+    }
+}
+
+impl FromIterator<StateCheckpoint> for CheckpointTree {
+    fn from_iter<I: IntoIterator<Item = StateCheckpoint>>(iter: I) -> Self {
+        Self::new(iter)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CheckpointError {
+    #[error("Checkpoint not found: {0}")]
+    NotFound(String),
+    #[error("Checkpoint already exists: {0}")]
+    Duplicate(String),
+    #[error("Checkpoint storage failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Checkpoint encoding failed: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("Checkpoint encryption failed: {0}")]
+    Cipher(String),
+}
+
+impl From<CheckpointError> for HandlerError {
+    fn from(e: CheckpointError) -> Self {
+        match e {
+            CheckpointError::NotFound(id) => HandlerError::checkpoint_not_found(&id),
+            CheckpointError::Duplicate(id) => HandlerError::checkpoint_conflict(&id),
+            e => HandlerError::internal(e),
+        }
+    }
+}
+
+impl From<CheckpointError> for JsonRpcError {
+    fn from(e: CheckpointError) -> Self {
+        HandlerError::from(e).into()
+    }
+}
+
+/// Checkpoints per `state/checkpoints/list` page when the host sets no limit.
+pub const CHECKPOINT_PAGE_SIZE: u32 = 100;
+
+/// Answer `state/checkpoints/list` from `store`: the checkpoints of
+/// `feature_sets`, oldest first, a page at a time. The cursor is the offset
+/// of the next page.
+pub(crate) fn list_page(
+    store: &dyn CheckpointStore,
+    feature_sets: &[String],
+    params: &StateCheckpointsListParams,
+) -> Result<StateCheckpointsListResult, JsonRpcError> {
+    let offset = match params.cursor.as_deref() {
+        Some(cursor) => cursor
+            .parse::<usize>()
+            .map_err(|_| JsonRpcError::invalid_params(format!("Invalid cursor: {}", cursor)))?,
+        None => 0,
+    };
+    let limit = params.limit.unwrap_or(CHECKPOINT_PAGE_SIZE).max(1) as usize;
+    let mut checkpoints = Vec::new();
+    for feature_set in feature_sets {
+        checkpoints.extend(store.list(feature_set)?);
+    }
+    if feature_sets.len() > 1 {
+        checkpoints.sort_by(|a, b| (&a.timestamp, &a.id).cmp(&(&b.timestamp, &b.id)));
+    }
+    let end = offset.saturating_add(limit);
+    let next_cursor = (end < checkpoints.len()).then(|| end.to_string());
+    Ok(StateCheckpointsListResult {
+        checkpoints: checkpoints.into_iter().skip(offset).take(limit).collect(),
+        next_cursor,
+    })
+}
+
+/// Checkpoints kept in memory, lost when the process exits.
+#[derive(Debug, Default)]
+pub struct MemoryCheckpointStore {
+    inner: Mutex<MemoryCheckpoints>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryCheckpoints {
+    checkpoints: HashMap<String, StoredCheckpoint>,
+    /// Ids by feature set, in creation order.
+    order: HashMap<String, Vec<String>>,
+    /// Ids by feature set and label, in creation order.
+    labels: HashMap<(String, String), Vec<String>>,
+}
+
+impl MemoryCheckpointStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CheckpointStore for MemoryCheckpointStore {
+    fn create(&self, checkpoint: StoredCheckpoint) -> Result<(), CheckpointError> {
+        let mut inner = self.inner.lock().unwrap();
+        let meta = &checkpoint.checkpoint;
+        if inner.checkpoints.contains_key(&meta.id) {
+            return Err(CheckpointError::Duplicate(meta.id.clone()));
+        }
+        if let Some(parent) = meta.parent.as_ref() {
+            if !inner.checkpoints.contains_key(parent) {
+                return Err(CheckpointError::NotFound(parent.clone()));
+            }
+        }
+        inner
+            .order
+            .entry(meta.feature_set.clone())
+            .or_default()
+            .push(meta.id.clone());
+        if let Some(label) = &meta.label {
+            inner
+                .labels
+                .entry((meta.feature_set.clone(), label.clone()))
+                .or_default()
+                .push(meta.id.clone());
+        }
+        inner.checkpoints.insert(meta.id.clone(), checkpoint);
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<StoredCheckpoint>, CheckpointError> {
+        Ok(self.inner.lock().unwrap().checkpoints.get(id).cloned())
+    }
+
+    fn list(&self, feature_set: &str) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        let inner = self.inner.lock().unwrap();
+        let ids = inner
+            .order
+            .get(feature_set)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        Ok(ids
+            .iter()
+            .filter_map(|id| inner.checkpoints.get(id))
+            .map(|stored| stored.checkpoint.clone())
+            .collect())
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, CheckpointError> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(removed) = inner.checkpoints.remove(id) else {
+            return Ok(false);
+        };
+        let meta = removed.checkpoint;
+        if let Some(ids) = inner.order.get_mut(&meta.feature_set) {
+            ids.retain(|other| other != id);
+        }
+        if let Some(label) = meta.label {
+            if let Some(ids) = inner.labels.get_mut(&(meta.feature_set, label)) {
+                ids.retain(|other| other != id);
+            }
+        }
+        Ok(true)
+    }
+
+    fn find_by_label(
+        &self,
+        feature_set: &str,
+        label: &str,
+    ) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        let inner = self.inner.lock().unwrap();
+        let key = (feature_set.to_string(), label.to_string());
+        let ids = inner
+            .labels
+            .get(&key)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        Ok(ids
+            .iter()
+            .filter_map(|id| inner.checkpoints.get(id))
+            .map(|stored| stored.checkpoint.clone())
+            .collect())
+    }
+}
+
+/// `time` as an RFC 3339 UTC timestamp with millisecond precision, e.g.
+/// `2024-05-01T12:30:00.250Z`.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parse an RFC 3339 UTC timestamp as written by [`rfc3339`], with any
+/// number of fractional digits. Offsets other than `Z` are not supported.
+pub(crate) fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let text = text.strip_suffix('Z').or_else(|| text.strip_suffix('z'))?;
+    let (date, time) = text.split_once(['T', 't'])?;
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    if second > 60 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Days since 1970-01-01 from the civil date (Howard Hinnant's algorithm)
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = u64::try_from(era * 146_097 + doe - 719_468).ok()?;
+    let nanos = format!("{:0<9}", &fraction[..fraction.len().min(9)])
+        .parse::<u32>()
+        .ok()?;
+    let secs = days * 86_400 + hour * 3_600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// Checkpoints kept on disk, one JSON file per checkpoint in a directory per
+/// feature set, so they survive restarts.
+///
+/// Files are written to a temporary name, synced, and renamed into place,
+/// so a crash leaves either the old state or the new one; temporary files
+/// left by an interrupted write are removed by [`open`](Self::open).
+/// Metadata is indexed in memory; states are read from disk on
+/// [`get`](CheckpointStore::get).
+///
+/// Records are plaintext JSON unless the store is opened with
+/// [`open_encrypted`](Self::open_encrypted).
+pub struct FileCheckpointStore {
+    root: PathBuf,
+    /// Metadata of every stored checkpoint, by id.
+    index: Mutex<HashMap<String, StateCheckpoint>>,
+    cipher: Option<Box<dyn CheckpointCipher>>,
+}
+
+/// Encryption of the records a [`FileCheckpointStore`] writes, with a key
+/// the embedder holds.
+///
+/// Use an authenticated cipher (e.g. ChaCha20-Poly1305 or AES-GCM) with a
+/// fresh nonce per record kept in the sealed bytes, so that a tampered
+/// record fails to open rather than restoring altered state.
+///
+/// ```ignore
+/// struct Sealed(ChaCha20Poly1305);
+///
+/// impl CheckpointCipher for Sealed {
+///     fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, CheckpointError> {
+///         let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+///         let sealed = self.0.encrypt(&nonce, plaintext).map_err(|e| CheckpointError::Cipher(e.to_string()))?;
+///         Ok([nonce.as_slice(), &sealed].concat())
+///     }
+///     // open splits off the nonce and decrypts the rest
+/// }
+/// let store = FileCheckpointStore::open_encrypted(dir, Sealed(ChaCha20Poly1305::new(&key)))?;
+/// ```
+pub trait CheckpointCipher: Send + Sync {
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, CheckpointError>;
+
+    /// Fails if `sealed` was not produced by [`seal`](Self::seal) with the
+    /// same key, or was altered since.
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, CheckpointError>;
+}
+
+const CHECKPOINT_EXTENSION: &str = "json";
+/// Encrypted records, so they are never mistaken for plaintext ones.
+const SEALED_EXTENSION: &str = "sealed";
+pub(crate) const TEMP_EXTENSION: &str = "tmp";
+
+impl FileCheckpointStore {
+    /// Open the store in `root`, creating the directory if needed and
+    /// indexing the checkpoints already there. Files that cannot be read
+    /// as checkpoints are logged and skipped.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, CheckpointError> {
+        Self::open_with(root.into(), None)
+    }
+
+    /// Like [`open`](Self::open), but records are sealed with `cipher` when
+    /// written and opened with it when read. Plaintext records in `root`
+    /// are skipped.
+    pub fn open_encrypted(
+        root: impl Into<PathBuf>,
+        cipher: impl CheckpointCipher + 'static,
+    ) -> Result<Self, CheckpointError> {
+        Self::open_with(root.into(), Some(Box::new(cipher)))
+    }
+
+    fn open_with(
+        root: PathBuf,
+        cipher: Option<Box<dyn CheckpointCipher>>,
+    ) -> Result<Self, CheckpointError> {
+        let extension = record_extension(cipher.is_some());
+        fs::create_dir_all(&root)?;
+        let mut index = HashMap::new();
+        for dir in fs::read_dir(&root)? {
+            let dir = dir?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(&dir)? {
+                let path = file?.path();
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some(TEMP_EXTENSION) => {
+                        tracing::debug!("Removing interrupted checkpoint write {}", path.display());
+                        fs::remove_file(&path)?;
+                    }
+                    Some(ext) if ext == extension => {
+                        match read_checkpoint(&path, cipher.as_deref()) {
+                            Ok(stored) => {
+                                index.insert(stored.checkpoint.id.clone(), stored.checkpoint);
+                            }
+                            Err(e) => {
+                                tracing::warn!(
+                                    "Skipping unreadable checkpoint {}: {}",
+                                    path.display(),
+                                    e
+                                );
+                            }
+                        }
+                    }
+                    Some(CHECKPOINT_EXTENSION | SEALED_EXTENSION) => {
+                        tracing::warn!(
+                            "Skipping checkpoint {} written {} encryption",
+                            path.display(),
+                            if cipher.is_some() { "without" } else { "with" }
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Self {
+            root,
+            index: Mutex::new(index),
+            cipher,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, checkpoint: &StateCheckpoint) -> PathBuf {
+        self.root
+            .join(file_name(&checkpoint.feature_set))
+            .join(file_name(&checkpoint.id))
+            .with_extension(record_extension(self.cipher.is_some()))
+    }
+}
+
+impl fmt::Debug for FileCheckpointStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCheckpointStore")
+            .field("root", &self.root)
+            .field("encrypted", &self.cipher.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CheckpointStore for FileCheckpointStore {
+    fn create(&self, checkpoint: StoredCheckpoint) -> Result<(), CheckpointError> {
+        let mut index = self.index.lock().unwrap();
+        let meta = &checkpoint.checkpoint;
+        if index.contains_key(&meta.id) {
+            return Err(CheckpointError::Duplicate(meta.id.clone()));
+        }
+        if let Some(parent) = meta.parent.as_ref() {
+            if !index.contains_key(parent) {
+                return Err(CheckpointError::NotFound(parent.clone()));
+            }
+        }
+        let path = self.path(meta);
+        fs::create_dir_all(path.parent().expect("checkpoint paths have a directory"))?;
+        let mut bytes = serde_json::to_vec(&checkpoint)?;
+        if let Some(cipher) = &self.cipher {
+            bytes = cipher.seal(&bytes)?;
+        }
+        write_atomic(&path, &bytes)?;
+        index.insert(meta.id.clone(), checkpoint.checkpoint);
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<StoredCheckpoint>, CheckpointError> {
+        let Some(meta) = self.index.lock().unwrap().get(id).cloned() else {
+            return Ok(None);
+        };
+        match read_checkpoint(&self.path(&meta), self.cipher.as_deref()) {
+            Ok(stored) => Ok(Some(stored)),
+            // Deleted since the index was read
+            Err(CheckpointError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checkpoints of `feature_set`, oldest first by timestamp.
+    fn list(&self, feature_set: &str) -> Result<Vec<StateCheckpoint>, CheckpointError> {
+        let mut checkpoints: Vec<StateCheckpoint> = self
+            .index
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|meta| meta.feature_set == feature_set)
+            .cloned()
+            .collect();
+        checkpoints.sort_by(|a, b| (&a.timestamp, &a.id).cmp(&(&b.timestamp, &b.id)));
+        Ok(checkpoints)
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, CheckpointError> {
+        let mut index = self.index.lock().unwrap();
+        let Some(meta) = index.get(id) else {
+            return Ok(false);
+        };
+        let path = self.path(meta);
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        sync_dir(path.parent().expect("checkpoint paths have a directory"))?;
+        index.remove(id);
+        Ok(true)
+    }
+}
+
+fn record_extension(encrypted: bool) -> &'static str {
+    if encrypted {
+        SEALED_EXTENSION
+    } else {
+        CHECKPOINT_EXTENSION
+    }
+}
+
+fn read_checkpoint(
+    path: &Path,
+    cipher: Option<&dyn CheckpointCipher>,
+) -> Result<StoredCheckpoint, CheckpointError> {
+    let mut bytes = fs::read(path)?;
+    if let Some(cipher) = cipher {
+        bytes = cipher.open(&bytes)?;
+    }
+    let stored: StoredCheckpoint = serde_json::from_slice(&bytes)?;
+    // A sealed record moved to another checkpoint's file still opens
+    let meta = &stored.checkpoint;
+    let expected = Path::new(&file_name(&meta.feature_set)).join(file_name(&meta.id));
+    if !path.with_extension("").ends_with(&expected) {
+        return Err(CheckpointError::Cipher(format!(
+            "record of checkpoint {} found at {}",
+            meta.id,
+            path.display()
+        )));
+    }
+    Ok(stored)
+}
+
+/// Write `bytes` to `path` so that readers, and a restart after a crash,
+/// see either the previous file or all of the new one.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp = path.with_extension(TEMP_EXTENSION);
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp, path)?;
+    sync_dir(path.parent().expect("checkpoint paths have a directory"))
+}
+
+/// Persist a directory's entries (creations, renames, removals).
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// `name` as a single path component: characters other than ASCII letters,
+/// digits, `-` and `_` are written as `%XX` bytes, so ids and feature set
+/// names cannot escape the store or collide with its extensions.
+fn file_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
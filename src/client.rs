@@ -0,0 +1,257 @@
+use crate::capabilities::*;
+use crate::connection::{decode_result, ConnectionError, ConnectionHandle, McplConnection};
+use crate::inference::InferenceStream;
+use crate::methods::*;
+use crate::types::JsonRpcId;
+
+/// Typed calls for the MCPL methods, in both directions, over a connection.
+///
+/// ```ignore
+/// let client = McplClient::new(connection.handle());
+/// let result = client.state_rollback("game", "cp-3").await?;
+/// ```
+///
+/// Responses are routed by the connection's reader task, but something must
+/// still drive the connection (e.g. a serve loop) to see incoming requests.
+#[derive(Clone)]
+pub struct McplClient {
+    conn: ConnectionHandle,
+}
+
+impl McplClient {
+    pub fn new(conn: ConnectionHandle) -> Self {
+        Self { conn }
+    }
+
+    /// The underlying handle, for methods without a typed wrapper.
+    pub fn connection(&self) -> &ConnectionHandle {
+        &self.conn
+    }
+
+    /// Send `initialize`, then `notifications/initialized` once the server
+    /// has answered.
+    pub async fn initialize(
+        &self,
+        params: &McplInitializeParams,
+    ) -> Result<McplInitializeResult, ConnectionError> {
+        let result = self
+            .conn
+            .send_request_typed(method::INITIALIZE, params)
+            .await?;
+        self.conn
+            .send_notification(method::NOTIFICATIONS_INITIALIZED, None)
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn ping(&self) -> Result<(), ConnectionError> {
+        self.conn.send_request(method::PING, None).await?;
+        Ok(())
+    }
+
+    pub async fn feature_sets_update(
+        &self,
+        params: &FeatureSetsUpdateParams,
+    ) -> Result<(), ConnectionError> {
+        self.conn
+            .send_notification_typed(method::FEATURE_SETS_UPDATE, params)
+            .await
+    }
+
+    pub async fn feature_sets_changed(
+        &self,
+        params: &FeatureSetsChangedParams,
+    ) -> Result<(), ConnectionError> {
+        self.conn
+            .send_notification_typed(method::FEATURE_SETS_CHANGED, params)
+            .await
+    }
+
+    pub async fn feature_sets_list(&self) -> Result<FeatureSetsListResult, ConnectionError> {
+        let result = self.conn.request_raw(method::FEATURE_SETS_LIST, None).await?;
+        decode_result(method::FEATURE_SETS_LIST, &result)
+    }
+
+    pub async fn scope_elevate(
+        &self,
+        params: &ScopeElevateParams,
+    ) -> Result<ScopeElevateResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::SCOPE_ELEVATE, params)
+            .await
+    }
+
+    pub async fn state_rollback(
+        &self,
+        feature_set: impl Into<String>,
+        checkpoint: impl Into<String>,
+    ) -> Result<StateRollbackResult, ConnectionError> {
+        let params = StateRollbackParams {
+            feature_set: feature_set.into(),
+            checkpoint: checkpoint.into(),
+        };
+        self.conn
+            .send_request_typed(method::STATE_ROLLBACK, &params)
+            .await
+    }
+
+    pub async fn state_checkpoints_list(
+        &self,
+        params: &StateCheckpointsListParams,
+    ) -> Result<StateCheckpointsListResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::STATE_CHECKPOINTS_LIST, params)
+            .await
+    }
+
+    /// Every checkpoint of `feature_set`, following `nextCursor` across pages.
+    pub async fn state_checkpoints_list_all(
+        &self,
+        feature_set: impl Into<String>,
+    ) -> Result<Vec<StateCheckpoint>, ConnectionError> {
+        let mut params = StateCheckpointsListParams {
+            feature_set: Some(feature_set.into()),
+            ..Default::default()
+        };
+        let mut checkpoints = Vec::new();
+        loop {
+            let page = self.state_checkpoints_list(&params).await?;
+            checkpoints.extend(page.checkpoints);
+            match page.next_cursor {
+                Some(cursor) => params.cursor = Some(cursor),
+                None => return Ok(checkpoints),
+            }
+        }
+    }
+
+    pub async fn state_checkpoint_create(
+        &self,
+        params: &StateCheckpointCreateParams,
+    ) -> Result<StateCheckpointCreateResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::STATE_CHECKPOINT_CREATE, params)
+            .await
+    }
+
+    pub async fn push_event(
+        &self,
+        params: &PushEventParams,
+    ) -> Result<PushEventResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::PUSH_EVENT, params)
+            .await
+    }
+
+    pub async fn push_event_batch(
+        &self,
+        events: Vec<PushEventParams>,
+    ) -> Result<PushEventBatchResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::PUSH_EVENT_BATCH, &PushEventBatchParams { events })
+            .await
+    }
+
+    /// Tell the server an inference it is preparing context for will not
+    /// happen.
+    pub async fn context_cancel(
+        &self,
+        params: &ContextCancelParams,
+    ) -> Result<(), ConnectionError> {
+        self.conn
+            .send_notification_typed(method::CONTEXT_CANCEL, params)
+            .await
+    }
+
+    pub async fn inference_request(
+        &self,
+        params: &InferenceRequestParams,
+    ) -> Result<InferenceRequestResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::INFERENCE_REQUEST, params)
+            .await
+    }
+
+    /// Send `inference/request` with `stream: true`, yielding the host's
+    /// chunks as they arrive.
+    pub async fn request_inference_streaming(
+        &self,
+        params: &InferenceRequestParams,
+    ) -> Result<InferenceStream, ConnectionError> {
+        let params = InferenceRequestParams {
+            stream: Some(true),
+            ..params.clone()
+        };
+        let params = serde_json::to_value(&params)?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let response = self
+            .conn
+            .send_request_streaming(method::INFERENCE_REQUEST, Some(params), tx)
+            .await?;
+        Ok(InferenceStream::new(self.conn.clone(), response, rx))
+    }
+
+    /// Tell the host the answer to the `inference/request` with id
+    /// `request_id` is no longer needed.
+    pub async fn inference_cancel(
+        &self,
+        request_id: JsonRpcId,
+        reason: Option<String>,
+    ) -> Result<(), ConnectionError> {
+        let params = InferenceCancelParams { request_id, reason };
+        self.conn
+            .send_notification_typed(method::INFERENCE_CANCEL, &params)
+            .await
+    }
+
+    pub async fn model_info(&self) -> Result<ModelInfoResult, ConnectionError> {
+        let result = self.conn.request_raw(method::MODEL_INFO, None).await?;
+        decode_result(method::MODEL_INFO, &result)
+    }
+
+    pub async fn channels_list(&self) -> Result<ChannelsListResult, ConnectionError> {
+        let result = self.conn.request_raw(method::CHANNELS_LIST, None).await?;
+        decode_result(method::CHANNELS_LIST, &result)
+    }
+
+    pub async fn channels_open(
+        &self,
+        params: &ChannelsOpenParams,
+    ) -> Result<ChannelsOpenResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::CHANNELS_OPEN, params)
+            .await
+    }
+
+    pub async fn channels_close(
+        &self,
+        channel_id: impl Into<String>,
+    ) -> Result<ChannelsCloseResult, ConnectionError> {
+        let params = ChannelsCloseParams {
+            channel_id: channel_id.into(),
+        };
+        self.conn
+            .send_request_typed(method::CHANNELS_CLOSE, &params)
+            .await
+    }
+
+    pub async fn channels_publish(
+        &self,
+        params: &ChannelsPublishParams,
+    ) -> Result<ChannelsPublishResult, ConnectionError> {
+        self.conn
+            .send_request_typed(method::CHANNELS_PUBLISH, params)
+            .await
+    }
+}
+
+impl From<ConnectionHandle> for McplClient {
+    fn from(conn: ConnectionHandle) -> Self {
+        Self::new(conn)
+    }
+}
+
+impl From<&McplConnection> for McplClient {
+    fn from(conn: &McplConnection) -> Self {
+        Self::new(conn.handle())
+    }
+}
@@ -1,10 +1,33 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::value::{to_raw_value, RawValue};
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
 
+use crate::capabilities::McplCapabilities;
+use crate::id::{IdGenerator, SequentialIds};
+use crate::interceptor::{Intercept, Interceptor};
+use crate::methods::{
+    method, CancelledParams, InferenceCancelParams, InferenceChunkParams, ProgressParams,
+};
+use crate::request::RequestContext;
+use crate::session::{McplSession, SessionState};
+use crate::version::VersionMismatch;
+use crate::wiretap::{FrameDirection, WireFrame, Wiretap};
 use crate::types::*;
 
+pub use tokio_util::sync::CancellationToken;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConnectionError {
     #[error("IO error: {0}")]
@@ -13,14 +36,106 @@ pub enum ConnectionError {
     Json(#[from] serde_json::Error),
     #[error("Connection closed")]
     Closed,
+    #[error("Write side of the connection is closed")]
+    WriteClosed,
     #[error("Request timed out")]
     Timeout,
+    #[error("Request cancelled")]
+    Cancelled,
     #[error("RPC error {code}: {message}")]
     Rpc { code: i32, message: String },
     #[error("Unrecognized JSON-RPC message: {0}")]
     UnrecognizedMessage(String),
+    #[error("Cannot send {method} while connection is {state:?}")]
+    NotReady {
+        method: String,
+        state: ConnectionState,
+    },
+    #[error("Invalid JSON-RPC message: {reason}")]
+    InvalidMessage {
+        /// Id of the offending message, when it could be read.
+        id: Option<JsonRpcId>,
+        /// Whether the offending message was shaped like a response.
+        response: bool,
+        reason: String,
+    },
+    #[error("Rejected by interceptor ({code}): {message}")]
+    Rejected { code: i32, message: String },
+    #[error("Incoming queue full; {dropped} message(s) dropped")]
+    QueueFull { dropped: usize },
+    #[error("Unexpected {kind} response for id {id:?}")]
+    UnexpectedResponse {
+        id: JsonRpcId,
+        kind: UnexpectedResponseKind,
+    },
+    #[error("Cannot send {method}: capability not negotiated")]
+    NotNegotiated { method: String },
+    #[error(transparent)]
+    VersionMismatch(#[from] VersionMismatch),
+    #[error("Invalid result for {method}: {source}")]
+    InvalidResult {
+        method: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Lifecycle of a connection around the MCP `initialize` handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No `initialize` request has been exchanged yet.
+    Uninitialized,
+    /// An `initialize` request is awaiting its response.
+    Initializing,
+    /// `initialize` succeeded; all methods are available.
+    Ready,
+    /// [`McplConnection::close`] is shutting the transport down, or the peer
+    /// has closed its side and responses can still be flushed.
+    Closing,
+    /// The transport is closed.
+    Closed,
+}
+
+/// Methods usable before the handshake completes.
+fn allowed_before_ready(method: &str) -> bool {
+    method == method::INITIALIZE || method == method::PING || method.starts_with("notifications/")
+}
+
+/// Why a response could not be matched to an outstanding request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnexpectedResponseKind {
+    /// No request with this id was sent (or it was abandoned by its caller).
+    Unknown,
+    /// The request with this id was already answered.
+    Duplicate,
+}
+
+impl fmt::Display for UnexpectedResponseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnexpectedResponseKind::Unknown => f.write_str("unknown"),
+            UnexpectedResponseKind::Duplicate => f.write_str("duplicate"),
+        }
+    }
+}
+
+/// What to do with responses that match no outstanding request.
+#[derive(Clone, Default)]
+pub enum UnexpectedResponsePolicy {
+    /// Drop silently.
+    Ignore,
+    /// Drop and log a warning.
+    #[default]
+    Warn,
+    /// Surface [`ConnectionError::UnexpectedResponse`] from `next_message`.
+    Error,
+    /// Hand the response to a callback.
+    Callback(UnexpectedResponseCallback),
 }
 
+pub type UnexpectedResponseCallback =
+    Arc<dyn Fn(&JsonRpcResponse, UnexpectedResponseKind) + Send + Sync>;
+
 /// Incoming message from the remote side — either a request or notification.
 #[derive(Debug)]
 pub enum IncomingMessage {
@@ -28,18 +143,495 @@ pub enum IncomingMessage {
     Notification(JsonRpcNotification),
 }
 
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+pub(crate) type ResponseResult = Result<Box<RawValue>, ConnectionError>;
+
+/// Construction-time settings for [`McplConnection`].
+pub struct ConnectionOptions {
+    /// Generates ids for outgoing requests. Defaults to [`SequentialIds`].
+    pub id_generator: Box<dyn IdGenerator>,
+    /// Handling of unknown and duplicate responses.
+    pub unexpected_responses: UnexpectedResponsePolicy,
+    /// Maximum number of incoming messages buffered for `next_message`.
+    /// `None` means unbounded, the default.
+    pub incoming_capacity: Option<usize>,
+    /// What to do when an incoming message arrives and the buffer is full.
+    pub overflow: OverflowPolicy,
+    /// Write through a background task fed by a prioritized queue instead of
+    /// writing inline. Sends then return once queued, and responses overtake
+    /// queued requests, which overtake queued notifications.
+    pub background_writer: bool,
+    /// Time allowed for answering each incoming request, measured from its
+    /// arrival and exposed as [`RequestContext::deadline`]. Advisory only.
+    pub request_deadline: Option<Duration>,
+    /// Answer `ping` requests with an empty result before they reach
+    /// `next_message`.
+    pub answer_pings: bool,
+    /// Refuse methods other than `initialize`, `ping`, and `notifications/*`
+    /// until the handshake completes: outgoing sends fail with
+    /// [`ConnectionError::NotReady`], incoming requests are answered with
+    /// `ERR_NOT_INITIALIZED`.
+    pub enforce_lifecycle: bool,
+    /// Once the handshake completes, refuse MCPL methods whose capability
+    /// was not advertised by both sides of `initialize`: outgoing sends fail
+    /// with [`ConnectionError::NotNegotiated`], incoming requests are
+    /// answered with `ERR_FEATURE_SET_NOT_ENABLED`.
+    pub enforce_capabilities: bool,
+    /// Validate incoming frames strictly: `jsonrpc` must be `"2.0"`, a
+    /// response may not carry both `result` and `error`, and `params` must be
+    /// an object or array. Violating requests are answered with
+    /// `-32600 Invalid Request`; violating responses fail their caller with
+    /// that error. Either way the connection stays up.
+    pub strict: bool,
+    /// Skip frames that fail to parse instead of reporting them from
+    /// `next_message`. Each is passed to `on_parse_error`, and answered with
+    /// an error response when its id can be salvaged.
+    pub recover_parse_errors: bool,
+    pub on_parse_error: Option<ParseErrorCallback>,
+    /// Treat the connection as idle after this long without a frame in
+    /// either direction, and apply `on_idle`.
+    pub idle_timeout: Option<Duration>,
+    pub on_idle: IdlePolicy,
+    /// Retry outgoing requests that fail with a transient error code.
+    /// Applies to `send_request` and its typed and progress variants, not to
+    /// `send_request_deferred`.
+    pub retry: Option<RetryPolicy>,
+}
+
+/// Backoff schedule and error codes for [`ConnectionOptions::retry`].
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Factor applied to the delay after each retry.
+    pub multiplier: f64,
+    /// Upper bound on any single delay.
+    pub max_backoff: Duration,
+    /// RPC error codes worth retrying. Empty by default, since no standard
+    /// code signals a transient failure.
+    pub retryable_codes: HashSet<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(5),
+            retryable_codes: HashSet::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, attempt: u32, err: &ConnectionError) -> bool {
+        attempt < self.max_attempts
+            && matches!(err, ConnectionError::Rpc { code, .. } if self.retryable_codes.contains(code))
+    }
+
+    /// Delay before retry number `retry` (starting at 1).
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.saturating_sub(1) as i32);
+        self.initial_backoff.mul_f64(factor).min(self.max_backoff)
+    }
+}
+
+/// What to do when a connection exceeds [`ConnectionOptions::idle_timeout`].
+#[derive(Clone, Default)]
+pub enum IdlePolicy {
+    /// Shut down the transport; `next_message` then reports
+    /// [`ConnectionError::Closed`].
+    #[default]
+    Close,
+    /// Leave the connection open and call back with the time since the last
+    /// frame, once per idle period.
+    Callback(IdleCallback),
+}
+
+pub type IdleCallback = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// A frame skipped under [`ConnectionOptions::recover_parse_errors`].
+#[derive(Debug, Clone)]
+pub struct ParseErrorEvent {
+    /// The offending frame.
+    pub raw: String,
+    pub error: String,
+    /// Id salvaged from the frame, if any.
+    pub id: Option<JsonRpcId>,
+}
+
+pub type ParseErrorCallback = Arc<dyn Fn(&ParseErrorEvent) + Send + Sync>;
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            id_generator: Box::new(SequentialIds::default()),
+            unexpected_responses: UnexpectedResponsePolicy::default(),
+            incoming_capacity: None,
+            overflow: OverflowPolicy::default(),
+            background_writer: false,
+            request_deadline: None,
+            answer_pings: false,
+            enforce_lifecycle: false,
+            enforce_capabilities: false,
+            strict: false,
+            recover_parse_errors: false,
+            on_parse_error: None,
+            idle_timeout: None,
+            on_idle: IdlePolicy::default(),
+            retry: None,
+        }
+    }
+}
+
+/// Behavior of a full incoming buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the oldest buffered message to make room.
+    DropOldest,
+    /// Discard the message that just arrived.
+    DropNewest,
+    /// Discard the message that just arrived and report
+    /// [`ConnectionError::QueueFull`] from the next `next_message` call.
+    Error,
+    /// Stop reading from the transport until the application has drained
+    /// the buffer to half its capacity, letting transport flow control (e.g.
+    /// the TCP window) push back on the peer.
+    ///
+    /// Responses are not read while paused either, so a `send_request` issued
+    /// without draining `next_message` concurrently can stall.
+    #[default]
+    Backpressure,
+}
+
+/// How many answered request ids are remembered for duplicate detection.
+const RECENT_RESPONSE_IDS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Completion {
+    Answered,
+    Cancelled,
+}
+
 /// Bidirectional async JSON-RPC 2.0 connection.
 ///
 /// Messages are framed as newline-delimited JSON (one JSON object per line).
 /// Transport-agnostic: works over TCP, stdio, or any async reader/writer pair.
 ///
-/// Incoming messages received while `send_request` is waiting for a response
-/// are buffered and returned by subsequent `next_message` calls.
+/// A background task reads from the transport, resolves responses to
+/// requests sent with `send_request`, and queues incoming requests and
+/// notifications for `next_message`. The queue is unbounded unless
+/// [`ConnectionOptions::incoming_capacity`] is set (see [`OverflowPolicy`]).
+/// Must be constructed inside a Tokio runtime.
 pub struct McplConnection {
-    writer: Box<dyn AsyncWrite + Unpin + Send>,
-    reader: BufReader<Box<dyn AsyncRead + Unpin + Send>>,
-    next_id: i64,
-    incoming_buffer: VecDeque<IncomingMessage>,
+    shared: Arc<Shared>,
+}
+
+/// State shared between the connection, its reader task, and any handles.
+struct Shared {
+    sink: Sink,
+    /// Outgoing requests awaiting a response, keyed by request id.
+    pending: Mutex<HashMap<JsonRpcId, oneshot::Sender<ResponseResult>>>,
+    /// Incoming requests not yet answered, with the token fired when the
+    /// peer cancels them.
+    inflight: Mutex<HashMap<JsonRpcId, InflightRequest>>,
+    request_deadline: Option<Duration>,
+    /// Progress listeners for outgoing requests, keyed by progress token.
+    progress: Mutex<HashMap<ProgressToken, mpsc::UnboundedSender<ProgressParams>>>,
+    /// Chunk listeners for streamed `inference/request`s, keyed by request
+    /// id.
+    chunks: Mutex<HashMap<JsonRpcId, mpsc::UnboundedSender<InferenceChunkParams>>>,
+    /// Methods the application handles; other requests are rejected by the
+    /// reader task. `None` delivers every request.
+    claimed_methods: Mutex<Option<HashSet<String>>>,
+    /// Recently completed outgoing requests, oldest first.
+    completed: Mutex<VecDeque<(JsonRpcId, Completion)>>,
+    unexpected_responses: UnexpectedResponsePolicy,
+    incoming: IncomingQueue,
+    interceptors: RwLock<Vec<Arc<dyn Interceptor>>>,
+    wiretap: RwLock<Option<Wiretap>>,
+    answer_pings: bool,
+    lifecycle: Mutex<Lifecycle>,
+    enforce_lifecycle: bool,
+    enforce_capabilities: bool,
+    strict: bool,
+    recover_parse_errors: bool,
+    on_parse_error: Option<ParseErrorCallback>,
+    activity: Mutex<Activity>,
+    on_idle: IdlePolicy,
+    /// Fired to stop the reader task and idle watchdog.
+    shutdown: CancellationToken,
+    read_closed: AtomicBool,
+    write_closed: AtomicBool,
+    ids: Mutex<Box<dyn IdGenerator>>,
+    retry: Option<RetryPolicy>,
+    session: SessionState,
+}
+
+/// Notifications about an outgoing request that go to its sender rather
+/// than to `next_message`.
+#[derive(Clone)]
+enum Listener {
+    Progress(mpsc::UnboundedSender<ProgressParams>),
+    Chunks(mpsc::UnboundedSender<InferenceChunkParams>),
+}
+
+struct Activity {
+    opened_at: Instant,
+    last_received: Option<Instant>,
+    last_sent: Option<Instant>,
+}
+
+impl Activity {
+    fn last(&self) -> Instant {
+        [self.last_received, self.last_sent]
+            .into_iter()
+            .flatten()
+            .fold(self.opened_at, Instant::max)
+    }
+}
+
+struct Lifecycle {
+    state: ConnectionState,
+    /// Id of the `initialize` request in flight, in either direction.
+    init_id: Option<JsonRpcId>,
+    /// MCPL capabilities from the `initialize` request in flight.
+    offered: Option<McplCapabilities>,
+    /// Capabilities of both sides once the handshake has completed.
+    negotiated: Option<[Option<McplCapabilities>; 2]>,
+}
+
+/// Where outgoing frames go.
+enum Sink {
+    /// Written inline by the sending task.
+    Direct(tokio::sync::Mutex<BoxedWriter>),
+    /// Queued for the background writer task.
+    Queued(Arc<OutboundQueue>),
+}
+
+/// Priority lanes of the outbound queue, highest first.
+const LANE_RESPONSE: usize = 0;
+const LANE_REQUEST: usize = 1;
+const LANE_NOTIFICATION: usize = 2;
+
+/// Prioritized frames awaiting the background writer task.
+struct OutboundQueue {
+    state: Mutex<OutboundState>,
+    ready: Notify,
+    /// Notified when the writer has written everything queued, or stopped.
+    idle: Notify,
+}
+
+#[derive(Default)]
+struct OutboundState {
+    lanes: [VecDeque<String>; 3],
+    /// The writer has taken a frame and not finished writing it.
+    writing: bool,
+    /// No more frames are accepted; the writer drains what is left and exits.
+    closed: bool,
+    /// The writer hit an IO error and stopped.
+    failed: bool,
+}
+
+impl OutboundQueue {
+    fn push(&self, lane: usize, line: String) -> Result<(), ConnectionError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.failed {
+            return Err(ConnectionError::WriteClosed);
+        }
+        state.lanes[lane].push_back(line);
+        drop(state);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    async fn pop(&self) -> Option<String> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(line) = state.lanes.iter_mut().find_map(|lane| lane.pop_front()) {
+                    state.writing = true;
+                    return Some(line);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    /// Record that the writer finished a frame, successfully or not.
+    fn written(&self, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.writing = false;
+        state.failed |= !ok;
+        let idle = state.failed || state.lanes.iter().all(VecDeque::is_empty);
+        drop(state);
+        if idle {
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// Wait until every queued frame has been written, or the writer failed.
+    async fn flushed(&self) {
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let state = self.state.lock().unwrap();
+                if state.failed || (!state.writing && state.lanes.iter().all(VecDeque::is_empty)) {
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+async fn write_loop(mut writer: BoxedWriter, queue: Arc<OutboundQueue>) {
+    while let Some(line) = queue.pop().await {
+        let result = async {
+            writer.write_all(line.as_bytes()).await?;
+            writer.flush().await
+        }
+        .await;
+        queue.written(result.is_ok());
+        if let Err(e) = result {
+            tracing::warn!("Background writer stopped: {}", e);
+            return;
+        }
+    }
+}
+
+/// Bookkeeping for an incoming request that has not been answered yet.
+struct InflightRequest {
+    cancel: CancellationToken,
+    received_at: Instant,
+}
+
+type IncomingItem = Result<IncomingMessage, ConnectionError>;
+
+/// Buffer between the reader task and `next_message`, with a single
+/// producer and a single consumer.
+struct IncomingQueue {
+    state: Mutex<QueueState>,
+    capacity: Option<usize>,
+    overflow: OverflowPolicy,
+    readable: Notify,
+    writable: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<IncomingItem>,
+    /// Messages discarded under [`OverflowPolicy::Error`] and not yet reported.
+    unreported_drops: usize,
+    /// The reader is waiting for room under [`OverflowPolicy::Backpressure`].
+    paused: bool,
+    closed: bool,
+}
+
+impl IncomingQueue {
+    fn new(capacity: Option<usize>, overflow: OverflowPolicy) -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            capacity,
+            overflow,
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().items.len()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    async fn push(&self, item: IncomingItem) {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                // Nobody will read it
+                if state.closed {
+                    return;
+                }
+                let len = state.items.len();
+                let full = match self.capacity {
+                    // Once paused, resume only after draining to half capacity
+                    // so reading does not flap on every message.
+                    Some(cap) if state.paused => len > cap / 2,
+                    Some(cap) => len >= cap,
+                    None => false,
+                };
+                if !full {
+                    state.paused = false;
+                    state.items.push_back(item);
+                    break;
+                }
+                match self.overflow {
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                        state.items.push_back(item);
+                        tracing::warn!("Incoming queue full, dropped oldest message");
+                        break;
+                    }
+                    OverflowPolicy::DropNewest => {
+                        tracing::warn!("Incoming queue full, dropped newest message");
+                        return;
+                    }
+                    OverflowPolicy::Error => {
+                        state.unreported_drops += 1;
+                        break;
+                    }
+                    OverflowPolicy::Backpressure => state.paused = true,
+                }
+            }
+            self.writable.notified().await;
+        }
+        self.readable.notify_one();
+    }
+
+    async fn pop(&self) -> Option<IncomingItem> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.unreported_drops > 0 {
+                    let dropped = std::mem::take(&mut state.unreported_drops);
+                    return Some(Err(ConnectionError::QueueFull { dropped }));
+                }
+                if let Some(item) = state.items.pop_front() {
+                    drop(state);
+                    self.writable.notify_one();
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_one();
+        // Release a reader waiting for room
+        self.writable.notify_one();
+    }
 }
 
 impl McplConnection {
@@ -50,65 +642,141 @@ impl McplConnection {
 
     /// Create from a TCP stream (explicit name).
     pub fn from_tcp(stream: TcpStream) -> Self {
+        Self::from_tcp_with_options(stream, ConnectionOptions::default())
+    }
+
+    /// Create from a TCP stream with custom options.
+    pub fn from_tcp_with_options(stream: TcpStream, options: ConnectionOptions) -> Self {
         let (read_half, write_half) = stream.into_split();
-        Self {
-            writer: Box::new(write_half),
-            reader: BufReader::new(Box::new(read_half) as Box<dyn AsyncRead + Unpin + Send>),
-            next_id: 1,
-            incoming_buffer: VecDeque::new(),
-        }
+        Self::from_parts_with_options(Box::new(read_half), Box::new(write_half), options)
     }
 
     /// Create from arbitrary async reader/writer (e.g., stdin/stdout).
-    pub fn from_parts(
-        reader: Box<dyn AsyncRead + Unpin + Send>,
-        writer: Box<dyn AsyncWrite + Unpin + Send>,
+    pub fn from_parts(reader: BoxedReader, writer: BoxedWriter) -> Self {
+        Self::from_parts_with_options(reader, writer, ConnectionOptions::default())
+    }
+
+    /// Create from arbitrary async reader/writer with custom options.
+    pub fn from_parts_with_options(
+        reader: BoxedReader,
+        writer: BoxedWriter,
+        options: ConnectionOptions,
     ) -> Self {
-        Self {
-            writer,
-            reader: BufReader::new(reader),
-            next_id: 1,
-            incoming_buffer: VecDeque::new(),
+        let sink = if options.background_writer {
+            let queue = Arc::new(OutboundQueue {
+                state: Mutex::new(OutboundState::default()),
+                ready: Notify::new(),
+                idle: Notify::new(),
+            });
+            tokio::spawn(write_loop(writer, Arc::clone(&queue)));
+            Sink::Queued(queue)
+        } else {
+            Sink::Direct(tokio::sync::Mutex::new(writer))
+        };
+        let shared = Arc::new(Shared {
+            sink,
+            pending: Mutex::new(HashMap::new()),
+            inflight: Mutex::new(HashMap::new()),
+            request_deadline: options.request_deadline,
+            progress: Mutex::new(HashMap::new()),
+            chunks: Mutex::new(HashMap::new()),
+            claimed_methods: Mutex::new(None),
+            completed: Mutex::new(VecDeque::new()),
+            unexpected_responses: options.unexpected_responses,
+            incoming: IncomingQueue::new(options.incoming_capacity, options.overflow),
+            interceptors: RwLock::new(Vec::new()),
+            wiretap: RwLock::new(None),
+            answer_pings: options.answer_pings,
+            lifecycle: Mutex::new(Lifecycle {
+                state: ConnectionState::Uninitialized,
+                init_id: None,
+                offered: None,
+                negotiated: None,
+            }),
+            enforce_lifecycle: options.enforce_lifecycle,
+            enforce_capabilities: options.enforce_capabilities,
+            strict: options.strict,
+            recover_parse_errors: options.recover_parse_errors,
+            on_parse_error: options.on_parse_error,
+            activity: Mutex::new(Activity {
+                opened_at: Instant::now(),
+                last_received: None,
+                last_sent: None,
+            }),
+            on_idle: options.on_idle,
+            shutdown: CancellationToken::new(),
+            read_closed: AtomicBool::new(false),
+            write_closed: AtomicBool::new(false),
+            ids: Mutex::new(options.id_generator),
+            retry: options.retry,
+            session: SessionState::default(),
+        });
+        tokio::spawn(read_loop(BufReader::new(reader), Arc::clone(&shared)));
+        if let Some(timeout) = options.idle_timeout {
+            tokio::spawn(idle_watchdog(Arc::downgrade(&shared), timeout));
         }
+        Self { shared }
     }
 
     /// Send a JSON-RPC request and wait for the response.
     ///
     /// Incoming requests and notifications that arrive while waiting are
     /// buffered and returned by subsequent [`next_message`] calls.
+    ///
+    /// Resolves to [`ConnectionError::Cancelled`] if the request is cancelled
+    /// through a [`CancelHandle`] while waiting.
     pub async fn send_request(
         &mut self,
         method: &str,
         params: Option<serde_json::Value>,
     ) -> Result<serde_json::Value, ConnectionError> {
-        let id = self.next_id;
-        self.next_id += 1;
-        let request = JsonRpcRequest::new(id, method, params);
+        self.request(method, params, None).await
+    }
+
+    /// Send a request that asks the peer to report progress.
+    ///
+    /// A progress token (the request id) is attached as `_meta.progressToken`,
+    /// and every matching `notifications/progress` is forwarded to `progress`
+    /// instead of `next_message` until the response arrives.
+    pub async fn send_request_with_progress(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        progress: mpsc::UnboundedSender<ProgressParams>,
+    ) -> Result<serde_json::Value, ConnectionError> {
+        self.request(method, params, Some(Listener::Progress(progress))).await
+    }
 
-        self.write_message(&JsonRpcMessage::Request(request)).await?;
+    /// Send a request without waiting for its response.
+    ///
+    /// The returned [`PendingResponse`] resolves like `send_request` once the
+    /// response arrives, so several requests can be in flight at once and
+    /// awaited together. Dropping it abandons the response.
+    pub async fn send_request_deferred(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<PendingResponse, ConnectionError> {
+        self.start_request(method, params, None).await
+    }
 
-        // Drive reads until we get our response
-        loop {
-            match self.read_next_internal().await? {
-                InternalMessage::Response(resp) => {
-                    if resp.id == JsonRpcId::Number(id) {
-                        if let Some(error) = resp.error {
-                            return Err(ConnectionError::Rpc {
-                                code: error.code,
-                                message: error.message,
-                            });
-                        }
-                        return Ok(resp.result.unwrap_or(serde_json::Value::Null));
-                    }
-                    // Response for a different request — discard (no concurrent callers)
-                    tracing::warn!("Received response for unknown id {:?}", resp.id);
-                }
-                InternalMessage::Incoming(msg) => {
-                    // Buffer incoming requests/notifications for next_message()
-                    self.incoming_buffer.push_back(msg);
-                }
-            }
-        }
+    async fn request(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        listener: Option<Listener>,
+    ) -> Result<serde_json::Value, ConnectionError> {
+        let result = self.shared.request(method, params.map(to_raw), listener).await?;
+        Ok(serde_json::from_str(result.get())?)
+    }
+
+    async fn start_request(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        listener: Option<Listener>,
+    ) -> Result<PendingResponse, ConnectionError> {
+        self.shared.start_request(method, params.map(to_raw), listener).await
     }
 
     /// Send a JSON-RPC notification (no response expected).
@@ -117,8 +785,38 @@ impl McplConnection {
         method: &str,
         params: Option<serde_json::Value>,
     ) -> Result<(), ConnectionError> {
-        let notification = JsonRpcNotification::new(method, params);
-        self.write_message(&JsonRpcMessage::Notification(notification)).await
+        self.shared.send_notification(method, params.map(to_raw)).await
+    }
+
+    /// Send a request with typed params and deserialize its result.
+    ///
+    /// A result that does not match `R` fails with
+    /// [`ConnectionError::InvalidResult`].
+    pub async fn send_request_typed<P, R>(
+        &mut self,
+        method: &str,
+        params: &P,
+    ) -> Result<R, ConnectionError>
+    where
+        P: Serialize + ?Sized,
+        R: DeserializeOwned,
+    {
+        let params = to_raw_value(params)?;
+        let result = self.shared.request(method, Some(params), None).await?;
+        decode_result(method, &result)
+    }
+
+    /// Send a notification with typed params.
+    pub async fn send_notification_typed<P>(
+        &mut self,
+        method: &str,
+        params: &P,
+    ) -> Result<(), ConnectionError>
+    where
+        P: Serialize + ?Sized,
+    {
+        let params = to_raw_value(params)?;
+        self.shared.send_notification(method, Some(params)).await
     }
 
     /// Send a JSON-RPC response (answering an incoming request).
@@ -127,8 +825,9 @@ impl McplConnection {
         id: JsonRpcId,
         result: serde_json::Value,
     ) -> Result<(), ConnectionError> {
-        let response = JsonRpcResponse::success(id, result);
-        self.write_message(&JsonRpcMessage::Response(response)).await
+        self.shared
+            .respond(JsonRpcResponse::success(id, result))
+            .await
     }
 
     /// Send a JSON-RPC error response.
@@ -138,87 +837,1273 @@ impl McplConnection {
         code: i32,
         message: impl Into<String>,
     ) -> Result<(), ConnectionError> {
-        let response = JsonRpcResponse::error(
-            id,
-            JsonRpcError {
-                code,
-                message: message.into(),
-                data: None,
-            },
-        );
-        self.write_message(&JsonRpcMessage::Response(response)).await
+        let error = JsonRpcError {
+            code,
+            message: message.into(),
+            data: None,
+        };
+        self.shared
+            .respond(JsonRpcResponse::error(id, error))
+            .await
     }
 
-    /// Read the next incoming request or notification.
+    /// Register an interceptor at the end of the chain.
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + 'static) {
+        self.shared
+            .interceptors
+            .write()
+            .unwrap()
+            .push(Arc::new(interceptor));
+    }
+
+    /// Observe every raw frame in both directions. Replaces any previous tap;
+    /// `None` removes it.
+    pub fn set_wiretap(&mut self, wiretap: Option<Wiretap>) {
+        *self.shared.wiretap.write().unwrap() = wiretap;
+    }
+
+    /// Convenience for [`set_wiretap`](Self::set_wiretap) with a channel.
+    pub fn wiretap_channel(&mut self) -> mpsc::UnboundedReceiver<WireFrame> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.set_wiretap(Some(Wiretap::Channel(tx)));
+        rx
+    }
+
+    /// Detach the reply to an incoming request from this connection.
     ///
-    /// Drains any messages buffered during `send_request` before reading
-    /// from the wire.
-    pub async fn next_message(&mut self) -> Result<IncomingMessage, ConnectionError> {
-        // Drain buffered messages first
-        if let Some(buffered) = self.incoming_buffer.pop_front() {
-            return Ok(buffered);
+    /// The returned [`Responder`] can be moved to another task and answers
+    /// the request exactly once, without borrowing the connection.
+    pub fn responder(&self, request: &JsonRpcRequest) -> Responder {
+        Responder {
+            shared: Arc::clone(&self.shared),
+            id: Some(request.id.clone()),
         }
+    }
 
-        loop {
-            match self.read_next_internal().await? {
-                InternalMessage::Response(resp) => {
-                    // Unexpected response (no pending request) — discard
-                    tracing::warn!("Received response for unknown id {:?}", resp.id);
-                }
-                InternalMessage::Incoming(msg) => return Ok(msg),
-            }
+    /// Report progress on an incoming request that carried a progress token.
+    pub async fn send_progress(
+        &mut self,
+        token: ProgressToken,
+        progress: f64,
+        total: Option<f64>,
+    ) -> Result<(), ConnectionError> {
+        let params = ProgressParams {
+            progress_token: token,
+            progress,
+            total,
+            message: None,
+        };
+        self.shared
+            .send_notification(
+                method::NOTIFICATIONS_PROGRESS,
+                Some(to_raw_value(&params)?),
+            )
+            .await
+    }
+
+    /// Answer a request the application has no handler for with
+    /// `-32601 Method not found`, so the peer does not wait forever.
+    pub async fn reject_unhandled(&mut self, request: &JsonRpcRequest) -> Result<(), ConnectionError> {
+        self.shared.reject_unhandled(request).await
+    }
+
+    /// Declare the request methods the application handles.
+    ///
+    /// Once set, incoming requests for any other method are answered with
+    /// `-32601 Method not found` automatically and never reach
+    /// `next_message`. Notifications are unaffected.
+    pub fn claim_methods<I, S>(&mut self, methods: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let methods = methods.into_iter().map(Into::into).collect();
+        *self.shared.claimed_methods.lock().unwrap() = Some(methods);
+    }
+
+    /// Cancel an outstanding request sent by this side.
+    ///
+    /// Emits `notifications/cancelled` to the peer and resolves the waiting
+    /// caller with [`ConnectionError::Cancelled`]. Any response that arrives
+    /// afterwards is ignored.
+    pub async fn cancel_request(
+        &mut self,
+        id: JsonRpcId,
+        reason: Option<String>,
+    ) -> Result<(), ConnectionError> {
+        self.shared.cancel_request(id, reason).await
+    }
+
+    /// A cloneable handle for sending from other tasks, e.g. while another
+    /// task drives `next_message`.
+    pub fn handle(&self) -> ConnectionHandle {
+        ConnectionHandle {
+            shared: Arc::clone(&self.shared),
         }
     }
 
-    async fn write_message(&mut self, msg: &JsonRpcMessage) -> Result<(), ConnectionError> {
-        let mut line = serde_json::to_string(msg)?;
-        line.push('\n');
-        self.writer.write_all(line.as_bytes()).await?;
-        self.writer.flush().await?;
-        Ok(())
+    /// Negotiated state of this connection.
+    pub fn session(&self) -> McplSession {
+        McplSession::new(self.handle())
     }
 
-    async fn read_next_internal(&mut self) -> Result<InternalMessage, ConnectionError> {
-        loop {
-            let mut line = String::new();
-            let bytes_read = self.reader.read_line(&mut line).await?;
-            if bytes_read == 0 {
-                return Err(ConnectionError::Closed);
-            }
-
-            let trimmed = line.trim();
-            if trimmed.is_empty() {
-                continue;
-            }
-
-            // JSON-RPC distinguishes by presence of `id` and `method`:
-            //   Request:      has `id` + `method`
-            //   Response:     has `id` + (`result` or `error`)
-            //   Notification: has `method`, no `id`
-            let value: serde_json::Value = serde_json::from_str(trimmed)?;
-
-            let has_id = value.get("id").is_some();
-            let has_method = value.get("method").is_some();
-            let has_result = value.get("result").is_some();
-            let has_error = value.get("error").is_some();
-
-            if has_id && has_method {
-                let request: JsonRpcRequest = serde_json::from_value(value)?;
-                return Ok(InternalMessage::Incoming(IncomingMessage::Request(request)));
-            } else if has_id && (has_result || has_error) {
-                let response: JsonRpcResponse = serde_json::from_value(value)?;
-                return Ok(InternalMessage::Response(response));
-            } else if has_method && !has_id {
-                let notification: JsonRpcNotification = serde_json::from_value(value)?;
-                return Ok(InternalMessage::Incoming(IncomingMessage::Notification(notification)));
-            } else {
-                return Err(ConnectionError::UnrecognizedMessage(trimmed.to_string()));
+    /// Get a cloneable handle for cancelling requests from other tasks while
+    /// this connection is busy in `send_request`.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Wrap an incoming request with its responder, cancellation token, and
+    /// deadline for handing to handler code.
+    pub fn request_context(&self, request: JsonRpcRequest) -> RequestContext {
+        let (cancel, deadline) = {
+            let inflight = self.shared.inflight.lock().unwrap();
+            match inflight.get(&request.id) {
+                Some(entry) => (
+                    entry.cancel.clone(),
+                    self.shared.request_deadline.map(|d| entry.received_at + d),
+                ),
+                None => (CancellationToken::new(), None),
             }
+        };
+        let responder = self.responder(&request);
+        RequestContext::new(request, responder, cancel, deadline, self.session())
+    }
+
+    /// Token that fires when the peer cancels the given incoming request via
+    /// `notifications/cancelled`, or `inference/cancel` for an
+    /// `inference/request`.
+    ///
+    /// Returns `None` if the request is unknown or has already been answered.
+    pub fn cancellation_token(&self, id: &JsonRpcId) -> Option<CancellationToken> {
+        let inflight = self.shared.inflight.lock().unwrap();
+        inflight.get(id).map(|entry| entry.cancel.clone())
+    }
+
+    /// Read the next incoming request or notification.
+    ///
+    /// Returns messages in arrival order, including those that arrived while
+    /// `send_request` was waiting for its response.
+    pub async fn next_message(&mut self) -> Result<IncomingMessage, ConnectionError> {
+        match self.shared.incoming.pop().await {
+            Some(result) => result,
+            None => Err(ConnectionError::Closed),
+        }
+    }
+
+    /// Current lifecycle state.
+    pub fn state(&self) -> ConnectionState {
+        self.shared.lifecycle.lock().unwrap().state
+    }
+
+    /// Flush and shut down the write side, moving through
+    /// [`ConnectionState::Closing`] to [`ConnectionState::Closed`].
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.shared.set_state(ConnectionState::Closing);
+        let result = self.shared.shutdown_writer().await;
+        self.shared.set_state(ConnectionState::Closed);
+        result
+    }
+
+    /// Whether the peer has closed its side (or the reader stopped). Responses
+    /// and notifications can still be written; new requests fail with
+    /// [`ConnectionError::Closed`].
+    pub fn is_read_closed(&self) -> bool {
+        self.shared.read_closed.load(Ordering::SeqCst)
+    }
+
+    /// Whether the write side has been shut down or has failed. Further sends
+    /// fail with [`ConnectionError::WriteClosed`].
+    pub fn is_write_closed(&self) -> bool {
+        self.shared.is_write_closed()
+    }
+
+    /// When the last frame arrived from the peer, if any.
+    pub fn last_received_at(&self) -> Option<Instant> {
+        self.shared.activity.lock().unwrap().last_received
+    }
+
+    /// When the last frame was sent to the peer, if any.
+    pub fn last_sent_at(&self) -> Option<Instant> {
+        self.shared.activity.lock().unwrap().last_sent
+    }
+
+    /// Number of incoming messages buffered and not yet read.
+    pub fn incoming_depth(&self) -> usize {
+        self.shared.incoming.len()
+    }
+
+    /// Whether reading from the transport is paused because the incoming
+    /// buffer is full (see [`OverflowPolicy::Backpressure`]).
+    pub fn is_read_paused(&self) -> bool {
+        self.shared.incoming.is_paused()
+    }
+}
+
+impl Drop for McplConnection {
+    fn drop(&mut self) {
+        // Stop the reader through its own cleanup, so requests still waiting
+        // through handles fail with `Closed` instead of hanging
+        self.shared.shutdown.cancel();
+        self.shared.incoming.close();
+        // Let the background writer flush what is already queued, then exit
+        if let Sink::Queued(queue) = &self.shared.sink {
+            queue.close();
         }
     }
 }
 
-enum InternalMessage {
-    Response(JsonRpcResponse),
-    Incoming(IncomingMessage),
+/// Cloneable handle for cancelling outstanding requests of a connection.
+#[derive(Clone)]
+pub struct CancelHandle {
+    shared: Arc<Shared>,
+}
+
+impl CancelHandle {
+    /// Cancel an outstanding request. See [`McplConnection::cancel_request`].
+    pub async fn cancel_request(
+        &self,
+        id: JsonRpcId,
+        reason: Option<String>,
+    ) -> Result<(), ConnectionError> {
+        self.shared.cancel_request(id, reason).await
+    }
+}
+
+/// One-shot handle for answering an incoming request from any task.
+///
+/// Obtained from [`McplConnection::responder`]. Replying consumes the
+/// handle; dropping it without replying logs a warning, as the peer is left
+/// waiting.
+pub struct Responder {
+    shared: Arc<Shared>,
+    id: Option<JsonRpcId>,
+}
+
+impl Responder {
+    /// Id of the request being answered.
+    pub fn id(&self) -> &JsonRpcId {
+        self.id.as_ref().expect("responder id is present until replied")
+    }
+
+    /// Answer with a successful result.
+    pub async fn reply(mut self, result: serde_json::Value) -> Result<(), ConnectionError> {
+        let id = self.id.take().expect("responder id is present until replied");
+        self.shared
+            .answer(JsonRpcResponse::success(id, result))
+            .await
+    }
+
+    /// Answer with either a result or an error.
+    pub async fn respond(
+        mut self,
+        result: Result<serde_json::Value, JsonRpcError>,
+    ) -> Result<(), ConnectionError> {
+        let id = self.id.take().expect("responder id is present until replied");
+        let response = match result {
+            Ok(result) => JsonRpcResponse::success(id, result),
+            Err(error) => JsonRpcResponse::error(id, error),
+        };
+        self.shared.answer(response).await
+    }
+
+    /// Answer with a JSON-RPC error.
+    pub async fn reply_error(
+        mut self,
+        code: i32,
+        message: impl Into<String>,
+    ) -> Result<(), ConnectionError> {
+        let id = self.id.take().expect("responder id is present until replied");
+        let error = JsonRpcError {
+            code,
+            message: message.into(),
+            data: None,
+        };
+        self.shared
+            .answer(JsonRpcResponse::error(id, error))
+            .await
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if let Some(id) = &self.id {
+            tracing::warn!("Responder for request {:?} dropped without a reply", id);
+        }
+    }
+}
+
+impl Shared {
+    async fn respond(&self, response: JsonRpcResponse) -> Result<(), ConnectionError> {
+        self.finish_inflight(&response.id);
+        self.write_message(JsonRpcMessage::Response(response))
+            .await
+    }
+
+    /// Send a [`Responder`]'s answer. Only the first answer to a request is
+    /// sent; later ones are logged and dropped.
+    async fn answer(&self, response: JsonRpcResponse) -> Result<(), ConnectionError> {
+        if !self.finish_inflight(&response.id) {
+            tracing::warn!(
+                "Dropping duplicate response to {:?}; the request was already answered",
+                response.id
+            );
+            return Ok(());
+        }
+        self.write_message(JsonRpcMessage::Response(response))
+            .await
+    }
+
+    fn tap(&self, direction: FrameDirection, raw: &str) {
+        let now = Instant::now();
+        let mut activity = self.activity.lock().unwrap();
+        match direction {
+            FrameDirection::Incoming => activity.last_received = Some(now),
+            FrameDirection::Outgoing => activity.last_sent = Some(now),
+        }
+        drop(activity);
+        if let Some(wiretap) = &*self.wiretap.read().unwrap() {
+            wiretap.record(direction, raw);
+        }
+    }
+
+    async fn request(
+        self: &Arc<Self>,
+        method: &str,
+        params: Option<Box<RawValue>>,
+        listener: Option<Listener>,
+    ) -> ResponseResult {
+        let mut attempt = 1;
+        loop {
+            let mut pending = self
+                .start_request(method, params.clone(), listener.clone())
+                .await?;
+            let result = std::future::poll_fn(|cx| pending.poll_raw(cx)).await;
+            match (&self.retry, result) {
+                (Some(retry), Err(err)) if retry.should_retry(attempt, &err) => {
+                    let delay = retry.backoff(attempt);
+                    tracing::debug!("Retrying {} in {:?} after: {}", method, delay, err);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                (_, result) => return result,
+            }
+        }
+    }
+
+    async fn start_request(
+        self: &Arc<Self>,
+        method: &str,
+        params: Option<Box<RawValue>>,
+        listener: Option<Listener>,
+    ) -> Result<PendingResponse, ConnectionError> {
+        // No response could ever be read
+        if self.read_closed.load(Ordering::SeqCst) {
+            return Err(ConnectionError::Closed);
+        }
+        let id = self.ids.lock().unwrap().next_id();
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), tx);
+        let pending = PendingResponse {
+            shared: Arc::clone(self),
+            id: id.clone(),
+            rx,
+        };
+        // The reader may have stopped, and cleared `pending`, since the check
+        if self.read_closed.load(Ordering::SeqCst) {
+            return Err(ConnectionError::Closed);
+        }
+
+        let mut request = JsonRpcRequest {
+            jsonrpc: "2.0".into(),
+            id: id.clone(),
+            method: method.into(),
+            params,
+        };
+        match listener {
+            Some(Listener::Progress(listener)) => {
+                self.progress.lock().unwrap().insert(id.clone(), listener);
+                request = request.with_progress_token(id);
+            }
+            Some(Listener::Chunks(listener)) => {
+                self.chunks.lock().unwrap().insert(id, listener);
+            }
+            None => {}
+        }
+        self.write_message(JsonRpcMessage::Request(request)).await?;
+
+        Ok(pending)
+    }
+
+    /// Wait until everything sent so far has reached the transport.
+    async fn flush(&self) -> Result<(), ConnectionError> {
+        match &self.sink {
+            Sink::Direct(writer) => {
+                if !self.is_write_closed() {
+                    writer.lock().await.flush().await?;
+                }
+            }
+            Sink::Queued(queue) => queue.flushed().await,
+        }
+        Ok(())
+    }
+
+    async fn shutdown_writer(&self) -> Result<(), ConnectionError> {
+        if self.write_closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        match &self.sink {
+            Sink::Direct(writer) => writer.lock().await.shutdown().await?,
+            Sink::Queued(queue) => queue.close(),
+        }
+        Ok(())
+    }
+
+    /// Run the interceptor chain over a message.
+    fn intercept(&self, msg: &mut JsonRpcMessage, outgoing: bool) -> Intercept {
+        let interceptors = self.interceptors.read().unwrap();
+        for interceptor in interceptors.iter() {
+            let verdict = if outgoing {
+                interceptor.on_outgoing(msg)
+            } else {
+                interceptor.on_incoming(msg)
+            };
+            if !matches!(verdict, Intercept::Continue) {
+                return verdict;
+            }
+        }
+        Intercept::Continue
+    }
+
+    async fn recover_from_parse_error(&self, frame: &str, error: ConnectionError) {
+        let event = ParseErrorEvent {
+            raw: frame.to_string(),
+            error: error.to_string(),
+            id: salvage_id(frame),
+        };
+        tracing::warn!("Skipping unparseable frame: {}", event.error);
+        if let Some(callback) = &self.on_parse_error {
+            callback(&event);
+        }
+        self.report_malformed(frame, &error).await;
+    }
+
+    /// Answer a frame that could not be read as a message with `-32700` (not
+    /// JSON) or `-32600` (not a JSON-RPC message), if its id can be salvaged.
+    /// Frames shaped like responses are never answered.
+    async fn report_malformed(&self, frame: &str, error: &ConnectionError) {
+        let (code, label) = match error {
+            ConnectionError::Json(e) if e.is_syntax() || e.is_eof() => {
+                (ERR_PARSE_ERROR, "Parse error")
+            }
+            ConnectionError::Json(_) | ConnectionError::UnrecognizedMessage(_) => {
+                (ERR_INVALID_REQUEST, "Invalid Request")
+            }
+            _ => return,
+        };
+        let Some(id) = salvage_id(frame) else {
+            return;
+        };
+        let is_response = serde_json::from_str::<Envelope>(frame)
+            .map(|envelope| envelope.result.is_some() || envelope.error.is_some())
+            .unwrap_or(false);
+        if is_response {
+            return;
+        }
+        let error = JsonRpcError {
+            code,
+            message: format!("{}: {}", label, error),
+            data: None,
+        };
+        if let Err(e) = self.respond(JsonRpcResponse::error(id, error)).await {
+            tracing::warn!("Failed to report malformed frame: {}", e);
+        }
+    }
+
+    /// Report a protocol violation to the peer (or the waiting caller, for a
+    /// malformed response) without ending the session.
+    async fn answer_violation(&self, id: Option<JsonRpcId>, response: bool, reason: String) {
+        tracing::warn!("Protocol violation from peer: {}", reason);
+        let Some(id) = id else {
+            return;
+        };
+        let error = JsonRpcError {
+            code: ERR_INVALID_REQUEST,
+            message: format!("Invalid Request: {}", reason),
+            data: None,
+        };
+        if response {
+            self.resolve(JsonRpcResponse::error(id, error));
+        } else if let Err(e) = self.respond(JsonRpcResponse::error(id, error)).await {
+            tracing::warn!("Failed to report protocol violation: {}", e);
+        }
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.lifecycle.lock().unwrap().state = state;
+    }
+
+    fn is_ready(&self) -> bool {
+        self.lifecycle.lock().unwrap().state == ConnectionState::Ready
+    }
+
+    /// Advance the lifecycle for a message about to be sent, refusing it if
+    /// the handshake has not completed.
+    fn note_outgoing(&self, msg: &JsonRpcMessage) -> Result<(), ConnectionError> {
+        let method = match msg {
+            JsonRpcMessage::Request(req) if req.method == method::INITIALIZE => {
+                self.begin_handshake(req);
+                return Ok(());
+            }
+            JsonRpcMessage::Request(req) => &req.method,
+            JsonRpcMessage::Notification(notif) => &notif.method,
+            JsonRpcMessage::Response(resp) => {
+                self.finish_handshake(resp);
+                return Ok(());
+            }
+        };
+        let state = self.lifecycle.lock().unwrap().state;
+        if self.enforce_lifecycle
+            && state != ConnectionState::Ready
+            && !allowed_before_ready(method)
+        {
+            return Err(ConnectionError::NotReady {
+                method: method.clone(),
+                state,
+            });
+        }
+        if !self.is_negotiated(method) {
+            return Err(ConnectionError::NotNegotiated {
+                method: method.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn begin_handshake(&self, req: &JsonRpcRequest) {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        lifecycle.state = ConnectionState::Initializing;
+        lifecycle.init_id = Some(req.id.clone());
+        lifecycle.offered = mcpl_capabilities(req.params.as_deref());
+        lifecycle.negotiated = None;
+    }
+
+    /// Complete the handshake if `resp` (sent or received) answers the
+    /// `initialize` request in flight.
+    fn finish_handshake(&self, resp: &JsonRpcResponse) {
+        let mut lifecycle = self.lifecycle.lock().unwrap();
+        if lifecycle.init_id.as_ref() == Some(&resp.id) {
+            lifecycle.init_id = None;
+            let offered = lifecycle.offered.take();
+            lifecycle.state = if resp.error.is_none() {
+                let accepted = mcpl_capabilities(resp.result.as_deref());
+                lifecycle.negotiated = Some([offered, accepted]);
+                ConnectionState::Ready
+            } else {
+                ConnectionState::Uninitialized
+            };
+        }
+    }
+
+    /// Whether `method` may be used under [`ConnectionOptions::enforce_capabilities`].
+    /// Everything is allowed until the handshake completes.
+    fn is_negotiated(&self, method: &str) -> bool {
+        if !self.enforce_capabilities {
+            return true;
+        }
+        match &self.lifecycle.lock().unwrap().negotiated {
+            Some(sides) => sides
+                .iter()
+                .all(|caps| caps.as_ref().is_some_and(|c| c.supports_method(method))),
+            None => true,
+        }
+    }
+
+    async fn write_message(&self, mut msg: JsonRpcMessage) -> Result<(), ConnectionError> {
+        self.note_outgoing(&msg)?;
+        match self.intercept(&mut msg, true) {
+            Intercept::Continue => {
+                self.write_frame(&msg).await?;
+                self.session.observe(&msg, true);
+                Ok(())
+            }
+            Intercept::Drop => Ok(()),
+            Intercept::Reject(error) => Err(ConnectionError::Rejected {
+                code: error.code,
+                message: error.message,
+            }),
+            Intercept::Respond(result) => {
+                if let JsonRpcMessage::Request(req) = msg {
+                    self.resolve(JsonRpcResponse::success(req.id, result));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn is_write_closed(&self) -> bool {
+        if self.write_closed.load(Ordering::SeqCst) {
+            return true;
+        }
+        match &self.sink {
+            Sink::Direct(_) => false,
+            Sink::Queued(queue) => {
+                let state = queue.state.lock().unwrap();
+                state.closed || state.failed
+            }
+        }
+    }
+
+    async fn write_frame(&self, msg: &JsonRpcMessage) -> Result<(), ConnectionError> {
+        if self.is_write_closed() {
+            return Err(ConnectionError::WriteClosed);
+        }
+        let mut line = serde_json::to_string(msg)?;
+        self.tap(FrameDirection::Outgoing, &line);
+        line.push('\n');
+        match &self.sink {
+            Sink::Direct(writer) => {
+                let mut writer = writer.lock().await;
+                let result = async {
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.flush().await
+                }
+                .await;
+                if result.is_err() {
+                    // A failed write leaves the stream in an unknown state
+                    self.write_closed.store(true, Ordering::SeqCst);
+                }
+                Ok(result?)
+            }
+            Sink::Queued(queue) => {
+                let lane = match msg {
+                    JsonRpcMessage::Response(_) => LANE_RESPONSE,
+                    JsonRpcMessage::Request(_) => LANE_REQUEST,
+                    JsonRpcMessage::Notification(_) => LANE_NOTIFICATION,
+                };
+                queue.push(lane, line)
+            }
+        }
+    }
+
+    async fn send_notification(
+        &self,
+        method: &str,
+        params: Option<Box<RawValue>>,
+    ) -> Result<(), ConnectionError> {
+        let notification = JsonRpcNotification {
+            jsonrpc: "2.0".into(),
+            method: method.into(),
+            params,
+        };
+        self.write_message(JsonRpcMessage::Notification(notification))
+            .await
+    }
+
+    async fn cancel_request(
+        &self,
+        id: JsonRpcId,
+        reason: Option<String>,
+    ) -> Result<(), ConnectionError> {
+        let waiter = self.pending.lock().unwrap().remove(&id);
+        if let Some(tx) = waiter {
+            self.record_completion(id.clone(), Completion::Cancelled);
+            let _ = tx.send(Err(ConnectionError::Cancelled));
+        }
+        let params = CancelledParams {
+            request_id: id,
+            reason,
+        };
+        self.send_notification(
+            method::NOTIFICATIONS_CANCELLED,
+            Some(to_raw_value(&params)?),
+        )
+        .await
+    }
+
+    async fn reject_unhandled(&self, request: &JsonRpcRequest) -> Result<(), ConnectionError> {
+        let error = JsonRpcError {
+            code: ERR_METHOD_NOT_FOUND,
+            message: format!("Method not found: {}", request.method),
+            data: None,
+        };
+        self.respond(JsonRpcResponse::error(request.id.clone(), error))
+            .await
+    }
+
+    fn is_claimed(&self, method: &str) -> bool {
+        match &*self.claimed_methods.lock().unwrap() {
+            Some(methods) => methods.contains(method),
+            None => true,
+        }
+    }
+
+    /// Whether `id` was awaiting an answer.
+    fn finish_inflight(&self, id: &JsonRpcId) -> bool {
+        self.inflight.lock().unwrap().remove(id).is_some()
+    }
+
+    fn record_completion(&self, id: JsonRpcId, completion: Completion) {
+        let mut completed = self.completed.lock().unwrap();
+        if completed.len() == RECENT_RESPONSE_IDS {
+            completed.pop_front();
+        }
+        completed.push_back((id, completion));
+    }
+
+    /// Deliver a response to its waiting caller.
+    ///
+    /// Returns an error to surface from `next_message` when the response is
+    /// unexpected and the policy asks for it.
+    fn resolve(&self, resp: JsonRpcResponse) -> Option<ConnectionError> {
+        self.finish_handshake(&resp);
+        let waiter = self.pending.lock().unwrap().remove(&resp.id);
+        let Some(tx) = waiter else {
+            return self.unexpected(resp);
+        };
+        self.record_completion(resp.id.clone(), Completion::Answered);
+        let result = match resp.error {
+            Some(error) => Err(ConnectionError::Rpc {
+                code: error.code,
+                message: error.message,
+            }),
+            None => Ok(resp.result.unwrap_or_else(|| RawValue::NULL.to_owned())),
+        };
+        let _ = tx.send(result);
+        None
+    }
+
+    fn unexpected(&self, resp: JsonRpcResponse) -> Option<ConnectionError> {
+        let kind = {
+            let mut completed = self.completed.lock().unwrap();
+            match completed.iter_mut().find(|(id, _)| *id == resp.id) {
+                // A late answer to a request we cancelled is expected once
+                Some(entry) if entry.1 == Completion::Cancelled => {
+                    entry.1 = Completion::Answered;
+                    return None;
+                }
+                Some(_) => UnexpectedResponseKind::Duplicate,
+                None => UnexpectedResponseKind::Unknown,
+            }
+        };
+        match &self.unexpected_responses {
+            UnexpectedResponsePolicy::Ignore => None,
+            UnexpectedResponsePolicy::Warn => {
+                tracing::warn!("Received {} response for id {:?}", kind, resp.id);
+                None
+            }
+            UnexpectedResponsePolicy::Error => Some(ConnectionError::UnexpectedResponse {
+                id: resp.id,
+                kind,
+            }),
+            UnexpectedResponsePolicy::Callback(callback) => {
+                callback(&resp, kind);
+                None
+            }
+        }
+    }
+
+    /// Track an incoming request, apply an incoming cancellation (including
+    /// `inference/cancel`), or route progress or inference chunks to their
+    /// listener.
+    ///
+    /// Returns `true` if the message was consumed and must not be queued.
+    fn observe_incoming(&self, msg: &IncomingMessage) -> bool {
+        match msg {
+            IncomingMessage::Request(req) => {
+                if req.method == method::INITIALIZE {
+                    self.begin_handshake(req);
+                }
+                let entry = InflightRequest {
+                    cancel: CancellationToken::new(),
+                    received_at: Instant::now(),
+                };
+                self.inflight.lock().unwrap().insert(req.id.clone(), entry);
+            }
+            IncomingMessage::Notification(notif)
+                if notif.method == method::NOTIFICATIONS_CANCELLED =>
+            {
+                let Ok(params) = notif.params_as::<CancelledParams>() else {
+                    return false;
+                };
+                // Keep the entry so late lookups still observe the cancellation
+                if let Some(entry) = self.inflight.lock().unwrap().get(&params.request_id) {
+                    entry.cancel.cancel();
+                }
+            }
+            IncomingMessage::Notification(notif) if notif.method == method::INFERENCE_CANCEL => {
+                let Ok(params) = notif.params_as::<InferenceCancelParams>() else {
+                    return false;
+                };
+                if let Some(entry) = self.inflight.lock().unwrap().get(&params.request_id) {
+                    entry.cancel.cancel();
+                }
+            }
+            IncomingMessage::Notification(notif)
+                if notif.method == method::NOTIFICATIONS_PROGRESS =>
+            {
+                let Ok(params) = notif.params_as::<ProgressParams>() else {
+                    return false;
+                };
+                if let Some(listener) = self.progress.lock().unwrap().get(&params.progress_token) {
+                    let _ = listener.send(params);
+                    return true;
+                }
+            }
+            IncomingMessage::Notification(notif) if notif.method == method::INFERENCE_CHUNK => {
+                let Ok(params) = notif.params_as::<InferenceChunkParams>() else {
+                    return false;
+                };
+                if let Some(listener) = self.chunks.lock().unwrap().get(&params.request_id) {
+                    let _ = listener.send(params);
+                    return true;
+                }
+            }
+            IncomingMessage::Notification(_) => {}
+        }
+        false
+    }
+}
+
+/// Deserialize a request's result, failing with
+/// [`ConnectionError::InvalidResult`] if it does not match `R`.
+pub(crate) fn decode_result<R: DeserializeOwned>(
+    method: &str,
+    result: &RawValue,
+) -> Result<R, ConnectionError> {
+    serde_json::from_str(result.get()).map_err(|source| ConnectionError::InvalidResult {
+        method: method.to_string(),
+        source,
+    })
+}
+
+/// Sending side of an [`McplConnection`], shareable across tasks.
+///
+/// Requests sent through a handle share the connection's id generator and
+/// retry policy, and their responses are routed by its reader task.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    shared: Arc<Shared>,
+}
+
+impl ConnectionHandle {
+    /// See [`McplConnection::send_request`].
+    pub async fn send_request(
+        &self,
+        method: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value, ConnectionError> {
+        let result = self.shared.request(method, params.map(to_raw), None).await?;
+        Ok(serde_json::from_str(result.get())?)
+    }
+
+    /// Like [`Self::send_request`], leaving the result as raw JSON.
+    pub(crate) async fn request_raw(
+        &self,
+        method: &str,
+        params: Option<Box<RawValue>>,
+    ) -> ResponseResult {
+        self.shared.request(method, params, None).await
+    }
+
+    /// See [`McplConnection::send_request_deferred`].
+    pub async fn send_request_deferred(
+        &self,
+        method: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<PendingResponse, ConnectionError> {
+        self.shared.start_request(method, params.map(to_raw), None).await
+    }
+
+    /// Send a request without waiting for its response, forwarding every
+    /// `inference/chunk` for it to `chunks` instead of delivering it as a
+    /// notification. Chunks are matched by request id.
+    pub async fn send_request_streaming(
+        &self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        chunks: mpsc::UnboundedSender<InferenceChunkParams>,
+    ) -> Result<PendingResponse, ConnectionError> {
+        let listener = Some(Listener::Chunks(chunks));
+        self.shared.start_request(method, params.map(to_raw), listener).await
+    }
+
+    /// See [`McplConnection::send_request_typed`].
+    pub async fn send_request_typed<P, R>(&self, method: &str, params: &P) -> Result<R, ConnectionError>
+    where
+        P: Serialize + ?Sized,
+        R: DeserializeOwned,
+    {
+        let params = to_raw_value(params)?;
+        let result = self.shared.request(method, Some(params), None).await?;
+        decode_result(method, &result)
+    }
+
+    pub async fn send_notification(
+        &self,
+        method: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<(), ConnectionError> {
+        self.shared.send_notification(method, params.map(to_raw)).await
+    }
+
+    pub async fn send_notification_typed<P>(&self, method: &str, params: &P) -> Result<(), ConnectionError>
+    where
+        P: Serialize + ?Sized,
+    {
+        let params = to_raw_value(params)?;
+        self.shared.send_notification(method, Some(params)).await
+    }
+
+    /// See [`McplConnection::cancel_request`].
+    pub async fn cancel_request(
+        &self,
+        id: JsonRpcId,
+        reason: Option<String>,
+    ) -> Result<(), ConnectionError> {
+        self.shared.cancel_request(id, reason).await
+    }
+
+    /// Wait until every frame sent so far has been written to the
+    /// transport. Only waits with [`ConnectionOptions::background_writer`];
+    /// otherwise sends are written before they return.
+    pub async fn flush(&self) -> Result<(), ConnectionError> {
+        self.shared.flush().await
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.shared.lifecycle.lock().unwrap().state
+    }
+
+    /// See [`McplConnection::session`].
+    pub fn session(&self) -> McplSession {
+        McplSession::new(self.clone())
+    }
+
+    pub(crate) fn session_state(&self) -> &SessionState {
+        &self.shared.session
+    }
+}
+
+/// Response to a request sent with
+/// [`McplConnection::send_request_deferred`].
+pub struct PendingResponse {
+    shared: Arc<Shared>,
+    id: JsonRpcId,
+    rx: oneshot::Receiver<ResponseResult>,
+}
+
+impl PendingResponse {
+    /// Id of the outstanding request, e.g. for [`CancelHandle::cancel_request`].
+    pub fn id(&self) -> &JsonRpcId {
+        &self.id
+    }
+
+    /// Poll for the result without parsing it.
+    pub(crate) fn poll_raw(&mut self, cx: &mut Context<'_>) -> Poll<ResponseResult> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(ConnectionError::Closed)))
+    }
+}
+
+impl Future for PendingResponse {
+    type Output = Result<serde_json::Value, ConnectionError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_raw(cx)
+            .map(|result| Ok(serde_json::from_str(result?.get())?))
+    }
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        self.shared.pending.lock().unwrap().remove(&self.id);
+        self.shared.progress.lock().unwrap().remove(&self.id);
+        self.shared.chunks.lock().unwrap().remove(&self.id);
+        self.shared.session.forget(&self.id);
+    }
+}
+
+async fn read_loop(mut reader: BufReader<BoxedReader>, shared: Arc<Shared>) {
+    loop {
+        let next = tokio::select! {
+            _ = shared.shutdown.cancelled() => break,
+            next = read_next_internal(&mut reader, &shared) => next,
+        };
+        match next {
+            Ok(mut msg) => match shared.intercept(&mut msg, false) {
+                Intercept::Continue => dispatch(&shared, msg).await,
+                Intercept::Drop => {}
+                Intercept::Reject(error) => match msg {
+                    JsonRpcMessage::Request(req) => {
+                        if let Err(e) = shared.respond(JsonRpcResponse::error(req.id, error)).await {
+                            tracing::warn!("Failed to answer rejected {}: {}", req.method, e);
+                        }
+                    }
+                    JsonRpcMessage::Response(resp) => {
+                        shared.resolve(JsonRpcResponse::error(resp.id, error));
+                    }
+                    JsonRpcMessage::Notification(_) => {}
+                },
+                Intercept::Respond(result) => {
+                    if let JsonRpcMessage::Request(req) = msg {
+                        if let Err(e) = shared.respond(JsonRpcResponse::success(req.id, result)).await {
+                            tracing::warn!("Failed to answer intercepted {}: {}", req.method, e);
+                        }
+                    }
+                }
+            },
+            Err(ConnectionError::InvalidMessage { id, response, reason }) => {
+                shared.answer_violation(id, response, reason).await;
+            }
+            Err(ConnectionError::Closed) => break,
+            Err(err @ ConnectionError::Io(_)) => {
+                shared.incoming.push(Err(err)).await;
+                break;
+            }
+            // Malformed frames are reported but do not end the session
+            Err(err) => shared.incoming.push(Err(err)).await,
+        }
+    }
+
+    shared.read_closed.store(true, Ordering::SeqCst);
+    // Half-closed: responses to requests already received can still go out
+    shared.set_state(if shared.is_write_closed() {
+        ConnectionState::Closed
+    } else {
+        ConnectionState::Closing
+    });
+    shared.incoming.close();
+    // Dropping the senders resolves every waiting `send_request` with `Closed`
+    // and ends every progress and chunk stream.
+    shared.pending.lock().unwrap().clear();
+    shared.progress.lock().unwrap().clear();
+    shared.chunks.lock().unwrap().clear();
+}
+
+/// Apply [`ConnectionOptions::on_idle`] whenever no frame has crossed the
+/// connection for `timeout`.
+async fn idle_watchdog(shared: Weak<Shared>, timeout: Duration) {
+    let Some(shutdown) = shared.upgrade().map(|s| s.shutdown.clone()) else {
+        return;
+    };
+    let mut reported = None;
+    loop {
+        let last = match shared.upgrade() {
+            Some(shared) => shared.activity.lock().unwrap().last(),
+            None => return,
+        };
+        // Once an idle period is reported, poll until traffic resumes
+        let wake = if reported == Some(last) {
+            Instant::now() + timeout
+        } else {
+            last + timeout
+        };
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = tokio::time::sleep_until(wake.into()) => {}
+        }
+
+        let Some(shared) = shared.upgrade() else { return };
+        let last_now = shared.activity.lock().unwrap().last();
+        if last_now != last || reported == Some(last) {
+            continue;
+        }
+        let idle_for = last.elapsed();
+        match &shared.on_idle {
+            IdlePolicy::Close => {
+                tracing::info!("Closing connection idle for {:?}", idle_for);
+                shared.set_state(ConnectionState::Closing);
+                if let Err(e) = shared.shutdown_writer().await {
+                    tracing::warn!("Failed to shut down idle connection: {}", e);
+                }
+                shared.shutdown.cancel();
+                return;
+            }
+            IdlePolicy::Callback(callback) => {
+                callback(idle_for);
+                reported = Some(last);
+            }
+        }
+    }
+}
+
+/// Route a parsed incoming message to its waiting caller or the queue.
+async fn dispatch(shared: &Shared, msg: JsonRpcMessage) {
+    shared.session.observe(&msg, false);
+    match msg {
+        JsonRpcMessage::Response(resp) => {
+            if let Some(err) = shared.resolve(resp) {
+                shared.incoming.push(Err(err)).await;
+            }
+        }
+        JsonRpcMessage::Request(req) if shared.answer_pings && req.method == method::PING => {
+            let pong = JsonRpcResponse::success(req.id, serde_json::json!({}));
+            if let Err(e) = shared.respond(pong).await {
+                tracing::warn!("Failed to answer ping: {}", e);
+            }
+        }
+        JsonRpcMessage::Request(req)
+            if shared.enforce_lifecycle
+                && !shared.is_ready()
+                && !allowed_before_ready(&req.method) =>
+        {
+            let error = JsonRpcError {
+                code: ERR_NOT_INITIALIZED,
+                message: format!("Not initialized: {}", req.method),
+                data: None,
+            };
+            if let Err(e) = shared.respond(JsonRpcResponse::error(req.id, error)).await {
+                tracing::warn!("Failed to refuse early request: {}", e);
+            }
+        }
+        JsonRpcMessage::Notification(notif)
+            if shared.enforce_lifecycle
+                && !shared.is_ready()
+                && !allowed_before_ready(&notif.method) =>
+        {
+            tracing::warn!("Dropped {} received before initialization", notif.method);
+        }
+        JsonRpcMessage::Request(req) if !shared.is_negotiated(&req.method) => {
+            let error = JsonRpcError::new(
+                ERR_FEATURE_SET_NOT_ENABLED,
+                format!("Capability not negotiated: {}", req.method),
+            );
+            if let Err(e) = shared.respond(JsonRpcResponse::error(req.id, error)).await {
+                tracing::warn!("Failed to refuse {}: {}", req.method, e);
+            }
+        }
+        JsonRpcMessage::Notification(notif) if !shared.is_negotiated(&notif.method) => {
+            tracing::warn!("Dropped {}; capability not negotiated", notif.method);
+        }
+        JsonRpcMessage::Request(req) if !shared.is_claimed(&req.method) => {
+            if let Err(e) = shared.reject_unhandled(&req).await {
+                tracing::warn!("Failed to reject unhandled {}: {}", req.method, e);
+            }
+        }
+        JsonRpcMessage::Request(req) => {
+            let msg = IncomingMessage::Request(req);
+            if !shared.observe_incoming(&msg) {
+                shared.incoming.push(Ok(msg)).await;
+            }
+        }
+        JsonRpcMessage::Notification(notif) => {
+            let msg = IncomingMessage::Notification(notif);
+            if !shared.observe_incoming(&msg) {
+                shared.incoming.push(Ok(msg)).await;
+            }
+        }
+    }
+}
+
+/// The `experimental.mcpl` capabilities of `initialize` params or result.
+fn mcpl_capabilities(raw: Option<&RawValue>) -> Option<McplCapabilities> {
+    let capabilities = member(raw?, "capabilities")?;
+    let caps = member(member(capabilities, "experimental")?, "mcpl")?;
+    serde_json::from_str(caps.get()).ok()
+}
+
+async fn read_next_internal(
+    reader: &mut BufReader<BoxedReader>,
+    shared: &Shared,
+) -> Result<JsonRpcMessage, ConnectionError> {
+    loop {
+        let mut line = String::new();
+        let bytes_read = reader.read_line(&mut line).await?;
+        if bytes_read == 0 {
+            return Err(ConnectionError::Closed);
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        shared.tap(FrameDirection::Incoming, trimmed);
+
+        match parse_frame(trimmed, shared.strict) {
+            Ok(msg) => return Ok(msg),
+            Err(err @ ConnectionError::Json(_)) if shared.recover_parse_errors => {
+                shared.recover_from_parse_error(trimmed, err).await;
+            }
+            Err(err) => {
+                shared.report_malformed(trimmed, &err).await;
+                return Err(err);
+            }
+        }
+    }
+}
+
+fn parse_frame(frame: &str, strict: bool) -> Result<JsonRpcMessage, ConnectionError> {
+    let envelope: Envelope = match serde_json::from_str(frame) {
+        Ok(envelope) => envelope,
+        // Valid JSON, but not an object
+        Err(e) if e.is_data() && strict => {
+            return Err(ConnectionError::InvalidMessage {
+                id: None,
+                response: false,
+                reason: "message is not an object".to_string(),
+            })
+        }
+        Err(e) if e.is_data() => {
+            return Err(ConnectionError::UnrecognizedMessage(frame.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if strict {
+        validate_strict(&envelope)?;
+    }
+    envelope
+        .message()?
+        .ok_or_else(|| ConnectionError::UnrecognizedMessage(frame.to_string()))
+}
+
+/// Best-effort recovery of the `id` of a frame that failed to parse, by
+/// scanning for `"id":` followed by an integer or a simple string.
+fn salvage_id(frame: &str) -> Option<JsonRpcId> {
+    let start = frame.find("\"id\"")? + 4;
+    let rest = frame[start..].trim_start().strip_prefix(':')?.trim_start();
+    if let Some(quoted) = rest.strip_prefix('"') {
+        let end = quoted.find(['"', '\\'])?;
+        if quoted[end..].starts_with('\\') {
+            return None;
+        }
+        return Some(JsonRpcId::String(quoted[..end].to_string()));
+    }
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok().map(JsonRpcId::Number)
+}
+
+/// Checks enforced by [`ConnectionOptions::strict`].
+fn validate_strict(envelope: &Envelope<'_>) -> Result<(), ConnectionError> {
+    let has_result = envelope.result.is_some();
+    let has_error = envelope.error.is_some();
+    let violation = |reason: &str| ConnectionError::InvalidMessage {
+        id: envelope
+            .id
+            .and_then(|id| serde_json::from_str::<JsonRpcId>(id.get()).ok()),
+        response: envelope.method.is_none() && (has_result || has_error),
+        reason: reason.to_string(),
+    };
+
+    if envelope.jsonrpc.map(|v| v.get()) != Some("\"2.0\"") {
+        return Err(violation("jsonrpc must be \"2.0\""));
+    }
+    if has_result && has_error {
+        return Err(violation("response has both result and error"));
+    }
+    if let Some(params) = envelope.params {
+        if !params.get().starts_with(['{', '[']) {
+            return Err(violation("params must be an object or array"));
+        }
+    }
+    if envelope.method.is_some_and(|m| !m.get().starts_with('"')) {
+        return Err(violation("method must be a string"));
+    }
+    Ok(())
 }
@@ -0,0 +1,516 @@
+//! Merging the context injections several servers return from
+//! `context/beforeInference` into the segments of one prompt.
+//!
+//! ```ignore
+//! let mut assembler = ContextAssembler::new(ContextAssemblerOptions {
+//!     namespace_caps: HashMap::from([("telemetry".into(), 2_000)]),
+//!     ..Default::default()
+//! });
+//! for result in results {
+//!     assembler.add_result(result);
+//! }
+//! let context = assembler.assemble_for(&params.model);
+//! let system_prompt = format!("{base_prompt}\n\n{}", context.system);
+//! for trimmed in &context.trimmed {
+//!     tracing::debug!("Trimmed {} tokens from {}", trimmed.removed_tokens, trimmed.namespace);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::methods::{
+    ContextBeforeInferenceResult, ContextInjection, ContextInjectionContent,
+    ContextInjectionPosition, ModelInfo,
+};
+use crate::types::ContentBlock;
+
+/// Separator placed between injections rendered into the same segment
+/// unless configured otherwise.
+pub const DEFAULT_INJECTION_SEPARATOR: &str = "\n\n";
+
+/// Share of the model's context window injections may take unless
+/// configured otherwise.
+pub const DEFAULT_CONTEXT_BUDGET_FRACTION: f64 = 0.25;
+
+/// Counts the tokens a text takes in the model's context.
+pub trait Tokenizer: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+}
+
+/// Estimates one token per four characters, a fair average for English
+/// text with common tokenizers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicTokenizer;
+
+impl Tokenizer for HeuristicTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.chars().count().div_ceil(4)
+    }
+}
+
+/// What a [`ContextAssembler`] does with an injection into a namespace and
+/// position that already has one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the more important injection, or the first of equals.
+    #[default]
+    MostImportant,
+    /// Refuse the later injection with [`InjectionConflict::Duplicate`].
+    Error,
+    /// Replace the earlier injection.
+    LastWins,
+    /// Append the later injection's content after this separator, keeping
+    /// the higher priority of the two.
+    Concat(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InjectionConflict {
+    #[error("Namespace {namespace} already has a {position:?} injection")]
+    Duplicate {
+        namespace: String,
+        position: ContextInjectionPosition,
+        /// Feature set the refused injection came from.
+        feature_set: Option<String>,
+    },
+    #[error("Namespace {namespace} belongs to feature set {owner}")]
+    NotOwner {
+        namespace: String,
+        owner: String,
+        feature_set: Option<String>,
+    },
+}
+
+/// Construction-time settings for [`ContextAssembler`].
+#[derive(Clone)]
+pub struct ContextAssemblerOptions {
+    /// Maximum rendered length of an injection, in characters, by namespace.
+    pub namespace_caps: HashMap<String, usize>,
+    /// Cap for namespaces without one in `namespace_caps`.
+    pub default_cap: Option<usize>,
+    /// Placed between injections rendered into the same segment.
+    pub separator: String,
+    /// Share of the model's context window the injections may take in
+    /// [`ContextAssembler::assemble_for`].
+    pub budget_fraction: f64,
+    pub tokenizer: Arc<dyn Tokenizer>,
+    pub conflicts: ConflictPolicy,
+    /// Namespaces only the given feature set may inject into. Injections
+    /// from anywhere else are refused with [`InjectionConflict::NotOwner`].
+    pub namespace_owners: HashMap<String, String>,
+}
+
+impl Default for ContextAssemblerOptions {
+    fn default() -> Self {
+        Self {
+            namespace_caps: HashMap::new(),
+            default_cap: None,
+            separator: DEFAULT_INJECTION_SEPARATOR.into(),
+            budget_fraction: DEFAULT_CONTEXT_BUDGET_FRACTION,
+            tokenizer: Arc::new(HeuristicTokenizer),
+            conflicts: ConflictPolicy::default(),
+            namespace_owners: HashMap::new(),
+        }
+    }
+}
+
+/// Collects context injections from any number of servers and merges them.
+///
+/// Injections are ordered by position (system, before the user message,
+/// after it), then by [`priority`](ContextInjection::priority), most
+/// important first, then in the order they were added. Injections into a
+/// namespace and position that already has one are resolved per
+/// [`ConflictPolicy`], so the prompt does not depend on which server
+/// answered first. Injections longer than their namespace's cap are
+/// truncated.
+///
+/// [`assemble_for`](Self::assemble_for) also keeps the injections within a
+/// token budget, trimming the least important first.
+#[derive(Clone, Default)]
+pub struct ContextAssembler {
+    options: ContextAssemblerOptions,
+    injections: Vec<ContextInjection>,
+    /// Index into `injections` by namespace and position.
+    by_namespace: HashMap<(String, ContextInjectionPosition), usize>,
+}
+
+/// The merged injections, and the text of each segment. Hosts whose model
+/// takes images or audio read the segments as
+/// [blocks](Self::segment_blocks) instead.
+#[derive(Debug, Clone, Default)]
+pub struct AssembledContext {
+    /// Appended to the system prompt.
+    pub system: String,
+    /// Placed before the user's message.
+    pub before_user: String,
+    /// Placed after the user's message.
+    pub after_user: String,
+    /// The injections kept, in order, after capping and trimming.
+    pub injections: Vec<ContextInjection>,
+    /// Tokens the kept injections take.
+    pub tokens: usize,
+    /// Injections shortened or dropped to fit the token budget, in the
+    /// order they were trimmed.
+    pub trimmed: Vec<TrimmedInjection>,
+}
+
+/// An injection [`ContextAssembler::assemble_for`] trimmed to fit its
+/// token budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrimmedInjection {
+    pub namespace: String,
+    pub position: ContextInjectionPosition,
+    pub priority: i32,
+    pub removed_tokens: usize,
+    /// Whether the whole injection was dropped rather than truncated.
+    pub dropped: bool,
+}
+
+impl AssembledContext {
+    pub fn segment(&self, position: ContextInjectionPosition) -> &str {
+        match position {
+            ContextInjectionPosition::System => &self.system,
+            ContextInjectionPosition::BeforeUser => &self.before_user,
+            ContextInjectionPosition::AfterUser => &self.after_user,
+        }
+    }
+
+    /// The content of a segment's injections as blocks, in order, including
+    /// the images and audio left out of its text.
+    pub fn segment_blocks(&self, position: ContextInjectionPosition) -> Vec<ContentBlock> {
+        self.injections
+            .iter()
+            .filter(|injection| injection.position == position)
+            .flat_map(|injection| match &injection.content {
+                ContextInjectionContent::Text(text) => vec![ContentBlock::text(text.clone())],
+                ContextInjectionContent::Blocks(blocks) => blocks.clone(),
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.injections.is_empty()
+    }
+}
+
+impl ContextAssembler {
+    pub fn new(options: ContextAssemblerOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Add an injection that came from no particular feature set.
+    pub fn add(&mut self, injection: ContextInjection) -> Result<(), InjectionConflict> {
+        self.add_from(None, injection)
+    }
+
+    /// Add the injections of one server's `context/beforeInference` result,
+    /// as coming from its feature set. Returns the conflicts of those that
+    /// were refused; the others are added.
+    pub fn add_result(&mut self, result: ContextBeforeInferenceResult) -> Vec<InjectionConflict> {
+        let feature_set = result.feature_set;
+        result
+            .context_injections
+            .into_iter()
+            .filter_map(|injection| self.add_from(Some(&feature_set), injection).err())
+            .collect()
+    }
+
+    fn add_from(
+        &mut self,
+        feature_set: Option<&str>,
+        injection: ContextInjection,
+    ) -> Result<(), InjectionConflict> {
+        if let Some(owner) = self.options.namespace_owners.get(&injection.namespace) {
+            if feature_set != Some(owner.as_str()) {
+                return Err(InjectionConflict::NotOwner {
+                    namespace: injection.namespace,
+                    owner: owner.clone(),
+                    feature_set: feature_set.map(str::to_string),
+                });
+            }
+        }
+        let key = (injection.namespace.clone(), injection.position);
+        let Some(&at) = self.by_namespace.get(&key) else {
+            self.by_namespace.insert(key, self.injections.len());
+            self.injections.push(injection);
+            return Ok(());
+        };
+        let existing = &mut self.injections[at];
+        match &self.options.conflicts {
+            ConflictPolicy::MostImportant => {
+                if injection.priority() > existing.priority() {
+                    *existing = injection;
+                }
+            }
+            ConflictPolicy::Error => {
+                return Err(InjectionConflict::Duplicate {
+                    namespace: injection.namespace,
+                    position: injection.position,
+                    feature_set: feature_set.map(str::to_string),
+                });
+            }
+            ConflictPolicy::LastWins => *existing = injection,
+            ConflictPolicy::Concat(separator) => {
+                let priority = existing.priority.max(injection.priority);
+                concat(&mut existing.content, injection.content, separator);
+                existing.priority = priority;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.injections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.injections.is_empty()
+    }
+
+    /// The injections kept so far, ordered and capped.
+    pub fn injections(&self) -> Vec<ContextInjection> {
+        let mut ordered: Vec<(usize, &ContextInjection)> =
+            self.injections.iter().enumerate().collect();
+        ordered.sort_by_key(|(added, injection)| {
+            (
+                injection.position,
+                std::cmp::Reverse(injection.priority()),
+                *added,
+            )
+        });
+        ordered
+            .into_iter()
+            .map(|(_, injection)| self.capped(injection))
+            .collect()
+    }
+
+    /// Merge everything added, without a token budget.
+    pub fn assemble(&self) -> AssembledContext {
+        self.finish(self.injections(), Vec::new())
+    }
+
+    /// Merge everything added into at most
+    /// [`budget_fraction`](ContextAssemblerOptions::budget_fraction) of
+    /// `model`'s context window.
+    pub fn assemble_for(&self, model: &ModelInfo) -> AssembledContext {
+        let budget = f64::from(model.context_window) * self.options.budget_fraction;
+        self.assemble_within(budget as usize)
+    }
+
+    /// Merge everything added into at most `budget` tokens.
+    ///
+    /// While over budget, the least important injection is trimmed: the
+    /// last of those with the lowest priority. A text injection larger than
+    /// the excess is truncated; anything else is dropped.
+    pub fn assemble_within(&self, budget: usize) -> AssembledContext {
+        let tokenizer = &*self.options.tokenizer;
+        let mut injections = self.injections();
+        let mut tokens: Vec<usize> = injections
+            .iter()
+            .map(|injection| tokenizer.count_tokens(&render(&injection.content)))
+            .collect();
+        let mut trimmed = Vec::new();
+        let mut total: usize = tokens.iter().sum();
+        while total > budget {
+            let Some(at) = least_important(&injections) else {
+                break;
+            };
+            let excess = total - budget;
+            let before = tokens[at];
+            let shortened = match &injections[at].content {
+                ContextInjectionContent::Text(text) if before > excess => {
+                    Some(truncate_tokens(text, before - excess, tokenizer))
+                }
+                _ => None,
+            };
+            let after = shortened
+                .as_deref()
+                .map(|text| tokenizer.count_tokens(text))
+                .filter(|&after| after < before);
+            let injection = &injections[at];
+            trimmed.push(TrimmedInjection {
+                namespace: injection.namespace.clone(),
+                position: injection.position,
+                priority: injection.priority(),
+                removed_tokens: before - after.unwrap_or(0),
+                dropped: after.is_none(),
+            });
+            match (after, shortened) {
+                (Some(after), Some(text)) => {
+                    injections[at].content = ContextInjectionContent::Text(text);
+                    tokens[at] = after;
+                }
+                _ => {
+                    injections.remove(at);
+                    tokens.remove(at);
+                }
+            }
+            total = tokens.iter().sum();
+        }
+        self.finish(injections, trimmed)
+    }
+
+    /// Render the segments of `injections`.
+    fn finish(
+        &self,
+        injections: Vec<ContextInjection>,
+        trimmed: Vec<TrimmedInjection>,
+    ) -> AssembledContext {
+        let tokenizer = &*self.options.tokenizer;
+        let mut tokens = 0;
+        let mut segments: HashMap<ContextInjectionPosition, Vec<String>> = HashMap::new();
+        for injection in &injections {
+            let text = render(&injection.content);
+            tokens += tokenizer.count_tokens(&text);
+            if !text.is_empty() {
+                segments.entry(injection.position).or_default().push(text);
+            }
+        }
+        let mut join = |position| {
+            segments
+                .remove(&position)
+                .unwrap_or_default()
+                .join(&self.options.separator)
+        };
+        AssembledContext {
+            system: join(ContextInjectionPosition::System),
+            before_user: join(ContextInjectionPosition::BeforeUser),
+            after_user: join(ContextInjectionPosition::AfterUser),
+            injections,
+            tokens,
+            trimmed,
+        }
+    }
+
+    fn cap(&self, namespace: &str) -> Option<usize> {
+        self.options
+            .namespace_caps
+            .get(namespace)
+            .copied()
+            .or(self.options.default_cap)
+    }
+
+    fn capped(&self, injection: &ContextInjection) -> ContextInjection {
+        let mut injection = injection.clone();
+        if let Some(cap) = self.cap(&injection.namespace) {
+            truncate_content(&mut injection.content, cap);
+        }
+        injection
+    }
+}
+
+/// Adds each injection, logging and skipping those refused.
+impl Extend<ContextInjection> for ContextAssembler {
+    fn extend<I: IntoIterator<Item = ContextInjection>>(&mut self, iter: I) {
+        for injection in iter {
+            if let Err(e) = self.add(injection) {
+                tracing::warn!("Context injection refused: {}", e);
+            }
+        }
+    }
+}
+
+/// Append `more` to `content`, as text if both are text and as blocks
+/// otherwise.
+fn concat(content: &mut ContextInjectionContent, more: ContextInjectionContent, separator: &str) {
+    let blocks = |content| match content {
+        ContextInjectionContent::Text(text) => vec![ContentBlock::text(text)],
+        ContextInjectionContent::Blocks(blocks) => blocks,
+    };
+    let joined = match (
+        std::mem::replace(content, ContextInjectionContent::Blocks(Vec::new())),
+        more,
+    ) {
+        (ContextInjectionContent::Text(first), ContextInjectionContent::Text(second)) => {
+            ContextInjectionContent::Text(format!("{first}{separator}{second}"))
+        }
+        (first, second) => {
+            let mut joined = blocks(first);
+            joined.push(ContentBlock::text(separator));
+            joined.extend(blocks(second));
+            ContextInjectionContent::Blocks(joined)
+        }
+    };
+    *content = joined;
+}
+
+/// The text of an injection: its text blocks, one per line. Other blocks
+/// are left out.
+fn render(content: &ContextInjectionContent) -> String {
+    match content {
+        ContextInjectionContent::Text(text) => text.clone(),
+        ContextInjectionContent::Blocks(blocks) => blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Shorten `content`'s text to at most `cap` characters, dropping text
+/// blocks that no longer fit.
+fn truncate_content(content: &mut ContextInjectionContent, cap: usize) {
+    match content {
+        ContextInjectionContent::Text(text) => truncate_chars(text, cap),
+        ContextInjectionContent::Blocks(blocks) => {
+            let mut left = cap;
+            blocks.retain_mut(|block| {
+                let ContentBlock::Text { text } = block else {
+                    return true;
+                };
+                if left == 0 {
+                    return false;
+                }
+                truncate_chars(text, left);
+                left -= text.chars().count();
+                true
+            });
+        }
+    }
+}
+
+/// The last of the least important of `injections`.
+fn least_important(injections: &[ContextInjection]) -> Option<usize> {
+    let lowest = injections.iter().map(ContextInjection::priority).min()?;
+    injections
+        .iter()
+        .rposition(|injection| injection.priority() == lowest)
+}
+
+/// The longest prefix of `text` that takes at most `keep` tokens, without
+/// trailing whitespace.
+fn truncate_tokens(text: &str, keep: usize, tokenizer: &dyn Tokenizer) -> String {
+    let ends: Vec<usize> = text
+        .char_indices()
+        .map(|(at, _)| at)
+        .skip(1)
+        .chain([text.len()])
+        .collect();
+    // Number of characters kept, found by bisection
+    let (mut low, mut high) = (0, ends.len());
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if tokenizer.count_tokens(&text[..ends[mid - 1]]) <= keep {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    match low {
+        0 => String::new(),
+        chars => text[..ends[chars - 1]].trim_end().to_string(),
+    }
+}
+
+fn truncate_chars(text: &mut String, cap: usize) {
+    if let Some((at, _)) = text.char_indices().nth(cap) {
+        text.truncate(at);
+    }
+}
@@ -0,0 +1,241 @@
+//! Linking push events to the inferences they trigger.
+//!
+//! A host accepting a `push/event` may answer with the `inferenceId` of the
+//! inference the event started. On the server, an [`InferenceCorrelator`]
+//! remembers those links and resolves them when the inference's
+//! `context/afterInference` or channel output arrives:
+//!
+//! ```ignore
+//! let correlator = InferenceCorrelator::new();
+//! let server = McplServer::builder().handler(game).correlator(correlator.clone()).build();
+//! let queue = PushEventQueue::new(PushQueueOptions {
+//!     correlator: Some(correlator.clone()),
+//!     ..Default::default()
+//! });
+//! let id = queue.push(PushEvent::builder("game").text("Your move").build())?;
+//! match correlator.await_inference_outcome(&id).await? {
+//!     InferenceOutcome::AfterInference(params) => println!("{}", params.assistant_message),
+//!     InferenceOutcome::ChannelOutput(params) => println!("{:?}", params.content),
+//! }
+//! ```
+//!
+//! On the host, [`InferenceLinks`] records the same links from the other
+//! side.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+use crate::methods::{ChannelsOutgoingCompleteParams, ContextAfterInferenceParams};
+
+/// Default number of settled events whose outcome is kept for
+/// [`InferenceCorrelator::await_inference_outcome`].
+pub const DEFAULT_OUTCOME_RETENTION: usize = 1024;
+
+/// How an inference triggered by a push event ended, as seen by the server.
+#[derive(Debug, Clone)]
+pub enum InferenceOutcome {
+    AfterInference(ContextAfterInferenceParams),
+    ChannelOutput(ChannelsOutgoingCompleteParams),
+}
+
+impl InferenceOutcome {
+    pub fn inference_id(&self) -> &str {
+        match self {
+            InferenceOutcome::AfterInference(params) => &params.inference_id,
+            InferenceOutcome::ChannelOutput(params) => &params.inference_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CorrelationError {
+    /// The event was not delivered: the host rejected it or it was
+    /// discarded before being sent.
+    #[error("Push event {event_id} was not delivered: {reason}")]
+    Rejected { event_id: String, reason: String },
+    /// The host accepted the event without starting an inference.
+    #[error("Push event {0} did not trigger an inference")]
+    NoInference(String),
+}
+
+/// Server-side links from push event ids to inference ids, resolved as the
+/// inferences finish. Cheap to clone; clones share the links.
+///
+/// The first outcome reported for an inference settles every event linked
+/// to it; later ones are ignored. Settled outcomes are kept for the most
+/// recent [`DEFAULT_OUTCOME_RETENTION`] events.
+#[derive(Clone)]
+pub struct InferenceCorrelator {
+    inner: Arc<CorrelatorInner>,
+}
+
+struct CorrelatorInner {
+    retention: usize,
+    state: Mutex<CorrelatorState>,
+    settled: Notify,
+}
+
+#[derive(Default)]
+struct CorrelatorState {
+    /// Events waiting for their inference to finish, by inference id.
+    waiting: HashMap<String, Vec<String>>,
+    outcomes: HashMap<String, Result<InferenceOutcome, CorrelationError>>,
+    /// Settled event ids, oldest first, for bounding `outcomes`.
+    order: VecDeque<String>,
+}
+
+impl Default for InferenceCorrelator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InferenceCorrelator {
+    pub fn new() -> Self {
+        Self::with_retention(DEFAULT_OUTCOME_RETENTION)
+    }
+
+    /// Keep the outcomes of the last `retention` settled events.
+    pub fn with_retention(retention: usize) -> Self {
+        Self {
+            inner: Arc::new(CorrelatorInner {
+                retention,
+                state: Mutex::new(CorrelatorState::default()),
+                settled: Notify::new(),
+            }),
+        }
+    }
+
+    /// Note that `event_id` started `inference_id`.
+    pub fn link(&self, event_id: impl Into<String>, inference_id: impl Into<String>) {
+        self.state()
+            .waiting
+            .entry(inference_id.into())
+            .or_default()
+            .push(event_id.into());
+    }
+
+    /// Settle `event_id` with an error, e.g. because the host rejected it.
+    pub fn fail(&self, event_id: impl Into<String>, error: CorrelationError) {
+        self.state()
+            .settle(event_id.into(), Err(error), self.inner.retention);
+        self.inner.settled.notify_waiters();
+    }
+
+    /// Settle the events linked to the outcome's inference. Returns their
+    /// ids; empty if none were linked or they were settled before.
+    pub fn complete(&self, outcome: InferenceOutcome) -> Vec<String> {
+        let events = {
+            let mut state = self.state();
+            let events = state
+                .waiting
+                .remove(outcome.inference_id())
+                .unwrap_or_default();
+            for event_id in &events {
+                state.settle(event_id.clone(), Ok(outcome.clone()), self.inner.retention);
+            }
+            events
+        };
+        if !events.is_empty() {
+            self.inner.settled.notify_waiters();
+        }
+        events
+    }
+
+    /// The inference `event_id` started, while it has not finished.
+    pub fn inference_for(&self, event_id: &str) -> Option<String> {
+        self.state()
+            .waiting
+            .iter()
+            .find(|(_, events)| events.iter().any(|id| id == event_id))
+            .map(|(inference_id, _)| inference_id.clone())
+    }
+
+    /// Wait for the inference `event_id` triggered to finish.
+    ///
+    /// Resolves at once for an event settled recently. Waits indefinitely
+    /// for an event that is never linked or settled, e.g. one pushed
+    /// without a correlator; wrap in a timeout where that can happen.
+    pub async fn await_inference_outcome(
+        &self,
+        event_id: &str,
+    ) -> Result<InferenceOutcome, CorrelationError> {
+        loop {
+            let settled = self.inner.settled.notified();
+            tokio::pin!(settled);
+            settled.as_mut().enable();
+            if let Some(outcome) = self.state().outcomes.get(event_id) {
+                return outcome.clone();
+            }
+            settled.await;
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, CorrelatorState> {
+        self.inner.state.lock().unwrap()
+    }
+}
+
+impl CorrelatorState {
+    fn settle(
+        &mut self,
+        event_id: String,
+        outcome: Result<InferenceOutcome, CorrelationError>,
+        retention: usize,
+    ) {
+        if self.outcomes.contains_key(&event_id) || retention == 0 {
+            return;
+        }
+        while self.order.len() >= retention {
+            if let Some(oldest) = self.order.pop_front() {
+                self.outcomes.remove(&oldest);
+            }
+        }
+        self.outcomes.insert(event_id.clone(), outcome);
+        self.order.push_back(event_id);
+    }
+}
+
+/// Host-side record of which push events started which inference, kept by
+/// [`McplHost`](crate::McplHost) from the results of
+/// [`McplHostHandler::on_push_event`](crate::McplHostHandler::on_push_event).
+#[derive(Debug, Clone, Default)]
+pub struct InferenceLinks {
+    events: HashMap<String, Vec<String>>,
+}
+
+impl InferenceLinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn link(&mut self, event_id: impl Into<String>, inference_id: impl Into<String>) {
+        self.events
+            .entry(inference_id.into())
+            .or_default()
+            .push(event_id.into());
+    }
+
+    /// Events that started `inference_id`, in the order they were linked.
+    pub fn events_for(&self, inference_id: &str) -> &[String] {
+        self.events
+            .get(inference_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Forget `inference_id` once it has finished, returning its events.
+    pub fn finish(&mut self, inference_id: &str) -> Vec<String> {
+        self.events.remove(inference_id).unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::capabilities::*;
+use crate::handler::HandlerResult;
+use crate::methods::*;
+use crate::request::RequestContext;
+use crate::router::{RequestHandler, Router};
+use crate::types::*;
+
+/// Capabilities, feature set declarations, and request handlers of a
+/// server, defined together so they cannot drift apart.
+///
+/// ```ignore
+/// let (capabilities, router) = ServerDefinition::new(McplCapabilities::new("0.4"))
+///     .feature_set(
+///         FeatureSet::new("game")
+///             .description("Turn-based game state")
+///             .on(method::STATE_ROLLBACK, |ctx, params: StateRollbackParams| async move { ... })
+///             .on("game/move", |ctx, params: MoveParams| async move { ... }),
+///     )
+///     .feature_set(FeatureSet::new("lobby").on("lobby/join", join))
+///     .build();
+/// accept_initialize(&mut connection, capabilities, info).await?;
+/// router.serve(connection).await?;
+/// ```
+///
+/// Handling a method declares what it needs: `state/rollback` sets the
+/// `rollback` capability and the feature set's `rollback` flag,
+/// `context/beforeInference` and `context/afterInference` the context hook
+/// capabilities, and `channels/*` methods the `channels` capability.
+///
+/// Requests are routed by their `featureSet` param; one naming no feature
+/// set that handles the method is answered with `ERR_UNKNOWN_FEATURE_SET`.
+/// Requests without the param, such as `channels/*` and context hooks, go to
+/// the feature set handling the method when only one does.
+pub struct ServerDefinition {
+    capabilities: McplCapabilities,
+    feature_sets: Vec<FeatureSet>,
+    router: Router,
+}
+
+/// One feature set of a [`ServerDefinition`]: its declaration and the
+/// handlers for its methods.
+pub struct FeatureSet {
+    declaration: FeatureSetDeclaration,
+    router: Router,
+}
+
+impl ServerDefinition {
+    /// Start from `capabilities`; the definition adds feature sets and the
+    /// capabilities their handlers imply.
+    pub fn new(capabilities: McplCapabilities) -> Self {
+        Self {
+            capabilities,
+            feature_sets: Vec::new(),
+            router: Router::new(),
+        }
+    }
+
+    /// Add a feature set, replacing any with the same name.
+    pub fn feature_set(mut self, feature_set: FeatureSet) -> Self {
+        let name = &feature_set.declaration.name;
+        self.feature_sets.retain(|fs| &fs.declaration.name != name);
+        self.feature_sets.push(feature_set);
+        self
+    }
+
+    /// Handle a method that belongs to no feature set, as [`Router::on`].
+    pub fn on<P, R, F, Fut>(mut self, method: &str, handler: F) -> Self
+    where
+        P: DeserializeOwned + Send + 'static,
+        R: Serialize + 'static,
+        F: Fn(RequestContext, P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult<R>> + Send + 'static,
+    {
+        self.router.on(method, handler);
+        self
+    }
+
+    /// Further configure the router, e.g. to add notification handlers or
+    /// middleware.
+    pub fn router(mut self, configure: impl FnOnce(&mut Router)) -> Self {
+        configure(&mut self.router);
+        self
+    }
+
+    /// The capabilities to advertise and the router serving them.
+    pub fn build(self) -> (McplCapabilities, Router) {
+        let mut capabilities = self.capabilities;
+        let mut router = self.router;
+        let mut declarations = capabilities.feature_sets.take().unwrap_or_default();
+        let mut routes: HashMap<String, HashMap<String, RequestHandler>> = HashMap::new();
+
+        for feature_set in self.feature_sets {
+            let mut declaration = feature_set.declaration;
+            for method in feature_set.router.request_methods() {
+                if method == method::STATE_ROLLBACK {
+                    declaration.rollback = true;
+                }
+                implied_capability(&mut capabilities, method);
+                let handler = feature_set
+                    .router
+                    .request_handler(method)
+                    .expect("listed methods have handlers");
+                routes
+                    .entry(method.to_string())
+                    .or_default()
+                    .insert(declaration.name.clone(), handler);
+            }
+            declarations.retain(|d| d.name != declaration.name);
+            declarations.push(declaration);
+        }
+
+        let methods: BTreeSet<String> = routes.keys().cloned().collect();
+        for method in methods {
+            let handlers = Arc::new(routes.remove(&method).unwrap_or_default());
+            router.on_request(&method, move |ctx| {
+                let name = ctx.request().param::<String>("featureSet");
+                let handler = match name {
+                    Some(name) => handlers.get(&name).cloned().ok_or_else(|| {
+                        JsonRpcError::new(
+                            ERR_UNKNOWN_FEATURE_SET,
+                            format!("Unknown feature set: {}", name),
+                        )
+                    }),
+                    None if handlers.len() == 1 => {
+                        Ok(handlers.values().next().cloned().expect("one handler"))
+                    }
+                    None => Err(JsonRpcError::invalid_params("missing featureSet")),
+                };
+                async move { handler?(ctx).await }
+            });
+        }
+
+        if !declarations.is_empty() {
+            capabilities.feature_sets = Some(declarations);
+        }
+        (capabilities, router)
+    }
+}
+
+/// Set the capability a server handling `method` must advertise.
+fn implied_capability(capabilities: &mut McplCapabilities, method: &str) {
+    match method {
+        method::STATE_ROLLBACK => capabilities.rollback = Some(true),
+        method::CONTEXT_BEFORE_INFERENCE => {
+            capabilities
+                .context_hooks
+                .get_or_insert_with(no_context_hooks)
+                .before_inference = true;
+        }
+        method::CONTEXT_AFTER_INFERENCE => {
+            capabilities
+                .context_hooks
+                .get_or_insert_with(no_context_hooks)
+                .after_inference = Some(AfterInferenceCap { blocking: true });
+        }
+        method::CHANNELS_LIST
+        | method::CHANNELS_OPEN
+        | method::CHANNELS_CLOSE
+        | method::CHANNELS_PUBLISH => capabilities.channels = Some(true),
+        _ => {}
+    }
+}
+
+fn no_context_hooks() -> ContextHooksCap {
+    ContextHooksCap {
+        before_inference: false,
+        after_inference: None,
+    }
+}
+
+impl FeatureSet {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            declaration: FeatureSetDeclaration::named(name),
+            router: Router::new(),
+        }
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.declaration = self.declaration.description(description);
+        self
+    }
+
+    pub fn uses(mut self, uses: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.declaration = self.declaration.uses(uses);
+        self
+    }
+
+    pub fn host_state(mut self, host_state: bool) -> Self {
+        self.declaration = self.declaration.host_state(host_state);
+        self
+    }
+
+    /// Handle `method` for requests naming this feature set, as
+    /// [`Router::on`].
+    pub fn on<P, R, F, Fut>(mut self, method: &str, handler: F) -> Self
+    where
+        P: DeserializeOwned + Send + 'static,
+        R: Serialize + 'static,
+        F: Fn(RequestContext, P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult<R>> + Send + 'static,
+    {
+        self.router.on(method, handler);
+        self
+    }
+
+    pub fn declaration(&self) -> &FeatureSetDeclaration {
+        &self.declaration
+    }
+}
@@ -0,0 +1,688 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::methods::*;
+use crate::version::FeatureSetVersion;
+
+/// Declared feature sets and which of them the host has enabled, kept
+/// current by applying `featureSets/update` and `featureSets/changed`.
+///
+/// Usable on either side: a server declares its feature sets and applies
+/// the host's updates; a host applies the server's changes and its own
+/// updates.
+///
+/// ```ignore
+/// let mut registry = FeatureSetRegistry::from_declarations(capabilities.feature_sets.clone().unwrap_or_default());
+/// registry.apply_update(&params);
+/// if registry.is_enabled("game") { ... }
+/// ```
+///
+/// Tasks can [`watch`](Self::watch_enabled) the enabled set instead of
+/// polling it:
+///
+/// ```ignore
+/// let mut enabled = registry.watch_enabled();
+/// tokio::spawn(async move {
+///     enabled.wait_for(|enabled| !enabled.contains("telemetry")).await.ok();
+///     // the host disabled telemetry; stop pushing
+/// });
+/// ```
+#[derive(Debug, Default)]
+pub struct FeatureSetRegistry {
+    declarations: BTreeMap<String, FeatureSetDeclaration>,
+    /// Enabled feature sets; receivers see every change.
+    enabled: watch::Sender<BTreeSet<String>>,
+    scopes: HashMap<String, ScopeConfig>,
+    /// `scopes`, compiled when they are set.
+    matchers: HashMap<String, ScopeMatcher>,
+    /// Tools and channels `uses` entries may name besides feature sets.
+    provided: BTreeSet<String>,
+}
+
+/// A clone starts with the same state but its own watch channel.
+impl Clone for FeatureSetRegistry {
+    fn clone(&self) -> Self {
+        Self {
+            declarations: self.declarations.clone(),
+            enabled: watch::Sender::new(self.enabled.borrow().clone()),
+            scopes: self.scopes.clone(),
+            matchers: self.matchers.clone(),
+            provided: self.provided.clone(),
+        }
+    }
+}
+
+/// A [`FeatureSetRegistry`]'s declarations, enabled feature sets, and
+/// scopes, for persisting across restarts.
+///
+/// ```ignore
+/// std::fs::write(path, serde_json::to_vec(&registry.snapshot())?)?;
+/// // after restarting and reconnecting
+/// let snapshot: FeatureSetSnapshot = serde_json::from_slice(&std::fs::read(path)?)?;
+/// client.feature_sets_update(&snapshot.update_params()).await?;
+/// registry.restore(snapshot);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeatureSetSnapshot {
+    #[serde(default)]
+    pub declarations: Vec<FeatureSetDeclaration>,
+    #[serde(default)]
+    pub enabled: Vec<String>,
+    #[serde(default)]
+    pub scopes: BTreeMap<String, ScopeConfig>,
+}
+
+impl FeatureSetSnapshot {
+    /// The `featureSets/update` that re-enables the snapshot's feature sets
+    /// with their scopes, e.g. after reconnecting.
+    pub fn update_params(&self) -> FeatureSetsUpdateParams {
+        FeatureSetsUpdateParams {
+            enabled: Some(self.enabled.clone()),
+            disabled: None,
+            scopes: (!self.scopes.is_empty()).then(|| {
+                self.scopes
+                    .iter()
+                    .map(|(name, scope)| (name.clone(), scope.clone()))
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// A feature set names dependencies that are not available.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Feature set {feature_set} uses unknown {}", missing.join(", "))]
+pub struct UnresolvedDependencies {
+    pub feature_set: String,
+    pub missing: Vec<String>,
+}
+
+impl FeatureSetRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_declarations(
+        declarations: impl IntoIterator<Item = FeatureSetDeclaration>,
+    ) -> Self {
+        let mut registry = Self::new();
+        for declaration in declarations {
+            registry.declare(declaration);
+        }
+        registry
+    }
+
+    /// Add a declaration, returning the one it replaces. Replacing keeps the
+    /// feature set's enabled state and scope.
+    pub fn declare(&mut self, declaration: FeatureSetDeclaration) -> Option<FeatureSetDeclaration> {
+        self.declarations
+            .insert(declaration.name.clone(), declaration)
+    }
+
+    /// Declare a feature set only if every `uses` entry resolves: names a
+    /// declared feature set or something [`provide`](Self::provide)d.
+    pub fn register(
+        &mut self,
+        declaration: FeatureSetDeclaration,
+    ) -> Result<Option<FeatureSetDeclaration>, UnresolvedDependencies> {
+        let missing = self.unresolved(&declaration);
+        if !missing.is_empty() {
+            return Err(UnresolvedDependencies {
+                feature_set: declaration.name,
+                missing,
+            });
+        }
+        Ok(self.declare(declaration))
+    }
+
+    /// Make tools or channels available to `uses` entries.
+    pub fn provide(&mut self, names: impl IntoIterator<Item = impl Into<String>>) {
+        self.provided.extend(names.into_iter().map(Into::into));
+    }
+
+    /// `uses` entries of `declaration` that name neither a declared feature
+    /// set nor anything provided.
+    pub fn unresolved(&self, declaration: &FeatureSetDeclaration) -> Vec<String> {
+        declaration
+            .uses
+            .iter()
+            .filter(|name| {
+                *name != &declaration.name
+                    && !self.declarations.contains_key(*name)
+                    && !self.provided.contains(*name)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// `name` and the declared feature sets it uses, directly or through
+    /// others, sorted.
+    pub fn dependencies(&self, name: &str) -> Vec<String> {
+        let mut closure = BTreeSet::new();
+        let mut pending = vec![name.to_string()];
+        while let Some(name) = pending.pop() {
+            if let Some(declaration) = self.declarations.get(&name) {
+                if closure.insert(name) {
+                    pending.extend(declaration.uses.iter().cloned());
+                }
+            }
+        }
+        closure.into_iter().collect()
+    }
+
+    /// Remove a declaration; the feature set is no longer enabled and its
+    /// scope is forgotten.
+    pub fn remove(&mut self, name: &str) -> Option<FeatureSetDeclaration> {
+        self.enabled
+            .send_if_modified(|enabled| enabled.remove(name));
+        self.scopes.remove(name);
+        self.matchers.remove(name);
+        self.declarations.remove(name)
+    }
+
+    pub fn declaration(&self, name: &str) -> Option<&FeatureSetDeclaration> {
+        self.declarations.get(name)
+    }
+
+    /// Declarations, ordered by name.
+    pub fn declarations(&self) -> impl Iterator<Item = &FeatureSetDeclaration> {
+        self.declarations.values()
+    }
+
+    pub fn is_declared(&self, name: &str) -> bool {
+        self.declarations.contains_key(name)
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.borrow().contains(name)
+    }
+
+    /// Names of the enabled feature sets, sorted.
+    pub fn enabled(&self) -> Vec<String> {
+        self.enabled.borrow().iter().cloned().collect()
+    }
+
+    /// The enabled feature sets, updated whenever they change. Updates that
+    /// leave them as they were are not seen.
+    pub fn watch_enabled(&self) -> watch::Receiver<BTreeSet<String>> {
+        self.enabled.subscribe()
+    }
+
+    /// Scope configuration the host sent for `name`, if any.
+    pub fn scope(&self, name: &str) -> Option<&ScopeConfig> {
+        self.scopes.get(name)
+    }
+
+    /// Whether the scope of `feature_set` permits `target`, using the
+    /// patterns compiled when the scope was last updated. Feature sets
+    /// without a scope permit everything.
+    pub fn permits(&self, feature_set: &str, target: &str) -> bool {
+        self.matchers
+            .get(feature_set)
+            .is_none_or(|matcher| matcher.permits(target))
+    }
+
+    /// The compiled scope of `feature_set`, e.g. to apply
+    /// [`EmptyWhitelist::DenyAll`].
+    pub fn scope_matcher(&self, feature_set: &str) -> Option<&ScopeMatcher> {
+        self.matchers.get(feature_set)
+    }
+
+    /// Apply a host's `featureSets/update`. Enabling a feature set also
+    /// enables the feature sets it [depends on](Self::dependencies), and
+    /// wins over disabling the same name; a scope replaces the feature set's
+    /// previous one. Names that are not declared are ignored and returned.
+    pub fn apply_update(&mut self, update: &FeatureSetsUpdateParams) -> Vec<String> {
+        let mut unknown = Vec::new();
+        let mut known = |name: &String| {
+            let declared = self.declarations.contains_key(name);
+            if !declared && !unknown.contains(name) {
+                unknown.push(name.clone());
+            }
+            declared
+        };
+        let disabled: Vec<String> = update
+            .disabled
+            .iter()
+            .flatten()
+            .filter(|n| known(n))
+            .cloned()
+            .collect();
+        let enabled: Vec<String> = update
+            .enabled
+            .iter()
+            .flatten()
+            .filter(|n| known(n))
+            .flat_map(|n| self.dependencies(n))
+            .collect();
+        let scopes: Vec<(String, ScopeConfig)> = update
+            .scopes
+            .iter()
+            .flatten()
+            .filter(|(name, _)| known(name))
+            .map(|(name, scope)| (name.clone(), scope.clone()))
+            .collect();
+        self.enabled.send_if_modified(|current| {
+            let before = current.clone();
+            for name in &disabled {
+                current.remove(name);
+            }
+            current.extend(enabled);
+            *current != before
+        });
+        for (name, scope) in scopes {
+            self.matchers.insert(name.clone(), scope.compile());
+            self.scopes.insert(name, scope);
+        }
+        unknown
+    }
+
+    /// The declarations, enabled feature sets, and scopes, sorted by name.
+    /// What was [`provide`](Self::provide)d is not included.
+    pub fn snapshot(&self) -> FeatureSetSnapshot {
+        FeatureSetSnapshot {
+            declarations: self.declarations().cloned().collect(),
+            enabled: self.enabled(),
+            scopes: self
+                .scopes
+                .iter()
+                .map(|(name, scope)| (name.clone(), scope.clone()))
+                .collect(),
+        }
+    }
+
+    /// Replace the declarations, enabled feature sets, and scopes with a
+    /// snapshot's. Enabled names and scopes for feature sets the snapshot
+    /// does not declare are dropped; watchers see the new enabled set.
+    pub fn restore(&mut self, snapshot: FeatureSetSnapshot) {
+        self.declarations = snapshot
+            .declarations
+            .into_iter()
+            .map(|declaration| (declaration.name.clone(), declaration))
+            .collect();
+        let declared = |name: &String| self.declarations.contains_key(name);
+        let enabled: BTreeSet<String> = snapshot.enabled.into_iter().filter(declared).collect();
+        self.scopes = snapshot
+            .scopes
+            .into_iter()
+            .filter(|(name, _)| declared(name))
+            .collect();
+        self.matchers = self
+            .scopes
+            .iter()
+            .map(|(name, scope)| (name.clone(), scope.compile()))
+            .collect();
+        self.enabled.send_if_modified(|current| {
+            let changed = *current != enabled;
+            *current = enabled;
+            changed
+        });
+    }
+
+    /// [`version_support`](FeatureSetDeclaration::version_support) of every
+    /// declaration, by name. `understood` holds the versions the host
+    /// understands; feature sets missing from it have no expectations.
+    pub fn version_support(
+        &self,
+        host_version: FeatureSetVersion,
+        understood: &HashMap<String, FeatureSetVersion>,
+    ) -> BTreeMap<String, VersionSupport> {
+        self.declarations()
+            .map(|declaration| {
+                let support = declaration
+                    .version_support(host_version, understood.get(&declaration.name).copied());
+                (declaration.name.clone(), support)
+            })
+            .collect()
+    }
+
+    /// The `featureSets/changed` that turns `old`'s declarations into
+    /// `new`'s: declarations that are new or differ are added, missing ones
+    /// removed. Both fields are `None` when nothing changed.
+    pub fn diff(old: &FeatureSetRegistry, new: &FeatureSetRegistry) -> FeatureSetsChangedParams {
+        let added: HashMap<String, FeatureSetDeclaration> = new
+            .declarations()
+            .filter(|declaration| old.declaration(&declaration.name) != Some(*declaration))
+            .map(|declaration| (declaration.name.clone(), declaration.clone()))
+            .collect();
+        let removed: Vec<String> = old
+            .declarations
+            .keys()
+            .filter(|name| !new.is_declared(name))
+            .cloned()
+            .collect();
+        FeatureSetsChangedParams {
+            added: (!added.is_empty()).then_some(added),
+            removed: (!removed.is_empty()).then_some(removed),
+        }
+    }
+
+    /// Apply a server's `featureSets/changed`.
+    pub fn apply_changed(&mut self, changed: &FeatureSetsChangedParams) {
+        for name in changed.removed.iter().flatten() {
+            self.remove(name);
+        }
+        for declaration in changed.added.iter().flat_map(|added| added.values()) {
+            self.declare(declaration.clone());
+        }
+    }
+}
+
+/// Longest `description` a feature set declaration may have, in characters.
+pub const MAX_DESCRIPTION_LEN: usize = 1024;
+
+/// Why a feature set declaration, or a list of them, is invalid.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+    #[error("Feature set name is empty")]
+    EmptyName,
+    #[error("Feature set name {name:?} contains {character:?}; use ASCII letters, digits, '.', '_' and '-', starting with a letter or digit")]
+    InvalidName { name: String, character: char },
+    #[error("Feature set {0} is declared more than once")]
+    DuplicateName(String),
+    #[error("Feature set {name} has invalid version {version:?}")]
+    InvalidVersion { name: String, version: String },
+    #[error("Feature set {0} keeps host-managed state but does not support rollback")]
+    HostStateWithoutRollback(String),
+    #[error("Description of feature set {name} is {len} characters; at most {MAX_DESCRIPTION_LEN} are allowed")]
+    DescriptionTooLong { name: String, len: usize },
+}
+
+/// Whether a host can use a declared feature set, by version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionSupport {
+    /// The host understands the declared version, or no version is involved.
+    Supported,
+    /// The declared version is newer than the host understands, within the
+    /// same major version; the host uses it as this older version.
+    Downgraded(FeatureSetVersion),
+    /// The host cannot use the feature set, and should leave it disabled.
+    Refused(String),
+}
+
+impl FeatureSetDeclaration {
+    /// A feature set with no description, dependencies, or state support,
+    /// to build on fluently, e.g.
+    /// `FeatureSetDeclaration::named("game").uses(["commands"]).rollback(true)`.
+    pub fn named(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            description: None,
+            uses: Vec::new(),
+            rollback: false,
+            host_state: false,
+            version: None,
+            min_host_version: None,
+        }
+    }
+
+    /// A feature set whose state the host keeps and rolls back
+    /// (`hostState` and `rollback`).
+    pub fn host_managed(name: impl Into<String>) -> Self {
+        Self::named(name).host_state(true).rollback(true)
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Replace the tools, channels, and feature sets this one uses.
+    pub fn uses(mut self, uses: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.uses = uses.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn rollback(mut self, rollback: bool) -> Self {
+        self.rollback = rollback;
+        self
+    }
+
+    pub fn host_state(mut self, host_state: bool) -> Self {
+        self.host_state = host_state;
+        self
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn min_host_version(mut self, version: impl Into<String>) -> Self {
+        self.min_host_version = Some(version.into());
+        self
+    }
+
+    /// How a host at `host_version` that understands the feature set up to
+    /// version `understood` (`None` if it has no version expectations) can
+    /// use it.
+    ///
+    /// A `minHostVersion` above `host_version` refuses the feature set. A
+    /// declared `version` newer than `understood` is downgraded if the major
+    /// versions match and refused otherwise. Unparseable versions refuse.
+    pub fn version_support(
+        &self,
+        host_version: FeatureSetVersion,
+        understood: Option<FeatureSetVersion>,
+    ) -> VersionSupport {
+        let parse = |version: &Option<String>| {
+            version
+                .as_deref()
+                .map(str::parse::<FeatureSetVersion>)
+                .transpose()
+        };
+        let (min_host, declared) = match (parse(&self.min_host_version), parse(&self.version)) {
+            (Ok(min_host), Ok(declared)) => (min_host, declared),
+            (Err(e), _) | (_, Err(e)) => return VersionSupport::Refused(e.to_string()),
+        };
+        if let Some(min_host) = min_host.filter(|min_host| *min_host > host_version) {
+            return VersionSupport::Refused(format!(
+                "{} requires host version {} or later; this host is {}",
+                self.name, min_host, host_version
+            ));
+        }
+        match (declared, understood) {
+            (Some(declared), Some(understood)) if declared > understood => {
+                if declared.major == understood.major {
+                    VersionSupport::Downgraded(understood)
+                } else {
+                    VersionSupport::Refused(format!(
+                        "{} is version {}; this host understands up to {}",
+                        self.name, declared, understood
+                    ))
+                }
+            }
+            _ => VersionSupport::Supported,
+        }
+    }
+
+    /// Check the declaration against the rules hosts rely on: a non-empty
+    /// name of ASCII letters, digits, `.`, `_` and `-` that starts with a
+    /// letter or digit; `hostState` only together with `rollback`, since
+    /// host-managed state is restored through checkpoints; `version` and
+    /// `minHostVersion` that parse as [`FeatureSetVersion`]s; and a
+    /// description of at most [`MAX_DESCRIPTION_LEN`] characters.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let name = &self.name;
+        if name.is_empty() {
+            return Err(ValidationError::EmptyName);
+        }
+        let invalid = name.char_indices().find(|&(i, c)| {
+            let allowed = c.is_ascii_alphanumeric() || (i > 0 && matches!(c, '.' | '_' | '-'));
+            !allowed
+        });
+        if let Some((_, character)) = invalid {
+            return Err(ValidationError::InvalidName {
+                name: name.clone(),
+                character,
+            });
+        }
+        for version in [&self.version, &self.min_host_version]
+            .into_iter()
+            .flatten()
+        {
+            if version.parse::<FeatureSetVersion>().is_err() {
+                return Err(ValidationError::InvalidVersion {
+                    name: name.clone(),
+                    version: version.clone(),
+                });
+            }
+        }
+        if self.host_state && !self.rollback {
+            return Err(ValidationError::HostStateWithoutRollback(name.clone()));
+        }
+        let len = self.description.as_deref().map_or(0, |d| d.chars().count());
+        if len > MAX_DESCRIPTION_LEN {
+            return Err(ValidationError::DescriptionTooLong {
+                name: name.clone(),
+                len,
+            });
+        }
+        Ok(())
+    }
+
+    /// [`validate`](Self::validate) each declaration and check that no name
+    /// is declared twice.
+    pub fn validate_all(declarations: &[FeatureSetDeclaration]) -> Result<(), ValidationError> {
+        let mut names = BTreeSet::new();
+        for declaration in declarations {
+            declaration.validate()?;
+            if !names.insert(declaration.name.as_str()) {
+                return Err(ValidationError::DuplicateName(declaration.name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a [`ScopeConfig`] whose whitelist is present but empty permits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyWhitelist {
+    /// An empty whitelist restricts nothing, as if it were absent.
+    #[default]
+    AllowAll,
+    /// An empty whitelist permits nothing.
+    DenyAll,
+}
+
+impl ScopeConfig {
+    /// Whether `target` is in scope, treating an empty whitelist as
+    /// [`EmptyWhitelist::AllowAll`].
+    pub fn permits(&self, target: &str) -> bool {
+        self.permits_with(target, EmptyWhitelist::AllowAll)
+    }
+
+    /// Whether `target` is in scope.
+    ///
+    /// A target matching any blacklist pattern is denied, even if the
+    /// whitelist also matches it. Otherwise an absent whitelist permits
+    /// everything, an empty one follows `empty`, and a non-empty one permits
+    /// only targets matching one of its patterns.
+    ///
+    /// Patterns are globs: `*` matches any run of characters, including
+    /// none, and `?` exactly one; everything else matches itself.
+    ///
+    /// This parses the patterns on every call; [`compile`](Self::compile)
+    /// them to check many targets.
+    pub fn permits_with(&self, target: &str, empty: EmptyWhitelist) -> bool {
+        self.compile().permits_with(target, empty)
+    }
+
+    /// Parse the patterns once, for checking many targets.
+    pub fn compile(&self) -> ScopeMatcher {
+        let compile = |patterns: &Vec<String>| patterns.iter().map(|p| Pattern::new(p)).collect();
+        ScopeMatcher {
+            whitelist: self.whitelist.as_ref().map(compile),
+            blacklist: self.blacklist.as_ref().map(compile).unwrap_or_default(),
+        }
+    }
+}
+
+/// A [`ScopeConfig`] with its patterns parsed, so checking a target does not
+/// re-parse them. Exact names and patterns whose only wildcard is a trailing
+/// `*` (e.g. `chat.lobby.*`) are compared directly; other globs are matched
+/// character by character.
+#[derive(Debug, Clone)]
+pub struct ScopeMatcher {
+    whitelist: Option<Vec<Pattern>>,
+    blacklist: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Glob(Vec<char>),
+}
+
+impl ScopeMatcher {
+    /// As [`ScopeConfig::permits`].
+    pub fn permits(&self, target: &str) -> bool {
+        self.permits_with(target, EmptyWhitelist::AllowAll)
+    }
+
+    /// As [`ScopeConfig::permits_with`].
+    pub fn permits_with(&self, target: &str, empty: EmptyWhitelist) -> bool {
+        let matches = |patterns: &[Pattern]| patterns.iter().any(|p| p.matches(target));
+        if matches(&self.blacklist) {
+            return false;
+        }
+        match self.whitelist.as_deref() {
+            None => true,
+            Some([]) => empty == EmptyWhitelist::AllowAll,
+            Some(patterns) => matches(patterns),
+        }
+    }
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        let wildcard = |c: char| c == '*' || c == '?';
+        match pattern.find(wildcard) {
+            None => Pattern::Exact(pattern.to_string()),
+            Some(i) if i == pattern.len() - 1 && pattern.ends_with('*') => {
+                Pattern::Prefix(pattern[..i].to_string())
+            }
+            Some(_) => Pattern::Glob(pattern.chars().collect()),
+        }
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        match self {
+            Pattern::Exact(name) => name == target,
+            Pattern::Prefix(prefix) => target.starts_with(prefix.as_str()),
+            Pattern::Glob(pattern) => glob_match(pattern, target),
+        }
+    }
+}
+
+/// Match `target` against a glob of `*` and `?` wildcards.
+fn glob_match(pattern: &[char], target: &str) -> bool {
+    let target: Vec<char> = target.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*`, and the target position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < target.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == target[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
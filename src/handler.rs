@@ -0,0 +1,379 @@
+use std::fmt;
+use std::future::{poll_fn, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::connection::{CancellationToken, ConnectionError, IncomingMessage, McplConnection};
+use crate::methods::*;
+use crate::request::RequestContext;
+use crate::types::*;
+use crate::usage::BudgetExceeded;
+
+/// Result of a request handler; the error is sent to the peer as is.
+pub type HandlerResult<T> = Result<T, JsonRpcError>;
+
+/// Error a request handler answers with, built from an MCPL error code or
+/// converted from a common failure with `?`.
+///
+/// Converts into [`JsonRpcError`], so it can be returned from handlers
+/// written for [`mcpl_router`](crate::mcpl_router) as is, and from any
+/// [`HandlerResult`] handler with `?`:
+///
+/// ```ignore
+/// let checkpoint = self.checkpoints.get(&params.checkpoint)
+///     .ok_or_else(|| HandlerError::checkpoint_not_found(&params.checkpoint))?;
+/// ```
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{message} ({code})")]
+pub struct HandlerError {
+    pub code: i32,
+    pub message: String,
+    /// Sent as the error's `data`.
+    pub data: Option<serde_json::Value>,
+}
+
+impl HandlerError {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: serde_json::Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn invalid_params(reason: impl fmt::Display) -> Self {
+        JsonRpcError::invalid_params(reason).into()
+    }
+
+    pub fn internal(reason: impl fmt::Display) -> Self {
+        JsonRpcError::internal(reason).into()
+    }
+
+    pub fn feature_set_not_enabled(feature_set: &str) -> Self {
+        Self::new(
+            ERR_FEATURE_SET_NOT_ENABLED,
+            format!("Feature set not enabled: {}", feature_set),
+        )
+        .with_data(serde_json::json!({ "featureSet": feature_set }))
+    }
+
+    pub fn not_initialized() -> Self {
+        Self::new(ERR_NOT_INITIALIZED, "Not initialized")
+    }
+
+    pub fn unknown_feature_set(feature_set: &str) -> Self {
+        Self::new(
+            ERR_UNKNOWN_FEATURE_SET,
+            format!("Unknown feature set: {}", feature_set),
+        )
+        .with_data(serde_json::json!({ "featureSet": feature_set }))
+    }
+
+    pub fn checkpoint_not_found(checkpoint: &str) -> Self {
+        Self::new(
+            ERR_CHECKPOINT_NOT_FOUND,
+            format!("Checkpoint not found: {}", checkpoint),
+        )
+        .with_data(serde_json::json!({ "checkpoint": checkpoint }))
+    }
+
+    pub fn checkpoint_conflict(checkpoint: &str) -> Self {
+        Self::new(
+            ERR_CHECKPOINT_CONFLICT,
+            format!("Checkpoint already exists: {}", checkpoint),
+        )
+        .with_data(serde_json::json!({ "checkpoint": checkpoint }))
+    }
+
+    pub fn rollback_unsupported(feature_set: &str) -> Self {
+        Self::new(
+            ERR_ROLLBACK_UNSUPPORTED,
+            format!("Rollback not supported by feature set: {}", feature_set),
+        )
+        .with_data(serde_json::json!({ "featureSet": feature_set }))
+    }
+
+    /// Operation `index` of a patch failed for `reason`.
+    pub fn patch_failed(index: usize, reason: impl fmt::Display) -> Self {
+        let reason = reason.to_string();
+        Self::new(
+            ERR_PATCH_FAILED,
+            format!("Patch operation {} failed: {}", index, reason),
+        )
+        .with_data(serde_json::json!({ "index": index, "reason": reason }))
+    }
+
+    /// The model's answer did not match the requested `responseSchema`.
+    pub fn invalid_response(reason: impl fmt::Display) -> Self {
+        let reason = reason.to_string();
+        Self::new(
+            ERR_INVALID_RESPONSE,
+            format!("Invalid model response: {}", reason),
+        )
+        .with_data(serde_json::json!({ "reason": reason }))
+    }
+
+    pub fn budget_exceeded(exceeded: &BudgetExceeded) -> Self {
+        let data = match exceeded {
+            BudgetExceeded::FeatureSet { feature_set, limit } => {
+                serde_json::json!({ "featureSet": feature_set, "limit": limit.as_str() })
+            }
+            BudgetExceeded::Conversation {
+                conversation_id,
+                limit,
+            } => serde_json::json!({ "conversationId": conversation_id, "limit": limit.as_str() }),
+        };
+        Self::new(ERR_BUDGET_EXCEEDED, exceeded.to_string()).with_data(data)
+    }
+
+    pub fn channel_not_permitted(channel_id: &str) -> Self {
+        Self::new(
+            ERR_CHANNEL_NOT_PERMITTED,
+            format!("Channel not permitted: {}", channel_id),
+        )
+        .with_data(serde_json::json!({ "channelId": channel_id }))
+    }
+
+    pub fn unknown_channel(channel_id: &str) -> Self {
+        Self::new(ERR_UNKNOWN_CHANNEL, format!("Unknown channel: {}", channel_id))
+            .with_data(serde_json::json!({ "channelId": channel_id }))
+    }
+
+    pub fn channel_open_failed(reason: impl fmt::Display) -> Self {
+        Self::new(
+            ERR_CHANNEL_OPEN_FAILED,
+            format!("Channel open failed: {}", reason),
+        )
+    }
+}
+
+impl From<JsonRpcError> for HandlerError {
+    fn from(error: JsonRpcError) -> Self {
+        Self {
+            code: error.code,
+            message: error.message,
+            data: error.data,
+        }
+    }
+}
+
+impl From<HandlerError> for JsonRpcError {
+    fn from(error: HandlerError) -> Self {
+        Self {
+            code: error.code,
+            message: error.message,
+            data: error.data,
+        }
+    }
+}
+
+/// Payloads that do not match the expected shape: `-32602 Invalid params`.
+impl From<serde_json::Error> for HandlerError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::invalid_params(error)
+    }
+}
+
+impl From<std::io::Error> for HandlerError {
+    fn from(error: std::io::Error) -> Self {
+        Self::internal(error)
+    }
+}
+
+/// An error answer from a peer the handler called is passed on as is; other
+/// failures are internal errors.
+impl From<ConnectionError> for HandlerError {
+    fn from(error: ConnectionError) -> Self {
+        match error {
+            ConnectionError::Rpc { code, message } => Self::new(code, message),
+            error => Self::internal(error),
+        }
+    }
+}
+
+pub(crate) type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Default body for request handlers the application does not implement.
+pub(crate) fn not_found<T>(ctx: &RequestContext) -> impl Future<Output = HandlerResult<T>> + Send {
+    let error = JsonRpcError::method_not_found(ctx.method());
+    async move { Err(error) }
+}
+
+/// Typed request params; failures answer `-32602 Invalid params`.
+pub(crate) fn params<P: DeserializeOwned>(ctx: &RequestContext) -> HandlerResult<P> {
+    ctx.params().map_err(JsonRpcError::invalid_params)
+}
+
+pub(crate) fn encode<R: Serialize>(result: HandlerResult<R>) -> HandlerResult<serde_json::Value> {
+    serde_json::to_value(result?).map_err(JsonRpcError::internal)
+}
+
+/// Answer for a baseline method no handler was registered for: `ping`,
+/// `model/info` from the session's model, `featureSets/list` from the
+/// session's feature sets, and `channels/list` from the session's channel
+/// registry.
+pub(crate) fn builtin(ctx: &RequestContext) -> Option<HandlerResult<serde_json::Value>> {
+    match ctx.method() {
+        method::PING => Some(Ok(serde_json::json!({}))),
+        method::MODEL_INFO => Some(encode(model_info(ctx))),
+        method::FEATURE_SETS_LIST => Some(encode(feature_sets_list(ctx))),
+        method::CHANNELS_LIST => Some(encode(channels_list(ctx))),
+        _ => None,
+    }
+}
+
+pub(crate) fn model_info(ctx: &RequestContext) -> HandlerResult<ModelInfoResult> {
+    ctx.session()
+        .and_then(|session| session.model_info())
+        .ok_or_else(|| JsonRpcError::method_not_found(ctx.method()))
+}
+
+/// The feature sets this side declared, with the enabled state the host
+/// last set.
+pub(crate) fn feature_sets_list(ctx: &RequestContext) -> HandlerResult<FeatureSetsListResult> {
+    let Some(session) = ctx.session() else {
+        return Ok(FeatureSetsListResult {
+            feature_sets: Vec::new(),
+        });
+    };
+    let declared = session
+        .negotiated()
+        .and_then(|negotiated| negotiated.local_capabilities.feature_sets)
+        .unwrap_or_default();
+    let feature_sets = declared
+        .into_iter()
+        .map(|declaration| FeatureSetStatus {
+            enabled: session.is_feature_set_enabled(&declaration.name),
+            declaration,
+        })
+        .collect();
+    Ok(FeatureSetsListResult { feature_sets })
+}
+
+pub(crate) fn channels_list(ctx: &RequestContext) -> HandlerResult<ChannelsListResult> {
+    let channels = ctx
+        .session()
+        .map(|session| session.channels())
+        .unwrap_or_default();
+    Ok(ChannelsListResult { channels })
+}
+
+/// Typed notification params. Notifications cannot be answered, so bad
+/// params are only logged.
+pub(crate) fn notification_params<P: DeserializeOwned>(
+    notification: &JsonRpcNotification,
+) -> Option<P> {
+    match notification.params_as() {
+        Ok(params) => Some(params),
+        Err(e) => {
+            tracing::warn!(
+                "Ignoring {} with invalid params: {}",
+                notification.method,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Routing from incoming messages to application handlers, shared by the
+/// server and host runtimes.
+pub(crate) trait Dispatch: Send + Sync + 'static {
+    fn handle_request(
+        &self,
+        ctx: RequestContext,
+    ) -> impl Future<Output = HandlerResult<serde_json::Value>> + Send;
+
+    fn handle_notification(
+        &self,
+        notification: JsonRpcNotification,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+/// Run `dispatch` on a request, turning a handler panic into `-32603
+/// Internal error` so the request is still answered.
+pub(crate) async fn handle_guarded<D: Dispatch>(
+    dispatch: &D,
+    ctx: RequestContext,
+) -> HandlerResult<serde_json::Value> {
+    let method = ctx.method().to_string();
+    let mut handled = std::pin::pin!(dispatch.handle_request(ctx));
+    let caught = poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| handled.as_mut().poll(cx))) {
+            Ok(Poll::Ready(result)) => Poll::Ready(Ok(result)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    })
+    .await;
+    caught.unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        tracing::error!("Handler for {} panicked: {}", method, message);
+        Err(JsonRpcError::internal("handler panicked"))
+    })
+}
+
+/// Drive `conn` until the peer disconnects, answering each request from its
+/// own task and handling notifications in arrival order.
+///
+/// Malformed messages are logged and skipped; only transport errors end the
+/// loop early.
+pub(crate) async fn serve_loop<D: Dispatch>(
+    mut conn: McplConnection,
+    dispatch: Arc<D>,
+) -> Result<(), ConnectionError> {
+    serve_until(&mut conn, dispatch, &CancellationToken::new()).await
+}
+
+/// [`serve_loop`] that also returns `Ok` once `shutdown` fires or the
+/// session is shut down, leaving the connection open for the caller.
+pub(crate) async fn serve_until<D: Dispatch>(
+    conn: &mut McplConnection,
+    dispatch: Arc<D>,
+    shutdown: &CancellationToken,
+) -> Result<(), ConnectionError> {
+    let session = conn.session();
+    let session_shutdown = &session.connection().session_state().shutdown;
+    loop {
+        let next = tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = session_shutdown.cancelled() => return Ok(()),
+            next = conn.next_message() => next,
+        };
+        match next {
+            Ok(IncomingMessage::Request(request)) => {
+                let mut ctx = conn.request_context(request);
+                let responder = ctx
+                    .detach_responder()
+                    .expect("fresh request context has a responder");
+                let dispatch = Arc::clone(&dispatch);
+                tokio::spawn(async move {
+                    let result = handle_guarded(&*dispatch, ctx).await;
+                    if let Err(e) = responder.respond(result).await {
+                        tracing::warn!("Failed to send response: {}", e);
+                    }
+                });
+            }
+            Ok(IncomingMessage::Notification(notification)) => {
+                dispatch.handle_notification(notification).await;
+            }
+            Err(ConnectionError::Closed) => return Ok(()),
+            Err(err @ ConnectionError::Io(_)) => return Err(err),
+            Err(err) => tracing::warn!("Skipping bad message: {}", err),
+        }
+    }
+}
@@ -0,0 +1,618 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::task::JoinHandle;
+
+use crate::capabilities::*;
+use crate::client::McplClient;
+use crate::connection::{ConnectionError, ConnectionHandle, McplConnection};
+use crate::correlation::InferenceLinks;
+use crate::inference::{
+    check_response, provider_fn, InferenceChunks, InferencePrompt, InferenceProvider,
+    ModelResolver, ProviderFn,
+};
+use crate::inference_queue::InferenceQueue;
+use crate::handler::{
+    channels_list, encode, model_info, not_found, notification_params, params, serve_loop, Dispatch,
+};
+use crate::methods::*;
+use crate::push::{PushSequenceTracker, SequenceCheck};
+use crate::request::RequestContext;
+use crate::session::McplSession;
+use crate::types::*;
+use crate::usage::{BudgetExceeded, InferenceBudgets};
+
+pub use crate::handler::HandlerResult;
+
+/// MCP protocol version a host requests unless configured otherwise.
+pub const DEFAULT_PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// Host-side callbacks for MCPL requests and notifications sent by a server.
+///
+/// Every method has a default: `model/info` and `channels/list` answer from
+/// the session, other requests answer `-32601 Method not found`, and
+/// notifications are ignored.
+pub trait McplHostHandler: Send + Sync + 'static {
+    fn on_push_event(
+        &self,
+        ctx: &RequestContext,
+        _params: PushEventParams,
+    ) -> impl Future<Output = HandlerResult<PushEventResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// A push event's `sequence` does not follow the last one accepted for
+    /// its feature set on this connection. Return a result to answer the
+    /// event with instead of passing it to
+    /// [`on_push_event`](Self::on_push_event), e.g. rejecting a repeat; by
+    /// default it is passed on.
+    fn on_push_out_of_sequence(
+        &self,
+        _ctx: &RequestContext,
+        _params: &PushEventParams,
+        _check: SequenceCheck,
+    ) -> impl Future<Output = Option<PushEventResult>> + Send {
+        async { None }
+    }
+
+    /// Answers each event with [`on_push_event`](Self::on_push_event), in
+    /// order; an event it fails is rejected with the error as the reason.
+    fn on_push_event_batch(
+        &self,
+        ctx: &RequestContext,
+        params: PushEventBatchParams,
+    ) -> impl Future<Output = HandlerResult<PushEventBatchResult>> + Send {
+        async move {
+            let mut results = Vec::with_capacity(params.events.len());
+            for event in params.events {
+                let event_id = event.event_id.clone();
+                let result = match self.on_push_event(ctx, event).await {
+                    Ok(result) => result,
+                    Err(e) => PushEventResult {
+                        accepted: false,
+                        inference_id: None,
+                        reason: Some(e.message),
+                    },
+                };
+                results.push(PushEventBatchEntry { event_id, result });
+            }
+            Ok(PushEventBatchResult { results })
+        }
+    }
+
+    fn on_scope_elevate(
+        &self,
+        ctx: &RequestContext,
+        _params: ScopeElevateParams,
+    ) -> impl Future<Output = HandlerResult<ScopeElevateResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// Dropped if the server cancels the request, which is then answered
+    /// with `finishReason: "cancelled"`.
+    fn on_inference_request(
+        &self,
+        ctx: &RequestContext,
+        _params: InferenceRequestParams,
+    ) -> impl Future<Output = HandlerResult<InferenceRequestResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// An `inference/request` was answered, by this handler or by the
+    /// host's [`InferenceProvider`]; `result.usage` is what it cost.
+    fn on_inference_answered(
+        &self,
+        _params: &InferenceRequestParams,
+        _result: &InferenceRequestResult,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Defaults to the model configured with
+    /// [`McplHostBuilder::model_info`] or [`McplSession::set_model_info`].
+    fn on_model_info(
+        &self,
+        ctx: &RequestContext,
+    ) -> impl Future<Output = HandlerResult<ModelInfoResult>> + Send {
+        let result = model_info(ctx);
+        async move { result }
+    }
+
+    /// Answered with an empty object on success.
+    fn on_channels_register(
+        &self,
+        ctx: &RequestContext,
+        _params: ChannelsRegisterParams,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        not_found(ctx)
+    }
+
+    fn on_channels_incoming(
+        &self,
+        ctx: &RequestContext,
+        _params: ChannelsIncomingParams,
+    ) -> impl Future<Output = HandlerResult<ChannelsIncomingResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// Defaults to the session's channel registry.
+    fn on_channels_list(
+        &self,
+        ctx: &RequestContext,
+    ) -> impl Future<Output = HandlerResult<ChannelsListResult>> + Send {
+        let result = channels_list(ctx);
+        async move { result }
+    }
+
+    fn on_feature_sets_changed(
+        &self,
+        _params: FeatureSetsChangedParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn on_channels_changed(
+        &self,
+        _params: ChannelsChangedParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Any request without a dedicated method above.
+    fn on_request(
+        &self,
+        ctx: &RequestContext,
+    ) -> impl Future<Output = HandlerResult<serde_json::Value>> + Send {
+        not_found(ctx)
+    }
+
+    /// Any notification without a dedicated method above.
+    fn on_notification(
+        &self,
+        _notification: JsonRpcNotification,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Host side of an MCPL connection: performs `initialize`, remembers what
+/// the server advertised, and dispatches the server's requests to an
+/// [`McplHostHandler`] on a background task.
+///
+/// ```ignore
+/// let host = McplHost::builder()
+///     .handler(MyHost)
+///     .host_info(ImplementationInfo { name: "host".into(), version: "0.1.0".into() })
+///     .capabilities(McplCapabilities::new("0.4"))
+///     .connect(connection)
+///     .await?;
+/// host.connection().send_notification(method::FEATURE_SETS_UPDATE, params).await?;
+/// ```
+pub struct McplHost<H> {
+    handler: Arc<H>,
+    handle: ConnectionHandle,
+    initialize: McplInitializeResult,
+    links: Arc<Mutex<InferenceLinks>>,
+    task: Option<JoinHandle<Result<(), ConnectionError>>>,
+}
+
+/// Builder for [`McplHost`]; `connect` is available once a handler is set.
+pub struct McplHostBuilder<H> {
+    handler: H,
+    host_info: ImplementationInfo,
+    capabilities: McplCapabilities,
+    protocol_version: String,
+    model_info: Option<ModelInfo>,
+    provider: Option<ProviderFn>,
+    models: Option<ModelResolver>,
+    budgets: InferenceBudgets,
+    queue: Option<InferenceQueue>,
+}
+
+impl McplHost<()> {
+    pub fn builder() -> McplHostBuilder<()> {
+        McplHostBuilder {
+            handler: (),
+            host_info: ImplementationInfo {
+                name: env!("CARGO_PKG_NAME").into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+            },
+            capabilities: McplCapabilities::default(),
+            protocol_version: DEFAULT_PROTOCOL_VERSION.into(),
+            model_info: None,
+            provider: None,
+            models: None,
+            budgets: InferenceBudgets::default(),
+            queue: None,
+        }
+    }
+}
+
+impl<H> McplHostBuilder<H> {
+    pub fn handler<T: McplHostHandler>(self, handler: T) -> McplHostBuilder<T> {
+        McplHostBuilder {
+            handler,
+            host_info: self.host_info,
+            capabilities: self.capabilities,
+            protocol_version: self.protocol_version,
+            model_info: self.model_info,
+            provider: self.provider,
+            models: self.models,
+            budgets: self.budgets,
+            queue: self.queue,
+        }
+    }
+
+    /// Name and version reported as `clientInfo`.
+    pub fn host_info(mut self, info: ImplementationInfo) -> Self {
+        self.host_info = info;
+        self
+    }
+
+    /// MCPL capabilities advertised under `experimental.mcpl`.
+    pub fn capabilities(mut self, capabilities: McplCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// MCP protocol version sent in `initialize`.
+    pub fn protocol_version(mut self, version: impl Into<String>) -> Self {
+        self.protocol_version = version.into();
+        self
+    }
+
+    /// Model reported to `model/info` unless the handler answers it.
+    pub fn model_info(mut self, model: ModelInfo) -> Self {
+        self.model_info = Some(model);
+        self
+    }
+
+    /// Answer `inference/request` with `provider` rather than the handler.
+    /// Advertise `inferenceRequest` in the capabilities for servers to use
+    /// it.
+    pub fn inference_provider(mut self, provider: impl InferenceProvider) -> Self {
+        self.provider = Some(provider_fn(provider));
+        self
+    }
+
+    /// Pick the model the provider answers with from the request's
+    /// `modelHints`. Without this, it is given the `model_info` model.
+    pub fn model_resolver(mut self, models: ModelResolver) -> Self {
+        self.models = Some(models);
+        self
+    }
+
+    /// Refuse `inference/request`s once a budget is used up, with
+    /// [`ERR_BUDGET_EXCEEDED`](crate::types::ERR_BUDGET_EXCEEDED).
+    pub fn inference_budgets(mut self, budgets: InferenceBudgets) -> Self {
+        self.budgets = budgets;
+        self
+    }
+
+    /// Answer `inference/request` only once `queue` admits it, waiting
+    /// behind other servers' requests sharing the queue.
+    pub fn inference_queue(mut self, queue: InferenceQueue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+}
+
+impl<H: McplHostHandler> McplHostBuilder<H> {
+    /// Run the `initialize` handshake over `conn`, send
+    /// `notifications/initialized`, and start dispatching the server's
+    /// requests.
+    pub async fn connect(self, mut conn: McplConnection) -> Result<McplHost<H>, ConnectionError> {
+        let params = McplInitializeParams {
+            protocol_version: self.protocol_version,
+            capabilities: InitializeCapabilities::with_mcpl(self.capabilities),
+            client_info: self.host_info,
+        };
+        conn.session().set_model_info(self.model_info);
+        let initialize: McplInitializeResult =
+            conn.send_request_typed(method::INITIALIZE, &params).await?;
+        conn.send_notification(method::NOTIFICATIONS_INITIALIZED, None)
+            .await?;
+
+        let handler = Arc::new(self.handler);
+        let handle = conn.handle();
+        let links = Arc::new(Mutex::new(InferenceLinks::new()));
+        let dispatch = Arc::new(HostDispatch {
+            handler: Arc::clone(&handler),
+            sequences: Mutex::new(PushSequenceTracker::new()),
+            links: Arc::clone(&links),
+            provider: self.provider,
+            models: self.models,
+            budgets: self.budgets,
+            queue: self.queue,
+        });
+        let task = tokio::spawn(serve_loop(conn, dispatch));
+        Ok(McplHost {
+            handler,
+            handle,
+            initialize,
+            links,
+            task: Some(task),
+        })
+    }
+}
+
+impl<H: McplHostHandler> McplHost<H> {
+    pub fn handler(&self) -> &Arc<H> {
+        &self.handler
+    }
+
+    /// Handle for sending requests and notifications to the server.
+    pub fn connection(&self) -> &ConnectionHandle {
+        &self.handle
+    }
+
+    /// State negotiated with the server.
+    pub fn session(&self) -> McplSession {
+        self.handle.session()
+    }
+
+    /// Typed calls to the server over the same connection.
+    pub fn client(&self) -> McplClient {
+        McplClient::new(self.handle.clone())
+    }
+
+    /// The server's `initialize` result.
+    pub fn initialize_result(&self) -> &McplInitializeResult {
+        &self.initialize
+    }
+
+    pub fn server_info(&self) -> &ImplementationInfo {
+        &self.initialize.server_info
+    }
+
+    /// MCPL capabilities the server advertised, if it speaks MCPL at all.
+    pub fn server_capabilities(&self) -> Option<&McplCapabilities> {
+        self.initialize.capabilities.mcpl()
+    }
+
+    /// Push events whose acceptance started `inference_id`.
+    pub fn inference_events(&self, inference_id: &str) -> Vec<String> {
+        self.links.lock().unwrap().events_for(inference_id).to_vec()
+    }
+
+    /// Forget `inference_id` once it has finished, returning the push events
+    /// that started it.
+    pub fn finish_inference(&self, inference_id: &str) -> Vec<String> {
+        self.links.lock().unwrap().finish(inference_id)
+    }
+
+    /// Wait until the server disconnects.
+    pub async fn closed(mut self) -> Result<(), ConnectionError> {
+        let task = self
+            .task
+            .take()
+            .expect("dispatch task is present until closed");
+        match task.await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+impl<H> Drop for McplHost<H> {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+struct HostDispatch<H> {
+    handler: Arc<H>,
+    /// Push event sequence numbers accepted on this connection.
+    sequences: Mutex<PushSequenceTracker>,
+    /// Inferences started by accepted push events, shared with [`McplHost`].
+    links: Arc<Mutex<InferenceLinks>>,
+    provider: Option<ProviderFn>,
+    models: Option<ModelResolver>,
+    budgets: InferenceBudgets,
+    queue: Option<InferenceQueue>,
+}
+
+impl<H: McplHostHandler> HostDispatch<H> {
+    /// Pass a push event to the handler, checking its sequence number first.
+    async fn push_event(
+        &self,
+        ctx: &RequestContext,
+        params: PushEventParams,
+    ) -> HandlerResult<PushEventResult> {
+        let Some(sequence) = params.sequence else {
+            let event_id = params.event_id.clone();
+            let result = self.handler.on_push_event(ctx, params).await?;
+            self.link(&event_id, &result);
+            return Ok(result);
+        };
+        let check = self.sequences().check(&params.feature_set, sequence);
+        if !check.is_in_order() {
+            tracing::debug!(
+                "Push event {} out of sequence: {:?}",
+                params.event_id,
+                check
+            );
+            let answer = self
+                .handler
+                .on_push_out_of_sequence(ctx, &params, check)
+                .await;
+            if let Some(result) = answer {
+                return Ok(result);
+            }
+        }
+        let feature_set = params.feature_set.clone();
+        let event_id = params.event_id.clone();
+        let result = self.handler.on_push_event(ctx, params).await?;
+        // A rejected event may be sent again with the same number
+        if result.accepted {
+            self.sequences().record(&feature_set, sequence);
+        }
+        self.link(&event_id, &result);
+        Ok(result)
+    }
+
+    async fn push_event_batch(
+        &self,
+        ctx: &RequestContext,
+        params: PushEventBatchParams,
+    ) -> HandlerResult<PushEventBatchResult> {
+        let result = self.handler.on_push_event_batch(ctx, params).await?;
+        for entry in &result.results {
+            self.link(&entry.event_id, &entry.result);
+        }
+        Ok(result)
+    }
+
+    /// Answer with the provider if there is one, streaming if asked to,
+    /// otherwise with the handler, once the queue if any admits the
+    /// request. Either is dropped if the server cancels the request, which
+    /// is then answered as cancelled.
+    async fn inference_request(
+        &self,
+        ctx: &RequestContext,
+        params: InferenceRequestParams,
+    ) -> HandlerResult<InferenceRequestResult> {
+        if let Some(session) = ctx.session() {
+            self.check_budgets(session, &params)?;
+        }
+        let answer = async {
+            let _permit = match &self.queue {
+                Some(queue) => {
+                    let server = ctx
+                        .session()
+                        .and_then(McplSession::negotiated)
+                        .map(|negotiated| negotiated.peer_info.name)
+                        .unwrap_or_default();
+                    Some(queue.acquire_for_server(&server).await?)
+                }
+                None => None,
+            };
+            match &self.provider {
+                Some(provider) => {
+                    let chunks = match (params.stream, ctx.session()) {
+                        (Some(true), Some(session)) => Some(InferenceChunks::new(
+                            session.connection().clone(),
+                            ctx.id().clone(),
+                        )),
+                        _ => None,
+                    };
+                    let mut prompt = InferencePrompt::from(params.clone());
+                    prompt.model = match &self.models {
+                        Some(models) => models.resolve(&prompt.preferences).cloned(),
+                        None => ctx.session().and_then(McplSession::model_info),
+                    };
+                    provider(prompt, chunks).await
+                }
+                None => self.handler.on_inference_request(ctx, params.clone()).await,
+            }
+        };
+        let mut result = tokio::select! {
+            result = answer => result?,
+            _ = ctx.cancellation_token().cancelled() => InferenceRequestResult {
+                content: String::new(),
+                model: ctx
+                    .session()
+                    .and_then(McplSession::model_info)
+                    .map(|model| model.id)
+                    .unwrap_or_default(),
+                finish_reason: FINISH_REASON_CANCELLED.into(),
+                usage: InferenceUsage::default(),
+                tool_calls: None,
+            },
+        };
+        self.handler.on_inference_answered(&params, &result).await;
+        // Answers that call tools are checked once the calls are answered
+        if let (Some(schema), false, []) = (
+            &params.response_schema,
+            result.is_cancelled(),
+            result.tool_calls(),
+        ) {
+            let value = check_response(schema, &result.content)?;
+            result.content = value.to_string();
+        }
+        Ok(result)
+    }
+
+    fn check_budgets(
+        &self,
+        session: &McplSession,
+        params: &InferenceRequestParams,
+    ) -> Result<(), BudgetExceeded> {
+        if let Some(budget) = self.budgets.for_feature_set(&params.feature_set) {
+            let used = session.feature_set_metrics(&params.feature_set).inference;
+            if let Some(limit) = budget.exceeded_by(&used) {
+                return Err(BudgetExceeded::FeatureSet {
+                    feature_set: params.feature_set.clone(),
+                    limit,
+                });
+            }
+        }
+        if let (Some(budget), Some(conversation_id)) =
+            (&self.budgets.conversation, &params.conversation_id)
+        {
+            let used = session.conversation_usage(conversation_id).server_initiated;
+            if let Some(limit) = budget.exceeded_by(&used) {
+                return Err(BudgetExceeded::Conversation {
+                    conversation_id: conversation_id.clone(),
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn link(&self, event_id: &str, result: &PushEventResult) {
+        if let (true, Some(inference_id)) = (result.accepted, &result.inference_id) {
+            self.links.lock().unwrap().link(event_id, inference_id);
+        }
+    }
+
+    fn sequences(&self) -> std::sync::MutexGuard<'_, PushSequenceTracker> {
+        self.sequences.lock().unwrap()
+    }
+}
+
+impl<H: McplHostHandler> Dispatch for HostDispatch<H> {
+    async fn handle_request(&self, ctx: RequestContext) -> HandlerResult<serde_json::Value> {
+        let ctx = &ctx;
+        let handler = &*self.handler;
+        match ctx.method() {
+            method::PING => Ok(serde_json::json!({})),
+            method::PUSH_EVENT => encode(self.push_event(ctx, params(ctx)?).await),
+            method::PUSH_EVENT_BATCH => encode(self.push_event_batch(ctx, params(ctx)?).await),
+            method::SCOPE_ELEVATE => encode(handler.on_scope_elevate(ctx, params(ctx)?).await),
+            method::INFERENCE_REQUEST => encode(self.inference_request(ctx, params(ctx)?).await),
+            method::MODEL_INFO => encode(handler.on_model_info(ctx).await),
+            method::CHANNELS_REGISTER => {
+                handler.on_channels_register(ctx, params(ctx)?).await?;
+                Ok(serde_json::json!({}))
+            }
+            method::CHANNELS_INCOMING => {
+                encode(handler.on_channels_incoming(ctx, params(ctx)?).await)
+            }
+            method::CHANNELS_LIST => encode(handler.on_channels_list(ctx).await),
+            _ => handler.on_request(ctx).await,
+        }
+    }
+
+    async fn handle_notification(&self, notification: JsonRpcNotification) {
+        let handler = &*self.handler;
+        match notification.method.as_str() {
+            method::FEATURE_SETS_CHANGED => {
+                if let Some(params) = notification_params(&notification) {
+                    handler.on_feature_sets_changed(params).await;
+                }
+            }
+            method::CHANNELS_CHANGED => {
+                if let Some(params) = notification_params(&notification) {
+                    handler.on_channels_changed(params).await;
+                }
+            }
+            // Already applied to the request by the connection
+            method::INFERENCE_CANCEL => {}
+            _ => handler.on_notification(notification).await,
+        }
+    }
+}
@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::checkpoint::rfc3339;
+use crate::handler::HandlerError;
+use crate::json_pointer::{self, PointerError};
+use crate::methods::*;
+use crate::types::JsonRpcError;
+
+/// Checkpoints between full snapshots unless configured otherwise.
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 16;
+
+/// Host-side owner of the state of `hostState` feature sets.
+///
+/// Servers of such feature sets return a [`HostManagedState`] with tool
+/// results: a checkpoint id and the JSON Patch (RFC 6902) that produced it.
+/// The container applies the patches to the canonical state, remembers each
+/// checkpoint, and can roll back to any of them. Every
+/// [`snapshot_interval`](Self::snapshot_interval)-th checkpoint keeps a full
+/// copy of the state; the others are rebuilt by replaying patches from the
+/// nearest snapshot.
+///
+/// ```ignore
+/// let mut states = HostStateContainer::new();
+/// states.insert("game", serde_json::json!({ "board": [] }));
+/// states.apply("game", &tool_result_state)?;
+/// injections.extend(states.render("game"));
+/// states.rollback("game", "cp-3")?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct HostStateContainer {
+    feature_sets: HashMap<String, HostState>,
+    snapshot_interval: usize,
+}
+
+#[derive(Debug, Clone)]
+struct HostState {
+    /// State before the first checkpoint.
+    base: Value,
+    current: Value,
+    /// The checkpoint `current` is at; `None` at `base`.
+    head: Option<String>,
+    checkpoints: HashMap<String, Recorded>,
+    /// Checkpoint ids in the order they were recorded.
+    order: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Recorded {
+    checkpoint: StateCheckpoint,
+    /// Patch from the parent's state (or `base`) to this one.
+    patch: Vec<JsonPatchOperation>,
+    snapshot: Option<Value>,
+    /// Patches to replay from the nearest snapshot.
+    depth: usize,
+}
+
+/// A state operation that failed. Converts into a [`JsonRpcError`] with a
+/// dedicated code and the failure in its `data`, so servers keeping state
+/// can answer with it as is.
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("Feature set has no host-managed state: {0}")]
+    UnknownFeatureSet(String),
+    #[error("Checkpoint not found: {0}")]
+    CheckpointNotFound(String),
+    #[error("Checkpoint already exists: {0}")]
+    DuplicateCheckpoint(String),
+    #[error("Rollback not supported by feature set: {0}")]
+    RollbackUnsupported(String),
+    #[error("Invalid host state snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error(transparent)]
+    Patch(#[from] PatchError),
+}
+
+impl From<StateError> for HandlerError {
+    fn from(e: StateError) -> Self {
+        match e {
+            StateError::UnknownFeatureSet(name) => HandlerError::unknown_feature_set(&name),
+            StateError::CheckpointNotFound(id) => HandlerError::checkpoint_not_found(&id),
+            StateError::DuplicateCheckpoint(id) => HandlerError::checkpoint_conflict(&id),
+            StateError::RollbackUnsupported(name) => HandlerError::rollback_unsupported(&name),
+            StateError::InvalidSnapshot(reason) => HandlerError::invalid_params(reason),
+            StateError::Patch(e) => e.into(),
+        }
+    }
+}
+
+impl From<StateError> for JsonRpcError {
+    fn from(e: StateError) -> Self {
+        HandlerError::from(e).into()
+    }
+}
+
+/// The state of one feature set and its checkpoint lineage, from
+/// [`HostStateContainer::export_snapshot`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostStateSnapshot {
+    #[serde(rename = "featureSet")]
+    pub feature_set: String,
+    /// State before the first checkpoint.
+    pub base: Value,
+    /// Oldest first; parents precede their children.
+    pub checkpoints: Vec<RecordedCheckpoint>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head: Option<String>,
+    /// State at `head`.
+    pub state: Value,
+}
+
+/// A checkpoint and the patch from its parent's state to its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedCheckpoint {
+    #[serde(flatten)]
+    pub checkpoint: StateCheckpoint,
+    pub patch: Vec<JsonPatchOperation>,
+}
+
+/// A JSON Patch operation that could not be applied.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("Patch operation {index} failed: {kind}")]
+pub struct PatchError {
+    /// Position of the operation in the patch.
+    pub index: usize,
+    pub kind: PatchErrorKind,
+}
+
+impl From<PatchError> for HandlerError {
+    fn from(e: PatchError) -> Self {
+        HandlerError::patch_failed(e.index, &e.kind)
+    }
+}
+
+impl From<PatchError> for JsonRpcError {
+    fn from(e: PatchError) -> Self {
+        HandlerError::from(e).into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PatchErrorKind {
+    #[error("invalid JSON pointer {0:?}")]
+    InvalidPointer(String),
+    #[error("no value at {0:?}")]
+    PathNotFound(String),
+    #[error("invalid array index at {0:?}")]
+    InvalidIndex(String),
+    #[error("operation needs a `{0}`")]
+    MissingField(&'static str),
+    #[error("cannot move {from:?} into its own child {path:?}")]
+    MoveIntoChild { from: String, path: String },
+    #[error("value at {0:?} differs")]
+    TestFailed(String),
+}
+
+impl From<PointerError> for PatchErrorKind {
+    fn from(e: PointerError) -> Self {
+        match e {
+            PointerError::Invalid(pointer) => PatchErrorKind::InvalidPointer(pointer),
+            PointerError::NotFound(pointer) => PatchErrorKind::PathNotFound(pointer),
+            PointerError::InvalidIndex(pointer) => PatchErrorKind::InvalidIndex(pointer),
+        }
+    }
+}
+
+impl Default for HostStateContainer {
+    fn default() -> Self {
+        Self {
+            feature_sets: HashMap::new(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        }
+    }
+}
+
+impl HostStateContainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep a full copy of the state every `interval` checkpoints (at least
+    /// 1, meaning every checkpoint).
+    pub fn snapshot_interval(mut self, interval: usize) -> Self {
+        self.snapshot_interval = interval.max(1);
+        self
+    }
+
+    /// Start tracking `feature_set` at `state`, forgetting anything recorded
+    /// for it before.
+    pub fn insert(&mut self, feature_set: impl Into<String>, state: Value) {
+        self.feature_sets.insert(
+            feature_set.into(),
+            HostState {
+                current: state.clone(),
+                base: state,
+                head: None,
+                checkpoints: HashMap::new(),
+                order: Vec::new(),
+            },
+        );
+    }
+
+    /// Stop tracking `feature_set`, returning its current state.
+    pub fn remove(&mut self, feature_set: &str) -> Option<Value> {
+        self.feature_sets
+            .remove(feature_set)
+            .map(|state| state.current)
+    }
+
+    pub fn state(&self, feature_set: &str) -> Option<&Value> {
+        self.feature_sets
+            .get(feature_set)
+            .map(|state| &state.current)
+    }
+
+    /// The checkpoint the current state of `feature_set` is at.
+    pub fn head(&self, feature_set: &str) -> Option<&str> {
+        self.feature_sets.get(feature_set)?.head.as_deref()
+    }
+
+    /// Checkpoints recorded for `feature_set`, oldest first.
+    pub fn checkpoints(&self, feature_set: &str) -> Vec<StateCheckpoint> {
+        let Some(state) = self.feature_sets.get(feature_set) else {
+            return Vec::new();
+        };
+        state
+            .order
+            .iter()
+            .map(|id| state.checkpoints[id].checkpoint.clone())
+            .collect()
+    }
+
+    /// Apply the patch of a tool result to `feature_set` and record its
+    /// checkpoint as a child of the current one. On error nothing changes.
+    pub fn apply(
+        &mut self,
+        feature_set: &str,
+        update: &HostManagedState,
+    ) -> Result<StateCheckpoint, StateError> {
+        let interval = self.snapshot_interval;
+        let state = self.tracked_mut(feature_set)?;
+        if state.checkpoints.contains_key(&update.checkpoint) {
+            return Err(StateError::DuplicateCheckpoint(update.checkpoint.clone()));
+        }
+        let patch = update.patch.clone().unwrap_or_default();
+        apply_patch(&mut state.current, &patch)?;
+
+        let checkpoint = StateCheckpoint {
+            id: update.checkpoint.clone(),
+            feature_set: feature_set.to_string(),
+            timestamp: rfc3339(SystemTime::now()),
+            parent: state.head.clone(),
+            label: None,
+            metadata: None,
+        };
+        let current = state.current.clone();
+        state.record(checkpoint.clone(), patch, &current, interval);
+        state.head = Some(checkpoint.id.clone());
+        Ok(checkpoint)
+    }
+
+    /// Put `feature_set` back into its state at `checkpoint`. Later
+    /// checkpoints are kept; the next [`apply`](Self::apply) branches from
+    /// `checkpoint`.
+    pub fn rollback(&mut self, feature_set: &str, checkpoint: &str) -> Result<&Value, StateError> {
+        let state = self.tracked_mut(feature_set)?;
+        if !state.checkpoints.contains_key(checkpoint) {
+            return Err(StateError::CheckpointNotFound(checkpoint.to_string()));
+        }
+        state.current = state.rebuild(checkpoint)?;
+        state.head = Some(checkpoint.to_string());
+        Ok(&state.current)
+    }
+
+    /// Everything recorded for `feature_set` in one serializable value: its
+    /// state, the checkpoints with the patches between them, and which one
+    /// it is at. [`import_snapshot`](Self::import_snapshot) restores it,
+    /// on this host or another.
+    pub fn export_snapshot(&self, feature_set: &str) -> Option<HostStateSnapshot> {
+        let state = self.feature_sets.get(feature_set)?;
+        Some(HostStateSnapshot {
+            feature_set: feature_set.to_string(),
+            base: state.base.clone(),
+            checkpoints: state
+                .order
+                .iter()
+                .map(|id| {
+                    let recorded = &state.checkpoints[id];
+                    RecordedCheckpoint {
+                        checkpoint: recorded.checkpoint.clone(),
+                        patch: recorded.patch.clone(),
+                    }
+                })
+                .collect(),
+            head: state.head.clone(),
+            state: state.current.clone(),
+        })
+    }
+
+    /// Track the feature set of `snapshot` as exported, replacing anything
+    /// recorded for it. Every checkpoint is replayed; a snapshot whose
+    /// patches do not lead to its `state` is refused and nothing changes.
+    pub fn import_snapshot(&mut self, snapshot: HostStateSnapshot) -> Result<(), StateError> {
+        let mut imported = HostState {
+            current: snapshot.base.clone(),
+            base: snapshot.base,
+            head: None,
+            checkpoints: HashMap::new(),
+            order: Vec::new(),
+        };
+        for RecordedCheckpoint { checkpoint, patch } in snapshot.checkpoints {
+            if imported.checkpoints.contains_key(&checkpoint.id) {
+                return Err(StateError::DuplicateCheckpoint(checkpoint.id));
+            }
+            let mut state = match &checkpoint.parent {
+                Some(parent) if !imported.checkpoints.contains_key(parent) => {
+                    return Err(StateError::InvalidSnapshot(format!(
+                        "checkpoint {} precedes its parent {}",
+                        checkpoint.id, parent
+                    )));
+                }
+                Some(parent) => imported.rebuild(parent)?,
+                None => imported.base.clone(),
+            };
+            apply_patch(&mut state, &patch)?;
+            imported.record(checkpoint, patch, &state, self.snapshot_interval);
+        }
+        if let Some(head) = &snapshot.head {
+            if !imported.checkpoints.contains_key(head) {
+                return Err(StateError::CheckpointNotFound(head.clone()));
+            }
+            imported.current = imported.rebuild(head)?;
+        }
+        if imported.current != snapshot.state {
+            return Err(StateError::InvalidSnapshot(format!(
+                "replayed state of {} differs from the exported one",
+                snapshot.feature_set
+            )));
+        }
+        imported.head = snapshot.head;
+        self.feature_sets.insert(snapshot.feature_set, imported);
+        Ok(())
+    }
+
+    /// The current state of `feature_set` as a system context injection
+    /// under its name, with the checkpoint in the metadata.
+    pub fn render(&self, feature_set: &str) -> Option<ContextInjection> {
+        let state = self.feature_sets.get(feature_set)?;
+        let text = serde_json::to_string_pretty(&state.current).ok()?;
+        Some(ContextInjection {
+            namespace: feature_set.to_string(),
+            position: ContextInjectionPosition::System,
+            content: ContextInjectionContent::Text(text),
+            priority: None,
+            metadata: state
+                .head
+                .as_ref()
+                .map(|head| serde_json::json!({ "checkpoint": head })),
+        })
+    }
+
+    /// Start staging patches to `feature_set` that are committed as one
+    /// checkpoint, e.g. those of several tools run in one inference turn.
+    ///
+    /// ```ignore
+    /// let mut tx = states.transaction("game")?;
+    /// for result in tool_results {
+    ///     tx.stage_state(&result.state)?;
+    /// }
+    /// tx.commit(turn_checkpoint)?;
+    /// ```
+    pub fn transaction(
+        &mut self,
+        feature_set: &str,
+    ) -> Result<HostStateTransaction<'_>, StateError> {
+        let working = self.tracked_mut(feature_set)?.current.clone();
+        Ok(HostStateTransaction {
+            container: self,
+            feature_set: feature_set.to_string(),
+            working,
+            staged: Vec::new(),
+        })
+    }
+
+    fn tracked_mut(&mut self, feature_set: &str) -> Result<&mut HostState, StateError> {
+        self.feature_sets
+            .get_mut(feature_set)
+            .ok_or_else(|| StateError::UnknownFeatureSet(feature_set.to_string()))
+    }
+}
+
+/// Patches staged by [`HostStateContainer::transaction`]. Each is checked
+/// against the state left by those before it, `test` operations included,
+/// when staged; nothing reaches the container until
+/// [`commit`](Self::commit). Dropping the transaction discards it.
+#[derive(Debug)]
+pub struct HostStateTransaction<'a> {
+    container: &'a mut HostStateContainer,
+    feature_set: String,
+    working: Value,
+    staged: Vec<JsonPatchOperation>,
+}
+
+impl HostStateTransaction<'_> {
+    /// Add `patch` to the transaction. If it does not apply, it is left out
+    /// and the transaction stays as it was.
+    pub fn stage(&mut self, patch: &[JsonPatchOperation]) -> Result<(), PatchError> {
+        apply_patch(&mut self.working, patch)?;
+        self.staged.extend_from_slice(patch);
+        Ok(())
+    }
+
+    /// Stage the patch of a tool result; its checkpoint id is not used.
+    pub fn stage_state(&mut self, update: &HostManagedState) -> Result<(), PatchError> {
+        self.stage(update.patch.as_deref().unwrap_or_default())
+    }
+
+    /// The state with every staged patch applied.
+    pub fn state(&self) -> &Value {
+        &self.working
+    }
+
+    /// Operations staged so far, in order.
+    pub fn staged(&self) -> &[JsonPatchOperation] {
+        &self.staged
+    }
+
+    /// Apply the staged patches as one checkpoint, a child of the current
+    /// one.
+    pub fn commit(self, checkpoint: impl Into<String>) -> Result<StateCheckpoint, StateError> {
+        let update = HostManagedState {
+            checkpoint: checkpoint.into(),
+            patch: Some(self.staged),
+        };
+        self.container.apply(&self.feature_set, &update)
+    }
+
+    /// Drop the staged patches, leaving the container as it was.
+    pub fn discard(self) {}
+}
+
+impl HostState {
+    /// Remember `checkpoint`, whose state is `state`, keeping a copy of the
+    /// state if a snapshot is due.
+    fn record(
+        &mut self,
+        checkpoint: StateCheckpoint,
+        patch: Vec<JsonPatchOperation>,
+        state: &Value,
+        interval: usize,
+    ) {
+        let parent_depth = checkpoint
+            .parent
+            .as_ref()
+            .map_or(0, |parent| self.checkpoints[parent].depth);
+        let depth = (parent_depth + 1) % interval;
+        self.order.push(checkpoint.id.clone());
+        self.checkpoints.insert(
+            checkpoint.id.clone(),
+            Recorded {
+                checkpoint,
+                patch,
+                snapshot: (depth == 0).then(|| state.clone()),
+                depth,
+            },
+        );
+    }
+
+    /// The state at `checkpoint`: the nearest snapshot at or above it, with
+    /// the patches below that replayed.
+    fn rebuild(&self, checkpoint: &str) -> Result<Value, PatchError> {
+        let mut replay = Vec::new();
+        let mut next = Some(checkpoint);
+        let mut state = loop {
+            let Some(id) = next else {
+                break self.base.clone();
+            };
+            let recorded = &self.checkpoints[id];
+            if let Some(snapshot) = &recorded.snapshot {
+                break snapshot.clone();
+            }
+            replay.push(&recorded.patch);
+            next = recorded.checkpoint.parent.as_deref();
+        };
+        for patch in replay.into_iter().rev() {
+            apply_patch(&mut state, patch)?;
+        }
+        Ok(state)
+    }
+}
+
+/// Apply a JSON Patch (RFC 6902) to `doc`. Operations apply in order; if
+/// one fails, `doc` is left unchanged.
+pub fn apply_patch(doc: &mut Value, patch: &[JsonPatchOperation]) -> Result<(), PatchError> {
+    let mut patched = doc.clone();
+    for (index, op) in patch.iter().enumerate() {
+        apply_operation(&mut patched, op).map_err(|kind| PatchError { index, kind })?;
+    }
+    *doc = patched;
+    Ok(())
+}
+
+fn apply_operation(doc: &mut Value, op: &JsonPatchOperation) -> Result<(), PatchErrorKind> {
+    let value = || {
+        op.value
+            .clone()
+            .ok_or(PatchErrorKind::MissingField("value"))
+    };
+    let from = || {
+        op.from
+            .as_deref()
+            .ok_or(PatchErrorKind::MissingField("from"))
+    };
+    match op.op {
+        JsonPatchOp::Add => add(doc, &op.path, value()?),
+        JsonPatchOp::Remove => {
+            json_pointer::remove(doc, &op.path)?;
+            Ok(())
+        }
+        JsonPatchOp::Replace => {
+            let target = json_pointer::get_mut(doc, &op.path)?;
+            *target = value()?;
+            Ok(())
+        }
+        JsonPatchOp::Move => {
+            let from = from()?;
+            if op.path.starts_with(from) && op.path[from.len()..].starts_with('/') {
+                return Err(PatchErrorKind::MoveIntoChild {
+                    from: from.to_string(),
+                    path: op.path.clone(),
+                });
+            }
+            let moved = json_pointer::remove(doc, from)?;
+            add(doc, &op.path, moved)
+        }
+        JsonPatchOp::Copy => {
+            let copied = json_pointer::get(doc, from()?)?.clone();
+            add(doc, &op.path, copied)
+        }
+        JsonPatchOp::Test => {
+            if *json_pointer::get(doc, &op.path)? == value()? {
+                Ok(())
+            } else {
+                Err(PatchErrorKind::TestFailed(op.path.clone()))
+            }
+        }
+    }
+}
+
+fn add(doc: &mut Value, path: &str, value: Value) -> Result<(), PatchErrorKind> {
+    let Some((parent, last)) = json_pointer::split_last(path)? else {
+        *doc = value;
+        return Ok(());
+    };
+    match json_pointer::get_mut(doc, parent)? {
+        Value::Object(map) => {
+            map.insert(last, value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let index = match last.as_str() {
+                "-" => Some(items.len()),
+                last => json_pointer::array_index(last),
+            };
+            match index {
+                Some(index) if index <= items.len() => {
+                    items.insert(index, value);
+                    Ok(())
+                }
+                _ => Err(PatchErrorKind::InvalidIndex(path.to_string())),
+            }
+        }
+        _ => Err(PatchErrorKind::PathNotFound(path.to_string())),
+    }
+}
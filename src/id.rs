@@ -0,0 +1,39 @@
+use crate::types::JsonRpcId;
+
+/// Source of ids for outgoing requests.
+///
+/// Ids must be unique among the requests outstanding on a connection.
+pub trait IdGenerator: Send {
+    fn next_id(&mut self) -> JsonRpcId;
+}
+
+/// Incrementing numeric ids starting at 1 (the default).
+#[derive(Debug, Clone)]
+pub struct SequentialIds {
+    next: i64,
+}
+
+impl Default for SequentialIds {
+    fn default() -> Self {
+        Self { next: 1 }
+    }
+}
+
+impl IdGenerator for SequentialIds {
+    fn next_id(&mut self) -> JsonRpcId {
+        let id = self.next;
+        self.next += 1;
+        JsonRpcId::Number(id)
+    }
+}
+
+/// String ids holding a UUIDv7, unique across reconnects and processes and
+/// sortable by creation time.
+#[derive(Debug, Clone, Default)]
+pub struct UuidV7Ids;
+
+impl IdGenerator for UuidV7Ids {
+    fn next_id(&mut self) -> JsonRpcId {
+        JsonRpcId::String(uuid::Uuid::now_v7().to_string())
+    }
+}
@@ -0,0 +1,507 @@
+//! Requesting inference from the host.
+//!
+//! With `stream: true` the host answers `inference/request` with
+//! `inference/chunk` notifications before the final result. An
+//! [`InferenceStream`] yields those chunks and then the result:
+//!
+//! ```ignore
+//! let mut stream = client.request_inference_streaming(&params).await?;
+//! while let Some(chunk) = stream.next_chunk().await {
+//!     print!("{}", chunk.delta);
+//! }
+//! let result = stream.final_result().await?;
+//! ```
+//!
+//! Hosts answer the request with an [`InferenceProvider`], picking the
+//! model with a [`ModelResolver`].
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::connection::{
+    decode_result, ConnectionError, ConnectionHandle, PendingResponse, ResponseResult,
+};
+use crate::handler::{BoxFuture, HandlerError, HandlerResult};
+use crate::methods::{
+    method, InferenceCancelParams, InferenceChunkParams, InferenceMessage, InferencePreferences,
+    InferenceRequestParams, InferenceRequestResult, InferenceTool, InferenceToolCall,
+    InferenceToolChoice, InferenceUsage, ModelInfo,
+};
+use crate::schema::{self, SchemaError};
+use crate::types::{JsonRpcError, JsonRpcId};
+
+/// The chunks of a streamed `inference/request`, followed by its result.
+///
+/// The stream ends once the host has answered; chunks sent before the
+/// answer are never lost. Its chunks are not passed to
+/// [`on_inference_chunk`](crate::McplServerHandler::on_inference_chunk).
+pub struct InferenceStream {
+    conn: ConnectionHandle,
+    request_id: JsonRpcId,
+    chunks: mpsc::UnboundedReceiver<InferenceChunkParams>,
+    /// The host's answer while it is awaited.
+    response: Option<PendingResponse>,
+    result: Option<ResponseResult>,
+}
+
+impl InferenceStream {
+    pub(crate) fn new(
+        conn: ConnectionHandle,
+        response: PendingResponse,
+        chunks: mpsc::UnboundedReceiver<InferenceChunkParams>,
+    ) -> Self {
+        Self {
+            conn,
+            request_id: response.id().clone(),
+            chunks,
+            response: Some(response),
+            result: None,
+        }
+    }
+
+    /// Id of the `inference/request`, which the chunks carry as
+    /// `requestId`.
+    pub fn request_id(&self) -> &JsonRpcId {
+        &self.request_id
+    }
+
+    /// The next chunk, or `None` once the host has answered.
+    pub async fn next_chunk(&mut self) -> Option<InferenceChunkParams> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// Ask the host to stop; the stream then ends with a result whose
+    /// `finishReason` is `"cancelled"`, once the host has answered.
+    pub async fn cancel(&self, reason: Option<String>) -> Result<(), ConnectionError> {
+        let params = InferenceCancelParams {
+            request_id: self.request_id.clone(),
+            reason,
+        };
+        self.conn
+            .send_notification_typed(method::INFERENCE_CANCEL, &params)
+            .await
+    }
+
+    /// The host's answer, skipping any chunks not yet read.
+    pub async fn final_result(mut self) -> Result<InferenceRequestResult, ConnectionError> {
+        let result = match (self.result.take(), self.response.take()) {
+            (Some(result), _) => result,
+            (None, Some(mut response)) => std::future::poll_fn(|cx| response.poll_raw(cx)).await,
+            (None, None) => Err(ConnectionError::Closed),
+        };
+        decode_result(method::INFERENCE_REQUEST, &result?)
+    }
+}
+
+impl Stream for InferenceStream {
+    type Item = InferenceChunkParams;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            // Ends once the answer is in, as that drops the sender
+            if let Poll::Ready(chunk) = this.chunks.poll_recv(cx) {
+                return Poll::Ready(chunk);
+            }
+            let Some(response) = this.response.as_mut() else {
+                return Poll::Pending;
+            };
+            match response.poll_raw(cx) {
+                Poll::Ready(result) => {
+                    this.result = Some(result);
+                    this.response = None;
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Generates the answer to a server's `inference/request`, e.g. by calling
+/// a model API.
+///
+/// Once set with the host builder's
+/// [`inference_provider`](crate::host::McplHostBuilder::inference_provider),
+/// the host answers `inference/request` with it instead of with
+/// [`on_inference_request`](crate::McplHostHandler::on_inference_request),
+/// emitting chunks and reporting usage on its behalf. If the server sends
+/// `inference/cancel`, the generation is dropped and the request answered
+/// as cancelled.
+///
+/// ```ignore
+/// impl InferenceProvider for Backend {
+///     async fn generate(
+///         &self,
+///         prompt: InferencePrompt,
+///     ) -> Result<InferenceRequestResult, HandlerError> {
+///         let response = self.api.complete(prompt.system, prompt.messages).await?;
+///         Ok(InferenceRequestResult { content: response.text, .. })
+///     }
+/// }
+/// ```
+pub trait InferenceProvider: Send + Sync + 'static {
+    fn generate(
+        &self,
+        prompt: InferencePrompt,
+    ) -> impl Future<Output = Result<InferenceRequestResult, HandlerError>> + Send;
+
+    /// Used when the server asks for a stream. Defaults to
+    /// [`generate`](Self::generate), sending its answer as one chunk.
+    fn generate_streaming(
+        &self,
+        prompt: InferencePrompt,
+        chunks: &mut InferenceChunks,
+    ) -> impl Future<Output = Result<InferenceRequestResult, HandlerError>> + Send {
+        async move {
+            let result = self.generate(prompt).await?;
+            chunks.send(&result.content).await?;
+            Ok(result)
+        }
+    }
+}
+
+/// An `inference/request` in the shape model APIs take: system messages
+/// apart from the conversation.
+#[derive(Debug, Clone)]
+pub struct InferencePrompt {
+    pub feature_set: String,
+    pub conversation_id: Option<String>,
+    /// The text of the system messages, joined by blank lines.
+    pub system: Option<String>,
+    /// The other messages, in order.
+    pub messages: Vec<InferenceMessage>,
+    pub preferences: InferencePreferences,
+    /// The model to answer with, as resolved from the preferences' hints.
+    /// `None` if the host has no models configured.
+    pub model: Option<ModelInfo>,
+    /// Tools the model may call; empty if none were offered.
+    pub tools: Vec<InferenceTool>,
+    pub tool_choice: Option<InferenceToolChoice>,
+    /// JSON Schema the answer must match. The host checks it, and sends
+    /// the content on as compact JSON.
+    pub response_schema: Option<serde_json::Value>,
+}
+
+impl From<InferenceRequestParams> for InferencePrompt {
+    fn from(params: InferenceRequestParams) -> Self {
+        let (system, messages): (Vec<_>, Vec<_>) = params
+            .messages
+            .into_iter()
+            .partition(|message| message.role == "system");
+        let system: Vec<String> = system
+            .iter()
+            .map(|message| message.content.text())
+            .collect();
+        Self {
+            feature_set: params.feature_set,
+            conversation_id: params.conversation_id,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages,
+            preferences: params.preferences.unwrap_or_default(),
+            model: None,
+            tools: params.tools.unwrap_or_default(),
+            tool_choice: params.tool_choice,
+            response_schema: params.response_schema,
+        }
+    }
+}
+
+/// An answer whose content does not match the request's `responseSchema`.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ResponseSchemaError {
+    #[error("Content is not JSON: {0}")]
+    NotJson(String),
+    #[error(transparent)]
+    Schema(#[from] SchemaError),
+}
+
+impl From<ResponseSchemaError> for HandlerError {
+    fn from(e: ResponseSchemaError) -> Self {
+        let error = HandlerError::invalid_response(&e);
+        match e {
+            ResponseSchemaError::Schema(e) => {
+                error.with_data(serde_json::json!({ "reason": e.to_string(), "path": e.path }))
+            }
+            ResponseSchemaError::NotJson(_) => error,
+        }
+    }
+}
+
+impl From<ResponseSchemaError> for JsonRpcError {
+    fn from(e: ResponseSchemaError) -> Self {
+        HandlerError::from(e).into()
+    }
+}
+
+/// `content` parsed as JSON and checked against `schema`. A Markdown code
+/// fence around the JSON, as models tend to write, is ignored.
+pub fn check_response(
+    schema: &serde_json::Value,
+    content: &str,
+) -> Result<serde_json::Value, ResponseSchemaError> {
+    let value = serde_json::from_str(unfenced(content))
+        .map_err(|e| ResponseSchemaError::NotJson(e.to_string()))?;
+    schema::validate(schema, &value)?;
+    Ok(value)
+}
+
+/// `content` without a surrounding Markdown code fence.
+fn unfenced(content: &str) -> &str {
+    let trimmed = content.trim();
+    let Some(inner) = trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+    else {
+        return content;
+    };
+    // Skip the language tag, e.g. `json`
+    match inner.split_once('\n') {
+        Some((_, body)) => body,
+        None => inner,
+    }
+}
+
+/// Picks which of a host's models answers an `inference/request`, from the
+/// `modelHints` of its preferences.
+///
+/// Hints are tried in order. A hint matches the model with that id or,
+/// failing that, the first model whose id contains it, ignoring case.
+/// Without a matching hint the default model, the first one, is used.
+#[derive(Debug, Clone)]
+pub struct ModelResolver {
+    models: Vec<ModelInfo>,
+}
+
+impl ModelResolver {
+    /// Resolve onto `models`, the first of them the default.
+    pub fn new(models: Vec<ModelInfo>) -> Self {
+        Self { models }
+    }
+
+    pub fn models(&self) -> &[ModelInfo] {
+        &self.models
+    }
+
+    /// The model for `preferences`; `None` only if there are no models.
+    pub fn resolve(&self, preferences: &InferencePreferences) -> Option<&ModelInfo> {
+        preferences
+            .model_hints
+            .iter()
+            .find_map(|hint| self.matching(hint))
+            .or_else(|| self.models.first())
+    }
+
+    fn matching(&self, hint: &str) -> Option<&ModelInfo> {
+        if hint.is_empty() {
+            return None;
+        }
+        let hint = hint.to_lowercase();
+        self.models
+            .iter()
+            .find(|model| model.id.to_lowercase() == hint)
+            .or_else(|| {
+                self.models
+                    .iter()
+                    .find(|model| model.id.to_lowercase().contains(&hint))
+            })
+    }
+}
+
+/// Shortest time between two chunks sent by [`InferenceChunks::emit`]
+/// unless configured otherwise.
+pub const DEFAULT_CHUNK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Sends the `inference/chunk` notifications of one streamed
+/// `inference/request`, numbering them in order.
+pub struct InferenceChunks {
+    conn: ConnectionHandle,
+    request_id: JsonRpcId,
+    next_index: u32,
+}
+
+/// An event of a model backend's streamed answer, as taken by
+/// [`InferenceChunks::emit`].
+#[derive(Debug, Clone)]
+pub enum ModelEvent {
+    /// More of the answer.
+    Text(String),
+    /// A complete tool call; calls are not sent as chunks.
+    ToolCall(InferenceToolCall),
+    /// The answer is complete.
+    Done {
+        model: String,
+        finish_reason: String,
+        usage: InferenceUsage,
+    },
+}
+
+/// How [`InferenceChunks::emit`] paces chunks.
+#[derive(Debug, Clone)]
+pub struct EmitOptions {
+    /// Shortest time between two chunks; text arriving in between is
+    /// coalesced into the next one.
+    pub interval: Duration,
+    /// Longest chunk, in characters; longer text is split over several.
+    /// `Some(0)` is treated as `Some(1)`.
+    pub max_chunk_chars: Option<usize>,
+}
+
+impl Default for EmitOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_CHUNK_INTERVAL,
+            max_chunk_chars: None,
+        }
+    }
+}
+
+impl InferenceChunks {
+    pub fn new(conn: ConnectionHandle, request_id: JsonRpcId) -> Self {
+        Self {
+            conn,
+            request_id,
+            next_index: 0,
+        }
+    }
+
+    /// Send `delta` as the next chunk; empty deltas are skipped.
+    pub async fn send(&mut self, delta: &str) -> Result<(), ConnectionError> {
+        if delta.is_empty() {
+            return Ok(());
+        }
+        let params = self.next(delta.to_string());
+        self.conn
+            .send_notification_typed(method::INFERENCE_CHUNK, &params)
+            .await
+    }
+
+    /// Send a model backend's streamed answer as chunks and return it as
+    /// the request's result.
+    ///
+    /// The first text is sent at once, later text at most every
+    /// `options.interval`; text arriving while a chunk is sent or waits
+    /// is sent with it, so a slow connection gets fewer, larger chunks.
+    /// Fails if `events` fails or ends before [`ModelEvent::Done`].
+    pub async fn emit<S, E>(
+        &mut self,
+        events: S,
+        options: &EmitOptions,
+    ) -> Result<InferenceRequestResult, HandlerError>
+    where
+        S: Stream<Item = Result<ModelEvent, E>>,
+        E: Into<HandlerError>,
+    {
+        let mut events = std::pin::pin!(events);
+        let mut content = String::new();
+        // Received and not yet sent
+        let mut unsent = String::new();
+        let mut tool_calls = Vec::new();
+        let mut done = None;
+        let mut sending: Option<BoxFuture<Result<(), ConnectionError>>> = None;
+        let mut next_send = Instant::now();
+        loop {
+            if sending.is_none() && !unsent.is_empty() {
+                if done.is_some() || Instant::now() >= next_send {
+                    let params = self.next(take_chunk(&mut unsent, options.max_chunk_chars));
+                    let conn = self.conn.clone();
+                    sending = Some(Box::pin(async move {
+                        conn.send_notification_typed(method::INFERENCE_CHUNK, &params)
+                            .await
+                    }));
+                    next_send = Instant::now() + options.interval;
+                    continue;
+                }
+            } else if sending.is_none() {
+                if let Some((model, finish_reason, usage)) = done {
+                    return Ok(InferenceRequestResult {
+                        content,
+                        model,
+                        finish_reason,
+                        usage,
+                        tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    });
+                }
+            }
+            tokio::select! {
+                biased;
+                result = async { sending.as_mut().unwrap().await }, if sending.is_some() => {
+                    sending = None;
+                    result?;
+                }
+                _ = tokio::time::sleep_until(next_send),
+                    if sending.is_none() && !unsent.is_empty() => {}
+                event = std::future::poll_fn(|cx| events.as_mut().poll_next(cx)),
+                    if done.is_none() => match event {
+                    Some(Ok(ModelEvent::Text(text))) => {
+                        content.push_str(&text);
+                        unsent.push_str(&text);
+                    }
+                    Some(Ok(ModelEvent::ToolCall(call))) => tool_calls.push(call),
+                    Some(Ok(ModelEvent::Done { model, finish_reason, usage })) => {
+                        done = Some((model, finish_reason, usage));
+                    }
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err(HandlerError::internal("Model stream ended unfinished")),
+                },
+            }
+        }
+    }
+
+    /// Number of chunks sent so far.
+    pub fn sent(&self) -> u32 {
+        self.next_index
+    }
+
+    fn next(&mut self, delta: String) -> InferenceChunkParams {
+        let params = InferenceChunkParams {
+            request_id: self.request_id.clone(),
+            index: self.next_index,
+            delta,
+        };
+        self.next_index += 1;
+        params
+    }
+}
+
+/// The first `max` characters of `text` (at least one), or all of it,
+/// removed from it.
+fn take_chunk(text: &mut String, max: Option<usize>) -> String {
+    match max.and_then(|max| text.char_indices().nth(max.max(1))) {
+        Some((at, _)) => {
+            let rest = text.split_off(at);
+            std::mem::replace(text, rest)
+        }
+        None => std::mem::take(text),
+    }
+}
+
+/// An [`InferenceProvider`] behind a pointer the host can keep.
+pub(crate) type ProviderFn = Arc<
+    dyn Fn(
+            InferencePrompt,
+            Option<InferenceChunks>,
+        ) -> BoxFuture<HandlerResult<InferenceRequestResult>>
+        + Send
+        + Sync,
+>;
+
+pub(crate) fn provider_fn<P: InferenceProvider>(provider: P) -> ProviderFn {
+    let provider = Arc::new(provider);
+    Arc::new(move |prompt, chunks| {
+        let provider = Arc::clone(&provider);
+        Box::pin(async move {
+            let result = match chunks {
+                Some(mut chunks) => provider.generate_streaming(prompt, &mut chunks).await,
+                None => provider.generate(prompt).await,
+            };
+            result.map_err(JsonRpcError::from)
+        })
+    })
+}
@@ -0,0 +1,255 @@
+//! Bounding the inference a host runs for its servers.
+//!
+//! One [`InferenceQueue`] is shared by all of a host's connections and by
+//! its own turns. Of the servers with waiting requests, the one served
+//! least recently goes next, so a chatty server delays mostly itself, and
+//! some slots can be kept for the host so servers cannot starve the user:
+//!
+//! ```ignore
+//! let queue = InferenceQueue::new(InferenceQueueOptions::default());
+//! let host = McplHost::builder()
+//!     .handler(handler)
+//!     .inference_queue(queue.clone())
+//!     .connect(conn)
+//!     .await?;
+//!
+//! // The user's turn
+//! let _permit = queue.acquire_for_host().await;
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::handler::HandlerError;
+use crate::types::{JsonRpcError, ERR_BUSY};
+
+/// Inferences running at once unless configured otherwise.
+pub const DEFAULT_INFERENCE_CONCURRENCY: usize = 4;
+
+/// Requests one server may have waiting unless configured otherwise.
+pub const DEFAULT_MAX_QUEUED_PER_SERVER: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct InferenceQueueOptions {
+    /// Inferences running at once, the host's own included.
+    pub concurrency: usize,
+    /// Of `concurrency`, slots only the host's own turns may take.
+    pub reserved_for_host: usize,
+    /// Requests one server may have waiting; more are refused with
+    /// `ERR_BUSY`.
+    pub max_queued_per_server: usize,
+}
+
+impl Default for InferenceQueueOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_INFERENCE_CONCURRENCY,
+            reserved_for_host: 1,
+            max_queued_per_server: DEFAULT_MAX_QUEUED_PER_SERVER,
+        }
+    }
+}
+
+/// A server's request refused because too many of its requests wait.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Busy: {queued} inference requests of server {server} already waiting")]
+pub struct QueueFull {
+    pub server: String,
+    pub queued: usize,
+}
+
+impl From<QueueFull> for HandlerError {
+    fn from(e: QueueFull) -> Self {
+        HandlerError::new(ERR_BUSY, e.to_string())
+            .with_data(serde_json::json!({ "server": e.server, "queued": e.queued }))
+    }
+}
+
+impl From<QueueFull> for JsonRpcError {
+    fn from(e: QueueFull) -> Self {
+        HandlerError::from(e).into()
+    }
+}
+
+/// Admits inferences up to a concurrency limit; cloning shares the queue.
+#[derive(Clone)]
+pub struct InferenceQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+/// A running inference's slot, freed when dropped.
+pub struct InferencePermit {
+    /// `None` once the slot is given back.
+    state: Option<Arc<Mutex<QueueState>>>,
+    for_server: bool,
+}
+
+struct QueueState {
+    options: InferenceQueueOptions,
+    running: usize,
+    running_for_servers: usize,
+    host_waiting: VecDeque<oneshot::Sender<InferencePermit>>,
+    server_waiting: HashMap<String, VecDeque<oneshot::Sender<InferencePermit>>>,
+    /// When each server was last admitted, counted in admissions.
+    last_served: HashMap<String, u64>,
+    admissions: u64,
+}
+
+impl InferenceQueue {
+    pub fn new(options: InferenceQueueOptions) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(QueueState {
+                options,
+                running: 0,
+                running_for_servers: 0,
+                host_waiting: VecDeque::new(),
+                server_waiting: HashMap::new(),
+                last_served: HashMap::new(),
+                admissions: 0,
+            })),
+        }
+    }
+
+    /// A slot for the host's own inference. Waiting host turns go before
+    /// any server's requests.
+    pub async fn acquire_for_host(&self) -> InferencePermit {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            if state.host_waiting.is_empty() && state.host_slot_free() {
+                return admit(&self.state, &mut state, None);
+            }
+            let (tx, rx) = oneshot::channel();
+            state.host_waiting.push_back(tx);
+            rx
+        };
+        waiting.await.expect("queue dropped a waiting request")
+    }
+
+    /// A slot for an `inference/request` from `server`, once it is its
+    /// turn.
+    pub async fn acquire_for_server(&self, server: &str) -> Result<InferencePermit, QueueFull> {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            if state.server_waiting.is_empty() && state.server_slot_free() {
+                return Ok(admit(&self.state, &mut state, Some(server)));
+            }
+            let max = state.options.max_queued_per_server;
+            let waiting = state.server_waiting.entry(server.to_string()).or_default();
+            // Requests given up on no longer count
+            waiting.retain(|tx| !tx.is_closed());
+            let queued = waiting.len();
+            if queued >= max {
+                if queued == 0 {
+                    state.server_waiting.remove(server);
+                }
+                return Err(QueueFull {
+                    server: server.to_string(),
+                    queued,
+                });
+            }
+            let (tx, rx) = oneshot::channel();
+            waiting.push_back(tx);
+            rx
+        };
+        Ok(waiting.await.expect("queue dropped a waiting request"))
+    }
+
+    /// Inferences running now.
+    pub fn running(&self) -> usize {
+        self.state.lock().unwrap().running
+    }
+}
+
+/// Take a slot for the host, or for `server`.
+fn admit(
+    queue: &Arc<Mutex<QueueState>>,
+    state: &mut QueueState,
+    server: Option<&str>,
+) -> InferencePermit {
+    state.running += 1;
+    if let Some(server) = server {
+        state.running_for_servers += 1;
+        state.admissions += 1;
+        let admissions = state.admissions;
+        state.last_served.insert(server.to_string(), admissions);
+    }
+    InferencePermit {
+        state: Some(Arc::clone(queue)),
+        for_server: server.is_some(),
+    }
+}
+
+impl QueueState {
+    fn host_slot_free(&self) -> bool {
+        self.running < self.options.concurrency
+    }
+
+    fn server_slot_free(&self) -> bool {
+        let limit = self
+            .options
+            .concurrency
+            .saturating_sub(self.options.reserved_for_host);
+        self.host_slot_free() && self.running_for_servers < limit
+    }
+
+    /// The server with waiting requests that was served least recently.
+    fn next_server(&self) -> Option<String> {
+        self.server_waiting
+            .keys()
+            .min_by_key(|server| (self.last_served.get(*server).copied().unwrap_or(0), *server))
+            .cloned()
+    }
+
+    fn release(&mut self, for_server: bool) {
+        self.running -= 1;
+        if for_server {
+            self.running_for_servers -= 1;
+        }
+    }
+}
+
+/// Hand free slots to waiting requests: the host's first, then those of
+/// the server served least recently.
+fn grant(queue: &Arc<Mutex<QueueState>>, state: &mut QueueState) {
+    while state.host_slot_free() {
+        let Some(tx) = state.host_waiting.pop_front() else {
+            break;
+        };
+        let permit = admit(queue, state, None);
+        if let Err(mut permit) = tx.send(permit) {
+            permit.state = None;
+            state.release(false);
+        }
+    }
+    while state.host_waiting.is_empty() && state.server_slot_free() {
+        let Some(server) = state.next_server() else {
+            break;
+        };
+        let waiting = state.server_waiting.get_mut(&server).unwrap();
+        let tx = waiting.pop_front();
+        if waiting.is_empty() {
+            state.server_waiting.remove(&server);
+        }
+        let Some(tx) = tx else {
+            continue;
+        };
+        let permit = admit(queue, state, Some(&server));
+        if let Err(mut permit) = tx.send(permit) {
+            permit.state = None;
+            state.release(true);
+        }
+    }
+}
+
+impl Drop for InferencePermit {
+    fn drop(&mut self) {
+        let Some(queue) = self.state.take() else {
+            return;
+        };
+        let mut state = queue.lock().unwrap();
+        state.release(self.for_server);
+        grant(&queue, &mut state);
+    }
+}
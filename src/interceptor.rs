@@ -0,0 +1,35 @@
+use crate::types::*;
+
+/// Verdict of an [`Interceptor`] hook.
+#[derive(Debug, Clone)]
+pub enum Intercept {
+    /// Pass the (possibly modified) message on to the next interceptor.
+    Continue,
+    /// Swallow the message silently.
+    Drop,
+    /// Refuse the message. An outgoing send fails with
+    /// [`ConnectionError::Rejected`](crate::connection::ConnectionError::Rejected);
+    /// an incoming request is answered with this error; an incoming response
+    /// resolves its caller with it.
+    Reject(JsonRpcError),
+    /// Answer a request without it reaching its destination: an outgoing
+    /// request resolves locally with this result, an incoming request is
+    /// answered with it. Treated as `Drop` for other messages.
+    Respond(serde_json::Value),
+}
+
+/// Hook for observing, rewriting, short-circuiting, or rejecting messages as
+/// they cross the connection.
+///
+/// Interceptors run in registration order; the first verdict other than
+/// [`Intercept::Continue`] ends the chain. Outgoing hooks see messages before
+/// serialization, incoming hooks after parsing and before routing.
+pub trait Interceptor: Send + Sync {
+    fn on_outgoing(&self, _msg: &mut JsonRpcMessage) -> Intercept {
+        Intercept::Continue
+    }
+
+    fn on_incoming(&self, _msg: &mut JsonRpcMessage) -> Intercept {
+        Intercept::Continue
+    }
+}
@@ -0,0 +1,162 @@
+//! JSON Pointers (RFC 6901): `/`-separated paths into a JSON document,
+//! with `~` written `~0` and `/` written `~1` inside a token. The empty
+//! pointer is the whole document.
+//!
+//! ```ignore
+//! let hp = json_pointer::get(&state, "/units/0/hp")?;
+//! json_pointer::set(&mut state, &json_pointer::push("/units/0", "hp"), json!(5))?;
+//! ```
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PointerError {
+    #[error("Invalid JSON pointer {0:?}")]
+    Invalid(String),
+    #[error("No value at {0:?}")]
+    NotFound(String),
+    #[error("Invalid array index at {0:?}")]
+    InvalidIndex(String),
+}
+
+/// `token` with `~` and `/` escaped, for use as one pointer token.
+pub fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+/// The token an escaped pointer token stands for; fails on a `~` not
+/// followed by `0` or `1`.
+pub fn unescape(token: &str) -> Result<String, PointerError> {
+    let mut unescaped = String::with_capacity(token.len());
+    let mut chars = token.chars();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => unescaped.push('~'),
+            Some('1') => unescaped.push('/'),
+            _ => return Err(PointerError::Invalid(token.to_string())),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// The unescaped tokens of `pointer`, outermost first.
+pub fn tokens(pointer: &str) -> Result<Vec<String>, PointerError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| PointerError::Invalid(pointer.to_string()))?;
+    rest.split('/')
+        .map(|token| unescape(token).map_err(|_| PointerError::Invalid(pointer.to_string())))
+        .collect()
+}
+
+/// `pointer` extended by the unescaped `token`.
+pub fn push(pointer: &str, token: &str) -> String {
+    format!("{}/{}", pointer, escape(token))
+}
+
+/// `pointer` split into its parent pointer and unescaped last token;
+/// `None` for the whole document, which has no parent.
+pub fn split_last(pointer: &str) -> Result<Option<(&str, String)>, PointerError> {
+    if pointer.is_empty() {
+        return Ok(None);
+    }
+    let at = pointer
+        .rfind('/')
+        .ok_or_else(|| PointerError::Invalid(pointer.to_string()))?;
+    let last =
+        unescape(&pointer[at + 1..]).map_err(|_| PointerError::Invalid(pointer.to_string()))?;
+    Ok(Some((&pointer[..at], last)))
+}
+
+/// `token` as an array index: decimal digits without leading zeros.
+pub fn array_index(token: &str) -> Option<usize> {
+    let leading_zero = token.len() > 1 && token.starts_with('0');
+    if leading_zero || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+pub fn get<'a>(doc: &'a Value, pointer: &str) -> Result<&'a Value, PointerError> {
+    let mut target = doc;
+    for token in tokens(pointer)? {
+        target = match target {
+            Value::Object(map) => map.get(&token),
+            Value::Array(items) => items.get(index(&token, pointer)?),
+            _ => None,
+        }
+        .ok_or_else(|| PointerError::NotFound(pointer.to_string()))?;
+    }
+    Ok(target)
+}
+
+pub fn get_mut<'a>(doc: &'a mut Value, pointer: &str) -> Result<&'a mut Value, PointerError> {
+    let mut target = doc;
+    for token in tokens(pointer)? {
+        target = match target {
+            Value::Object(map) => map.get_mut(&token),
+            Value::Array(items) => items.get_mut(index(&token, pointer)?),
+            _ => None,
+        }
+        .ok_or_else(|| PointerError::NotFound(pointer.to_string()))?;
+    }
+    Ok(target)
+}
+
+/// Put `value` at `pointer`, returning the value it replaced. The parent
+/// must exist; in an array, the index one past the end (or `-`) appends.
+pub fn set(doc: &mut Value, pointer: &str, value: Value) -> Result<Option<Value>, PointerError> {
+    let Some((parent, last)) = split_last(pointer)? else {
+        return Ok(Some(std::mem::replace(doc, value)));
+    };
+    match get_mut(doc, parent)? {
+        Value::Object(map) => Ok(map.insert(last, value)),
+        Value::Array(items) => {
+            let at = match last.as_str() {
+                "-" => items.len(),
+                last => index(last, pointer)?,
+            };
+            match at.cmp(&items.len()) {
+                std::cmp::Ordering::Less => Ok(Some(std::mem::replace(&mut items[at], value))),
+                std::cmp::Ordering::Equal => {
+                    items.push(value);
+                    Ok(None)
+                }
+                std::cmp::Ordering::Greater => Err(PointerError::InvalidIndex(pointer.to_string())),
+            }
+        }
+        _ => Err(PointerError::NotFound(pointer.to_string())),
+    }
+}
+
+/// Take the value at `pointer` out of `doc`; removing the whole document
+/// leaves `null`.
+pub fn remove(doc: &mut Value, pointer: &str) -> Result<Value, PointerError> {
+    let Some((parent, last)) = split_last(pointer)? else {
+        return Ok(std::mem::take(doc));
+    };
+    let not_found = || PointerError::NotFound(pointer.to_string());
+    match get_mut(doc, parent)? {
+        Value::Object(map) => map.remove(&last).ok_or_else(not_found),
+        Value::Array(items) => {
+            let at = index(&last, pointer)?;
+            if at < items.len() {
+                Ok(items.remove(at))
+            } else {
+                Err(not_found())
+            }
+        }
+        _ => Err(not_found()),
+    }
+}
+
+fn index(token: &str, pointer: &str) -> Result<usize, PointerError> {
+    array_index(token).ok_or_else(|| PointerError::InvalidIndex(pointer.to_string()))
+}
@@ -1,9 +1,95 @@
 pub mod types;
 pub mod methods;
 pub mod capabilities;
+pub mod auth;
+pub mod checkpoint;
+pub mod client;
 pub mod connection;
+pub mod context;
+pub mod correlation;
+pub mod definition;
+pub mod feature_sets;
+pub mod handler;
+pub mod host;
+pub mod host_state;
+pub mod id;
+pub mod inference;
+pub mod inference_queue;
+pub mod interceptor;
+pub mod json_pointer;
+pub mod pool;
+pub mod push;
+pub mod request;
+pub mod rollback;
+pub mod router;
+pub mod schema;
+pub mod server;
+pub mod service;
+pub mod session;
+pub mod template;
+pub mod usage;
+pub mod version;
+pub mod wiretap;
 
 pub use types::*;
 pub use methods::*;
 pub use capabilities::*;
-pub use connection::McplConnection;
+pub use auth::{Authenticator, MetaToken};
+pub use checkpoint::{
+    CheckpointCipher, CheckpointError, CheckpointStore, CheckpointTree, FileCheckpointStore,
+    MemoryCheckpointStore, StoredCheckpoint, CHECKPOINT_PAGE_SIZE,
+};
+pub use client::McplClient;
+pub use connection::{ConnectionOptions, McplConnection};
+pub use context::{
+    AssembledContext, ConflictPolicy, ContextAssembler, ContextAssemblerOptions,
+    HeuristicTokenizer, InjectionConflict, Tokenizer, TrimmedInjection,
+};
+pub use correlation::{CorrelationError, InferenceCorrelator, InferenceLinks, InferenceOutcome};
+pub use definition::{FeatureSet, ServerDefinition};
+pub use feature_sets::{
+    EmptyWhitelist, FeatureSetRegistry, FeatureSetSnapshot, ScopeMatcher, UnresolvedDependencies,
+    ValidationError, VersionSupport,
+};
+pub use id::*;
+pub use inference::{
+    check_response, EmitOptions, InferenceChunks, InferencePrompt, InferenceProvider,
+    InferenceStream, ModelEvent, ModelResolver, ResponseSchemaError,
+};
+pub use inference_queue::{InferencePermit, InferenceQueue, InferenceQueueOptions, QueueFull};
+pub use interceptor::{Intercept, Interceptor};
+pub use handler::{HandlerError, HandlerResult};
+pub use host::{McplHost, McplHostHandler};
+pub use host_state::{
+    apply_patch, HostStateContainer, HostStateSnapshot, HostStateTransaction, PatchError,
+    PatchErrorKind, RecordedCheckpoint, StateError,
+};
+pub use pool::{
+    BeforeInferenceOptions, BeforeInferenceRun, DegradationPolicy, PoolError, Pooled, ServerPool,
+};
+pub use push::{
+    DisabledPolicy, DropPolicy, ExcessPolicy, ExpiryCallback, FilePushEventStore, PushError,
+    PushEvent, PushEventBuilder, PushEventQueue, PushEventStore, PushQueueOptions, PushRejection,
+    PushRetryPolicy, PushSequenceTracker, PushStoreError, RateLimit, RateLimitStats,
+    RejectionCallback, SequenceCheck,
+};
+pub use request::RequestContext;
+pub use rollback::{RollbackCoordinator, RollbackOutcome, RollbackReport};
+pub use router::{BusyPolicy, ConcurrencyLimits, Router};
+pub use schema::{SchemaError, SchemaErrorKind};
+pub use server::{DisconnectReason, McplServer, McplServerHandler};
+pub use service::{serve_service, McplService};
+pub use session::{accept_initialize, negotiate, FeatureSetMetrics, McplSession, NegotiatedSession};
+pub use template::{TemplateError, TemplateEscape, TemplateOptions};
+pub use usage::{
+    BudgetExceeded, BudgetLimit, InferenceBudget, InferenceBudgets, SessionUsage, UsageTotals,
+};
+pub use version::{
+    negotiate_version, FeatureSetVersion, ProtocolVersion, VersionMismatch, SUPPORTED_VERSIONS,
+};
+pub use mcpl_macros::{mcpl_handler, mcpl_router};
+
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
@@ -1,11 +1,12 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::types::ContentBlock;
+use crate::types::{ContentBlock, JsonRpcId, ProgressToken};
 
 // ── Feature Sets (Section 6) ──
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FeatureSetDeclaration {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -16,6 +17,12 @@ pub struct FeatureSetDeclaration {
     pub rollback: bool,
     #[serde(rename = "hostState", default)]
     pub host_state: bool,
+    /// Version of the feature set's interface, `major.minor.patch`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Oldest host version that can use the feature set.
+    #[serde(rename = "minHostVersion", skip_serializing_if = "Option::is_none")]
+    pub min_host_version: Option<String>,
 }
 
 /// featureSets/update (Host → Server, Notification)
@@ -29,7 +36,7 @@ pub struct FeatureSetsUpdateParams {
     pub scopes: Option<HashMap<String, ScopeConfig>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ScopeConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub whitelist: Option<Vec<String>>,
@@ -37,6 +44,21 @@ pub struct ScopeConfig {
     pub blacklist: Option<Vec<String>>,
 }
 
+/// featureSets/list (Host → Server, Request) result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeatureSetsListResult {
+    #[serde(rename = "featureSets")]
+    pub feature_sets: Vec<FeatureSetStatus>,
+}
+
+/// A declared feature set and whether the host has enabled it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeatureSetStatus {
+    #[serde(flatten)]
+    pub declaration: FeatureSetDeclaration,
+    pub enabled: bool,
+}
+
 /// featureSets/changed (Server → Host, Notification)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FeatureSetsChangedParams {
@@ -91,7 +113,7 @@ pub struct StateRollbackResult {
 }
 
 /// State checkpoint metadata (Section 8.2).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct StateCheckpoint {
     pub id: String,
     #[serde(rename = "featureSet")]
@@ -101,6 +123,48 @@ pub struct StateCheckpoint {
     pub parent: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
+    /// Whatever the server wants to remember about the checkpoint.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// state/checkpoints/list (Host → Server, Request)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateCheckpointsListParams {
+    /// Only checkpoints of this feature set; all declared ones if absent.
+    #[serde(rename = "featureSet", skip_serializing_if = "Option::is_none")]
+    pub feature_set: Option<String>,
+    /// `nextCursor` of the previous page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateCheckpointsListResult {
+    pub checkpoints: Vec<StateCheckpoint>,
+    /// Present when more checkpoints follow.
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// state/checkpoint/create (Host → Server, Request)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateCheckpointCreateParams {
+    #[serde(rename = "featureSet")]
+    pub feature_set: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateCheckpointCreateResult {
+    pub checkpoint: StateCheckpoint,
 }
 
 /// JSON Patch operation (RFC 6902) for host-managed state (Section 8.3).
@@ -145,9 +209,34 @@ pub struct PushEventParams {
     pub timestamp: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub origin: Option<serde_json::Value>,
+    /// Position among the feature set's events on this connection, counting
+    /// from 1, so the host can detect gaps and reordering.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// How urgently the event should be handled; absent means normal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<PushPriority>,
+    /// RFC 3339 time after which the event is stale. Queues drop it rather
+    /// than deliver it late.
+    #[serde(rename = "expiresAt", default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
     pub payload: PushEventPayload,
 }
 
+/// Urgency of a push event, lowest first. Queues send more urgent events
+/// ahead of less urgent ones.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PushPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Critical,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PushEventPayload {
     pub content: Vec<ContentBlock>,
@@ -162,6 +251,45 @@ pub struct PushEventResult {
     pub reason: Option<String>,
 }
 
+/// push/eventBatch (Server → Host, Request)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushEventBatchParams {
+    pub events: Vec<PushEventParams>,
+}
+
+/// One result per event, in the order the events were sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushEventBatchResult {
+    pub results: Vec<PushEventBatchEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushEventBatchEntry {
+    #[serde(rename = "eventId")]
+    pub event_id: String,
+    #[serde(flatten)]
+    pub result: PushEventResult,
+}
+
+impl PushEventBatchResult {
+    /// The result for `event_id`, if the batch carried it.
+    pub fn get(&self, event_id: &str) -> Option<&PushEventResult> {
+        self.results
+            .iter()
+            .find(|entry| entry.event_id == event_id)
+            .map(|entry| &entry.result)
+    }
+
+    pub fn all_accepted(&self) -> bool {
+        self.results.iter().all(|entry| entry.result.accepted)
+    }
+
+    /// Entries the host rejected.
+    pub fn rejected(&self) -> impl Iterator<Item = &PushEventBatchEntry> {
+        self.results.iter().filter(|entry| !entry.result.accepted)
+    }
+}
+
 // ── Context Hooks (Section 10) ──
 
 /// Model info included in context hooks
@@ -186,19 +314,46 @@ pub struct ContextBeforeInferenceParams {
     pub turn_index: u32,
     #[serde(rename = "userMessage")]
     pub user_message: Option<String>,
+    /// The user's message with any images or audio, for servers that read
+    /// more than its text. `userMessage` still carries the text.
+    #[serde(
+        rename = "userContent",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub user_content: Option<Vec<ContentBlock>>,
     pub model: ModelInfo,
 }
 
+impl ContextBeforeInferenceParams {
+    /// The user's message as content blocks: `userContent`, or else the
+    /// text of `userMessage`.
+    pub fn user_blocks(&self) -> Vec<ContentBlock> {
+        message_blocks(&self.user_content, self.user_message.as_deref())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContextInjection {
     pub namespace: String,
     pub position: ContextInjectionPosition,
     pub content: ContextInjectionContent,
+    /// Importance relative to other injections, higher first; absent
+    /// means 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ContextInjection {
+    /// The injection's priority, 0 if it has none.
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum ContextInjectionPosition {
     System,
@@ -213,6 +368,18 @@ pub enum ContextInjectionContent {
     Blocks(Vec<ContentBlock>),
 }
 
+/// context/cancel (Host → Server, Notification)
+///
+/// The host gave up on an inference before it started, e.g. because the
+/// user sent another message; its `context/beforeInference` is obsolete.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContextCancelParams {
+    #[serde(rename = "inferenceId")]
+    pub inference_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContextBeforeInferenceResult {
     #[serde(rename = "featureSet")]
@@ -234,12 +401,48 @@ pub struct ContextAfterInferenceParams {
     pub user_message: String,
     #[serde(rename = "assistantMessage")]
     pub assistant_message: String,
+    /// `userMessage` with any non-text content.
+    #[serde(
+        rename = "userContent",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub user_content: Option<Vec<ContentBlock>>,
+    /// `assistantMessage` with any non-text content.
+    #[serde(
+        rename = "assistantContent",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub assistant_content: Option<Vec<ContentBlock>>,
     pub model: ModelInfo,
     pub usage: InferenceUsage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub channels: Option<serde_json::Value>,
 }
 
+impl ContextAfterInferenceParams {
+    /// The user's message as content blocks: `userContent`, or else the
+    /// text of `userMessage`.
+    pub fn user_blocks(&self) -> Vec<ContentBlock> {
+        message_blocks(&self.user_content, Some(&self.user_message))
+    }
+
+    /// The response as content blocks: `assistantContent`, or else the
+    /// text of `assistantMessage`.
+    pub fn assistant_blocks(&self) -> Vec<ContentBlock> {
+        message_blocks(&self.assistant_content, Some(&self.assistant_message))
+    }
+}
+
+fn message_blocks(content: &Option<Vec<ContentBlock>>, text: Option<&str>) -> Vec<ContentBlock> {
+    match (content, text) {
+        (Some(content), _) => content.clone(),
+        (None, Some(text)) if !text.is_empty() => vec![ContentBlock::text(text)],
+        (None, _) => Vec::new(),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContextAfterInferenceResult {
     #[serde(rename = "featureSet")]
@@ -252,7 +455,7 @@ pub struct ContextAfterInferenceResult {
 
 // ── Server-Initiated Inference (Section 11) ──
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InferenceUsage {
     #[serde(rename = "inputTokens")]
     pub input_tokens: u32,
@@ -272,20 +475,145 @@ pub struct InferenceRequestParams {
     pub messages: Vec<InferenceMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preferences: Option<InferencePreferences>,
+    /// Tools the model may call instead of, or besides, answering in text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<InferenceTool>>,
+    #[serde(rename = "toolChoice", skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<InferenceToolChoice>,
+    /// JSON Schema the answer's content must match, as JSON. Hosts check
+    /// it and fail the request if the model's output does not.
+    #[serde(rename = "responseSchema", skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<serde_json::Value>,
+}
+
+/// A tool offered to the host's model in an `inference/request`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InferenceTool {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema of the tool's input.
+    #[serde(rename = "inputSchema")]
+    pub input_schema: serde_json::Value,
+}
+
+/// Whether the model must call a tool. Hosts default to `auto`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum InferenceToolChoice {
+    /// The model decides.
+    Auto,
+    /// The model answers in text only.
+    None,
+    /// The model calls at least one tool.
+    Required,
+    /// The model calls the named tool.
+    Tool { name: String },
+}
+
+/// A tool invocation produced by the host's model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InferenceToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+}
+
+impl InferenceToolCall {
+    /// The input deserialized as `T`.
+    pub fn input_as<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        T::deserialize(&self.input)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceMessage {
     pub role: String,
-    pub content: String,
+    pub content: InferenceContent,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What an [`InferenceMessage`] says: plain text, or content blocks such
+/// as a game screenshot with a caption.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InferenceContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+impl InferenceContent {
+    /// The text, with text blocks one per line. Other blocks are left out.
+    pub fn text(&self) -> String {
+        match self {
+            InferenceContent::Text(text) => text.clone(),
+            InferenceContent::Blocks(blocks) => blocks
+                .iter()
+                .filter_map(|block| match block {
+                    ContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// The content as blocks; text is one text block, or none if empty.
+    pub fn blocks(&self) -> Vec<ContentBlock> {
+        match self {
+            InferenceContent::Text(text) => message_blocks(&None, Some(text)),
+            InferenceContent::Blocks(blocks) => blocks.clone(),
+        }
+    }
+
+    /// Whether every block is text, so text-only models can take it.
+    pub fn is_text(&self) -> bool {
+        match self {
+            InferenceContent::Text(_) => true,
+            InferenceContent::Blocks(blocks) => blocks
+                .iter()
+                .all(|block| matches!(block, ContentBlock::Text { .. })),
+        }
+    }
+}
+
+impl From<String> for InferenceContent {
+    fn from(text: String) -> Self {
+        InferenceContent::Text(text)
+    }
+}
+
+impl From<&str> for InferenceContent {
+    fn from(text: &str) -> Self {
+        InferenceContent::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ContentBlock>> for InferenceContent {
+    fn from(blocks: Vec<ContentBlock>) -> Self {
+        InferenceContent::Blocks(blocks)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InferencePreferences {
     #[serde(rename = "maxTokens", skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
+    #[serde(rename = "topP", skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// Sequences that end the answer when the model produces them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    /// Asks for repeatable sampling, where the model supports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(rename = "frequencyPenalty", skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    /// Preferred models, most preferred first: full ids or parts of them,
+    /// such as `"haiku"`. Hosts may ignore them.
+    #[serde(rename = "modelHints", default, skip_serializing_if = "Vec::is_empty")]
+    pub model_hints: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -295,17 +623,56 @@ pub struct InferenceRequestResult {
     #[serde(rename = "finishReason")]
     pub finish_reason: String,
     pub usage: InferenceUsage,
+    #[serde(rename = "toolCalls", skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<InferenceToolCall>>,
+}
+
+/// `finishReason` of an inference stopped by `inference/cancel`.
+pub const FINISH_REASON_CANCELLED: &str = "cancelled";
+
+/// `finishReason` of an inference that ended to have its tool calls run.
+pub const FINISH_REASON_TOOL_USE: &str = "tool_use";
+
+impl InferenceRequestResult {
+    /// Whether the inference was stopped by `inference/cancel`.
+    pub fn is_cancelled(&self) -> bool {
+        self.finish_reason == FINISH_REASON_CANCELLED
+    }
+
+    /// The content parsed as JSON into `T`, for requests with a
+    /// `responseSchema`.
+    pub fn parse_content<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_str(&self.content)
+    }
+
+    /// The tool calls the model made, in order.
+    pub fn tool_calls(&self) -> &[InferenceToolCall] {
+        self.tool_calls.as_deref().unwrap_or_default()
+    }
 }
 
 /// inference/chunk (Host → Server, Notification)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceChunkParams {
     #[serde(rename = "requestId")]
-    pub request_id: i64,
+    pub request_id: JsonRpcId,
     pub index: u32,
     pub delta: String,
 }
 
+/// inference/cancel (Server → Host, Notification)
+///
+/// The server no longer needs the answer to an `inference/request`, e.g.
+/// because the game ended. The host stops it and answers with
+/// `finishReason: "cancelled"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InferenceCancelParams {
+    #[serde(rename = "requestId")]
+    pub request_id: JsonRpcId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
 // ── Model Information (Section 12) ──
 
 /// model/info result (same as ModelInfo)
@@ -469,19 +836,64 @@ pub struct IncomingMessageResult {
     pub conversation_id: Option<String>,
 }
 
+// ── Cancellation (MCP) ──
+
+/// notifications/cancelled (Either direction, Notification)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelledParams {
+    #[serde(rename = "requestId")]
+    pub request_id: JsonRpcId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// ── Progress (MCP) ──
+
+/// notifications/progress (Either direction, Notification)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressParams {
+    #[serde(rename = "progressToken")]
+    pub progress_token: ProgressToken,
+    pub progress: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+// ── Shutdown (mcpl-core) ──
+
+/// notifications/shutdown (Server → Host, Notification)
+///
+/// Outside the spec: sent by [`McplServer::drain`](crate::McplServer::drain)
+/// just before the server closes the connection, so the host can reconnect
+/// elsewhere instead of treating the close as a failure.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
 // ── Method name constants ──
 
 pub mod method {
     pub const INITIALIZE: &str = "initialize";
+    pub const PING: &str = "ping";
     pub const FEATURE_SETS_UPDATE: &str = "featureSets/update";
     pub const FEATURE_SETS_CHANGED: &str = "featureSets/changed";
+    pub const FEATURE_SETS_LIST: &str = "featureSets/list";
     pub const SCOPE_ELEVATE: &str = "scope/elevate";
     pub const STATE_ROLLBACK: &str = "state/rollback";
+    pub const STATE_CHECKPOINTS_LIST: &str = "state/checkpoints/list";
+    pub const STATE_CHECKPOINT_CREATE: &str = "state/checkpoint/create";
     pub const PUSH_EVENT: &str = "push/event";
+    pub const PUSH_EVENT_BATCH: &str = "push/eventBatch";
     pub const CONTEXT_BEFORE_INFERENCE: &str = "context/beforeInference";
     pub const CONTEXT_AFTER_INFERENCE: &str = "context/afterInference";
+    pub const CONTEXT_CANCEL: &str = "context/cancel";
     pub const INFERENCE_REQUEST: &str = "inference/request";
     pub const INFERENCE_CHUNK: &str = "inference/chunk";
+    pub const INFERENCE_CANCEL: &str = "inference/cancel";
     pub const MODEL_INFO: &str = "model/info";
     pub const CHANNELS_REGISTER: &str = "channels/register";
     pub const CHANNELS_CHANGED: &str = "channels/changed";
@@ -492,4 +904,8 @@ pub mod method {
     pub const CHANNELS_OUTGOING_COMPLETE: &str = "channels/outgoing/complete";
     pub const CHANNELS_PUBLISH: &str = "channels/publish";
     pub const CHANNELS_INCOMING: &str = "channels/incoming";
+    pub const NOTIFICATIONS_INITIALIZED: &str = "notifications/initialized";
+    pub const NOTIFICATIONS_CANCELLED: &str = "notifications/cancelled";
+    pub const NOTIFICATIONS_PROGRESS: &str = "notifications/progress";
+    pub const NOTIFICATIONS_SHUTDOWN: &str = "notifications/shutdown";
 }
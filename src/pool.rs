@@ -0,0 +1,479 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::client::McplClient;
+use crate::connection::{CancellationToken, ConnectionError};
+use crate::context::{
+    AssembledContext, ContextAssembler, ContextAssemblerOptions, InjectionConflict,
+};
+use crate::methods::*;
+use crate::session::McplSession;
+
+/// How long [`ServerPool::run_before_inference`] waits for a server unless
+/// configured otherwise.
+pub const DEFAULT_BEFORE_INFERENCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Host-side view of several MCPL servers at once.
+///
+/// Each server is added under a name with the [`McplSession`] of its
+/// connection, e.g. from [`McplHost::session`](crate::McplHost::session).
+/// The pool reads feature sets and channels from the sessions, so it stays
+/// current as servers send `featureSets/changed` and `channels/changed`.
+///
+/// ```ignore
+/// let pool = ServerPool::new();
+/// pool.insert("game", game_host.session());
+/// pool.insert("lobby", lobby_host.session());
+/// pool.feature_sets_update(&FeatureSetsUpdateParams { enabled: Some(vec!["game".into()]), .. }).await;
+/// let result = pool.state_rollback("game", "cp-3").await?;
+/// ```
+#[derive(Default)]
+pub struct ServerPool {
+    servers: Mutex<BTreeMap<String, McplSession>>,
+    /// Each server's last `context/beforeInference` result, for
+    /// [`DegradationPolicy::UseCached`].
+    injections: Mutex<HashMap<String, ContextBeforeInferenceResult>>,
+    /// The inference each conversation is preparing context for, and the
+    /// token that abandons it.
+    preparing: Mutex<HashMap<String, (String, CancellationToken)>>,
+}
+
+/// Something one server of a [`ServerPool`] declared or answered.
+#[derive(Debug, Clone)]
+pub struct Pooled<T> {
+    /// Name the server was added under.
+    pub server: String,
+    pub value: T,
+}
+
+/// What [`ServerPool::run_before_inference`] does when a server fails or
+/// times out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DegradationPolicy {
+    /// Leave the server's injections out of this turn.
+    #[default]
+    Skip,
+    /// Use the injections the server returned last time, or skip it if it
+    /// never answered.
+    UseCached,
+    /// Fail the whole turn with [`PoolError::Connection`].
+    FailTurn,
+}
+
+/// Settings for [`ServerPool::run_before_inference`].
+#[derive(Clone)]
+pub struct BeforeInferenceOptions {
+    /// How long to wait for a server without an entry in `timeouts`.
+    pub timeout: Duration,
+    /// How long to wait for each server, by name.
+    pub timeouts: HashMap<String, Duration>,
+    /// What to do about a server without an entry in `degradations` that
+    /// fails or times out.
+    pub degradation: DegradationPolicy,
+    /// What to do about each server that fails or times out, by name.
+    pub degradations: HashMap<String, DegradationPolicy>,
+    /// How the servers' injections are merged.
+    pub assembler: ContextAssemblerOptions,
+}
+
+impl Default for BeforeInferenceOptions {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_BEFORE_INFERENCE_TIMEOUT,
+            timeouts: HashMap::new(),
+            degradation: DegradationPolicy::default(),
+            degradations: HashMap::new(),
+            assembler: ContextAssemblerOptions::default(),
+        }
+    }
+}
+
+/// The merged context injections of the servers asked for
+/// `context/beforeInference`, and how each of them fared.
+#[derive(Debug)]
+pub struct BeforeInferenceRun {
+    /// Injections merged within the model's token budget.
+    pub context: AssembledContext,
+    /// Servers whose injections were merged, sorted, including those in
+    /// `cached`.
+    pub answered: Vec<String>,
+    /// Servers that failed or timed out.
+    pub failed: Vec<Pooled<ConnectionError>>,
+    /// Failed servers whose previous injections were merged instead.
+    pub cached: Vec<String>,
+    /// Injections refused under the assembler's conflict policy or
+    /// namespace owners.
+    pub refused: Vec<Pooled<InjectionConflict>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PoolError {
+    #[error("No server declares feature set {0}")]
+    UnknownFeatureSet(String),
+    #[error("Server {server}: {source}")]
+    Connection {
+        server: String,
+        #[source]
+        source: ConnectionError,
+    },
+    #[error("Inference {0} was superseded or cancelled")]
+    Cancelled(String),
+}
+
+impl ServerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a server, replacing any server added under the same name.
+    pub fn insert(&self, name: impl Into<String>, session: McplSession) -> Option<McplSession> {
+        self.servers().insert(name.into(), session)
+    }
+
+    pub fn remove(&self, name: &str) -> Option<McplSession> {
+        self.injections.lock().unwrap().remove(name);
+        self.servers().remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<McplSession> {
+        self.servers().get(name).cloned()
+    }
+
+    /// Names of the servers in the pool, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.servers().keys().cloned().collect()
+    }
+
+    /// Feature sets declared by every server, by server name.
+    pub fn feature_sets(&self) -> Vec<Pooled<FeatureSetDeclaration>> {
+        self.snapshot()
+            .into_iter()
+            .flat_map(|(server, session)| {
+                declared(&session)
+                    .into_iter()
+                    .map(move |declaration| Pooled {
+                        server: server.clone(),
+                        value: declaration,
+                    })
+            })
+            .collect()
+    }
+
+    /// Channels registered by every server, by server name.
+    pub fn channels(&self) -> Vec<Pooled<ChannelDescriptor>> {
+        self.snapshot()
+            .into_iter()
+            .flat_map(|(server, session)| {
+                session.channels().into_iter().map(move |channel| Pooled {
+                    server: server.clone(),
+                    value: channel,
+                })
+            })
+            .collect()
+    }
+
+    /// Name of the server declaring `feature_set`; the first by name if
+    /// several do.
+    pub fn owner(&self, feature_set: &str) -> Option<String> {
+        self.owning(feature_set).map(|(server, _)| server)
+    }
+
+    /// Send each server the part of `update` that concerns the feature sets
+    /// it declares; servers with nothing to update are skipped.
+    pub async fn feature_sets_update(
+        &self,
+        update: &FeatureSetsUpdateParams,
+    ) -> Vec<Pooled<Result<(), ConnectionError>>> {
+        let mut calls = JoinSet::new();
+        for (server, session) in self.snapshot() {
+            let names: Vec<String> = declared(&session).into_iter().map(|d| d.name).collect();
+            let ours = |list: &Option<Vec<String>>| {
+                list.as_ref().map(|list| {
+                    list.iter()
+                        .filter(|name| names.contains(name))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+            };
+            let params = FeatureSetsUpdateParams {
+                enabled: ours(&update.enabled).filter(|list| !list.is_empty()),
+                disabled: ours(&update.disabled).filter(|list| !list.is_empty()),
+                scopes: update
+                    .scopes
+                    .as_ref()
+                    .map(|scopes| {
+                        scopes
+                            .iter()
+                            .filter(|(name, _)| names.contains(name))
+                            .map(|(name, scope)| (name.clone(), scope.clone()))
+                            .collect::<std::collections::HashMap<_, _>>()
+                    })
+                    .filter(|scopes| !scopes.is_empty()),
+            };
+            if params.enabled.is_none() && params.disabled.is_none() && params.scopes.is_none() {
+                continue;
+            }
+            let client = McplClient::new(session.connection().clone());
+            calls.spawn(async move {
+                let value = client.feature_sets_update(&params).await;
+                Pooled { server, value }
+            });
+        }
+        collect(calls).await
+    }
+
+    /// Ask every server that negotiated `context/beforeInference` for its
+    /// context injections.
+    pub async fn before_inference(
+        &self,
+        params: &ContextBeforeInferenceParams,
+    ) -> Vec<Pooled<Result<ContextBeforeInferenceResult, ConnectionError>>> {
+        let mut calls = JoinSet::new();
+        for (server, session) in self.snapshot() {
+            if !session.supports_method(method::CONTEXT_BEFORE_INFERENCE) {
+                continue;
+            }
+            let conn = session.connection().clone();
+            let params = params.clone();
+            calls.spawn(async move {
+                let value = conn
+                    .send_request_typed(method::CONTEXT_BEFORE_INFERENCE, &params)
+                    .await;
+                Pooled { server, value }
+            });
+        }
+        collect(calls).await
+    }
+
+    /// Ask every capable server for its context injections at once and
+    /// merge them for `params.model`.
+    ///
+    /// A server that fails or does not answer within its timeout is
+    /// reported in [`BeforeInferenceRun::failed`] and handled per its
+    /// [`DegradationPolicy`]; unless that fails the turn, the others are
+    /// still merged.
+    ///
+    /// Starting a run for a conversation supersedes the one still running
+    /// for it: the servers are sent `context/cancel` for the older
+    /// inference, which fails with [`PoolError::Cancelled`].
+    pub async fn run_before_inference(
+        &self,
+        params: &ContextBeforeInferenceParams,
+        options: &BeforeInferenceOptions,
+    ) -> Result<BeforeInferenceRun, PoolError> {
+        let cancelled = CancellationToken::new();
+        let superseded = self.preparing().insert(
+            params.conversation_id.clone(),
+            (params.inference_id.clone(), cancelled.clone()),
+        );
+        if let Some((inference_id, superseded)) = superseded {
+            superseded.cancel();
+            self.cancel_before_inference(&inference_id, Some("superseded".into()))
+                .await;
+        }
+        let result = self.gather(params, options, &cancelled).await;
+        let mut preparing = self.preparing();
+        if preparing
+            .get(&params.conversation_id)
+            .is_some_and(|(inference_id, _)| *inference_id == params.inference_id)
+        {
+            preparing.remove(&params.conversation_id);
+        }
+        result
+    }
+
+    /// Abandon `inference_id`: send `context/cancel` to every server that
+    /// supports it, and fail its [`run_before_inference`](Self::run_before_inference)
+    /// if one is running.
+    pub async fn cancel_before_inference(&self, inference_id: &str, reason: Option<String>) {
+        let running = self
+            .preparing()
+            .values()
+            .find(|(id, _)| id == inference_id)
+            .map(|(_, cancelled)| cancelled.clone());
+        if let Some(cancelled) = running {
+            cancelled.cancel();
+        }
+        let params = ContextCancelParams {
+            inference_id: inference_id.to_string(),
+            reason,
+        };
+        for (server, session) in self.snapshot() {
+            if !session.supports_method(method::CONTEXT_CANCEL) {
+                continue;
+            }
+            let client = McplClient::new(session.connection().clone());
+            if let Err(e) = client.context_cancel(&params).await {
+                tracing::warn!("Failed to cancel context for server {}: {}", server, e);
+            }
+        }
+    }
+
+    async fn gather(
+        &self,
+        params: &ContextBeforeInferenceParams,
+        options: &BeforeInferenceOptions,
+        cancelled: &CancellationToken,
+    ) -> Result<BeforeInferenceRun, PoolError> {
+        let mut calls = JoinSet::new();
+        for (server, session) in self.snapshot() {
+            if !session.supports_method(method::CONTEXT_BEFORE_INFERENCE) {
+                continue;
+            }
+            let timeout = options
+                .timeouts
+                .get(&server)
+                .copied()
+                .unwrap_or(options.timeout);
+            let conn = session.connection().clone();
+            let params = params.clone();
+            let cancelled = cancelled.clone();
+            calls.spawn(async move {
+                let call = conn.send_request_typed(method::CONTEXT_BEFORE_INFERENCE, &params);
+                let value: Result<ContextBeforeInferenceResult, _> = tokio::select! {
+                    result = tokio::time::timeout(timeout, call) => match result {
+                        Ok(result) => result,
+                        Err(_) => Err(ConnectionError::Timeout),
+                    },
+                    _ = cancelled.cancelled() => Err(ConnectionError::Cancelled),
+                };
+                Pooled { server, value }
+            });
+        }
+        let mut assembler = ContextAssembler::new(options.assembler.clone());
+        let mut answered = Vec::new();
+        let mut failed = Vec::new();
+        let mut cached = Vec::new();
+        let mut refused = Vec::new();
+        let results = collect(calls).await;
+        if cancelled.is_cancelled() {
+            return Err(PoolError::Cancelled(params.inference_id.clone()));
+        }
+        for Pooled { server, value } in results {
+            let result = match value {
+                Ok(result) => {
+                    self.injections().insert(server.clone(), result.clone());
+                    Some(result)
+                }
+                Err(e) => {
+                    let policy = options
+                        .degradations
+                        .get(&server)
+                        .copied()
+                        .unwrap_or(options.degradation);
+                    tracing::warn!(
+                        server = %server,
+                        hook = method::CONTEXT_BEFORE_INFERENCE,
+                        degradation = ?policy,
+                        "Context hook failed: {}",
+                        e
+                    );
+                    if policy == DegradationPolicy::FailTurn {
+                        return Err(PoolError::Connection { server, source: e });
+                    }
+                    let previous = match policy {
+                        DegradationPolicy::UseCached => self.injections().get(&server).cloned(),
+                        _ => None,
+                    };
+                    if previous.is_some() {
+                        cached.push(server.clone());
+                    }
+                    failed.push(Pooled {
+                        server: server.clone(),
+                        value: e,
+                    });
+                    previous
+                }
+            };
+            if let Some(result) = result {
+                for conflict in assembler.add_result(result) {
+                    tracing::warn!("Server {}: {}", server, conflict);
+                    refused.push(Pooled {
+                        server: server.clone(),
+                        value: conflict,
+                    });
+                }
+                answered.push(server);
+            }
+        }
+        Ok(BeforeInferenceRun {
+            context: assembler.assemble_for(&params.model),
+            answered,
+            failed,
+            cached,
+            refused,
+        })
+    }
+
+    /// Roll back `feature_set` on the server that declares it.
+    pub async fn state_rollback(
+        &self,
+        feature_set: &str,
+        checkpoint: impl Into<String>,
+    ) -> Result<Pooled<StateRollbackResult>, PoolError> {
+        let (server, session) = self
+            .owning(feature_set)
+            .ok_or_else(|| PoolError::UnknownFeatureSet(feature_set.to_string()))?;
+        let value = McplClient::new(session.connection().clone())
+            .state_rollback(feature_set, checkpoint)
+            .await
+            .map_err(|source| PoolError::Connection {
+                server: server.clone(),
+                source,
+            })?;
+        Ok(Pooled { server, value })
+    }
+
+    pub(crate) fn owning(&self, feature_set: &str) -> Option<(String, McplSession)> {
+        self.snapshot()
+            .into_iter()
+            .find(|(_, session)| declared(session).iter().any(|d| d.name == feature_set))
+    }
+
+    fn servers(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, McplSession>> {
+        self.servers.lock().unwrap()
+    }
+
+    fn preparing(&self) -> std::sync::MutexGuard<'_, HashMap<String, (String, CancellationToken)>> {
+        self.preparing.lock().unwrap()
+    }
+
+    fn injections(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<String, ContextBeforeInferenceResult>> {
+        self.injections.lock().unwrap()
+    }
+
+    /// The servers, so calls do not hold the lock.
+    fn snapshot(&self) -> Vec<(String, McplSession)> {
+        self.servers()
+            .iter()
+            .map(|(name, session)| (name.clone(), session.clone()))
+            .collect()
+    }
+}
+
+/// Feature sets a server declared, as last seen by the host.
+fn declared(session: &McplSession) -> Vec<FeatureSetDeclaration> {
+    session
+        .negotiated()
+        .and_then(|negotiated| negotiated.peer_capabilities)
+        .and_then(|caps| caps.feature_sets)
+        .unwrap_or_default()
+}
+
+/// Results of fanned-out calls, ordered by server name.
+async fn collect<T: 'static>(mut calls: JoinSet<Pooled<T>>) -> Vec<Pooled<T>> {
+    let mut results = Vec::with_capacity(calls.len());
+    while let Some(joined) = calls.join_next().await {
+        match joined {
+            Ok(result) => results.push(result),
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+    results.sort_by(|a, b| a.server.cmp(&b.server));
+    results
+}
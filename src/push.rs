@@ -0,0 +1,1220 @@
+//! Server-side buffering of `push/event` requests, and host-side checking
+//! of the sequence numbers the queue gives them.
+//!
+//! ```ignore
+//! let queue = PushEventQueue::new(PushQueueOptions::default());
+//! queue.push(event)?; // buffered until a host is attached
+//! queue.attach(McplClient::from(&connection));
+//! queue.flush().await;
+//! ```
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::checkpoint::{parse_rfc3339, rfc3339, sync_dir, write_atomic, TEMP_EXTENSION};
+use crate::client::McplClient;
+use crate::connection::ConnectionError;
+use crate::correlation::{CorrelationError, InferenceCorrelator};
+use crate::methods::{PushEventParams, PushEventPayload, PushEventResult, PushPriority};
+use crate::types::ContentBlock;
+
+/// Default bound on buffered events.
+pub const DEFAULT_PUSH_QUEUE_CAPACITY: usize = 1024;
+
+/// Default number of event ids remembered for deduplication.
+pub const DEFAULT_DEDUP_WINDOW: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PushError {
+    /// The event id was already queued or delivered.
+    #[error("Duplicate push event: {0}")]
+    Duplicate(String),
+    /// The queue is full and its policy discards new events.
+    #[error("Push event queue is full")]
+    Full,
+    /// The event's feature set is not enabled and the queue rejects such
+    /// events.
+    #[error("Feature set not enabled: {0}")]
+    NotEnabled(String),
+    /// The event could not be saved to the queue's [`PushEventStore`].
+    #[error("Failed to save push event: {0}")]
+    Store(String),
+}
+
+/// Builds [`PushEventParams`] with a UUIDv7 `event_id` and the current
+/// time as `timestamp`, either of which can be overridden.
+///
+/// ```ignore
+/// let event = PushEvent::builder("lobby")
+///     .text("User joined")
+///     .origin(json!({ "user": "ana" }))
+///     .build();
+/// queue.push(event)?;
+/// ```
+pub struct PushEvent;
+
+impl PushEvent {
+    pub fn builder(feature_set: impl Into<String>) -> PushEventBuilder {
+        PushEventBuilder {
+            params: PushEventParams {
+                feature_set: feature_set.into(),
+                event_id: uuid::Uuid::now_v7().to_string(),
+                timestamp: rfc3339(SystemTime::now()),
+                origin: None,
+                sequence: None,
+                priority: None,
+                expires_at: None,
+                payload: PushEventPayload {
+                    content: Vec::new(),
+                },
+            },
+        }
+    }
+}
+
+/// Builder returned by [`PushEvent::builder`].
+#[derive(Debug, Clone)]
+pub struct PushEventBuilder {
+    params: PushEventParams,
+}
+
+impl PushEventBuilder {
+    pub fn event_id(mut self, event_id: impl Into<String>) -> Self {
+        self.params.event_id = event_id.into();
+        self
+    }
+
+    /// When the event happened, if not now.
+    pub fn timestamp(mut self, time: SystemTime) -> Self {
+        self.params.timestamp = rfc3339(time);
+        self
+    }
+
+    /// Append a text block to the payload.
+    pub fn text(self, text: impl Into<String>) -> Self {
+        self.content(ContentBlock::text(text))
+    }
+
+    /// Append a block to the payload.
+    pub fn content(mut self, block: ContentBlock) -> Self {
+        self.params.payload.content.push(block);
+        self
+    }
+
+    pub fn origin(mut self, origin: serde_json::Value) -> Self {
+        self.params.origin = Some(origin);
+        self
+    }
+
+    pub fn priority(mut self, priority: PushPriority) -> Self {
+        self.params.priority = Some(priority);
+        self
+    }
+
+    /// Stop delivering the event after `time`.
+    pub fn expires_at(mut self, time: SystemTime) -> Self {
+        self.params.expires_at = Some(rfc3339(time));
+        self
+    }
+
+    /// Stop delivering the event once `ttl` has passed from now.
+    pub fn ttl(self, ttl: Duration) -> Self {
+        self.expires_at(SystemTime::now() + ttl)
+    }
+
+    pub fn build(self) -> PushEventParams {
+        self.params
+    }
+}
+
+impl From<PushEventBuilder> for PushEventParams {
+    fn from(builder: PushEventBuilder) -> Self {
+        builder.build()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PushStoreError {
+    #[error("Push event storage failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Push event encoding failed: {0}")]
+    Encoding(#[from] serde_json::Error),
+}
+
+/// Durable storage for events a [`PushEventQueue`] has not delivered yet,
+/// set as [`PushQueueOptions::store`]. A queue created over a store starts
+/// with the events left in it, so they are replayed with their original ids
+/// and timestamps once a host is attached again.
+pub trait PushEventStore: Send + Sync {
+    /// Record an event the queue accepted.
+    fn save(&self, event: &PushEventParams) -> Result<(), PushStoreError>;
+
+    /// Forget an event once it is delivered or will not be.
+    fn remove(&self, event_id: &str) -> Result<(), PushStoreError>;
+
+    /// Events saved and not removed, oldest first.
+    fn load(&self) -> Result<Vec<PushEventParams>, PushStoreError>;
+}
+
+/// A [`PushEventStore`] keeping one JSON file per event in a directory,
+/// named by a sequence number that preserves the order events were saved.
+///
+/// ```ignore
+/// let store = FilePushEventStore::open("/var/lib/game/push")?;
+/// let queue = PushEventQueue::new(PushQueueOptions {
+///     store: Some(Arc::new(store)),
+///     ..Default::default()
+/// });
+/// ```
+#[derive(Debug)]
+pub struct FilePushEventStore {
+    root: PathBuf,
+    index: Mutex<FileIndex>,
+}
+
+#[derive(Debug, Default)]
+struct FileIndex {
+    /// Sequence number of each stored event, by event id.
+    sequences: HashMap<String, u64>,
+    next: u64,
+}
+
+const EVENT_EXTENSION: &str = "json";
+
+impl FilePushEventStore {
+    /// Open the store in `root`, creating the directory if needed and
+    /// indexing the events already there. Files that cannot be read as
+    /// events are logged and skipped.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, PushStoreError> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        let mut index = FileIndex::default();
+        for (sequence, path) in event_files(&root)? {
+            match read_event(&path) {
+                Ok(event) => {
+                    index.sequences.insert(event.event_id, sequence);
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable push event {}: {}", path.display(), e);
+                }
+            }
+            index.next = index.next.max(sequence + 1);
+        }
+        Ok(Self {
+            root,
+            index: Mutex::new(index),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, sequence: u64) -> PathBuf {
+        self.root
+            .join(format!("{:020}", sequence))
+            .with_extension(EVENT_EXTENSION)
+    }
+}
+
+impl PushEventStore for FilePushEventStore {
+    fn save(&self, event: &PushEventParams) -> Result<(), PushStoreError> {
+        let mut index = self.index.lock().unwrap();
+        let sequence = match index.sequences.get(&event.event_id) {
+            Some(&sequence) => sequence,
+            None => {
+                let sequence = index.next;
+                index.next += 1;
+                sequence
+            }
+        };
+        write_atomic(&self.path(sequence), &serde_json::to_vec(event)?)?;
+        index.sequences.insert(event.event_id.clone(), sequence);
+        Ok(())
+    }
+
+    fn remove(&self, event_id: &str) -> Result<(), PushStoreError> {
+        let mut index = self.index.lock().unwrap();
+        let Some(&sequence) = index.sequences.get(event_id) else {
+            return Ok(());
+        };
+        match fs::remove_file(self.path(sequence)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        sync_dir(&self.root)?;
+        index.sequences.remove(event_id);
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<PushEventParams>, PushStoreError> {
+        let index = self.index.lock().unwrap();
+        let mut sequences: Vec<u64> = index.sequences.values().copied().collect();
+        sequences.sort_unstable();
+        sequences
+            .into_iter()
+            .map(|sequence| read_event(&self.path(sequence)))
+            .collect()
+    }
+}
+
+/// Event files in `root` by sequence number, removing interrupted writes.
+fn event_files(root: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for file in fs::read_dir(root)? {
+        let path = file?.path();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(TEMP_EXTENSION) => {
+                tracing::debug!("Removing interrupted push event write {}", path.display());
+                fs::remove_file(&path)?;
+            }
+            Some(EVENT_EXTENSION) => {
+                let sequence = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok());
+                if let Some(sequence) = sequence {
+                    files.push((sequence, path));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(files)
+}
+
+fn read_event(path: &Path) -> Result<PushEventParams, PushStoreError> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// What a [`PushEventQueue`] watching the enabled feature sets does with
+/// events of a feature set that is not enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisabledPolicy {
+    /// Refuse the event: `push` fails with [`PushError::NotEnabled`], and
+    /// one already queued when its feature set is disabled is passed to
+    /// [`PushQueueOptions::on_rejected`] instead of being sent.
+    #[default]
+    Reject,
+    /// Keep the event queued until its feature set is enabled again, while
+    /// events of enabled feature sets are sent past it.
+    Hold,
+}
+
+/// Behavior of a full [`PushEventQueue`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Discard the oldest of the least urgent buffered events to make room,
+    /// or the event being pushed if it is less urgent than all of them.
+    #[default]
+    DropOldest,
+    /// Refuse the event being pushed with [`PushError::Full`].
+    DropNewest,
+}
+
+/// What to do with an event sent faster than its feature set's
+/// [`RateLimit`] allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExcessPolicy {
+    /// Discard the event.
+    #[default]
+    Drop,
+    /// Discard the event if a later one of the same feature set is queued
+    /// to supersede it, otherwise delay it.
+    Coalesce,
+    /// Hold the event, and everything queued behind it, until the limit
+    /// allows it.
+    Delay,
+}
+
+/// A token bucket limiting how fast one feature set's events are sent.
+///
+/// ```ignore
+/// let limit = RateLimit::per_second(5.0).burst(20).excess(ExcessPolicy::Coalesce);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Tokens added per second; each event sent takes one.
+    pub per_second: f64,
+    /// Bucket size: how many events may be sent back to back.
+    pub burst: u32,
+    pub excess: ExcessPolicy,
+}
+
+impl RateLimit {
+    /// `rate` events per second with a burst of one, dropping the excess.
+    pub fn per_second(rate: f64) -> Self {
+        Self {
+            per_second: rate,
+            burst: 1,
+            excess: ExcessPolicy::default(),
+        }
+    }
+
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    pub fn excess(mut self, excess: ExcessPolicy) -> Self {
+        self.excess = excess;
+        self
+    }
+}
+
+/// Events of one feature set held back by its [`RateLimit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimitStats {
+    pub dropped: u64,
+    pub coalesced: u64,
+    pub delayed: u64,
+}
+
+/// Backoff schedule for events the host rejects with a transient reason,
+/// set as [`PushQueueOptions::retry`].
+#[derive(Debug, Clone)]
+pub struct PushRetryPolicy {
+    /// Total attempts, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Factor applied to the delay after each retry.
+    pub multiplier: f64,
+    /// Upper bound on any single delay.
+    pub max_backoff: Duration,
+    /// Rejection reasons worth retrying, matched exactly. Defaults to
+    /// `"busy"`.
+    pub transient_reasons: HashSet<String>,
+}
+
+impl Default for PushRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(5),
+            transient_reasons: HashSet::from(["busy".to_string()]),
+        }
+    }
+}
+
+impl PushRetryPolicy {
+    /// Delay before retrying an event answered with `result` on attempt
+    /// number `attempt` (starting at 1), or `None` to give up.
+    fn backoff(&self, attempt: u32, result: &PushEventResult) -> Option<Duration> {
+        let transient = result
+            .reason
+            .as_ref()
+            .is_some_and(|reason| self.transient_reasons.contains(reason));
+        if result.accepted || !transient || attempt >= self.max_attempts {
+            return None;
+        }
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        Some(self.initial_backoff.mul_f64(factor).min(self.max_backoff))
+    }
+}
+
+/// An event that will not be delivered: the host rejected it with a reason
+/// that is not transient, or after the last retry, or the queue refused it
+/// under [`DisabledPolicy::Reject`] without sending it.
+#[derive(Debug, Clone)]
+pub struct PushRejection {
+    pub event: PushEventParams,
+    pub reason: Option<String>,
+    /// Times the event was sent.
+    pub attempts: u32,
+}
+
+pub type RejectionCallback = Arc<dyn Fn(&PushRejection) + Send + Sync>;
+
+/// Called with each event dropped because its
+/// [`expires_at`](PushEventParams::expires_at) passed before it was sent.
+pub type ExpiryCallback = Arc<dyn Fn(&PushEventParams) + Send + Sync>;
+
+/// Construction-time settings for [`PushEventQueue`].
+#[derive(Clone)]
+pub struct PushQueueOptions {
+    /// Maximum number of buffered events, not counting one being sent.
+    pub capacity: usize,
+    pub drop_policy: DropPolicy,
+    /// How many recent event ids are remembered to refuse duplicates.
+    pub dedup_window: usize,
+    /// Send rate limits by feature set; feature sets without one are sent
+    /// as fast as the host answers.
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// Resend events the host rejects with a transient reason. The queue
+    /// waits for the retries before sending the next event.
+    pub retry: Option<PushRetryPolicy>,
+    pub on_rejected: Option<RejectionCallback>,
+    pub on_expired: Option<ExpiryCallback>,
+    /// The enabled feature sets, from
+    /// [`FeatureSetRegistry::watch_enabled`](crate::FeatureSetRegistry::watch_enabled).
+    /// When set, events of other feature sets are handled per `disabled`
+    /// rather than sent to be refused by the host.
+    pub enabled: Option<watch::Receiver<BTreeSet<String>>>,
+    pub disabled: DisabledPolicy,
+    /// Keep undelivered events here so they survive a restart.
+    pub store: Option<Arc<dyn PushEventStore>>,
+    /// Link delivered events to the inferences the host reports starting,
+    /// and settle those that were not delivered.
+    pub correlator: Option<InferenceCorrelator>,
+}
+
+impl Default for PushQueueOptions {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_PUSH_QUEUE_CAPACITY,
+            drop_policy: DropPolicy::default(),
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            rate_limits: HashMap::new(),
+            retry: None,
+            on_rejected: None,
+            on_expired: None,
+            enabled: None,
+            disabled: DisabledPolicy::default(),
+            store: None,
+            correlator: None,
+        }
+    }
+}
+
+/// Buffers `push/event` requests and sends them to the attached host one at
+/// a time: most [urgent](PushEventParams::priority) first, and in the order
+/// they were pushed within a priority.
+///
+/// Events are held while no host is attached or the queue is
+/// [paused](Self::pause). A send that fails at the transport puts its event
+/// back at the front and detaches the host, so nothing is lost across a
+/// reconnect; one the host answers with an error is logged and dropped.
+/// Events whose [`expires_at`](PushEventParams::expires_at) passes while
+/// they wait are dropped instead of being sent late.
+/// Cheap to clone; clones share the queue.
+#[derive(Clone)]
+pub struct PushEventQueue {
+    inner: Arc<QueueInner>,
+}
+
+struct QueueInner {
+    options: PushQueueOptions,
+    state: Mutex<QueueState>,
+    /// Notified on every change the drain task or `flush` may wait for.
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<PushEventParams>,
+    seen: HashSet<String>,
+    seen_order: VecDeque<String>,
+    client: Option<McplClient>,
+    /// Bumped on every attach and detach, retiring older drain tasks.
+    generation: u64,
+    paused: bool,
+    sending: bool,
+    dropped: u64,
+    expired: u64,
+    buckets: HashMap<String, TokenBucket>,
+    rate_limited: HashMap<String, RateLimitStats>,
+    /// Last sequence number sent to the attached host, by feature set.
+    sequences: HashMap<String, u64>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// The rate limit's verdict on the event about to be sent.
+enum Admission {
+    Send,
+    Discard,
+    Wait(Duration),
+}
+
+impl PushEventQueue {
+    /// A queue holding the events left in `options.store`, if any. A store
+    /// that fails to load is logged and the queue starts empty.
+    pub fn new(options: PushQueueOptions) -> Self {
+        let mut state = QueueState::default();
+        let stored = options.store.as_ref().map(|store| store.load());
+        match stored {
+            Some(Ok(events)) => {
+                for event in events {
+                    state.remember(event.event_id.clone(), options.dedup_window);
+                    state.enqueue(event);
+                }
+            }
+            Some(Err(e)) => tracing::warn!("Failed to load stored push events: {}", e),
+            None => {}
+        }
+        Self {
+            inner: Arc::new(QueueInner {
+                options,
+                state: Mutex::new(state),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Queue `event` for delivery and return its id.
+    ///
+    /// An empty `event_id` is filled with a UUIDv7 and an empty `timestamp`
+    /// with the current time.
+    pub fn push(&self, mut event: PushEventParams) -> Result<String, PushError> {
+        if event.event_id.is_empty() {
+            event.event_id = uuid::Uuid::now_v7().to_string();
+        }
+        if event.timestamp.is_empty() {
+            event.timestamp = rfc3339(SystemTime::now());
+        }
+        let options = &self.inner.options;
+        if self.len() >= options.capacity {
+            let expired = self.inner.state().take_expired(SystemTime::now());
+            self.inner.expire(expired);
+        }
+        let mut state = self.inner.state();
+        if state.seen.contains(&event.event_id) {
+            return Err(PushError::Duplicate(event.event_id));
+        }
+        if options.disabled == DisabledPolicy::Reject && !self.inner.is_enabled(&event) {
+            return Err(PushError::NotEnabled(event.feature_set));
+        }
+        let full = state.pending.len() >= options.capacity;
+        if full && options.drop_policy == DropPolicy::DropNewest {
+            state.dropped += 1;
+            return Err(PushError::Full);
+        }
+        if let Some(store) = &options.store {
+            store
+                .save(&event)
+                .map_err(|e| PushError::Store(e.to_string()))?;
+        }
+        let id = event.event_id.clone();
+        state.remember(id.clone(), options.dedup_window);
+        let mut event = Some(event);
+        if full {
+            state.dropped += 1;
+            // The oldest of the least urgent events, which may be this one
+            let victim = match state.least_urgent() {
+                Some(at) if priority(&state.pending[at]) <= priority(event.as_ref().unwrap()) => {
+                    state.pending.remove(at)
+                }
+                _ => event.take(),
+            };
+            let victim = victim.map_or_else(|| id.clone(), |victim| victim.event_id);
+            tracing::debug!("Push queue full, dropping event {}", victim);
+            self.inner.discard(&victim, "dropped from a full queue");
+        }
+        if let Some(event) = event {
+            state.enqueue(event);
+        }
+        drop(state);
+        self.inner.changed.notify_waiters();
+        Ok(id)
+    }
+
+    /// Start draining to `client`, replacing any host attached before.
+    /// Sequence numbers start again from 1 for the new host.
+    pub fn attach(&self, client: McplClient) {
+        let generation = {
+            let mut state = self.inner.state();
+            state.generation += 1;
+            state.client = Some(client);
+            state.sequences.clear();
+            state.generation
+        };
+        self.inner.changed.notify_waiters();
+        tokio::spawn(drain(Arc::clone(&self.inner), generation));
+    }
+
+    /// Stop draining; events buffer until the next [`attach`](Self::attach).
+    pub fn detach(&self) {
+        self.inner.state().detach();
+        self.inner.changed.notify_waiters();
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.inner.state().client.is_some()
+    }
+
+    /// Hold events while the host is busy. One already being sent still
+    /// completes.
+    pub fn pause(&self) {
+        self.inner.state().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.inner.state().paused = false;
+        self.inner.changed.notify_waiters();
+    }
+
+    /// Number of buffered events, not counting one being sent.
+    pub fn len(&self) -> usize {
+        self.inner.state().pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.state().pending.is_empty()
+    }
+
+    /// Events discarded because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.inner.state().dropped
+    }
+
+    /// Events dropped because they expired before they could be sent.
+    pub fn expired(&self) -> u64 {
+        self.inner.state().expired
+    }
+
+    /// Events of `feature_set` held back by its rate limit so far.
+    pub fn rate_limit_stats(&self, feature_set: &str) -> RateLimitStats {
+        self.inner
+            .state()
+            .rate_limited
+            .get(feature_set)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Wait until every buffered event has been sent, including any held
+    /// for a disabled feature set.
+    pub async fn flush(&self) {
+        self.inner
+            .wait(|state| state.pending.is_empty() && !state.sending)
+            .await;
+    }
+}
+
+impl QueueInner {
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap()
+    }
+
+    async fn wait(&self, done: impl FnMut(&mut QueueState) -> bool) {
+        self.wait_with(&mut None, done).await
+    }
+
+    /// [`wait`](Self::wait), also checking again whenever the `enabled`
+    /// feature sets change.
+    async fn wait_with(
+        &self,
+        enabled: &mut Option<watch::Receiver<BTreeSet<String>>>,
+        mut done: impl FnMut(&mut QueueState) -> bool,
+    ) {
+        loop {
+            let notified = self.changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if done(&mut self.state()) {
+                return;
+            }
+            match enabled {
+                Some(rx) => tokio::select! {
+                    _ = notified => {}
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            // The registry is gone; its last set stays in force
+                            *enabled = None;
+                        }
+                    }
+                },
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Drop events that expired before they could be sent.
+    fn expire(&self, events: Vec<PushEventParams>) {
+        for event in events {
+            tracing::debug!("Push event {} expired before it was sent", event.event_id);
+            self.discard(&event.event_id, "expired");
+            if let Some(on_expired) = &self.options.on_expired {
+                on_expired(&event);
+            }
+        }
+    }
+
+    /// Forget an event the queue gave up on before sending it.
+    fn discard(&self, event_id: &str, reason: &str) {
+        self.forget(event_id);
+        self.fail(event_id, reason);
+    }
+
+    /// Settle an undelivered event with the correlator.
+    fn fail(&self, event_id: &str, reason: &str) {
+        if let Some(correlator) = &self.options.correlator {
+            correlator.fail(
+                event_id,
+                CorrelationError::Rejected {
+                    event_id: event_id.to_string(),
+                    reason: reason.to_string(),
+                },
+            );
+        }
+    }
+
+    /// Remove a delivered or discarded event from the store.
+    fn forget(&self, event_id: &str) {
+        if let Some(store) = &self.options.store {
+            if let Err(e) = store.remove(event_id) {
+                tracing::warn!("Failed to remove stored push event {}: {}", event_id, e);
+            }
+        }
+    }
+
+    fn is_enabled(&self, event: &PushEventParams) -> bool {
+        self.options
+            .enabled
+            .as_ref()
+            .is_none_or(|enabled| enabled.borrow().contains(&event.feature_set))
+    }
+}
+
+impl QueueState {
+    /// Queue `event` behind every event at least as urgent.
+    fn enqueue(&mut self, event: PushEventParams) {
+        let urgency = priority(&event);
+        let at = self
+            .pending
+            .iter()
+            .rposition(|queued| priority(queued) >= urgency)
+            .map_or(0, |at| at + 1);
+        self.pending.insert(at, event);
+    }
+
+    /// Put back an event taken for sending, ahead of the events as urgent.
+    fn requeue(&mut self, event: PushEventParams) {
+        let urgency = priority(&event);
+        let at = self
+            .pending
+            .iter()
+            .position(|queued| priority(queued) <= urgency)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(at, event);
+    }
+
+    /// Remove the queued events that expired by `now`.
+    fn take_expired(&mut self, now: SystemTime) -> Vec<PushEventParams> {
+        let mut expired = Vec::new();
+        for event in std::mem::take(&mut self.pending) {
+            if is_expired(&event, now) {
+                expired.push(event);
+            } else {
+                self.pending.push_back(event);
+            }
+        }
+        self.expired += expired.len() as u64;
+        expired
+    }
+
+    /// Position of the oldest of the least urgent queued events.
+    fn least_urgent(&self) -> Option<usize> {
+        let lowest = priority(self.pending.back()?);
+        self.pending
+            .iter()
+            .position(|queued| priority(queued) == lowest)
+    }
+
+    fn remember(&mut self, id: String, window: usize) {
+        if window == 0 {
+            return;
+        }
+        while self.seen_order.len() >= window {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id.clone());
+        self.seen_order.push_back(id);
+    }
+
+    fn detach(&mut self) {
+        self.client = None;
+        self.generation += 1;
+    }
+
+    /// Take a token for `event` under `limit`, or decide what to do without
+    /// one. `waited` says whether the event has already been delayed.
+    fn admit(&mut self, event: &PushEventParams, limit: &RateLimit, waited: bool) -> Admission {
+        let now = Instant::now();
+        let burst = f64::from(limit.burst.max(1));
+        let bucket = self
+            .buckets
+            .entry(event.feature_set.clone())
+            .or_insert(TokenBucket {
+                tokens: burst,
+                refilled: now,
+            });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Admission::Send;
+        }
+        let wait = if limit.per_second > 0.0 {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / limit.per_second)
+        } else {
+            Duration::MAX
+        };
+        let superseded = self
+            .pending
+            .iter()
+            .any(|queued| queued.feature_set == event.feature_set);
+        let stats = self
+            .rate_limited
+            .entry(event.feature_set.clone())
+            .or_default();
+        match limit.excess {
+            ExcessPolicy::Drop => {
+                stats.dropped += 1;
+                Admission::Discard
+            }
+            ExcessPolicy::Coalesce if superseded => {
+                stats.coalesced += 1;
+                Admission::Discard
+            }
+            ExcessPolicy::Coalesce | ExcessPolicy::Delay => {
+                if !waited {
+                    stats.delayed += 1;
+                }
+                Admission::Wait(wait)
+            }
+        }
+    }
+}
+
+fn priority(event: &PushEventParams) -> PushPriority {
+    event.priority.unwrap_or_default()
+}
+
+/// Whether `event` went stale before `now`. An `expires_at` that does not
+/// parse never expires.
+fn is_expired(event: &PushEventParams, now: SystemTime) -> bool {
+    event
+        .expires_at
+        .as_deref()
+        .and_then(parse_rfc3339)
+        .is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Send events to the host attached as `generation` until it is replaced
+/// or detached.
+async fn drain(inner: Arc<QueueInner>, generation: u64) {
+    let mut enabled = inner.options.enabled.clone();
+    loop {
+        let mut next = None;
+        let mut refused = Vec::new();
+        let mut expired = Vec::new();
+        inner
+            .wait_with(&mut enabled, |state| {
+                if state.generation != generation {
+                    return true;
+                }
+                if state.paused || state.sending {
+                    return false;
+                }
+                let now = SystemTime::now();
+                let event = loop {
+                    let Some(event) = next_enabled(&inner, state, &mut refused) else {
+                        return !refused.is_empty() || !expired.is_empty();
+                    };
+                    if !is_expired(&event, now) {
+                        break event;
+                    }
+                    state.expired += 1;
+                    expired.push(event);
+                };
+                state.sending = true;
+                next = state.client.clone().map(|client| (client, event));
+                true
+            })
+            .await;
+        inner.expire(expired);
+        for event in refused {
+            inner.discard(&event.event_id, "feature set not enabled");
+            if let Some(on_rejected) = &inner.options.on_rejected {
+                let reason = format!("Feature set not enabled: {}", event.feature_set);
+                on_rejected(&PushRejection {
+                    event,
+                    reason: Some(reason),
+                    attempts: 0,
+                });
+            }
+        }
+        let Some((client, mut event)) = next else {
+            if inner.state().generation != generation {
+                return;
+            }
+            inner.changed.notify_waiters();
+            continue;
+        };
+        if !wait_for_rate_limit(&inner, generation, &event).await {
+            let mut state = inner.state();
+            state.sending = false;
+            if state.generation != generation {
+                state.requeue(event);
+                drop(state);
+                inner.changed.notify_waiters();
+                return;
+            }
+            drop(state);
+            inner.discard(&event.event_id, "rate limited");
+            inner.changed.notify_waiters();
+            continue;
+        }
+        // A delay under the rate limit may have outlasted the event
+        if is_expired(&event, SystemTime::now()) {
+            {
+                let mut state = inner.state();
+                state.sending = false;
+                state.expired += 1;
+            }
+            inner.expire(vec![event]);
+            inner.changed.notify_waiters();
+            continue;
+        }
+        // Numbered only once it is sent, so discarded events leave no gaps
+        {
+            let mut state = inner.state();
+            let sequence = state
+                .sequences
+                .entry(event.feature_set.clone())
+                .or_default();
+            *sequence += 1;
+            event.sequence = Some(*sequence);
+        }
+        let mut attempts = 0;
+        let outcome = loop {
+            attempts += 1;
+            let outcome = client.push_event(&event).await;
+            let backoff = match (&outcome, &inner.options.retry) {
+                (Ok(result), Some(retry)) => retry.backoff(attempts, result),
+                _ => None,
+            };
+            let Some(backoff) = backoff else {
+                break outcome;
+            };
+            tracing::debug!(
+                "Host busy with push event {}, retrying in {:?}",
+                event.event_id,
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            if inner.state().generation != generation {
+                // Replaced or detached while backing off: requeue as if lost
+                break Err(ConnectionError::Closed);
+            }
+        };
+        let event_id = event.event_id.clone();
+        let mut requeued = false;
+        let mut rejection = None;
+        {
+            let mut state = inner.state();
+            state.sending = false;
+            match outcome {
+                Ok(result) if !result.accepted => {
+                    let reason = result.reason.as_deref().unwrap_or("no reason given");
+                    tracing::debug!("Host rejected push event {}: {}", event.event_id, reason);
+                    inner.fail(&event_id, reason);
+                    rejection = Some(PushRejection {
+                        event,
+                        reason: result.reason,
+                        attempts,
+                    });
+                }
+                Ok(result) => {
+                    if let Some(correlator) = &inner.options.correlator {
+                        match result.inference_id {
+                            Some(inference_id) => correlator.link(event_id.clone(), inference_id),
+                            None => correlator.fail(
+                                event_id.clone(),
+                                CorrelationError::NoInference(event_id.clone()),
+                            ),
+                        }
+                    }
+                }
+                Err(e) if is_disconnect(&e) => {
+                    tracing::warn!("Failed to push event {}: {}", event.event_id, e);
+                    state.requeue(event);
+                    requeued = true;
+                    if state.generation == generation {
+                        state.detach();
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Host failed push event {}: {}", event.event_id, e);
+                    inner.fail(&event_id, &e.to_string());
+                }
+            }
+        }
+        if !requeued {
+            inner.forget(&event_id);
+        }
+        if let (Some(rejection), Some(on_rejected)) = (&rejection, &inner.options.on_rejected) {
+            on_rejected(rejection);
+        }
+        inner.changed.notify_waiters();
+    }
+}
+
+/// Take the next event to send: the first queued one whose feature set is
+/// enabled. Events before it are held, or moved to `refused` under
+/// [`DisabledPolicy::Reject`].
+fn next_enabled(
+    inner: &QueueInner,
+    state: &mut QueueState,
+    refused: &mut Vec<PushEventParams>,
+) -> Option<PushEventParams> {
+    match inner.options.disabled {
+        DisabledPolicy::Reject => {
+            while let Some(event) = state.pending.pop_front() {
+                if inner.is_enabled(&event) {
+                    return Some(event);
+                }
+                tracing::debug!(
+                    "Feature set disabled, refusing push event {}",
+                    event.event_id
+                );
+                refused.push(event);
+            }
+            None
+        }
+        DisabledPolicy::Hold => {
+            let at = state
+                .pending
+                .iter()
+                .position(|event| inner.is_enabled(event))?;
+            state.pending.remove(at)
+        }
+    }
+}
+
+/// Wait until `event` may be sent under its feature set's rate limit.
+/// Returns false if it was discarded instead, or the host was replaced or
+/// detached while waiting.
+async fn wait_for_rate_limit(inner: &QueueInner, generation: u64, event: &PushEventParams) -> bool {
+    let Some(limit) = inner.options.rate_limits.get(&event.feature_set) else {
+        return true;
+    };
+    let mut waited = false;
+    loop {
+        let admission = {
+            let mut state = inner.state();
+            if state.generation != generation {
+                return false;
+            }
+            state.admit(event, limit, waited)
+        };
+        match admission {
+            Admission::Send => return true,
+            Admission::Discard => {
+                tracing::debug!("Rate limit discarded push event {}", event.event_id);
+                return false;
+            }
+            Admission::Wait(delay) => {
+                waited = true;
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = inner.changed.notified() => {}
+                }
+            }
+        }
+    }
+}
+
+/// Whether `e` means the host cannot currently be reached, rather than that
+/// it refused the event.
+fn is_disconnect(e: &ConnectionError) -> bool {
+    matches!(
+        e,
+        ConnectionError::Io(_)
+            | ConnectionError::Closed
+            | ConnectionError::WriteClosed
+            | ConnectionError::Timeout
+            | ConnectionError::NotReady { .. }
+    )
+}
+
+/// The result of checking a push event's sequence number against the
+/// events of its feature set seen before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SequenceCheck {
+    /// The next number, or the first seen for the feature set.
+    InOrder,
+    /// Events between the last one seen and this one are missing.
+    Gap { expected: u64, received: u64 },
+    /// A repeat, or an event older than one already seen.
+    Stale { expected: u64, received: u64 },
+}
+
+impl SequenceCheck {
+    pub fn is_in_order(&self) -> bool {
+        *self == SequenceCheck::InOrder
+    }
+}
+
+/// Host-side record of the last push event sequence number accepted per
+/// feature set, for detecting gaps and reordering on one connection.
+///
+/// ```ignore
+/// let check = tracker.check(&event.feature_set, sequence);
+/// if check.is_in_order() {
+///     tracker.record(&event.feature_set, sequence);
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PushSequenceTracker {
+    last: HashMap<String, u64>,
+}
+
+impl PushSequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How `sequence` follows the last number recorded for `feature_set`.
+    pub fn check(&self, feature_set: &str, sequence: u64) -> SequenceCheck {
+        let Some(&last) = self.last.get(feature_set) else {
+            return SequenceCheck::InOrder;
+        };
+        let expected = last + 1;
+        match sequence.cmp(&expected) {
+            std::cmp::Ordering::Equal => SequenceCheck::InOrder,
+            std::cmp::Ordering::Greater => SequenceCheck::Gap {
+                expected,
+                received: sequence,
+            },
+            std::cmp::Ordering::Less => SequenceCheck::Stale {
+                expected,
+                received: sequence,
+            },
+        }
+    }
+
+    /// Note `sequence` as the last accepted event of `feature_set`; later
+    /// checks expect the number after it.
+    pub fn record(&mut self, feature_set: &str, sequence: u64) {
+        self.last.insert(feature_set.to_string(), sequence);
+    }
+
+    /// The last sequence number recorded for `feature_set`.
+    pub fn last(&self, feature_set: &str) -> Option<u64> {
+        self.last.get(feature_set).copied()
+    }
+
+    /// Forget `feature_set`, so its next event is taken as in order.
+    pub fn reset(&mut self, feature_set: &str) {
+        self.last.remove(feature_set);
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+
+use crate::connection::{CancellationToken, ConnectionError, Responder};
+use crate::session::McplSession;
+use crate::types::*;
+
+/// An incoming request bundled with everything a handler needs to answer it.
+///
+/// Obtained from [`McplConnection::request_context`](crate::McplConnection::request_context).
+pub struct RequestContext {
+    request: JsonRpcRequest,
+    /// `None` once a router has taken over answering the request.
+    responder: Option<Responder>,
+    cancel: CancellationToken,
+    deadline: Option<Instant>,
+    /// `None` for requests that did not arrive on a connection.
+    session: Option<McplSession>,
+}
+
+impl RequestContext {
+    pub(crate) fn new(
+        request: JsonRpcRequest,
+        responder: Responder,
+        cancel: CancellationToken,
+        deadline: Option<Instant>,
+        session: McplSession,
+    ) -> Self {
+        Self {
+            request,
+            responder: Some(responder),
+            cancel,
+            deadline,
+            session: Some(session),
+        }
+    }
+
+    /// A context for a request that did not arrive on a connection, e.g.
+    /// one passed to a `tower::Service`. It is answered by the handler's
+    /// return value and is never cancelled.
+    pub(crate) fn detached(request: JsonRpcRequest) -> Self {
+        Self {
+            request,
+            responder: None,
+            cancel: CancellationToken::new(),
+            deadline: None,
+            session: None,
+        }
+    }
+
+    pub fn id(&self) -> &JsonRpcId {
+        &self.request.id
+    }
+
+    pub fn method(&self) -> &str {
+        &self.request.method
+    }
+
+    pub fn request(&self) -> &JsonRpcRequest {
+        &self.request
+    }
+
+    /// Deserialize the params into a typed struct, e.g.
+    /// `ctx.params::<PushEventParams>()`. Missing params deserialize from
+    /// `null`.
+    pub fn params<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        self.request.params_as()
+    }
+
+    /// The request's `_meta` object, if present.
+    pub fn meta(&self) -> Option<serde_json::Map<String, serde_json::Value>> {
+        self.request.meta()
+    }
+
+    /// A single `_meta` field.
+    pub fn meta_field(&self, key: &str) -> Option<serde_json::Value> {
+        self.meta()?.remove(key)
+    }
+
+    pub fn progress_token(&self) -> Option<ProgressToken> {
+        self.request.progress_token()
+    }
+
+    /// When the request should be answered by, if a deadline is configured.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left until the deadline; zero once it has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// State negotiated on the connection the request arrived on; `None` for
+    /// requests passed to a `tower::Service` directly.
+    pub fn session(&self) -> Option<&McplSession> {
+        self.session.as_ref()
+    }
+
+    /// Token that fires when the peer cancels this request.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Answer with a successful result.
+    pub async fn reply(self, result: serde_json::Value) -> Result<(), ConnectionError> {
+        self.respond(Ok(result)).await
+    }
+
+    /// Answer with a JSON-RPC error.
+    pub async fn reply_error(
+        self,
+        code: i32,
+        message: impl Into<String>,
+    ) -> Result<(), ConnectionError> {
+        self.respond(Err(JsonRpcError::new(code, message))).await
+    }
+
+    /// Answer with either a result or an error.
+    pub async fn respond(
+        self,
+        result: Result<serde_json::Value, JsonRpcError>,
+    ) -> Result<(), ConnectionError> {
+        match self.responder {
+            Some(responder) => responder.respond(result).await,
+            None => {
+                tracing::warn!(
+                    "Ignoring reply to {:?}; the handler's return value answers it",
+                    self.request.id
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Split into the raw request and its responder.
+    ///
+    /// # Panics
+    ///
+    /// If the context was handed to a router handler, which answers through
+    /// its return value instead.
+    pub fn into_parts(self) -> (JsonRpcRequest, Responder) {
+        let responder = self
+            .responder
+            .expect("request is answered by the handler's return value");
+        (self.request, responder)
+    }
+
+    /// Take over answering the request; later replies through the context
+    /// are ignored.
+    pub(crate) fn detach_responder(&mut self) -> Option<Responder> {
+        self.responder.take()
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use tokio::task::JoinSet;
+
+use crate::client::McplClient;
+use crate::methods::StateRollbackResult;
+use crate::pool::{PoolError, Pooled, ServerPool};
+
+/// Host-side bookkeeping for rewinding a conversation across feature sets.
+///
+/// The host records, per conversation turn, the checkpoint each feature set
+/// reported (e.g. from a tool result's host-managed state). Rewinding to a
+/// turn rolls every feature set back to the last checkpoint it recorded at
+/// or before that turn, so their states are consistent with each other.
+///
+/// ```ignore
+/// let rollbacks = RollbackCoordinator::new().parallel(true);
+/// rollbacks.record(3, "game", "cp-7");
+/// rollbacks.record(3, "lobby", "lb-2");
+/// let report = rollbacks.rollback(&pool, 3).await;
+/// if report.is_complete() {
+///     rollbacks.forget_after(3);
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct RollbackCoordinator {
+    /// Checkpoints by turn index, then feature set.
+    turns: Mutex<BTreeMap<u32, BTreeMap<String, String>>>,
+    parallel: bool,
+}
+
+/// What [`RollbackCoordinator::rollback`] did for each feature set.
+#[derive(Debug)]
+pub struct RollbackReport {
+    pub turn_index: u32,
+    /// One per feature set rolled back, ordered by feature set.
+    pub outcomes: Vec<RollbackOutcome>,
+    /// Feature sets whose first checkpoint is after the turn, so they have
+    /// nothing to roll back to.
+    pub unavailable: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct RollbackOutcome {
+    pub feature_set: String,
+    pub checkpoint: String,
+    pub result: Result<Pooled<StateRollbackResult>, PoolError>,
+}
+
+impl RollbackOutcome {
+    /// The server answered and reported success.
+    pub fn succeeded(&self) -> bool {
+        matches!(&self.result, Ok(pooled) if pooled.value.success)
+    }
+}
+
+impl RollbackReport {
+    /// Every feature set was rolled back.
+    pub fn is_complete(&self) -> bool {
+        self.unavailable.is_empty() && self.outcomes.iter().all(RollbackOutcome::succeeded)
+    }
+
+    /// Rollbacks that failed or that the server refused.
+    pub fn failures(&self) -> impl Iterator<Item = &RollbackOutcome> {
+        self.outcomes.iter().filter(|outcome| !outcome.succeeded())
+    }
+}
+
+impl RollbackCoordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send the `state/rollback` requests concurrently rather than one after
+    /// another.
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.parallel = parallel;
+        self
+    }
+
+    /// Note that `feature_set` was at `checkpoint` after turn `turn_index`,
+    /// replacing any checkpoint it recorded for that turn.
+    pub fn record(
+        &self,
+        turn_index: u32,
+        feature_set: impl Into<String>,
+        checkpoint: impl Into<String>,
+    ) {
+        self.turns()
+            .entry(turn_index)
+            .or_default()
+            .insert(feature_set.into(), checkpoint.into());
+    }
+
+    /// The checkpoint each feature set would be rolled back to for
+    /// `turn_index`.
+    pub fn plan(&self, turn_index: u32) -> BTreeMap<String, String> {
+        let mut plan = BTreeMap::new();
+        for checkpoints in self.turns().range(..=turn_index).map(|(_, c)| c) {
+            plan.extend(checkpoints.clone());
+        }
+        plan
+    }
+
+    /// Drop what was recorded after `turn_index`, e.g. once the conversation
+    /// has been rewound to it.
+    pub fn forget_after(&self, turn_index: u32) {
+        let mut turns = self.turns();
+        if let Some(after) = turn_index.checked_add(1) {
+            turns.split_off(&after);
+        }
+    }
+
+    /// Roll every recorded feature set back to its checkpoint for
+    /// `turn_index`, through the server in `pool` declaring it. Failures do
+    /// not stop the other rollbacks; they are reported per feature set.
+    pub async fn rollback(&self, pool: &ServerPool, turn_index: u32) -> RollbackReport {
+        let plan = self.plan(turn_index);
+        let recorded: BTreeSet<String> = self
+            .turns()
+            .values()
+            .flat_map(|checkpoints| checkpoints.keys().cloned())
+            .collect();
+        let unavailable = recorded
+            .into_iter()
+            .filter(|feature_set| !plan.contains_key(feature_set))
+            .collect();
+
+        let mut outcomes = Vec::with_capacity(plan.len());
+        let mut calls = JoinSet::new();
+        for (feature_set, checkpoint) in plan {
+            let Some((server, session)) = pool.owning(&feature_set) else {
+                outcomes.push(RollbackOutcome {
+                    result: Err(PoolError::UnknownFeatureSet(feature_set.clone())),
+                    feature_set,
+                    checkpoint,
+                });
+                continue;
+            };
+            let client = McplClient::new(session.connection().clone());
+            let call = async move {
+                let result = client
+                    .state_rollback(&feature_set, &checkpoint)
+                    .await
+                    .map(|value| Pooled {
+                        server: server.clone(),
+                        value,
+                    })
+                    .map_err(|source| PoolError::Connection { server, source });
+                RollbackOutcome {
+                    feature_set,
+                    checkpoint,
+                    result,
+                }
+            };
+            if self.parallel {
+                calls.spawn(call);
+            } else {
+                outcomes.push(call.await);
+            }
+        }
+        while let Some(joined) = calls.join_next().await {
+            match joined {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+        outcomes.sort_by(|a, b| a.feature_set.cmp(&b.feature_set));
+        RollbackReport {
+            turn_index,
+            outcomes,
+            unavailable,
+        }
+    }
+
+    fn turns(&self) -> std::sync::MutexGuard<'_, BTreeMap<u32, BTreeMap<String, String>>> {
+        self.turns.lock().unwrap()
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::Instrument;
+
+use crate::auth::Authenticator;
+use crate::connection::{ConnectionError, McplConnection};
+use crate::handler::{builtin, encode, params, serve_loop, BoxFuture, Dispatch, HandlerResult};
+use crate::service::McplService;
+use crate::request::RequestContext;
+use crate::types::*;
+
+pub(crate) type RequestHandler =
+    Arc<dyn Fn(RequestContext) -> BoxFuture<HandlerResult<serde_json::Value>> + Send + Sync>;
+type NotificationHandler = Arc<dyn Fn(JsonRpcNotification) -> BoxFuture<()> + Send + Sync>;
+/// A future borrowing the router it runs in, for recursing into sub-routers.
+type Routed<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Method-name routing table for serving a connection with closures.
+///
+/// ```ignore
+/// let mut router = Router::new();
+/// router.on_request(method::PUSH_EVENT, |ctx| async move {
+///     let params: PushEventParams = ctx.params().map_err(JsonRpcError::invalid_params)?;
+///     Ok(serde_json::json!({ "accepted": true }))
+/// });
+/// router.serve(connection).await?;
+/// ```
+///
+/// Requests for unregistered methods are answered with `-32601 Method not
+/// found`, except `ping`, `model/info`, `featureSets/list`, and
+/// `channels/list`, which have built-in answers backed by the connection's
+/// [`McplSession`](crate::McplSession).
+/// Unregistered notifications are ignored.
+#[derive(Clone, Default)]
+pub struct Router {
+    requests: HashMap<String, RequestHandler>,
+    notifications: HashMap<String, NotificationHandler>,
+    limits: Option<ConcurrencyLimits>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    auth_error_code: Option<i32>,
+    traced: bool,
+    nested: Vec<(String, Router)>,
+}
+
+/// Caps on request handlers running at once, counted per connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyLimits {
+    /// Handlers running at once across all methods.
+    pub max_in_flight: Option<usize>,
+    /// Handlers running at once for individual methods, within
+    /// `max_in_flight`.
+    pub per_method: HashMap<String, usize>,
+    pub when_busy: BusyPolicy,
+}
+
+/// What happens to a request that would exceed a [`ConcurrencyLimits`] cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BusyPolicy {
+    /// Wait for a running handler to finish.
+    #[default]
+    Queue,
+    /// Answer at once with `ERR_BUSY`.
+    Reject,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle requests for `method`. The returned value (or error) is sent
+    /// as the response. Replaces any earlier handler for the same method.
+    pub fn on_request<F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(RequestContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult<serde_json::Value>> + Send + 'static,
+    {
+        self.requests.insert(
+            method.to_string(),
+            Arc::new(move |ctx| Box::pin(handler(ctx))),
+        );
+        self
+    }
+
+    /// Handle requests for `method` with typed params and result:
+    ///
+    /// ```ignore
+    /// router.on(method::PUSH_EVENT, |ctx, params: PushEventParams| async move {
+    ///     Ok(PushEventResult { accepted: true, inference_id: None, reason: None })
+    /// });
+    /// ```
+    ///
+    /// Params that do not deserialize as `P` are answered with `-32602
+    /// Invalid params` without calling the handler.
+    /// [`HandlerError`](crate::HandlerError)s convert into the error type
+    /// with `?`.
+    pub fn on<P, R, F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        P: DeserializeOwned + Send + 'static,
+        R: Serialize + 'static,
+        F: Fn(RequestContext, P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HandlerResult<R>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.on_request(method, move |ctx| {
+            let handler = Arc::clone(&handler);
+            async move {
+                let params = params(&ctx)?;
+                encode(handler(ctx, params).await)
+            }
+        })
+    }
+
+    /// Handle notifications for `method`, in arrival order.
+    pub fn on_notification<F, Fut>(&mut self, method: &str, handler: F) -> &mut Self
+    where
+        F: Fn(JsonRpcNotification) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.notifications.insert(
+            method.to_string(),
+            Arc::new(move |notification| Box::pin(handler(notification))),
+        );
+        self
+    }
+
+    /// Cap how many request handlers run at once on each served connection.
+    /// Notifications are not limited.
+    pub fn limit_concurrency(&mut self, limits: ConcurrencyLimits) -> &mut Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Run every request past `authenticator` before dispatch. Refused
+    /// requests are answered with `ERR_UNAUTHORIZED` unless
+    /// [`auth_error_code`](Self::auth_error_code) says otherwise.
+    /// Notifications are not checked.
+    pub fn authenticate(&mut self, authenticator: impl Authenticator + 'static) -> &mut Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Error code for requests the authenticator refuses.
+    pub fn auth_error_code(&mut self, code: i32) -> &mut Self {
+        self.auth_error_code = Some(code);
+        self
+    }
+
+    /// Handle each request inside a `mcpl.request` span carrying its method,
+    /// id, feature set (from a `featureSet` param), and on completion its
+    /// duration. Error responses are logged at warn level.
+    pub fn trace_requests(&mut self) -> &mut Self {
+        self.traced = true;
+        self
+    }
+
+    /// Mount `router` under `prefix`: requests and notifications for
+    /// `prefix/<method>` go to its handlers for `<method>`.
+    ///
+    /// ```ignore
+    /// let mut channels = Router::new();
+    /// channels.on(method, |ctx, params: ChannelsOpenParams| async move { ... });
+    /// router.nest("channels", channels); // serves channels/open
+    /// ```
+    ///
+    /// The sub-router's authenticator, concurrency limits, and tracing apply
+    /// to its methods in addition to this router's. Methods under `prefix`
+    /// go to the first router mounted there, even if this router registers
+    /// them too.
+    pub fn nest(&mut self, prefix: &str, router: Router) -> &mut Self {
+        self.nested
+            .push((prefix.trim_end_matches('/').to_string(), router));
+        self
+    }
+
+    /// Methods with a request handler registered directly on this router.
+    pub(crate) fn request_methods(&self) -> impl Iterator<Item = &str> {
+        self.requests.keys().map(String::as_str)
+    }
+
+    pub(crate) fn request_handler(&self, method: &str) -> Option<RequestHandler> {
+        self.requests.get(method).cloned()
+    }
+
+    /// Whether a request handler is registered for `method`, here or in a
+    /// sub-router.
+    pub fn handles(&self, method: &str) -> bool {
+        match nested_for(&self.nested, method) {
+            Some((router, rest)) => router.handles(rest),
+            None => self.requests.contains_key(method),
+        }
+    }
+
+    /// Serve `conn` until the peer disconnects, handling each request on
+    /// its own task.
+    pub async fn serve(&self, conn: McplConnection) -> Result<(), ConnectionError> {
+        serve_loop(conn, Arc::new(Connected::new(self.clone()))).await
+    }
+
+    /// The request handlers as a `tower::Service`, for wrapping in
+    /// middleware. Notification handlers are not part of the service, and
+    /// concurrency limits apply across all its calls.
+    pub fn into_service(self) -> McplService {
+        McplService::new(Arc::new(Connected::new(self)))
+    }
+}
+
+/// A router serving one connection, with that connection's limits, behind
+/// its authenticator.
+struct Connected {
+    router: Router,
+    limiter: Option<Limiter>,
+    nested: Vec<(String, Connected)>,
+}
+
+impl Connected {
+    fn new(router: Router) -> Self {
+        let limiter = router.limits.as_ref().map(Limiter::new);
+        let nested = router
+            .nested
+            .iter()
+            .map(|(prefix, router)| (prefix.clone(), Connected::new(router.clone())))
+            .collect();
+        Self {
+            router,
+            limiter,
+            nested,
+        }
+    }
+
+    /// Handle a request whose method, past its first `skip` bytes, is
+    /// relative to this router.
+    fn call(&self, skip: usize, ctx: RequestContext) -> Routed<'_, HandlerResult<serde_json::Value>> {
+        Box::pin(async move {
+            if !self.router.traced {
+                return self.admit_and_route(skip, ctx).await;
+            }
+            let feature_set = ctx.request().param::<String>("featureSet");
+            let span = tracing::info_span!(
+                "mcpl.request",
+                method = ctx.method(),
+                id = ?ctx.id(),
+                feature_set = feature_set.as_deref(),
+                duration_ms = tracing::field::Empty,
+            );
+            let started = Instant::now();
+            let result = self.admit_and_route(skip, ctx).instrument(span.clone()).await;
+            let _entered = span.enter();
+            span.record("duration_ms", started.elapsed().as_millis() as u64);
+            match &result {
+                Ok(_) => tracing::debug!("Request handled"),
+                Err(error) => tracing::warn!(code = error.code, "Request failed: {}", error.message),
+            }
+            result
+        })
+    }
+
+    async fn admit_and_route(
+        &self,
+        skip: usize,
+        ctx: RequestContext,
+    ) -> HandlerResult<serde_json::Value> {
+        if let Some(authenticator) = &self.router.authenticator {
+            authenticator.authenticate(&ctx).map_err(|reason| {
+                let code = self.router.auth_error_code.unwrap_or(ERR_UNAUTHORIZED);
+                JsonRpcError::new(code, format!("Unauthorized: {}", reason))
+            })?;
+        }
+        let path = &ctx.method()[skip..];
+        let _permits = match &self.limiter {
+            Some(limiter) => limiter.admit(path).await?,
+            None => Vec::new(),
+        };
+        if let Some((nested, rest)) = nested_for(&self.nested, path) {
+            let skip = ctx.method().len() - rest.len();
+            return nested.call(skip, ctx).await;
+        }
+        match self.router.requests.get(path).cloned() {
+            Some(handler) => handler(ctx).await,
+            None => builtin(&ctx)
+                .unwrap_or_else(|| Err(JsonRpcError::method_not_found(ctx.method()))),
+        }
+    }
+
+    fn notify(&self, skip: usize, notification: JsonRpcNotification) -> Routed<'_, ()> {
+        let path = &notification.method[skip..];
+        if let Some((nested, rest)) = nested_for(&self.nested, path) {
+            let skip = notification.method.len() - rest.len();
+            return nested.notify(skip, notification);
+        }
+        let handler = self.router.notifications.get(path).cloned();
+        Box::pin(async move {
+            match handler {
+                Some(handler) => handler(notification).await,
+                None => tracing::debug!("Ignoring unhandled notification {}", notification.method),
+            }
+        })
+    }
+}
+
+impl Dispatch for Connected {
+    fn handle_request(
+        &self,
+        ctx: RequestContext,
+    ) -> impl Future<Output = HandlerResult<serde_json::Value>> + Send {
+        self.call(0, ctx)
+    }
+
+    fn handle_notification(
+        &self,
+        notification: JsonRpcNotification,
+    ) -> impl Future<Output = ()> + Send {
+        self.notify(0, notification)
+    }
+}
+
+/// The sub-router mounted at the start of `path`, and the rest of the path.
+fn nested_for<'a, 'p, T>(nested: &'a [(String, T)], path: &'p str) -> Option<(&'a T, &'p str)> {
+    nested.iter().find_map(|(prefix, router)| {
+        let rest = path.strip_prefix(prefix.as_str())?.strip_prefix('/')?;
+        Some((router, rest))
+    })
+}
+
+struct Limiter {
+    total: Option<Arc<Semaphore>>,
+    per_method: HashMap<String, Arc<Semaphore>>,
+    when_busy: BusyPolicy,
+}
+
+impl Limiter {
+    fn new(limits: &ConcurrencyLimits) -> Self {
+        Self {
+            total: limits.max_in_flight.map(|max| Arc::new(Semaphore::new(max))),
+            per_method: limits
+                .per_method
+                .iter()
+                .map(|(method, max)| (method.clone(), Arc::new(Semaphore::new(*max))))
+                .collect(),
+            when_busy: limits.when_busy,
+        }
+    }
+
+    /// Take a slot under every cap that applies to `method`; the method's own
+    /// first, so a queued request does not hold a shared slot while waiting.
+    async fn admit(&self, method: &str) -> HandlerResult<Vec<OwnedSemaphorePermit>> {
+        let semaphores = self.per_method.get(method).into_iter().chain(&self.total);
+        let mut permits = Vec::with_capacity(2);
+        for semaphore in semaphores {
+            let semaphore = Arc::clone(semaphore);
+            let permit = match self.when_busy {
+                BusyPolicy::Queue => semaphore.acquire_owned().await.ok(),
+                BusyPolicy::Reject => semaphore.try_acquire_owned().ok(),
+            };
+            permits.push(permit.ok_or_else(|| JsonRpcError::busy(method))?);
+        }
+        Ok(permits)
+    }
+}
@@ -0,0 +1,248 @@
+//! Checking JSON values against a JSON Schema.
+//!
+//! Supports the keywords model output is usually constrained with: `type`,
+//! `enum`, `const`, `properties`, `required`, `additionalProperties`,
+//! `items`, `minItems`, `maxItems`, `minLength`, `maxLength`, `minimum`,
+//! `maximum`, `allOf`, `anyOf` and `oneOf`. Other keywords, including
+//! `$ref` and `format`, are ignored.
+//!
+//! ```ignore
+//! schema::validate(&json!({ "type": "string", "enum": ["e4", "d4"] }), &answer)?;
+//! ```
+
+use serde_json::{Map, Value};
+
+use crate::json_pointer;
+
+/// A value that does not match its schema, and where.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("Value at {path:?} {kind}")]
+pub struct SchemaError {
+    /// JSON pointer to the offending value.
+    pub path: String,
+    pub kind: SchemaErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SchemaErrorKind {
+    #[error("is not of type {0}")]
+    Type(String),
+    #[error("is not one of the allowed values")]
+    NotInEnum,
+    #[error("is not the required constant")]
+    NotConst,
+    #[error("lacks required property {0:?}")]
+    MissingProperty(String),
+    #[error("has unexpected property {0:?}")]
+    AdditionalProperty(String),
+    #[error("has fewer than {0} items")]
+    MinItems(u64),
+    #[error("has more than {0} items")]
+    MaxItems(u64),
+    #[error("is shorter than {0} characters")]
+    MinLength(u64),
+    #[error("is longer than {0} characters")]
+    MaxLength(u64),
+    #[error("is less than {0}")]
+    Minimum(f64),
+    #[error("is greater than {0}")]
+    Maximum(f64),
+    #[error("matches none of the allowed schemas")]
+    NoMatch,
+    #[error("matches more than one of the `oneOf` schemas")]
+    MultipleMatches,
+    #[error("is rejected by a `false` schema")]
+    False,
+}
+
+/// Whether `value` matches `schema`, failing with the first mismatch.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), SchemaError> {
+    check(schema, value, "")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), SchemaError> {
+    let fail = |kind| {
+        Err(SchemaError {
+            path: path.to_string(),
+            kind,
+        })
+    };
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return fail(SchemaErrorKind::False),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(types) = schema.get("type") {
+        let matches = match types {
+            Value::String(name) => has_type(value, name),
+            Value::Array(names) => names
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|name| has_type(value, name)),
+            _ => true,
+        };
+        if !matches {
+            return fail(SchemaErrorKind::Type(type_names(types)));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return fail(SchemaErrorKind::NotInEnum);
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return fail(SchemaErrorKind::NotConst);
+        }
+    }
+
+    match value {
+        Value::Object(object) => check_object(schema, object, path)?,
+        Value::Array(items) => {
+            if let Some(min) = uint(schema, "minItems") {
+                if (items.len() as u64) < min {
+                    return fail(SchemaErrorKind::MinItems(min));
+                }
+            }
+            if let Some(max) = uint(schema, "maxItems") {
+                if items.len() as u64 > max {
+                    return fail(SchemaErrorKind::MaxItems(max));
+                }
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    check(
+                        item_schema,
+                        item,
+                        &json_pointer::push(path, &index.to_string()),
+                    )?;
+                }
+            }
+        }
+        Value::String(text) => {
+            let chars = text.chars().count() as u64;
+            if let Some(min) = uint(schema, "minLength") {
+                if chars < min {
+                    return fail(SchemaErrorKind::MinLength(min));
+                }
+            }
+            if let Some(max) = uint(schema, "maxLength") {
+                if chars > max {
+                    return fail(SchemaErrorKind::MaxLength(max));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                if number < min {
+                    return fail(SchemaErrorKind::Minimum(min));
+                }
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                if number > max {
+                    return fail(SchemaErrorKind::Maximum(max));
+                }
+            }
+        }
+        Value::Null | Value::Bool(_) => {}
+    }
+
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        for schema in schemas {
+            check(schema, value, path)?;
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if !schemas
+            .iter()
+            .any(|schema| check(schema, value, path).is_ok())
+        {
+            return fail(SchemaErrorKind::NoMatch);
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+        match schemas
+            .iter()
+            .filter(|schema| check(schema, value, path).is_ok())
+            .count()
+        {
+            0 => return fail(SchemaErrorKind::NoMatch),
+            1 => {}
+            _ => return fail(SchemaErrorKind::MultipleMatches),
+        }
+    }
+    Ok(())
+}
+
+fn check_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+) -> Result<(), SchemaError> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                return Err(SchemaError {
+                    path: path.to_string(),
+                    kind: SchemaErrorKind::MissingProperty(name.to_string()),
+                });
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, value) in object {
+        let path = json_pointer::push(path, name);
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property) => check(property, value, &path)?,
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    return Err(SchemaError {
+                        path,
+                        kind: SchemaErrorKind::AdditionalProperty(name.clone()),
+                    })
+                }
+                Some(additional) => check(additional, value, &path)?,
+                None => {}
+            },
+        }
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => match value {
+            Value::Number(number) => {
+                number.is_i64()
+                    || number.is_u64()
+                    || number.as_f64().is_some_and(|n| n.fract() == 0.0)
+            }
+            _ => false,
+        },
+        _ => true,
+    }
+}
+
+fn type_names(types: &Value) -> String {
+    match types {
+        Value::Array(names) => names
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" or "),
+        types => types.as_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn uint(schema: &Map<String, Value>, keyword: &str) -> Option<u64> {
+    schema.get(keyword).and_then(Value::as_u64)
+}
@@ -0,0 +1,1000 @@
+use std::future::Future;
+use std::io;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::capabilities::*;
+use crate::checkpoint::{list_page, CheckpointStore, StoredCheckpoint};
+use crate::connection::{CancellationToken, ConnectionError, McplConnection};
+use crate::correlation::{InferenceCorrelator, InferenceOutcome};
+use crate::feature_sets::{FeatureSetRegistry, ValidationError};
+use crate::handler::{
+    channels_list, encode, feature_sets_list, not_found, notification_params, params,
+    serve_until, Dispatch,
+};
+use crate::host_state::StateError;
+use crate::methods::*;
+use crate::request::RequestContext;
+use crate::service::McplService;
+use crate::session::{accept_version, McplSession, accepted_capabilities, version_mismatch_error};
+use crate::types::*;
+use crate::version::ProtocolVersion;
+
+pub use crate::handler::HandlerResult;
+use crate::handler::HandlerError;
+
+/// Server-side callbacks for MCPL requests and notifications sent by the host.
+///
+/// Every method has a default: `featureSets/list` answers from the session's
+/// declared and enabled feature sets, `channels/list` from the session's
+/// channel registry, other requests answer `-32601 Method not found`, and
+/// notifications are ignored. Requests are handled concurrently, each on its
+/// own task; notifications are handled in arrival order.
+pub trait McplServerHandler: Send + Sync + 'static {
+    /// Inspect or adjust the `initialize` result before it is sent. `result`
+    /// is prepared from the server's configured info and capabilities.
+    fn on_initialize(
+        &self,
+        _ctx: &RequestContext,
+        _params: McplInitializeParams,
+        result: McplInitializeResult,
+    ) -> impl Future<Output = HandlerResult<McplInitializeResult>> + Send {
+        async { Ok(result) }
+    }
+
+    /// A host connected; called before any of its messages are handled.
+    fn on_connected(&self, _session: &McplSession) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// The host sent `notifications/initialized`.
+    fn on_initialized(&self, _session: &McplSession) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// [`McplServer::shutdown`] or [`McplSession::shutdown`] was called. The
+    /// connection is still open, so final notifications can be sent.
+    fn on_shutdown(&self, _session: &McplSession) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// The connection ended; the last hook called for it.
+    fn on_disconnect(
+        &self,
+        _session: &McplSession,
+        _reason: DisconnectReason,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn on_feature_sets_update(
+        &self,
+        _params: FeatureSetsUpdateParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Defaults to the feature sets declared on this connection and whether
+    /// the host enabled them.
+    fn on_feature_sets_list(
+        &self,
+        ctx: &RequestContext,
+    ) -> impl Future<Output = HandlerResult<FeatureSetsListResult>> + Send {
+        let result = feature_sets_list(ctx);
+        async move { result }
+    }
+
+    /// `state/rollback`, unless the server has a
+    /// [checkpoint store](McplServerBuilder::checkpoints); then
+    /// [`on_checkpoint_restore`](Self::on_checkpoint_restore) is called.
+    fn on_state_rollback(
+        &self,
+        ctx: &RequestContext,
+        _params: StateRollbackParams,
+    ) -> impl Future<Output = HandlerResult<StateRollbackResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// Put the feature set back into the state saved in `checkpoint`, which
+    /// the server's checkpoint store found for a `state/rollback`. The host
+    /// is told the rollback succeeded once this returns `Ok`.
+    fn on_checkpoint_restore(
+        &self,
+        ctx: &RequestContext,
+        _checkpoint: StoredCheckpoint,
+    ) -> impl Future<Output = HandlerResult<()>> + Send {
+        not_found(ctx)
+    }
+
+    /// `state/checkpoints/list`, unless the server has a
+    /// [checkpoint store](McplServerBuilder::checkpoints) to answer it.
+    fn on_state_checkpoints_list(
+        &self,
+        ctx: &RequestContext,
+        _params: StateCheckpointsListParams,
+    ) -> impl Future<Output = HandlerResult<StateCheckpointsListResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// `state/checkpoint/create`, unless the server has a
+    /// [checkpoint store](McplServerBuilder::checkpoints); then
+    /// [`on_checkpoint_capture`](Self::on_checkpoint_capture) is called.
+    fn on_state_checkpoint_create(
+        &self,
+        ctx: &RequestContext,
+        _params: StateCheckpointCreateParams,
+    ) -> impl Future<Output = HandlerResult<StateCheckpointCreateResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// The current state of `feature_set`, for the server's checkpoint store
+    /// to save on `state/checkpoint/create`.
+    fn on_checkpoint_capture(
+        &self,
+        ctx: &RequestContext,
+        _feature_set: &str,
+    ) -> impl Future<Output = HandlerResult<serde_json::Value>> + Send {
+        not_found(ctx)
+    }
+
+    fn on_context_before_inference(
+        &self,
+        ctx: &RequestContext,
+        _params: ContextBeforeInferenceParams,
+    ) -> impl Future<Output = HandlerResult<ContextBeforeInferenceResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// `context/afterInference` sent as a request (blocking hook).
+    fn on_context_after_inference(
+        &self,
+        ctx: &RequestContext,
+        _params: ContextAfterInferenceParams,
+    ) -> impl Future<Output = HandlerResult<ContextAfterInferenceResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// The host gave up on an inference. Any `context/beforeInference`
+    /// still running for it has already seen its request
+    /// [cancelled](RequestContext::is_cancelled).
+    fn on_context_cancel(&self, _params: ContextCancelParams) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// `context/afterInference` sent as a notification (non-blocking hook).
+    fn on_context_after_inference_notification(
+        &self,
+        _params: ContextAfterInferenceParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn on_inference_chunk(&self, _params: InferenceChunkParams) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Defaults to the session's channel registry.
+    fn on_channels_list(
+        &self,
+        ctx: &RequestContext,
+    ) -> impl Future<Output = HandlerResult<ChannelsListResult>> + Send {
+        let result = channels_list(ctx);
+        async move { result }
+    }
+
+    fn on_channels_open(
+        &self,
+        ctx: &RequestContext,
+        _params: ChannelsOpenParams,
+    ) -> impl Future<Output = HandlerResult<ChannelsOpenResult>> + Send {
+        not_found(ctx)
+    }
+
+    fn on_channels_close(
+        &self,
+        ctx: &RequestContext,
+        _params: ChannelsCloseParams,
+    ) -> impl Future<Output = HandlerResult<ChannelsCloseResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// `channels/publish` sent as a request.
+    fn on_channels_publish(
+        &self,
+        ctx: &RequestContext,
+        _params: ChannelsPublishParams,
+    ) -> impl Future<Output = HandlerResult<ChannelsPublishResult>> + Send {
+        not_found(ctx)
+    }
+
+    /// `channels/publish` sent as a notification.
+    fn on_channels_publish_notification(
+        &self,
+        _params: ChannelsPublishParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn on_channels_outgoing_chunk(
+        &self,
+        _params: ChannelsOutgoingChunkParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn on_channels_outgoing_complete(
+        &self,
+        _params: ChannelsOutgoingCompleteParams,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Any request without a dedicated method above.
+    fn on_request(
+        &self,
+        ctx: &RequestContext,
+    ) -> impl Future<Output = HandlerResult<serde_json::Value>> + Send {
+        not_found(ctx)
+    }
+
+    /// Any notification without a dedicated method above.
+    fn on_notification(
+        &self,
+        _notification: JsonRpcNotification,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Pause after a failed `accept` before trying again.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Why a served connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The host closed the connection.
+    PeerClosed,
+    /// [`McplServer::shutdown`] or [`McplSession::shutdown`] closed it.
+    Shutdown,
+    /// A transport error ended it.
+    Error(String),
+}
+
+/// Runs the serve loop for an [`McplServerHandler`] over a connection.
+///
+/// ```ignore
+/// let server = McplServer::builder()
+///     .handler(MyHandler)
+///     .server_info(ImplementationInfo { name: "game".into(), version: "0.1.0".into() })
+///     .capabilities(McplCapabilities::new("0.4"))
+///     .build();
+/// server.serve(connection).await?;
+/// ```
+pub struct McplServer<H> {
+    inner: Arc<ServerInner<H>>,
+}
+
+struct ServerInner<H> {
+    handler: Arc<H>,
+    server_info: ImplementationInfo,
+    capabilities: McplCapabilities,
+    checkpoints: Option<Arc<dyn CheckpointStore>>,
+    correlator: Option<InferenceCorrelator>,
+    shutdown: CancellationToken,
+    /// Bounds concurrent connections from the accept loops.
+    connection_slots: Option<Arc<Semaphore>>,
+    /// Currently declared feature sets; `None` if the server declares none,
+    /// in which case requests are not checked against them.
+    feature_sets: Mutex<Option<Vec<FeatureSetDeclaration>>>,
+    /// Sessions of the connections being served, by serve call.
+    sessions: Mutex<HashMap<u64, McplSession>>,
+    next_session: AtomicU64,
+    draining: AtomicBool,
+    /// Requests being handled across all connections.
+    in_flight: AtomicUsize,
+    /// Notified when the last in-flight request finishes or a serve call
+    /// ends.
+    idle: Notify,
+}
+
+/// Dispatch for one served connection, or for the tower service when
+/// `session` is `None`.
+struct ServerConnection<H> {
+    inner: Arc<ServerInner<H>>,
+    session: Option<McplSession>,
+    /// Cancellation of the `context/beforeInference` requests in flight, by
+    /// inference id and request id.
+    preparing: Mutex<HashMap<String, HashMap<JsonRpcId, CancellationToken>>>,
+}
+
+impl<H> Clone for McplServer<H> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// Builder for [`McplServer`]; `build` is available once a handler is set.
+pub struct McplServerBuilder<H> {
+    handler: H,
+    server_info: ImplementationInfo,
+    capabilities: McplCapabilities,
+    max_connections: Option<usize>,
+    checkpoints: Option<Arc<dyn CheckpointStore>>,
+    correlator: Option<InferenceCorrelator>,
+}
+
+impl McplServer<()> {
+    pub fn builder() -> McplServerBuilder<()> {
+        McplServerBuilder {
+            handler: (),
+            server_info: ImplementationInfo {
+                name: env!("CARGO_PKG_NAME").into(),
+                version: env!("CARGO_PKG_VERSION").into(),
+            },
+            capabilities: McplCapabilities::default(),
+            max_connections: None,
+            checkpoints: None,
+            correlator: None,
+        }
+    }
+}
+
+impl<H> McplServerBuilder<H> {
+    pub fn handler<T: McplServerHandler>(self, handler: T) -> McplServerBuilder<T> {
+        McplServerBuilder {
+            handler,
+            server_info: self.server_info,
+            capabilities: self.capabilities,
+            max_connections: self.max_connections,
+            checkpoints: self.checkpoints,
+            correlator: self.correlator,
+        }
+    }
+
+    /// Name and version reported as `serverInfo`.
+    pub fn server_info(mut self, info: ImplementationInfo) -> Self {
+        self.server_info = info;
+        self
+    }
+
+    /// MCPL capabilities advertised under `experimental.mcpl`.
+    pub fn capabilities(mut self, capabilities: McplCapabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Hosts served at once by [`McplServer::serve_tcp`] and
+    /// [`McplServer::serve_unix`]; further hosts wait to be accepted.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Answer `state/rollback` from `store`: the checkpoint is looked up
+    /// there (unknown ones are refused with `ERR_CHECKPOINT_NOT_FOUND`) and
+    /// handed to [`McplServerHandler::on_checkpoint_restore`]. The
+    /// `state/checkpoints/list` and `state/checkpoint/create` requests are
+    /// answered from it too, the latter saving the state
+    /// [`McplServerHandler::on_checkpoint_capture`] returns. The `rollback`
+    /// capability is advertised.
+    pub fn checkpoints(mut self, store: impl CheckpointStore + 'static) -> Self {
+        self.checkpoints = Some(Arc::new(store));
+        self
+    }
+
+    /// Report each `context/afterInference` and `channels/outgoing/complete`
+    /// to `correlator` before the handler sees it, settling the push events
+    /// linked to the inference.
+    pub fn correlator(mut self, correlator: InferenceCorrelator) -> Self {
+        self.correlator = Some(correlator);
+        self
+    }
+}
+
+impl<H: McplServerHandler> McplServerBuilder<H> {
+    /// [`build`](Self::build), first [validating](FeatureSetDeclaration::validate_all)
+    /// the declared feature sets so mistakes surface at startup rather than
+    /// in hosts.
+    pub fn try_build(self) -> Result<McplServer<H>, ValidationError> {
+        if let Some(feature_sets) = &self.capabilities.feature_sets {
+            FeatureSetDeclaration::validate_all(feature_sets)?;
+        }
+        Ok(self.build())
+    }
+
+    pub fn build(mut self) -> McplServer<H> {
+        if self.checkpoints.is_some() {
+            self.capabilities.rollback = Some(true);
+        }
+        McplServer {
+            inner: Arc::new(ServerInner {
+                handler: Arc::new(self.handler),
+                server_info: self.server_info,
+                feature_sets: Mutex::new(self.capabilities.feature_sets.clone()),
+                capabilities: self.capabilities,
+                checkpoints: self.checkpoints,
+                correlator: self.correlator,
+                shutdown: CancellationToken::new(),
+                connection_slots: self
+                    .max_connections
+                    .map(|max| Arc::new(Semaphore::new(max))),
+                sessions: Mutex::new(HashMap::new()),
+                next_session: AtomicU64::new(0),
+                draining: AtomicBool::new(false),
+                in_flight: AtomicUsize::new(0),
+                idle: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl<H: McplServerHandler> McplServer<H> {
+    pub fn handler(&self) -> &Arc<H> {
+        &self.inner.handler
+    }
+
+    /// The correlator given to [`McplServerBuilder::correlator`].
+    pub fn correlator(&self) -> Option<&InferenceCorrelator> {
+        self.inner.correlator.as_ref()
+    }
+
+    /// The store given to [`McplServerBuilder::checkpoints`].
+    pub fn checkpoints(&self) -> Option<&Arc<dyn CheckpointStore>> {
+        self.inner.checkpoints.as_ref()
+    }
+
+    /// Serve requests from `conn` until the host disconnects or
+    /// [`shutdown`](Self::shutdown) is called.
+    ///
+    /// Requests are handled concurrently, each on its own task. Malformed
+    /// messages are logged and skipped; only transport errors end the loop
+    /// early. The handler's lifecycle hooks run around the loop.
+    pub async fn serve(&self, mut conn: McplConnection) -> Result<(), ConnectionError> {
+        let handler = &self.inner.handler;
+        let session = conn.session();
+        let key = self.inner.next_session.fetch_add(1, Ordering::Relaxed);
+        self.inner.sessions().insert(key, session.clone());
+        handler.on_connected(&session).await;
+        let dispatch = Arc::new(ServerConnection {
+            inner: Arc::clone(&self.inner),
+            session: Some(session.clone()),
+            preparing: Mutex::default(),
+        });
+        let result = serve_until(&mut conn, dispatch, &self.inner.shutdown).await;
+        let reason = match &result {
+            Ok(()) if self.inner.shutdown.is_cancelled() || session.is_shut_down() => {
+                handler.on_shutdown(&session).await;
+                if let Err(e) = conn.close().await {
+                    tracing::warn!("Failed to close connection on shutdown: {}", e);
+                }
+                DisconnectReason::Shutdown
+            }
+            Ok(()) => DisconnectReason::PeerClosed,
+            Err(e) => DisconnectReason::Error(e.to_string()),
+        };
+        self.inner.sessions().remove(&key);
+        self.inner.idle.notify_waiters();
+        handler.on_disconnect(&session, reason).await;
+        result
+    }
+
+    /// Accept hosts from `listener` and serve each on its own task until
+    /// [`shutdown`](Self::shutdown) is called, then wait for the connections
+    /// to finish.
+    pub async fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        self.accept_loop(|| async {
+            let (stream, peer) = listener.accept().await?;
+            tracing::debug!("Accepted host from {}", peer);
+            Ok(McplConnection::from_tcp(stream))
+        })
+        .await
+    }
+
+    /// [`serve_tcp`](Self::serve_tcp) over a Unix domain socket.
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        self.accept_loop(|| async {
+            let (stream, _) = listener.accept().await?;
+            let (read_half, write_half) = stream.into_split();
+            Ok(McplConnection::from_parts(
+                Box::new(read_half),
+                Box::new(write_half),
+            ))
+        })
+        .await
+    }
+
+    async fn accept_loop<F, Fut>(&self, mut accept: F) -> io::Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<McplConnection>>,
+    {
+        let shutdown = &self.inner.shutdown;
+        let mut connections = JoinSet::new();
+        loop {
+            // Wait for a free slot before accepting, so excess hosts queue in
+            // the listener's backlog
+            let permit = match &self.inner.connection_slots {
+                Some(slots) => tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    permit = Arc::clone(slots).acquire_owned() => {
+                        Some(permit.expect("connection semaphore is never closed"))
+                    }
+                },
+                None => None,
+            };
+            let conn = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                conn = accept() => conn,
+            };
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    // Usually out of file descriptors; back off instead of spinning
+                    tracing::warn!("Failed to accept host: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let server = self.clone();
+            connections.spawn(async move {
+                let _permit = permit;
+                if let Err(e) = server.serve(conn).await {
+                    tracing::warn!("Connection ended with error: {}", e);
+                }
+            });
+            // Reap finished connections so the set does not grow unbounded
+            while connections.try_join_next().is_some() {}
+        }
+        while connections.join_next().await.is_some() {}
+        Ok(())
+    }
+
+    /// Stop every [`serve`](Self::serve) call of this server (and its
+    /// clones): each runs `on_shutdown`, closes its connection, and returns.
+    /// Connections served afterwards close immediately.
+    pub fn shutdown(&self) {
+        self.inner.shutdown.cancel();
+    }
+
+    /// Shut down gracefully: refuse new requests with `ERR_BUSY` (`ping`
+    /// is still answered), wait for the ones in flight to be answered and for
+    /// output already sent (e.g. channel messages) to be written, send each
+    /// host `notifications/shutdown`, then [`shutdown`](Self::shutdown) and
+    /// wait for every connection to run `on_shutdown` and close. Hosts can
+    /// retry refused requests elsewhere.
+    pub async fn drain(&self) {
+        self.inner.draining.store(true, Ordering::SeqCst);
+        self.inner
+            .wait_idle(|inner| inner.in_flight.load(Ordering::SeqCst) == 0)
+            .await;
+        let sessions: Vec<McplSession> = self.inner.sessions().values().cloned().collect();
+        let notice = ShutdownParams {
+            reason: Some("draining".into()),
+        };
+        for session in &sessions {
+            let conn = session.connection();
+            let sent = async {
+                conn.flush().await?;
+                conn.send_notification_typed(method::NOTIFICATIONS_SHUTDOWN, &notice)
+                    .await
+            }
+            .await;
+            if let Err(e) = sent {
+                tracing::warn!("Failed to notify host of shutdown: {}", e);
+            }
+        }
+        self.shutdown();
+        self.inner.wait_idle(|inner| inner.sessions().is_empty()).await;
+    }
+
+    /// Whether [`drain`](Self::drain) has been called.
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    /// Feature sets currently declared to hosts.
+    pub fn feature_sets(&self) -> Vec<FeatureSetDeclaration> {
+        self.inner.declared().clone().unwrap_or_default()
+    }
+
+    /// Declare a feature set at runtime, replacing any declaration with the
+    /// same name. Hosts that connect later see it in `initialize`; connected
+    /// hosts are sent `featureSets/changed`.
+    pub async fn add_feature_set(&self, feature_set: FeatureSetDeclaration) {
+        {
+            let mut declared = self.inner.declared();
+            let declared = declared.get_or_insert_with(Vec::new);
+            declared.retain(|d| d.name != feature_set.name);
+            declared.push(feature_set.clone());
+        }
+        self.announce(FeatureSetsChangedParams {
+            added: Some([(feature_set.name.clone(), feature_set)].into()),
+            removed: None,
+        })
+        .await;
+    }
+
+    /// Withdraw a feature set at runtime: requests naming it are refused
+    /// with `ERR_UNKNOWN_FEATURE_SET`, and connected hosts are sent
+    /// `featureSets/changed`. Returns whether it was declared.
+    pub async fn remove_feature_set(&self, name: &str) -> bool {
+        let removed = {
+            let mut declared = self.inner.declared();
+            let Some(declared) = declared.as_mut() else {
+                return false;
+            };
+            let before = declared.len();
+            declared.retain(|d| d.name != name);
+            declared.len() != before
+        };
+        if removed {
+            self.announce(FeatureSetsChangedParams {
+                added: None,
+                removed: Some(vec![name.to_string()]),
+            })
+            .await;
+        }
+        removed
+    }
+
+    /// Replace every declared feature set, e.g. after reloading them.
+    /// Connected hosts are sent one `featureSets/changed` with only what
+    /// differs, or nothing if the declarations are the same.
+    pub async fn set_feature_sets(&self, feature_sets: Vec<FeatureSetDeclaration>) {
+        let change = {
+            let mut declared = self.inner.declared();
+            let old = FeatureSetRegistry::from_declarations(declared.clone().unwrap_or_default());
+            let new = FeatureSetRegistry::from_declarations(feature_sets.clone());
+            *declared = Some(feature_sets);
+            FeatureSetRegistry::diff(&old, &new)
+        };
+        if change.added.is_some() || change.removed.is_some() {
+            self.announce(change).await;
+        }
+    }
+
+    /// Send `featureSets/changed` to every host past `initialize`.
+    async fn announce(&self, change: FeatureSetsChangedParams) {
+        let sessions: Vec<McplSession> = self.inner.sessions().values().cloned().collect();
+        for session in sessions.iter().filter(|s| s.negotiated().is_some()) {
+            let sent = session
+                .connection()
+                .send_notification_typed(method::FEATURE_SETS_CHANGED, &change)
+                .await;
+            if let Err(e) = sent {
+                tracing::warn!("Failed to announce feature set change: {}", e);
+            }
+        }
+    }
+
+    /// Request dispatch as a `tower::Service`, for wrapping in middleware.
+    /// Notifications are not part of the service.
+    pub fn service(&self) -> McplService {
+        McplService::new(Arc::new(ServerConnection {
+            inner: Arc::clone(&self.inner),
+            session: None,
+            preparing: Mutex::default(),
+        }))
+    }
+}
+
+impl<H> ServerInner<H> {
+    async fn wait_idle(&self, done: impl Fn(&Self) -> bool) {
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if done(self) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn declared(&self) -> std::sync::MutexGuard<'_, Option<Vec<FeatureSetDeclaration>>> {
+        self.feature_sets.lock().unwrap()
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<u64, McplSession>> {
+        self.sessions.lock().unwrap()
+    }
+
+    /// Refuse requests naming a feature set the server does not declare.
+    fn check_feature_set(&self, ctx: &RequestContext) -> HandlerResult<()> {
+        let named = named_feature_set(ctx);
+        let declared = self.declared();
+        let (Some(name), Some(declared)) = (named, declared.as_ref()) else {
+            return Ok(());
+        };
+        if declared.iter().any(|d| d.name == name) {
+            Ok(())
+        } else {
+            Err(JsonRpcError::new(
+                ERR_UNKNOWN_FEATURE_SET,
+                format!("Unknown feature set: {}", name),
+            ))
+        }
+    }
+
+    /// Whether the feature set a request names can be rolled back: it
+    /// declares `rollback`, or the server declares no feature sets.
+    fn declares_rollback(&self, ctx: &RequestContext) -> bool {
+        let named = named_feature_set(ctx);
+        let declared = self.declared();
+        let (Some(name), Some(declared)) = (named, declared.as_ref()) else {
+            return true;
+        };
+        declared
+            .iter()
+            .find(|d| d.name == name)
+            .is_none_or(|d| d.rollback)
+    }
+
+    /// Settle the push events linked to an inference that has finished.
+    fn correlate(&self, outcome: impl FnOnce() -> InferenceOutcome) {
+        if let Some(correlator) = &self.correlator {
+            correlator.complete(outcome());
+        }
+    }
+
+    fn initialize_result(
+        &self,
+        protocol_version: String,
+        version: Option<ProtocolVersion>,
+    ) -> McplInitializeResult {
+        McplInitializeResult {
+            protocol_version,
+            capabilities: InitializeCapabilities::with_mcpl(accepted_capabilities(
+                &McplCapabilities {
+                    feature_sets: self.declared().clone(),
+                    ..self.capabilities.clone()
+                },
+                version,
+            )),
+            server_info: self.server_info.clone(),
+        }
+    }
+}
+
+/// The `featureSet` a request's params name, if any.
+fn named_feature_set(ctx: &RequestContext) -> Option<String> {
+    ctx.request().param("featureSet")
+}
+
+/// Answer `state/rollback` by restoring a checkpoint from `store`.
+async fn restore<H: McplServerHandler>(
+    handler: &H,
+    store: &dyn CheckpointStore,
+    ctx: &RequestContext,
+) -> HandlerResult<StateRollbackResult> {
+    let params: StateRollbackParams = params(ctx)?;
+    let stored = store
+        .get(&params.checkpoint)?
+        .filter(|stored| stored.checkpoint.feature_set == params.feature_set)
+        .ok_or_else(|| HandlerError::checkpoint_not_found(&params.checkpoint))?;
+    handler.on_checkpoint_restore(ctx, stored).await?;
+    Ok(StateRollbackResult {
+        checkpoint: params.checkpoint,
+        success: true,
+        reason: None,
+    })
+}
+
+/// Answer `state/checkpoint/create` by saving the handler's current state
+/// of the feature set in `store`.
+async fn capture<H: McplServerHandler>(
+    handler: &H,
+    store: &dyn CheckpointStore,
+    ctx: &RequestContext,
+) -> HandlerResult<StateCheckpointCreateResult> {
+    let params: StateCheckpointCreateParams = params(ctx)?;
+    let state = handler.on_checkpoint_capture(ctx, &params.feature_set).await?;
+    let mut stored = StoredCheckpoint::new(params.feature_set, state);
+    stored.checkpoint.parent = params.parent;
+    stored.checkpoint.label = params.label;
+    stored.checkpoint.metadata = params.metadata;
+    let checkpoint = stored.checkpoint.clone();
+    store.create(stored)?;
+    Ok(StateCheckpointCreateResult { checkpoint })
+}
+
+/// Counts a request as in flight until dropped.
+struct InFlight<'a, H>(&'a ServerInner<H>);
+
+impl<'a, H> InFlight<'a, H> {
+    fn enter(inner: &'a ServerInner<H>) -> Self {
+        inner.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(inner)
+    }
+}
+
+impl<H> Drop for InFlight<'_, H> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Registers a `context/beforeInference` request under its inference id
+/// until dropped, so `context/cancel` can cancel it.
+struct Preparing<'a> {
+    preparing: &'a Mutex<HashMap<String, HashMap<JsonRpcId, CancellationToken>>>,
+    inference_id: String,
+    request_id: JsonRpcId,
+}
+
+impl<'a> Preparing<'a> {
+    fn enter(
+        preparing: &'a Mutex<HashMap<String, HashMap<JsonRpcId, CancellationToken>>>,
+        inference_id: &str,
+        ctx: &RequestContext,
+    ) -> Self {
+        preparing
+            .lock()
+            .unwrap()
+            .entry(inference_id.to_string())
+            .or_default()
+            .insert(ctx.id().clone(), ctx.cancellation_token().clone());
+        Self {
+            preparing,
+            inference_id: inference_id.to_string(),
+            request_id: ctx.id().clone(),
+        }
+    }
+}
+
+impl Drop for Preparing<'_> {
+    fn drop(&mut self) {
+        let mut preparing = self.preparing.lock().unwrap();
+        if let Some(requests) = preparing.get_mut(&self.inference_id) {
+            requests.remove(&self.request_id);
+            if requests.is_empty() {
+                preparing.remove(&self.inference_id);
+            }
+        }
+    }
+}
+
+impl<H: McplServerHandler> Dispatch for ServerConnection<H> {
+    async fn handle_request(&self, ctx: RequestContext) -> HandlerResult<serde_json::Value> {
+        let ctx = &ctx;
+        let inner = &*self.inner;
+        let handler = &*inner.handler;
+        // Counted before checking for a drain, so drain cannot miss it
+        let _in_flight = InFlight::enter(inner);
+        if inner.draining.load(Ordering::SeqCst) && ctx.method() != method::PING {
+            return Err(JsonRpcError::draining());
+        }
+        inner.check_feature_set(ctx)?;
+        match ctx.method() {
+            method::INITIALIZE => {
+                let params: McplInitializeParams = params(ctx)?;
+                let version = accept_version(&inner.capabilities, params.capabilities.mcpl())
+                    .map_err(|mismatch| version_mismatch_error(&mismatch))?;
+                // Echo the host's MCP version; only MCPL's is negotiated
+                let init = inner.initialize_result(params.protocol_version.clone(), version);
+                encode(handler.on_initialize(ctx, params, init).await)
+            }
+            method::PING => Ok(serde_json::json!({})),
+            method::FEATURE_SETS_LIST => encode(handler.on_feature_sets_list(ctx).await),
+            method::STATE_ROLLBACK if !inner.declares_rollback(ctx) => {
+                let params: StateRollbackParams = params(ctx)?;
+                Err(StateError::RollbackUnsupported(params.feature_set).into())
+            }
+            method::STATE_ROLLBACK => match &inner.checkpoints {
+                Some(store) => encode(restore(handler, store.as_ref(), ctx).await),
+                None => encode(handler.on_state_rollback(ctx, params(ctx)?).await),
+            },
+            method::STATE_CHECKPOINTS_LIST => match &inner.checkpoints {
+                Some(store) => {
+                    let params: StateCheckpointsListParams = params(ctx)?;
+                    let feature_sets = match &params.feature_set {
+                        Some(name) => vec![name.clone()],
+                        None => inner
+                            .declared()
+                            .iter()
+                            .flatten()
+                            .map(|declaration| declaration.name.clone())
+                            .collect(),
+                    };
+                    encode(list_page(store.as_ref(), &feature_sets, &params))
+                }
+                None => encode(handler.on_state_checkpoints_list(ctx, params(ctx)?).await),
+            },
+            method::STATE_CHECKPOINT_CREATE => match &inner.checkpoints {
+                Some(store) => encode(capture(handler, store.as_ref(), ctx).await),
+                None => encode(handler.on_state_checkpoint_create(ctx, params(ctx)?).await),
+            },
+            method::CONTEXT_BEFORE_INFERENCE => {
+                let params: ContextBeforeInferenceParams = params(ctx)?;
+                let _preparing = Preparing::enter(&self.preparing, &params.inference_id, ctx);
+                encode(handler.on_context_before_inference(ctx, params).await)
+            }
+            method::CONTEXT_AFTER_INFERENCE => {
+                let params: ContextAfterInferenceParams = params(ctx)?;
+                inner.correlate(|| InferenceOutcome::AfterInference(params.clone()));
+                encode(handler.on_context_after_inference(ctx, params).await)
+            }
+            method::CHANNELS_LIST => encode(handler.on_channels_list(ctx).await),
+            method::CHANNELS_OPEN => encode(handler.on_channels_open(ctx, params(ctx)?).await),
+            method::CHANNELS_CLOSE => encode(handler.on_channels_close(ctx, params(ctx)?).await),
+            method::CHANNELS_PUBLISH => {
+                encode(handler.on_channels_publish(ctx, params(ctx)?).await)
+            }
+            _ => handler.on_request(ctx).await,
+        }
+    }
+
+    async fn handle_notification(&self, notification: JsonRpcNotification) {
+        let handler = &*self.inner.handler;
+        match notification.method.as_str() {
+            method::NOTIFICATIONS_INITIALIZED => {
+                if let Some(session) = &self.session {
+                    handler.on_initialized(session).await;
+                }
+            }
+            method::FEATURE_SETS_UPDATE => {
+                if let Some(params) = notification_params(&notification) {
+                    handler.on_feature_sets_update(params).await;
+                }
+            }
+            method::CONTEXT_AFTER_INFERENCE => {
+                if let Some(params) =
+                    notification_params::<ContextAfterInferenceParams>(&notification)
+                {
+                    self.inner
+                        .correlate(|| InferenceOutcome::AfterInference(params.clone()));
+                    handler
+                        .on_context_after_inference_notification(params)
+                        .await;
+                }
+            }
+            method::INFERENCE_CHUNK => {
+                if let Some(params) = notification_params(&notification) {
+                    handler.on_inference_chunk(params).await;
+                }
+            }
+            method::CONTEXT_CANCEL => {
+                if let Some(params) = notification_params::<ContextCancelParams>(&notification) {
+                    let preparing = self.preparing.lock().unwrap().remove(&params.inference_id);
+                    for token in preparing.into_iter().flat_map(HashMap::into_values) {
+                        token.cancel();
+                    }
+                    handler.on_context_cancel(params).await;
+                }
+            }
+            method::CHANNELS_PUBLISH => {
+                if let Some(params) = notification_params(&notification) {
+                    handler.on_channels_publish_notification(params).await;
+                }
+            }
+            method::CHANNELS_OUTGOING_CHUNK => {
+                if let Some(params) = notification_params(&notification) {
+                    handler.on_channels_outgoing_chunk(params).await;
+                }
+            }
+            method::CHANNELS_OUTGOING_COMPLETE => {
+                if let Some(params) =
+                    notification_params::<ChannelsOutgoingCompleteParams>(&notification)
+                {
+                    self.inner
+                        .correlate(|| InferenceOutcome::ChannelOutput(params.clone()));
+                    handler.on_channels_outgoing_complete(params).await;
+                }
+            }
+            _ => handler.on_notification(notification).await,
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tower_service::Service;
+
+use crate::connection::{ConnectionError, IncomingMessage, McplConnection};
+use crate::handler::{handle_guarded, BoxFuture, Dispatch, HandlerResult};
+use crate::request::RequestContext;
+use crate::types::*;
+
+type DispatchFn =
+    Arc<dyn Fn(RequestContext) -> BoxFuture<HandlerResult<serde_json::Value>> + Send + Sync>;
+
+/// Request dispatch of a [`Router`](crate::Router) or
+/// [`McplServer`](crate::McplServer) as a `tower::Service`.
+///
+/// Handler errors become error responses, so the service itself never
+/// fails. Handlers see a context that is never cancelled and has no
+/// deadline; use tower middleware for timeouts instead.
+///
+/// ```ignore
+/// let service = ServiceBuilder::new()
+///     .timeout(Duration::from_secs(5))
+///     .concurrency_limit(16)
+///     .service(router.into_service());
+/// serve_service(connection, service).await?;
+/// ```
+#[derive(Clone)]
+pub struct McplService {
+    dispatch: DispatchFn,
+}
+
+impl McplService {
+    pub(crate) fn new<D: Dispatch>(dispatch: Arc<D>) -> Self {
+        Self {
+            dispatch: Arc::new(move |ctx| {
+                let dispatch = Arc::clone(&dispatch);
+                Box::pin(async move { handle_guarded(&*dispatch, ctx).await })
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for McplService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("McplService").finish_non_exhaustive()
+    }
+}
+
+impl Service<JsonRpcRequest> for McplService {
+    type Response = JsonRpcResponse;
+    type Error = Infallible;
+    type Future = BoxFuture<Result<JsonRpcResponse, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: JsonRpcRequest) -> Self::Future {
+        let id = request.id.clone();
+        let handled = (self.dispatch)(RequestContext::detached(request));
+        Box::pin(async move {
+            Ok(match handled.await {
+                Ok(result) => JsonRpcResponse::success(id, result),
+                Err(error) => JsonRpcResponse::error(id, error),
+            })
+        })
+    }
+}
+
+/// Serve the requests on `conn` with a tower service until the peer
+/// disconnects.
+///
+/// The loop waits for the service to be ready before reading each request,
+/// so backpressure from middleware such as concurrency limits reaches the
+/// peer. Service errors (e.g. a timeout) are answered with `-32603 Internal
+/// error`. Notifications bypass the service and are ignored.
+pub async fn serve_service<S>(
+    mut conn: McplConnection,
+    mut service: S,
+) -> Result<(), ConnectionError>
+where
+    S: Service<JsonRpcRequest, Response = JsonRpcResponse>,
+    S::Error: fmt::Display,
+    S::Future: Send + 'static,
+{
+    loop {
+        if let Err(e) = poll_fn(|cx| service.poll_ready(cx)).await {
+            tracing::warn!("Service failed; no longer serving: {}", e);
+            return Ok(());
+        }
+        match conn.next_message().await {
+            Ok(IncomingMessage::Request(request)) => {
+                let (request, responder) = conn.request_context(request).into_parts();
+                let response = service.call(request);
+                tokio::spawn(async move {
+                    let result = match response.await {
+                        Ok(JsonRpcResponse {
+                            error: Some(error), ..
+                        }) => Err(error),
+                        Ok(response) => response.result_as().map_err(JsonRpcError::internal),
+                        Err(e) => Err(JsonRpcError::internal(e)),
+                    };
+                    if let Err(e) = responder.respond(result).await {
+                        tracing::warn!("Failed to send response: {}", e);
+                    }
+                });
+            }
+            Ok(IncomingMessage::Notification(notification)) => {
+                tracing::debug!("Ignoring notification {}", notification.method);
+            }
+            Err(ConnectionError::Closed) => return Ok(()),
+            Err(err @ ConnectionError::Io(_)) => return Err(err),
+            Err(err) => tracing::warn!("Skipping bad message: {}", err),
+        }
+    }
+}
@@ -0,0 +1,725 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+
+use crate::capabilities::*;
+use crate::connection::{
+    CancellationToken, ConnectionError, ConnectionHandle, IncomingMessage, McplConnection,
+};
+use crate::host::DEFAULT_PROTOCOL_VERSION;
+use crate::methods::*;
+use crate::types::*;
+use crate::usage::{SessionUsage, UsageTotals};
+use crate::version::{negotiate_version, ProtocolVersion, VersionMismatch};
+
+/// What both sides declared during the `initialize` handshake, as seen from
+/// one of them.
+#[derive(Debug, Clone)]
+pub struct NegotiatedSession {
+    /// MCP protocol version the server answered with.
+    pub protocol_version: String,
+    /// Negotiated MCPL version; `None` if the peer does not speak MCPL.
+    pub version: Option<ProtocolVersion>,
+    pub local_info: ImplementationInfo,
+    pub local_capabilities: McplCapabilities,
+    pub peer_info: ImplementationInfo,
+    /// `None` if the peer does not speak MCPL.
+    pub peer_capabilities: Option<McplCapabilities>,
+}
+
+impl NegotiatedSession {
+    /// Whether both sides advertised the capability `method` needs.
+    pub fn supports_method(&self, method: &str) -> bool {
+        self.version.is_some_and(|v| v.supports_method(method))
+            && self.local_capabilities.supports_method(method)
+            && self
+                .peer_capabilities
+                .as_ref()
+                .is_some_and(|caps| caps.supports_method(method))
+    }
+}
+
+/// How much one feature set has been used on a session, as counted by
+/// [`McplSession::feature_set_metrics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureSetMetrics {
+    /// Requests naming the feature set that this side answered.
+    pub requests_handled: u64,
+    /// `push/event`s for the feature set the host accepted.
+    pub push_events_accepted: u64,
+    /// `push/event`s for the feature set the host declined or failed.
+    pub push_events_rejected: u64,
+    /// Successful `state/rollback`s of the feature set.
+    pub rollbacks: u64,
+    /// Usage of answered `inference/request`s naming the feature set.
+    pub inference: UsageTotals,
+}
+
+/// Negotiated state of one connection, shared by everything that holds it.
+///
+/// The session follows the traffic on its connection in both directions:
+///
+/// - the `initialize` exchange sets [`negotiated`](Self::negotiated);
+/// - `featureSets/update` enables and disables feature sets (none are
+///   enabled before the first update);
+/// - `channels/register`, `channels/changed`, and successful
+///   `channels/open` / `channels/close` maintain the channel registry;
+/// - approved `scope/elevate` requests grant their scope label;
+/// - answered requests naming a feature set update its
+///   [metrics](Self::feature_set_metrics);
+/// - answered `inference/request`s and `context/afterInference` add to the
+///   [usage](Self::usage) totals.
+///
+/// Handlers reach it through [`RequestContext::session`](crate::RequestContext::session).
+#[derive(Clone)]
+pub struct McplSession {
+    conn: ConnectionHandle,
+}
+
+impl McplSession {
+    pub(crate) fn new(conn: ConnectionHandle) -> Self {
+        Self { conn }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, SessionData> {
+        self.conn.session_state().data.lock().unwrap()
+    }
+
+    /// Handle for sending requests and notifications to the peer.
+    pub fn connection(&self) -> &ConnectionHandle {
+        &self.conn
+    }
+
+    /// The handshake outcome, once `initialize` has been answered.
+    pub fn negotiated(&self) -> Option<NegotiatedSession> {
+        self.state().negotiated.clone()
+    }
+
+    /// Whether `method` may be used, per [`NegotiatedSession::supports_method`].
+    /// `false` before the handshake completes.
+    pub fn supports_method(&self, method: &str) -> bool {
+        self.state()
+            .negotiated
+            .as_ref()
+            .is_some_and(|n| n.supports_method(method))
+    }
+
+    pub fn is_feature_set_enabled(&self, name: &str) -> bool {
+        self.state().feature_sets.contains(name)
+    }
+
+    pub fn enabled_feature_sets(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.state().feature_sets.iter().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn channel(&self, id: &str) -> Option<ChannelDescriptor> {
+        self.state().channels.get(id).cloned()
+    }
+
+    /// Registered channels, ordered by id.
+    pub fn channels(&self) -> Vec<ChannelDescriptor> {
+        let mut channels: Vec<ChannelDescriptor> =
+            self.state().channels.values().cloned().collect();
+        channels.sort_by(|a, b| a.id.cmp(&b.id));
+        channels
+    }
+
+    pub fn has_scope(&self, feature_set: &str, label: &str) -> bool {
+        self.state()
+            .scopes
+            .get(feature_set)
+            .is_some_and(|labels| labels.contains(label))
+    }
+
+    /// Scope labels granted for `feature_set`, sorted.
+    pub fn granted_scopes(&self, feature_set: &str) -> Vec<String> {
+        let mut labels: Vec<String> = self
+            .state()
+            .scopes
+            .get(feature_set)
+            .map(|labels| labels.iter().cloned().collect())
+            .unwrap_or_default();
+        labels.sort();
+        labels
+    }
+
+    /// Usage of `feature_set` on this session; all zero if it was never
+    /// used.
+    pub fn feature_set_metrics(&self, feature_set: &str) -> FeatureSetMetrics {
+        self.state()
+            .metrics
+            .get(feature_set)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Usage of every feature set used on this session, by name.
+    pub fn metrics(&self) -> BTreeMap<String, FeatureSetMetrics> {
+        self.state()
+            .metrics
+            .iter()
+            .map(|(name, metrics)| (name.clone(), metrics.clone()))
+            .collect()
+    }
+
+    /// Inference usage on this session so far.
+    pub fn usage(&self) -> SessionUsage {
+        self.state().usage
+    }
+
+    /// Inference usage of one conversation; all zero if it had none.
+    pub fn conversation_usage(&self, conversation_id: &str) -> SessionUsage {
+        self.state()
+            .conversations
+            .get(conversation_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Who is on the other end, as established by the transport (e.g. a
+    /// verified client certificate) or an earlier authentication step.
+    pub fn identity(&self) -> Option<String> {
+        self.state().identity.clone()
+    }
+
+    pub fn set_identity(&self, identity: Option<String>) {
+        self.state().identity = identity;
+    }
+
+    /// Model reported by the built-in `model/info` handlers.
+    pub fn model_info(&self) -> Option<ModelInfo> {
+        self.state().model_info.clone()
+    }
+
+    /// Set the model the built-in `model/info` handlers report, e.g. when
+    /// the host switches models.
+    pub fn set_model_info(&self, model: Option<ModelInfo>) {
+        self.state().model_info = model;
+    }
+
+    /// Record a scope granted out of band, e.g. by configuration.
+    pub fn grant_scope(&self, feature_set: impl Into<String>, label: impl Into<String>) {
+        let mut state = self.state();
+        state
+            .scopes
+            .entry(feature_set.into())
+            .or_default()
+            .insert(label.into());
+    }
+
+    pub fn revoke_scope(&self, feature_set: &str, label: &str) {
+        if let Some(labels) = self.state().scopes.get_mut(feature_set) {
+            labels.remove(label);
+        }
+    }
+
+    /// Stop serving this connection. The loop serving it (an
+    /// [`McplServer`](crate::McplServer), [`Router`](crate::Router), or
+    /// [`McplHost`](crate::McplHost)) returns; a server runs its
+    /// `on_shutdown` hook and closes the connection first.
+    pub fn shutdown(&self) {
+        self.conn.session_state().shutdown.cancel();
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.conn.session_state().shutdown.is_cancelled()
+    }
+}
+
+/// Session bookkeeping kept by the connection and updated from its traffic.
+#[derive(Default)]
+pub(crate) struct SessionState {
+    data: Mutex<SessionData>,
+    /// Fired by [`McplSession::shutdown`].
+    pub(crate) shutdown: CancellationToken,
+}
+
+#[derive(Default)]
+struct SessionData {
+    negotiated: Option<NegotiatedSession>,
+    feature_sets: HashSet<String>,
+    channels: HashMap<String, ChannelDescriptor>,
+    scopes: HashMap<String, HashSet<String>>,
+    model_info: Option<ModelInfo>,
+    identity: Option<String>,
+    /// Requests whose answer updates the session, keyed by whether we sent
+    /// them and their id.
+    pending: HashMap<(bool, JsonRpcId), Tracked>,
+    /// Requests naming a feature set, keyed like `pending`, with the
+    /// feature set and method; counted when answered.
+    metered: HashMap<(bool, JsonRpcId), (String, String)>,
+    metrics: HashMap<String, FeatureSetMetrics>,
+    usage: SessionUsage,
+    /// Usage by conversation id.
+    conversations: HashMap<String, SessionUsage>,
+}
+
+enum Tracked {
+    Initialize(McplInitializeParams),
+    /// An `inference/request`, with its conversation.
+    Inference(Option<String>),
+    Elevate(ScopeElevateParams),
+    Open,
+    Close(String),
+}
+
+impl SessionState {
+    /// Update the session from a message sent (`outgoing`) or received.
+    pub(crate) fn observe(&self, msg: &JsonRpcMessage, outgoing: bool) {
+        let mut data = self.data.lock().unwrap();
+        match msg {
+            JsonRpcMessage::Request(req) => {
+                if let Some(feature_set) = req.param::<String>("featureSet") {
+                    data.metered.insert(
+                        (outgoing, req.id.clone()),
+                        (feature_set, req.method.clone()),
+                    );
+                }
+                let tracked = match req.method.as_str() {
+                    method::INITIALIZE => req.params_as().ok().map(Tracked::Initialize),
+                    method::SCOPE_ELEVATE => req.params_as().ok().map(Tracked::Elevate),
+                    method::CHANNELS_OPEN => Some(Tracked::Open),
+                    method::INFERENCE_REQUEST => {
+                        Some(Tracked::Inference(req.param("conversationId")))
+                    }
+                    method::CONTEXT_AFTER_INFERENCE => {
+                        if let Ok(params) = req.params_as() {
+                            data.add_host_usage(params);
+                        }
+                        None
+                    }
+                    method::CHANNELS_CLOSE => req.param("channelId").map(Tracked::Close),
+                    method::CHANNELS_REGISTER => {
+                        if let Some(channels) = req.param::<Vec<ChannelDescriptor>>("channels") {
+                            data.add_channels(channels);
+                        }
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(tracked) = tracked {
+                    data.pending.insert((outgoing, req.id.clone()), tracked);
+                }
+            }
+            JsonRpcMessage::Notification(notif) => match notif.method.as_str() {
+                method::NOTIFICATIONS_CANCELLED => {
+                    // Cancelled requests may never be answered
+                    if let Some(id) = notif.param::<JsonRpcId>("requestId") {
+                        data.untrack(&(outgoing, id));
+                    }
+                }
+                method::FEATURE_SETS_UPDATE => {
+                    if let Ok(params) = notif.params_as::<FeatureSetsUpdateParams>() {
+                        let enabled = params.enabled.into_iter().flatten();
+                        data.feature_sets.extend(enabled);
+                        for name in params.disabled.into_iter().flatten() {
+                            data.feature_sets.remove(&name);
+                        }
+                    }
+                }
+                method::FEATURE_SETS_CHANGED => {
+                    if let Ok(params) = notif.params_as::<FeatureSetsChangedParams>() {
+                        data.change_feature_sets(params, outgoing);
+                    }
+                }
+                method::CONTEXT_AFTER_INFERENCE => {
+                    if let Ok(params) = notif.params_as() {
+                        data.add_host_usage(params);
+                    }
+                }
+                method::CHANNELS_CHANGED => {
+                    if let Ok(params) = notif.params_as::<ChannelsChangedParams>() {
+                        for id in params.removed.into_iter().flatten() {
+                            data.channels.remove(&id);
+                        }
+                        data.add_channels(params.added.into_iter().flatten());
+                        data.add_channels(params.updated.into_iter().flatten());
+                    }
+                }
+                _ => {}
+            },
+            JsonRpcMessage::Response(resp) => {
+                // A response answers a request that travelled the other way
+                if let Some((feature_set, method)) =
+                    data.metered.remove(&(!outgoing, resp.id.clone()))
+                {
+                    data.meter(feature_set, &method, resp, outgoing);
+                }
+                let Some(tracked) = data.pending.remove(&(!outgoing, resp.id.clone())) else {
+                    return;
+                };
+                if resp.error.is_some() || resp.result.is_none() {
+                    return;
+                }
+                match tracked {
+                    Tracked::Initialize(params) => {
+                        if let Ok(result) = resp.result_as() {
+                            data.negotiated =
+                                Some(NegotiatedSession::observed(params, result, !outgoing));
+                        }
+                    }
+                    Tracked::Elevate(params) => {
+                        let approved = resp
+                            .result_as::<ScopeElevateResult>()
+                            .is_ok_and(|r| r.approved);
+                        if approved {
+                            data.scopes
+                                .entry(params.feature_set)
+                                .or_default()
+                                .insert(params.scope.label);
+                        }
+                    }
+                    Tracked::Open => {
+                        if let Ok(result) = resp.result_as::<ChannelsOpenResult>() {
+                            data.add_channels([result.channel]);
+                        }
+                    }
+                    Tracked::Close(id) => {
+                        data.channels.remove(&id);
+                    }
+                    Tracked::Inference(conversation_id) => {
+                        if let Some(usage) = result_member::<InferenceUsage>(resp, "usage") {
+                            data.usage.server_initiated.add(&usage);
+                            if let Some(id) = conversation_id {
+                                let conversation = data.conversations.entry(id).or_default();
+                                conversation.server_initiated.add(&usage);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop tracking a request we sent that will not be answered.
+    pub(crate) fn forget(&self, id: &JsonRpcId) {
+        self.data.lock().unwrap().untrack(&(true, id.clone()));
+    }
+}
+
+impl SessionData {
+    /// Drop a request from `pending` and `metered`.
+    fn untrack(&mut self, key: &(bool, JsonRpcId)) {
+        self.pending.remove(key);
+        self.metered.remove(key);
+    }
+
+    /// Apply the server's `featureSets/changed` to its declared feature sets;
+    /// removed sets are no longer enabled.
+    fn change_feature_sets(&mut self, params: FeatureSetsChangedParams, outgoing: bool) {
+        let removed = params.removed.unwrap_or_default();
+        let added = params.added.unwrap_or_default();
+        for name in &removed {
+            self.feature_sets.remove(name);
+        }
+        let Some(negotiated) = self.negotiated.as_mut() else {
+            return;
+        };
+        // Only servers send featureSets/changed
+        let server = if outgoing {
+            Some(&mut negotiated.local_capabilities)
+        } else {
+            negotiated.peer_capabilities.as_mut()
+        };
+        let Some(server) = server else {
+            return;
+        };
+        let declared = server.feature_sets.get_or_insert_with(Vec::new);
+        declared.retain(|d| !removed.contains(&d.name) && !added.contains_key(&d.name));
+        let mut added: Vec<FeatureSetDeclaration> = added.into_values().collect();
+        added.sort_by(|a, b| a.name.cmp(&b.name));
+        declared.extend(added);
+    }
+
+    /// Count an answered request naming `feature_set`.
+    fn meter(&mut self, feature_set: String, method: &str, resp: &JsonRpcResponse, outgoing: bool) {
+        let answered = resp.error.is_none();
+        let metrics = self.metrics.entry(feature_set).or_default();
+        if outgoing {
+            metrics.requests_handled += 1;
+        }
+        match method {
+            method::PUSH_EVENT => {
+                let accepted = answered
+                    && resp
+                        .result_as::<PushEventResult>()
+                        .is_ok_and(|r| r.accepted);
+                if accepted {
+                    metrics.push_events_accepted += 1;
+                } else {
+                    metrics.push_events_rejected += 1;
+                }
+            }
+            method::INFERENCE_REQUEST => {
+                let usage = result_member::<InferenceUsage>(resp, "usage").filter(|_| answered);
+                if let Some(usage) = usage {
+                    metrics.inference.add(&usage);
+                }
+            }
+            method::STATE_ROLLBACK => {
+                let rolled_back = answered
+                    && resp
+                        .result_as::<StateRollbackResult>()
+                        .is_ok_and(|r| r.success);
+                if rolled_back {
+                    metrics.rollbacks += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Count the host's own inference, reported in `context/afterInference`.
+    fn add_host_usage(&mut self, params: ContextAfterInferenceParams) {
+        self.usage.host_initiated.add(&params.usage);
+        self.conversations
+            .entry(params.conversation_id)
+            .or_default()
+            .host_initiated
+            .add(&params.usage);
+    }
+
+    fn add_channels(&mut self, channels: impl IntoIterator<Item = ChannelDescriptor>) {
+        for channel in channels {
+            self.channels.insert(channel.id.clone(), channel);
+        }
+    }
+}
+
+/// The top-level member `key` of a response's result, parsed into `T`.
+fn result_member<T: DeserializeOwned>(resp: &JsonRpcResponse, key: &str) -> Option<T> {
+    let raw = member(resp.result.as_deref()?, key)?;
+    serde_json::from_str(raw.get()).ok()
+}
+
+impl NegotiatedSession {
+    /// The session as seen by the client (`we_initiated`) or the server of
+    /// an observed handshake.
+    fn observed(
+        params: McplInitializeParams,
+        result: McplInitializeResult,
+        we_initiated: bool,
+    ) -> Self {
+        let client_caps = params.capabilities.mcpl().cloned();
+        let server_caps = result.capabilities.mcpl().cloned();
+        let version = match (&client_caps, &server_caps) {
+            (Some(_), Some(server)) => server.protocol_version().ok(),
+            _ => None,
+        };
+        let (local_info, local_caps, peer_info, peer_caps) = if we_initiated {
+            (
+                params.client_info,
+                client_caps,
+                result.server_info,
+                server_caps,
+            )
+        } else {
+            (
+                result.server_info,
+                server_caps,
+                params.client_info,
+                client_caps,
+            )
+        };
+        Self {
+            protocol_version: result.protocol_version,
+            version,
+            local_info,
+            local_capabilities: local_caps.unwrap_or_default(),
+            peer_info,
+            peer_capabilities: peer_caps,
+        }
+    }
+}
+
+/// Perform the client side of the handshake: send `initialize`, then
+/// `notifications/initialized` once the server has answered.
+///
+/// Every version in `capabilities` is offered (all supported ones if it
+/// declares none). If the server picks a version that was not offered, the
+/// handshake fails with [`ConnectionError::VersionMismatch`] and
+/// `notifications/initialized` is not sent.
+///
+/// Messages the server sends meanwhile stay queued for `next_message`.
+pub async fn negotiate(
+    conn: &mut McplConnection,
+    capabilities: McplCapabilities,
+    info: ImplementationInfo,
+) -> Result<NegotiatedSession, ConnectionError> {
+    let offered = capabilities.offered_versions();
+    let mut advertised = capabilities.clone();
+    advertised.version = offered
+        .iter()
+        .max()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    advertised.supported_versions = Some(offered.iter().map(ToString::to_string).collect());
+    let params = McplInitializeParams {
+        protocol_version: DEFAULT_PROTOCOL_VERSION.into(),
+        capabilities: InitializeCapabilities::with_mcpl(advertised),
+        client_info: info.clone(),
+    };
+    let result: McplInitializeResult = conn.send_request_typed(method::INITIALIZE, &params).await?;
+    let peer_capabilities = result.capabilities.mcpl().cloned();
+    let version = match &peer_capabilities {
+        Some(peer) => match peer.protocol_version() {
+            Ok(version) if offered.contains(&version) => Some(version),
+            _ => {
+                return Err(VersionMismatch {
+                    ours: offered,
+                    theirs: peer.versions(),
+                }
+                .into())
+            }
+        },
+        None => None,
+    };
+    conn.send_notification(method::NOTIFICATIONS_INITIALIZED, None)
+        .await?;
+    Ok(NegotiatedSession {
+        protocol_version: result.protocol_version,
+        version,
+        local_info: info,
+        local_capabilities: capabilities,
+        peer_capabilities,
+        peer_info: result.server_info,
+    })
+}
+
+/// Pick the MCPL version for an incoming `initialize`: the highest one both
+/// sides support, or `None` if the client does not speak MCPL.
+pub(crate) fn accept_version(
+    local: &McplCapabilities,
+    peer: Option<&McplCapabilities>,
+) -> Result<Option<ProtocolVersion>, VersionMismatch> {
+    match peer {
+        Some(peer) => negotiate_version(&local.offered_versions(), &peer.versions()).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Error answering an `initialize` whose versions do not overlap ours.
+pub(crate) fn version_mismatch_error(mismatch: &VersionMismatch) -> JsonRpcError {
+    JsonRpcError {
+        data: Some(serde_json::json!({
+            "supported": mismatch.ours,
+            "requested": mismatch.theirs,
+        })),
+        ..JsonRpcError::invalid_params(mismatch)
+    }
+}
+
+/// `local` as advertised in an `initialize` result for `version`.
+pub(crate) fn accepted_capabilities(
+    local: &McplCapabilities,
+    version: Option<ProtocolVersion>,
+) -> McplCapabilities {
+    let mut accepted = local.clone();
+    if let Some(version) = version {
+        accepted.version = version.to_string();
+    }
+    accepted
+}
+
+/// Perform the server side of the handshake: answer the client's
+/// `initialize`, then wait for `notifications/initialized`.
+///
+/// Until then `ping` is answered, other requests are refused with
+/// `ERR_NOT_INITIALIZED`, and other notifications are dropped. The client's
+/// MCP protocol version is echoed back; the MCPL version is the highest both
+/// sides support. Without one, `initialize` is refused and the handshake
+/// fails with [`ConnectionError::VersionMismatch`].
+pub async fn accept_initialize(
+    conn: &mut McplConnection,
+    capabilities: McplCapabilities,
+    info: ImplementationInfo,
+) -> Result<NegotiatedSession, ConnectionError> {
+    let (params, version) = loop {
+        let request = match conn.next_message().await? {
+            IncomingMessage::Request(request) => request,
+            IncomingMessage::Notification(notification) => {
+                tracing::warn!("Dropped {} received before initialize", notification.method);
+                continue;
+            }
+        };
+        match request.method.as_str() {
+            method::INITIALIZE => {
+                match request.params_as::<McplInitializeParams>() {
+                    Ok(params) => {
+                        let version =
+                            match accept_version(&capabilities, params.capabilities.mcpl()) {
+                                Ok(version) => version,
+                                Err(mismatch) => {
+                                    let error = version_mismatch_error(&mismatch);
+                                    conn.responder(&request).respond(Err(error)).await?;
+                                    return Err(mismatch.into());
+                                }
+                            };
+                        let result = McplInitializeResult {
+                            protocol_version: params.protocol_version.clone(),
+                            capabilities: InitializeCapabilities::with_mcpl(accepted_capabilities(
+                                &capabilities,
+                                version,
+                            )),
+                            server_info: info.clone(),
+                        };
+                        conn.send_response(request.id, serde_json::to_value(result)?)
+                            .await?;
+                        break (params, version);
+                    }
+                    Err(e) => {
+                        let error = JsonRpcError::invalid_params(e);
+                        conn.send_error(request.id, error.code, error.message)
+                            .await?;
+                    }
+                }
+            }
+            method::PING => {
+                conn.send_response(request.id, serde_json::json!({}))
+                    .await?
+            }
+            _ => {
+                let message = format!("Not initialized: {}", request.method);
+                conn.send_error(request.id, ERR_NOT_INITIALIZED, message)
+                    .await?
+            }
+        }
+    };
+
+    loop {
+        match conn.next_message().await? {
+            IncomingMessage::Notification(notification)
+                if notification.method == method::NOTIFICATIONS_INITIALIZED =>
+            {
+                break
+            }
+            IncomingMessage::Notification(notification) => {
+                tracing::warn!(
+                    "Dropped {} received before initialized",
+                    notification.method
+                );
+            }
+            IncomingMessage::Request(request) if request.method == method::PING => {
+                conn.send_response(request.id, serde_json::json!({}))
+                    .await?
+            }
+            IncomingMessage::Request(request) => {
+                let message = format!("Not initialized: {}", request.method);
+                conn.send_error(request.id, ERR_NOT_INITIALIZED, message)
+                    .await?
+            }
+        }
+    }
+
+    Ok(NegotiatedSession {
+        protocol_version: params.protocol_version,
+        version,
+        local_info: info,
+        local_capabilities: capabilities,
+        peer_capabilities: params.capabilities.mcpl().cloned(),
+        peer_info: params.client_info,
+    })
+}
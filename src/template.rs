@@ -0,0 +1,202 @@
+//! Rendering state documents into context injections, so servers need not
+//! write their own string-formatting glue.
+//!
+//! A template is text with `{{pointer}}` placeholders, each replaced by the
+//! value the [JSON pointer](crate::json_pointer) selects in the state.
+//! Strings are inserted as they are, `null` as nothing, and arrays and
+//! objects as compact JSON. A placeholder may name a filter after a `|`:
+//! `raw` skips escaping and `json` inserts the value as JSON. A literal
+//! `{{` is written `\{{`.
+//!
+//! ```ignore
+//! let injection = ContextInjection::from_template(
+//!     "game",
+//!     "Turn {{/turn}}. You have {{/units/0/hp}} HP. Board: {{/board|json}}",
+//!     &state,
+//! )?;
+//! ```
+
+use serde_json::Value;
+
+use crate::json_pointer::{self, PointerError};
+use crate::methods::{ContextInjection, ContextInjectionContent, ContextInjectionPosition};
+
+/// Characters a placeholder's value may take unless configured otherwise.
+pub const DEFAULT_TEMPLATE_VALUE_CHARS: usize = 1_000;
+
+/// Characters a rendered template may take unless configured otherwise.
+pub const DEFAULT_TEMPLATE_CHARS: usize = 8_000;
+
+/// Marks a value shortened to its limit.
+const ELLIPSIS: char = '…';
+
+/// How values are escaped before they are inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TemplateEscape {
+    /// Insert values as they are.
+    None,
+    /// Escape `&`, `<` and `>`, so values cannot open or close the tags
+    /// a prompt is structured with.
+    #[default]
+    Markup,
+    /// Escape values for use inside a JSON string.
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct TemplateOptions {
+    /// Longest value inserted for a placeholder, in characters; longer
+    /// values are shortened and end with `…`.
+    pub max_value_chars: Option<usize>,
+    /// Longest rendered template, in characters; longer renderings fail
+    /// with [`TemplateError::TooLong`].
+    pub max_chars: Option<usize>,
+    pub escape: TemplateEscape,
+}
+
+impl Default for TemplateOptions {
+    fn default() -> Self {
+        Self {
+            max_value_chars: Some(DEFAULT_TEMPLATE_VALUE_CHARS),
+            max_chars: Some(DEFAULT_TEMPLATE_CHARS),
+            escape: TemplateEscape::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TemplateError {
+    #[error("Unclosed placeholder at byte {0}")]
+    Unclosed(usize),
+    #[error("Unknown template filter {0:?}")]
+    UnknownFilter(String),
+    #[error(transparent)]
+    Pointer(#[from] PointerError),
+    #[error("Rendered template has {chars} characters, over the limit of {max}")]
+    TooLong { chars: usize, max: usize },
+}
+
+/// `template` with its placeholders replaced by values from `state`.
+pub fn render(
+    template: &str,
+    state: &Value,
+    options: &TemplateOptions,
+) -> Result<String, TemplateError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find("{{") {
+        if rest[..open].ends_with('\\') {
+            rendered.push_str(&rest[..open - 1]);
+            rendered.push_str("{{");
+            rest = &rest[open + 2..];
+            continue;
+        }
+        rendered.push_str(&rest[..open]);
+        let offset = template.len() - rest.len() + open;
+        let close = rest[open..]
+            .find("}}")
+            .ok_or(TemplateError::Unclosed(offset))?;
+        let placeholder = &rest[open + 2..open + close];
+        rendered.push_str(&placeholder_value(placeholder, state, options)?);
+        rest = &rest[open + close + 2..];
+    }
+    rendered.push_str(rest);
+
+    if let Some(max) = options.max_chars {
+        let chars = rendered.chars().count();
+        if chars > max {
+            return Err(TemplateError::TooLong { chars, max });
+        }
+    }
+    Ok(rendered)
+}
+
+impl ContextInjection {
+    /// A system injection rendered from `template` and `state` with the
+    /// default [`TemplateOptions`].
+    pub fn from_template(
+        namespace: impl Into<String>,
+        template: &str,
+        state: &Value,
+    ) -> Result<Self, TemplateError> {
+        Self::from_template_with(namespace, template, state, &TemplateOptions::default())
+    }
+
+    /// A system injection rendered from `template` and `state`.
+    pub fn from_template_with(
+        namespace: impl Into<String>,
+        template: &str,
+        state: &Value,
+        options: &TemplateOptions,
+    ) -> Result<Self, TemplateError> {
+        Ok(Self {
+            namespace: namespace.into(),
+            position: ContextInjectionPosition::System,
+            content: ContextInjectionContent::Text(render(template, state, options)?),
+            priority: None,
+            metadata: None,
+        })
+    }
+}
+
+/// The text a placeholder (without its braces) is replaced by.
+fn placeholder_value(
+    placeholder: &str,
+    state: &Value,
+    options: &TemplateOptions,
+) -> Result<String, TemplateError> {
+    let (pointer, filter) = match placeholder.split_once('|') {
+        Some((pointer, filter)) => (pointer.trim(), Some(filter.trim())),
+        None => (placeholder.trim(), None),
+    };
+    let value = json_pointer::get(state, pointer)?;
+    let (mut text, escape) = match filter {
+        None => (plain(value), options.escape),
+        Some("raw") => (plain(value), TemplateEscape::None),
+        Some("json") => (value.to_string(), options.escape),
+        Some(filter) => return Err(TemplateError::UnknownFilter(filter.to_string())),
+    };
+    if let Some(max) = options.max_value_chars {
+        shorten(&mut text, max);
+    }
+    Ok(escaped(&text, escape))
+}
+
+fn plain(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Shorten `text` to at most `max` characters, the last of them `…`.
+fn shorten(text: &mut String, max: usize) {
+    if text.chars().count() <= max {
+        return;
+    }
+    match max
+        .checked_sub(1)
+        .and_then(|keep| text.char_indices().nth(keep))
+    {
+        Some((at, _)) => {
+            text.truncate(at);
+            text.push(ELLIPSIS);
+        }
+        None => text.clear(),
+    }
+}
+
+fn escaped(text: &str, escape: TemplateEscape) -> String {
+    match escape {
+        TemplateEscape::None => text.to_string(),
+        TemplateEscape::Markup => text
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;"),
+        TemplateEscape::Json => {
+            let quoted = Value::String(text.to_string()).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        }
+    }
+}
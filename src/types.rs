@@ -1,8 +1,14 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+use std::fmt;
 
 /// JSON-RPC 2.0 message types for MCPL transport.
+///
+/// Params and results are carried as raw JSON and only parsed when a typed
+/// view is asked for, e.g. with [`JsonRpcRequest::params_as`].
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum JsonRpcMessage {
     Request(JsonRpcRequest),
@@ -16,7 +22,7 @@ pub struct JsonRpcRequest {
     pub id: JsonRpcId,
     pub method: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub params: Option<serde_json::Value>,
+    pub params: Option<Box<RawValue>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,7 +30,7 @@ pub struct JsonRpcResponse {
     pub jsonrpc: String,
     pub id: JsonRpcId,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub result: Option<serde_json::Value>,
+    pub result: Option<Box<RawValue>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<JsonRpcError>,
 }
@@ -34,7 +40,7 @@ pub struct JsonRpcNotification {
     pub jsonrpc: String,
     pub method: String,
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    pub params: Option<serde_json::Value>,
+    pub params: Option<Box<RawValue>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -44,6 +50,9 @@ pub enum JsonRpcId {
     String(String),
 }
 
+/// Progress token carried in a request's `_meta.progressToken` (MCP).
+pub type ProgressToken = JsonRpcId;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JsonRpcError {
     pub code: i32,
@@ -58,9 +67,54 @@ impl JsonRpcRequest {
             jsonrpc: "2.0".into(),
             id: id.into(),
             method: method.into(),
-            params,
+            params: params.map(to_raw),
         }
     }
+
+    /// Parse the params into `T`; missing params parse from `null`.
+    pub fn params_as<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        parse_raw(self.params.as_deref())
+    }
+
+    /// The top-level param `key` parsed into `T`, leaving the others
+    /// unparsed.
+    pub fn param<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let raw = member(self.params.as_deref()?, key)?;
+        serde_json::from_str(raw.get()).ok()
+    }
+
+    /// The request's `_meta` object, if present.
+    pub fn meta(&self) -> Option<serde_json::Map<String, serde_json::Value>> {
+        self.param("_meta")
+    }
+
+    /// Progress token the caller asked progress to be reported against.
+    pub fn progress_token(&self) -> Option<ProgressToken> {
+        let meta = member(self.params.as_deref()?, "_meta")?;
+        serde_json::from_str(member(meta, "progressToken")?.get()).ok()
+    }
+
+    /// Attach `_meta.progressToken`, creating the params object if needed.
+    ///
+    /// Params that are not an object (e.g. positional arrays) are left as is.
+    pub fn with_progress_token(mut self, token: impl Into<ProgressToken>) -> Self {
+        let token = serde_json::to_value(token.into()).unwrap_or_default();
+        let mut params = match self.params_as::<Option<serde_json::Value>>() {
+            Ok(params) => params.unwrap_or_else(|| serde_json::Value::Object(Default::default())),
+            Err(_) => return self,
+        };
+        let Some(obj) = params.as_object_mut() else {
+            return self;
+        };
+        let meta = obj
+            .entry("_meta")
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if let Some(meta) = meta.as_object_mut() {
+            meta.insert("progressToken".into(), token);
+        }
+        self.params = Some(to_raw(params));
+        self
+    }
 }
 
 impl JsonRpcResponse {
@@ -68,7 +122,7 @@ impl JsonRpcResponse {
         Self {
             jsonrpc: "2.0".into(),
             id,
-            result: Some(result),
+            result: Some(to_raw(result)),
             error: None,
         }
     }
@@ -81,6 +135,43 @@ impl JsonRpcResponse {
             error: Some(error),
         }
     }
+
+    /// Parse the result into `T`; a missing result parses from `null`.
+    pub fn result_as<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        parse_raw(self.result.as_deref())
+    }
+}
+
+impl JsonRpcError {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(ERR_METHOD_NOT_FOUND, format!("Method not found: {}", method))
+    }
+
+    pub fn invalid_params(reason: impl fmt::Display) -> Self {
+        Self::new(ERR_INVALID_PARAMS, format!("Invalid params: {}", reason))
+    }
+
+    pub fn internal(reason: impl fmt::Display) -> Self {
+        Self::new(ERR_INTERNAL_ERROR, format!("Internal error: {}", reason))
+    }
+
+    /// `ERR_BUSY` for a request refused by a concurrency limit.
+    pub fn busy(method: &str) -> Self {
+        Self::new(ERR_BUSY, format!("Busy: too many {} requests in flight", method))
+    }
+
+    /// `ERR_BUSY` for a request refused while the server drains.
+    pub fn draining() -> Self {
+        Self::new(ERR_BUSY, "Busy: server is draining; retry later")
+    }
 }
 
 impl JsonRpcNotification {
@@ -88,8 +179,161 @@ impl JsonRpcNotification {
         Self {
             jsonrpc: "2.0".into(),
             method: method.into(),
-            params,
+            params: params.map(to_raw),
+        }
+    }
+
+    /// Parse the params into `T`; missing params parse from `null`.
+    pub fn params_as<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        parse_raw(self.params.as_deref())
+    }
+
+    /// The top-level param `key` parsed into `T`, leaving the others
+    /// unparsed.
+    pub fn param<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let raw = member(self.params.as_deref()?, key)?;
+        serde_json::from_str(raw.get()).ok()
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonRpcMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+        let envelope: Envelope = serde_json::from_str(raw.get()).map_err(de::Error::custom)?;
+        envelope
+            .message()
+            .map_err(de::Error::custom)?
+            .ok_or_else(|| de::Error::custom("not a JSON-RPC request, response, or notification"))
+    }
+}
+
+/// Top-level members of a frame with their values left unparsed, so reading
+/// a frame parses it once and copies its params or result as they are.
+#[derive(Deserialize)]
+pub(crate) struct Envelope<'a> {
+    #[serde(default, borrow, deserialize_with = "present")]
+    pub(crate) jsonrpc: Option<&'a RawValue>,
+    #[serde(default, borrow, deserialize_with = "present")]
+    pub(crate) id: Option<&'a RawValue>,
+    #[serde(default, borrow, deserialize_with = "present")]
+    pub(crate) method: Option<&'a RawValue>,
+    #[serde(default, borrow, deserialize_with = "present")]
+    pub(crate) params: Option<&'a RawValue>,
+    #[serde(default, borrow, deserialize_with = "present")]
+    pub(crate) result: Option<&'a RawValue>,
+    #[serde(default, borrow, deserialize_with = "present")]
+    pub(crate) error: Option<&'a RawValue>,
+}
+
+/// Treat a member as present even when it is `null`.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<&'de RawValue>, D::Error> {
+    <&RawValue>::deserialize(deserializer).map(Some)
+}
+
+impl Envelope<'_> {
+    /// The message the members make up, or `None` if they make up none.
+    pub(crate) fn message(&self) -> serde_json::Result<Option<JsonRpcMessage>> {
+        // JSON-RPC distinguishes by presence of `id` and `method`:
+        //   Request:      has `id` + `method`
+        //   Response:     has `id` + (`result` or `error`)
+        //   Notification: has `method`, no `id`
+        let jsonrpc = || required(self.jsonrpc, "jsonrpc");
+        let message = match (self.id, self.method) {
+            (Some(id), Some(method)) => JsonRpcMessage::Request(JsonRpcRequest {
+                jsonrpc: jsonrpc()?,
+                id: serde_json::from_str(id.get())?,
+                method: serde_json::from_str(method.get())?,
+                params: payload(self.params),
+            }),
+            (Some(id), None) if self.result.is_some() || self.error.is_some() => {
+                JsonRpcMessage::Response(JsonRpcResponse {
+                    jsonrpc: jsonrpc()?,
+                    id: serde_json::from_str(id.get())?,
+                    result: payload(self.result),
+                    error: parse_raw(self.error)?,
+                })
+            }
+            (None, Some(method)) => JsonRpcMessage::Notification(JsonRpcNotification {
+                jsonrpc: jsonrpc()?,
+                method: serde_json::from_str(method.get())?,
+                params: payload(self.params),
+            }),
+            _ => return Ok(None),
+        };
+        Ok(Some(message))
+    }
+}
+
+fn required<T: DeserializeOwned>(raw: Option<&RawValue>, name: &'static str) -> serde_json::Result<T> {
+    let raw = raw.ok_or_else(|| <serde_json::Error as de::Error>::missing_field(name))?;
+    serde_json::from_str(raw.get())
+}
+
+/// Params or a result as carried by a message; `null` counts as absent.
+fn payload(raw: Option<&RawValue>) -> Option<Box<RawValue>> {
+    raw.filter(|raw| raw.get() != "null").map(ToOwned::to_owned)
+}
+
+/// Parse raw JSON into `T`; missing JSON parses from `null`.
+fn parse_raw<T: DeserializeOwned>(raw: Option<&RawValue>) -> serde_json::Result<T> {
+    serde_json::from_str(raw.map_or("null", RawValue::get))
+}
+
+/// `value` as raw JSON.
+pub(crate) fn to_raw(value: serde_json::Value) -> Box<RawValue> {
+    serde_json::value::to_raw_value(&value).expect("JSON values always serialize")
+}
+
+/// The member `key` of the raw JSON object `raw`, found without parsing the
+/// other members. `None` if `raw` is not an object or has no such member.
+pub(crate) fn member<'a>(raw: &'a RawValue, key: &str) -> Option<&'a RawValue> {
+    let mut deserializer = serde_json::Deserializer::from_str(raw.get());
+    deserializer.deserialize_map(Member(key)).ok().flatten()
+}
+
+struct Member<'k>(&'k str);
+
+impl<'de> Visitor<'de> for Member<'_> {
+    type Value = Option<&'de RawValue>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        // Like `serde_json::Value`, the last of duplicate members wins
+        let mut found = None;
+        while let Some(matches) = map.next_key_seed(KeyIs(self.0))? {
+            if matches {
+                found = Some(map.next_value()?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
         }
+        Ok(found)
+    }
+}
+
+/// Whether a member name equals the given one, compared without copying it.
+struct KeyIs<'k>(&'k str);
+
+impl<'de> DeserializeSeed<'de> for KeyIs<'_> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl Visitor<'_> for KeyIs<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a member name")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<bool, E> {
+        Ok(name == self.0)
     }
 }
 
@@ -111,6 +355,13 @@ impl From<&str> for JsonRpcId {
     }
 }
 
+// JSON-RPC 2.0 error codes
+pub const ERR_PARSE_ERROR: i32 = -32700;
+pub const ERR_INVALID_REQUEST: i32 = -32600;
+pub const ERR_METHOD_NOT_FOUND: i32 = -32601;
+pub const ERR_INVALID_PARAMS: i32 = -32602;
+pub const ERR_INTERNAL_ERROR: i32 = -32603;
+
 // MCPL error codes
 pub const ERR_FEATURE_SET_NOT_ENABLED: i32 = -32001;
 pub const ERR_UNKNOWN_FEATURE_SET: i32 = -32003;
@@ -119,8 +370,26 @@ pub const ERR_CHANNEL_NOT_PERMITTED: i32 = -32017;
 pub const ERR_UNKNOWN_CHANNEL: i32 = -32023;
 pub const ERR_CHANNEL_OPEN_FAILED: i32 = -32024;
 
+// mcpl-core error codes, outside the spec's
+/// Too many requests in flight, or the server is draining; retry later.
+pub const ERR_BUSY: i32 = -32050;
+/// An [`Authenticator`](crate::Authenticator) refused the request.
+pub const ERR_UNAUTHORIZED: i32 = -32051;
+/// A JSON Patch to host-managed state did not apply.
+pub const ERR_PATCH_FAILED: i32 = -32052;
+/// A checkpoint with the same id already exists.
+pub const ERR_CHECKPOINT_CONFLICT: i32 = -32053;
+/// The feature set does not declare `rollback`.
+pub const ERR_ROLLBACK_UNSUPPORTED: i32 = -32054;
+/// The model's answer does not match the request's `responseSchema`.
+pub const ERR_INVALID_RESPONSE: i32 = -32055;
+/// An inference budget set by the host is used up.
+pub const ERR_BUDGET_EXCEEDED: i32 = -32056;
+/// A request arrived before the `initialize` handshake completed.
+pub const ERR_NOT_INITIALIZED: i32 = -32057;
+
 /// Content block types (Appendix B.1 of MCPL spec).
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ContentBlock {
     #[serde(rename = "text")]
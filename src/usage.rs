@@ -0,0 +1,170 @@
+//! Inference usage totals and budgets.
+//!
+//! The session adds up the `usage` of inference in both directions: of
+//! answered `inference/request`s, which servers initiate, and of
+//! `context/afterInference`, which hosts send after their own inference.
+//! Hosts can cap what servers request with [`InferenceBudgets`]:
+//!
+//! ```ignore
+//! let host = McplHost::builder()
+//!     .handler(handler)
+//!     .inference_budgets(InferenceBudgets {
+//!         conversation: Some(InferenceBudget::tokens(50_000)),
+//!         ..Default::default()
+//!     })
+//!     .connect(conn)
+//!     .await?;
+//! ```
+
+use std::collections::HashMap;
+
+use crate::handler::HandlerError;
+use crate::methods::InferenceUsage;
+use crate::types::JsonRpcError;
+
+/// Inference added up over a session, a feature set or a conversation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageTotals {
+    /// Inferences counted.
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl UsageTotals {
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+
+    /// Count one inference that used `usage`.
+    pub fn add(&mut self, usage: &InferenceUsage) {
+        self.requests += 1;
+        self.input_tokens += u64::from(usage.input_tokens);
+        self.output_tokens += u64::from(usage.output_tokens);
+    }
+}
+
+/// Inference usage by who initiated it, as counted by
+/// [`McplSession::usage`](crate::McplSession::usage).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionUsage {
+    /// Reported by the host in `context/afterInference`.
+    pub host_initiated: UsageTotals,
+    /// Of `inference/request`s the host answered.
+    pub server_initiated: UsageTotals,
+}
+
+impl SessionUsage {
+    /// Both directions added together.
+    pub fn combined(&self) -> UsageTotals {
+        UsageTotals {
+            requests: self.host_initiated.requests + self.server_initiated.requests,
+            input_tokens: self.host_initiated.input_tokens + self.server_initiated.input_tokens,
+            output_tokens: self.host_initiated.output_tokens + self.server_initiated.output_tokens,
+        }
+    }
+}
+
+/// Limits on server-initiated inference; `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InferenceBudget {
+    pub max_requests: Option<u64>,
+    /// Input and output tokens together.
+    pub max_tokens: Option<u64>,
+}
+
+impl InferenceBudget {
+    pub fn requests(max: u64) -> Self {
+        Self {
+            max_requests: Some(max),
+            max_tokens: None,
+        }
+    }
+
+    pub fn tokens(max: u64) -> Self {
+        Self {
+            max_requests: None,
+            max_tokens: Some(max),
+        }
+    }
+
+    /// The first limit `used` has reached, if any.
+    pub fn exceeded_by(&self, used: &UsageTotals) -> Option<BudgetLimit> {
+        if self.max_requests.is_some_and(|max| used.requests >= max) {
+            Some(BudgetLimit::Requests)
+        } else if self
+            .max_tokens
+            .is_some_and(|max| used.total_tokens() >= max)
+        {
+            Some(BudgetLimit::Tokens)
+        } else {
+            None
+        }
+    }
+}
+
+/// Budgets a host applies to the `inference/request`s of a session.
+///
+/// A request is rejected once the answered requests of its feature set or
+/// its conversation have used up a budget. Requests in flight together
+/// are checked against the same totals, so they may overrun it.
+#[derive(Debug, Clone, Default)]
+pub struct InferenceBudgets {
+    /// Budget of each feature set not in `feature_sets`.
+    pub feature_set: Option<InferenceBudget>,
+    /// Budgets of particular feature sets, by name.
+    pub feature_sets: HashMap<String, InferenceBudget>,
+    /// Budget of each conversation, across feature sets.
+    pub conversation: Option<InferenceBudget>,
+}
+
+impl InferenceBudgets {
+    pub fn for_feature_set(&self, feature_set: &str) -> Option<&InferenceBudget> {
+        self.feature_sets
+            .get(feature_set)
+            .or(self.feature_set.as_ref())
+    }
+}
+
+/// Which limit of an [`InferenceBudget`] was reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BudgetLimit {
+    Requests,
+    Tokens,
+}
+
+impl BudgetLimit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BudgetLimit::Requests => "requests",
+            BudgetLimit::Tokens => "tokens",
+        }
+    }
+}
+
+/// An `inference/request` refused because a budget is used up.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BudgetExceeded {
+    #[error("Inference {} budget of feature set {feature_set} exceeded", limit.as_str())]
+    FeatureSet {
+        feature_set: String,
+        limit: BudgetLimit,
+    },
+    #[error("Inference {} budget of conversation {conversation_id} exceeded", limit.as_str())]
+    Conversation {
+        conversation_id: String,
+        limit: BudgetLimit,
+    },
+}
+
+impl From<BudgetExceeded> for HandlerError {
+    fn from(e: BudgetExceeded) -> Self {
+        HandlerError::budget_exceeded(&e)
+    }
+}
+
+impl From<BudgetExceeded> for JsonRpcError {
+    fn from(e: BudgetExceeded) -> Self {
+        HandlerError::from(e).into()
+    }
+}
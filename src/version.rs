@@ -0,0 +1,151 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An MCPL protocol version, `major.minor` on the wire (e.g. `"0.4"`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    pub const V0_4: ProtocolVersion = ProtocolVersion::new(0, 4);
+    /// The newest version this crate implements.
+    pub const LATEST: ProtocolVersion = ProtocolVersion::V0_4;
+
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// Whether `method` exists in this version. Methods introduced by a
+    /// later version are refused on sessions that negotiated an older one.
+    pub fn supports_method(&self, method: &str) -> bool {
+        introduced_in(method).is_none_or(|introduced| *self >= introduced)
+    }
+}
+
+/// Versions this crate can speak, oldest first.
+pub const SUPPORTED_VERSIONS: &[ProtocolVersion] = &[ProtocolVersion::V0_4];
+
+/// First version that defines `method`, for methods that some supported
+/// version lacks. `None` for methods every version has, which is all of them
+/// while 0.4 is the only version the spec defines.
+fn introduced_in(_method: &str) -> Option<ProtocolVersion> {
+    None
+}
+
+/// Pick the highest version both sides support.
+pub fn negotiate_version(
+    ours: &[ProtocolVersion],
+    theirs: &[ProtocolVersion],
+) -> Result<ProtocolVersion, VersionMismatch> {
+    ours.iter()
+        .filter(|v| theirs.contains(v))
+        .max()
+        .copied()
+        .ok_or_else(|| VersionMismatch {
+            ours: ours.to_vec(),
+            theirs: theirs.to_vec(),
+        })
+}
+
+/// The two sides of a handshake share no protocol version.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "No common MCPL version: we support {}, the peer supports {}",
+    list(ours),
+    list(theirs)
+)]
+pub struct VersionMismatch {
+    pub ours: Vec<ProtocolVersion>,
+    pub theirs: Vec<ProtocolVersion>,
+}
+
+fn list(versions: &[ProtocolVersion]) -> String {
+    let versions: Vec<String> = versions.iter().map(ToString::to_string).collect();
+    format!("[{}]", versions.join(", "))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid MCPL version {0:?}; expected major.minor")]
+pub struct ParseVersionError(String);
+
+impl FromStr for ProtocolVersion {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseVersionError(s.to_string());
+        let (major, minor) = s.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            major: major.parse().map_err(|_| invalid())?,
+            minor: minor.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl Serialize for ProtocolVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Version of a feature set or of a host, as in a declaration's `version`
+/// and `minHostVersion`: `major[.minor[.patch]]`, missing parts being 0. A
+/// `-pre-release` or `+build` suffix is accepted and ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FeatureSetVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl FeatureSetVersion {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid version {0:?}; expected major[.minor[.patch]]")]
+pub struct ParseFeatureSetVersionError(String);
+
+impl FromStr for FeatureSetVersion {
+    type Err = ParseFeatureSetVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseFeatureSetVersionError(s.to_string());
+        let core = s.split(['-', '+']).next().unwrap_or_default();
+        let mut parts = [0; 3];
+        for (i, part) in core.split('.').enumerate() {
+            let slot = parts.get_mut(i).ok_or_else(invalid)?;
+            *slot = part.parse().map_err(|_| invalid())?;
+        }
+        let [major, minor, patch] = parts;
+        Ok(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for FeatureSetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
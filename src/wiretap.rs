@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio::sync::mpsc;
+
+/// Which way a frame crossed the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// A raw newline-delimited JSON frame as it appeared on the wire, without the
+/// trailing newline.
+#[derive(Debug, Clone)]
+pub struct WireFrame {
+    pub direction: FrameDirection,
+    pub timestamp: SystemTime,
+    pub raw: String,
+}
+
+pub type WiretapCallback = Arc<dyn Fn(&WireFrame) + Send + Sync>;
+
+/// Destination for raw frames observed on a connection.
+///
+/// Incoming frames are tapped before parsing (so malformed frames are seen
+/// too), outgoing frames right after serialization.
+#[derive(Clone)]
+pub enum Wiretap {
+    Callback(WiretapCallback),
+    Channel(mpsc::UnboundedSender<WireFrame>),
+}
+
+impl Wiretap {
+    pub(crate) fn record(&self, direction: FrameDirection, raw: &str) {
+        let frame = WireFrame {
+            direction,
+            timestamp: SystemTime::now(),
+            raw: raw.to_string(),
+        };
+        match self {
+            Wiretap::Callback(callback) => callback(&frame),
+            Wiretap::Channel(tx) => {
+                let _ = tx.send(frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+use mcpl_core::capabilities::*;
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::methods::*;
+use mcpl_core::server::{HandlerResult, McplServer, McplServerHandler};
+use mcpl_core::types::*;
+use mcpl_core::{
+    CheckpointCipher, CheckpointError, CheckpointStore, CheckpointTree, FileCheckpointStore,
+    McplClient, MemoryCheckpointStore, RequestContext, StoredCheckpoint,
+};
+
+mod common;
+use common::duplex_pair;
+
+fn init_params() -> McplInitializeParams {
+    McplInitializeParams {
+        protocol_version: "2024-11-05".into(),
+        capabilities: InitializeCapabilities::with_mcpl(McplCapabilities::new("0.4")),
+        client_info: ImplementationInfo {
+            name: "test-host".into(),
+            version: "0.1.0".into(),
+        },
+    }
+}
+
+#[test]
+fn test_memory_checkpoint_store() {
+    let store = MemoryCheckpointStore::new();
+    let root = StoredCheckpoint::new("game", serde_json::json!({ "turn": 0 })).label("start");
+    let root_id = root.checkpoint.id.clone();
+    assert!(root.checkpoint.timestamp.ends_with('Z'));
+    store.create(root.clone()).unwrap();
+    let child = StoredCheckpoint::new("game", serde_json::json!({ "turn": 1 }))
+        .id("turn-1")
+        .parent(&root_id);
+    store.create(child).unwrap();
+    store
+        .create(StoredCheckpoint::new("lobby", serde_json::json!({})).id("lobby-0"))
+        .unwrap();
+
+    assert_eq!(store.get(&root_id).unwrap(), Some(root));
+    let ids: Vec<String> = store
+        .list("game")
+        .unwrap()
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    assert_eq!(ids, [root_id.clone(), "turn-1".to_string()]);
+    assert_eq!(store.parent("turn-1").unwrap().unwrap().id, root_id);
+    assert_eq!(store.parent(&root_id).unwrap(), None);
+    assert!(matches!(
+        store.parent("turn-9"),
+        Err(CheckpointError::NotFound(id)) if id == "turn-9"
+    ));
+
+    assert!(matches!(
+        store.create(StoredCheckpoint::new("game", serde_json::json!({})).id("turn-1")),
+        Err(CheckpointError::Duplicate(_))
+    ));
+    assert!(matches!(
+        store.create(StoredCheckpoint::new("game", serde_json::json!({})).parent("turn-9")),
+        Err(CheckpointError::NotFound(_))
+    ));
+
+    let conflict = JsonRpcError::from(CheckpointError::Duplicate("turn-1".into()));
+    assert_eq!(conflict.code, ERR_CHECKPOINT_CONFLICT);
+    assert_eq!(conflict.data.unwrap()["checkpoint"], "turn-1");
+
+    assert!(store.delete("turn-1").unwrap());
+    assert!(!store.delete("turn-1").unwrap());
+    assert_eq!(store.list("game").unwrap().len(), 1);
+}
+
+#[test]
+fn test_file_checkpoint_store_survives_restarts() {
+    let root = std::env::temp_dir().join(format!("mcpl-checkpoints-{}", uuid::Uuid::now_v7()));
+    let store = FileCheckpointStore::open(&root).unwrap();
+    let first = StoredCheckpoint::new("game/main", serde_json::json!({ "turn": 1 })).id("turn 1");
+    store.create(first.clone()).unwrap();
+    let mut second = StoredCheckpoint::new("game/main", serde_json::json!({ "turn": 2 }))
+        .id("../turn-2")
+        .parent("turn 1");
+    // Ordered by timestamp, which may otherwise tie within a millisecond
+    second.checkpoint.timestamp = "9999-01-01T00:00:00.000Z".into();
+    store.create(second).unwrap();
+    store
+        .create(StoredCheckpoint::new("lobby", serde_json::json!({})).id("lobby-0"))
+        .unwrap();
+    assert!(store.delete("lobby-0").unwrap());
+    drop(store);
+
+    // Names are encoded into one directory per feature set
+    let game_dir = root.join("game%2Fmain");
+    assert!(game_dir.join("turn%201.json").is_file());
+    assert!(game_dir.join("%2E%2E%2Fturn-2.json").is_file());
+    // An interrupted write leaves a temporary file; a corrupt one is skipped
+    std::fs::write(game_dir.join("turn-3.tmp"), b"{\"checkpo").unwrap();
+    std::fs::write(game_dir.join("garbage.json"), b"not json").unwrap();
+
+    let store = FileCheckpointStore::open(&root).unwrap();
+    assert!(!game_dir.join("turn-3.tmp").exists());
+    assert_eq!(store.get("turn 1").unwrap(), Some(first));
+    let ids: Vec<String> = store
+        .list("game/main")
+        .unwrap()
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    assert_eq!(ids, ["turn 1", "../turn-2"]);
+    assert_eq!(store.parent("../turn-2").unwrap().unwrap().id, "turn 1");
+    assert!(store.get("lobby-0").unwrap().is_none());
+    assert!(matches!(
+        store.create(StoredCheckpoint::new("game/main", serde_json::json!({})).id("turn 1")),
+        Err(CheckpointError::Duplicate(_))
+    ));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_checkpoint_tree() {
+    // a ─ b ─ c ─ d
+    //      └─ e ─ f      (rolled back to b, then played on)
+    let store = MemoryCheckpointStore::new();
+    for (id, parent) in [
+        ("a", None),
+        ("b", Some("a")),
+        ("c", Some("b")),
+        ("d", Some("c")),
+        ("e", Some("b")),
+        ("f", Some("e")),
+    ] {
+        let mut checkpoint = StoredCheckpoint::new("game", serde_json::json!({})).id(id);
+        checkpoint.checkpoint.parent = parent.map(String::from);
+        store.create(checkpoint).unwrap();
+    }
+    let tree = CheckpointTree::from_store(&store, "game").unwrap();
+    let ids = |checkpoints: Vec<&StateCheckpoint>| -> Vec<String> {
+        checkpoints.into_iter().map(|c| c.id.clone()).collect()
+    };
+
+    assert_eq!(tree.len(), 6);
+    assert_eq!(ids(tree.roots()), ["a"]);
+    assert_eq!(ids(tree.children("b")), ["c", "e"]);
+    assert_eq!(ids(tree.ancestors("f")), ["e", "b", "a"]);
+    assert_eq!(ids(tree.path_to_root("d")), ["d", "c", "b", "a"]);
+    assert_eq!(ids(tree.descendants("b")), ["c", "e", "d", "f"]);
+    assert_eq!(tree.common_ancestor("d", "f").unwrap().id, "b");
+    assert_eq!(tree.common_ancestor("c", "d").unwrap().id, "c");
+    assert!(tree.is_ancestor("b", "f"));
+    assert!(!tree.is_ancestor("c", "f"));
+
+    // A partial listing: the parent of "c" is missing, so it is a root
+    let partial: CheckpointTree = store
+        .list("game")
+        .unwrap()
+        .into_iter()
+        .filter(|c| c.id != "b")
+        .collect();
+    assert_eq!(ids(partial.roots()), ["a", "c", "e"]);
+    assert!(partial.common_ancestor("d", "f").is_none());
+    assert!(partial.path_to_root("b").is_empty());
+}
+
+#[test]
+fn test_checkpoint_labels_and_time_ranges() {
+    let root = std::env::temp_dir().join(format!("mcpl-checkpoints-{}", uuid::Uuid::now_v7()));
+    let stores: [Box<dyn CheckpointStore>; 2] = [
+        Box::new(MemoryCheckpointStore::new()),
+        Box::new(FileCheckpointStore::open(&root).unwrap()),
+    ];
+    for store in stores {
+        let at = |second: u64, id: &str| {
+            let mut checkpoint = StoredCheckpoint::new("game", serde_json::json!({})).id(id);
+            checkpoint.checkpoint.timestamp = format!("1970-01-01T00:00:{:02}.000Z", second);
+            checkpoint
+        };
+        store.create(at(10, "cp-10").label("turn")).unwrap();
+        store
+            .create(
+                at(20, "cp-20")
+                    .label("nuke")
+                    .metadata(serde_json::json!({ "target": "moscow" })),
+            )
+            .unwrap();
+        store.create(at(30, "cp-30").label("turn")).unwrap();
+        store.create(at(40, "cp-40").label("nuke")).unwrap();
+        store
+            .create(StoredCheckpoint::new("lobby", serde_json::json!({})).label("nuke"))
+            .unwrap();
+        store.delete("cp-40").unwrap();
+
+        // "Roll back to before the nuke was launched"
+        let nukes = store.find_by_label("game", "nuke").unwrap();
+        assert_eq!(nukes.len(), 1);
+        assert_eq!(
+            nukes[0].metadata,
+            Some(serde_json::json!({ "target": "moscow" }))
+        );
+        let before = store
+            .between("game", UNIX_EPOCH, UNIX_EPOCH + Duration::from_secs(20))
+            .unwrap();
+        assert_eq!(before.last().unwrap().id, "cp-10");
+        let ids: Vec<String> = store
+            .between(
+                "game",
+                UNIX_EPOCH + Duration::from_secs(20),
+                UNIX_EPOCH + Duration::from_secs(60),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, ["cp-20", "cp-30"]);
+        assert_eq!(store.find_by_label("game", "turn").unwrap().len(), 2);
+        assert!(store.find_by_label("game", "peace").unwrap().is_empty());
+    }
+
+    // Metadata survives a restart of the file store
+    let store = FileCheckpointStore::open(&root).unwrap();
+    let nuke = store.get("cp-20").unwrap().unwrap();
+    assert_eq!(
+        nuke.checkpoint.metadata,
+        Some(serde_json::json!({ "target": "moscow" }))
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// The cipher an embedder would write: ChaCha20-Poly1305 with a random
+/// nonce stored before each record.
+struct Sealed(ChaCha20Poly1305);
+
+impl CheckpointCipher for Sealed {
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, CheckpointError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|e| CheckpointError::Cipher(e.to_string()))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, CheckpointError> {
+        if sealed.len() < 12 {
+            return Err(CheckpointError::Cipher("record too short".into()));
+        }
+        let (nonce, sealed) = sealed.split_at(12);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|e| CheckpointError::Cipher(e.to_string()))
+    }
+}
+
+#[test]
+fn test_encrypted_file_checkpoint_store() {
+    let root = std::env::temp_dir().join(format!("mcpl-checkpoints-{}", uuid::Uuid::now_v7()));
+    let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    let store =
+        FileCheckpointStore::open_encrypted(&root, Sealed(ChaCha20Poly1305::new(&key))).unwrap();
+    let secret =
+        StoredCheckpoint::new("game", serde_json::json!({ "password": "hunter2" })).id("cp-1");
+    store.create(secret.clone()).unwrap();
+    store
+        .create(StoredCheckpoint::new("game", serde_json::json!({ "turn": 2 })).id("cp-2"))
+        .unwrap();
+    drop(store);
+
+    // Not plaintext at rest
+    let game_dir = root.join("game");
+    let sealed = std::fs::read(game_dir.join("cp-1.sealed")).unwrap();
+    assert!(!String::from_utf8_lossy(&sealed).contains("hunter2"));
+
+    let store =
+        FileCheckpointStore::open_encrypted(&root, Sealed(ChaCha20Poly1305::new(&key))).unwrap();
+    assert_eq!(store.get("cp-1").unwrap(), Some(secret));
+    assert_eq!(store.list("game").unwrap().len(), 2);
+
+    // A record swapped into another checkpoint's file is refused
+    std::fs::copy(game_dir.join("cp-1.sealed"), game_dir.join("cp-2.sealed")).unwrap();
+    assert!(matches!(store.get("cp-2"), Err(CheckpointError::Cipher(_))));
+    drop(store);
+
+    // Another key opens nothing, and neither does a plaintext store
+    let other = ChaCha20Poly1305::generate_key(&mut OsRng);
+    let store =
+        FileCheckpointStore::open_encrypted(&root, Sealed(ChaCha20Poly1305::new(&other))).unwrap();
+    assert!(store.list("game").unwrap().is_empty());
+    assert!(FileCheckpointStore::open(&root)
+        .unwrap()
+        .list("game")
+        .unwrap()
+        .is_empty());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// A server whose board is restored from its checkpoint store.
+struct Board {
+    state: Mutex<serde_json::Value>,
+}
+
+impl McplServerHandler for Board {
+    async fn on_checkpoint_capture(
+        &self,
+        _ctx: &RequestContext,
+        _feature_set: &str,
+    ) -> HandlerResult<serde_json::Value> {
+        Ok(self.state.lock().unwrap().clone())
+    }
+
+    async fn on_checkpoint_restore(
+        &self,
+        _ctx: &RequestContext,
+        checkpoint: StoredCheckpoint,
+    ) -> HandlerResult<()> {
+        *self.state.lock().unwrap() = checkpoint.state;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_server_rolls_back_from_store() {
+    let store = Arc::new(MemoryCheckpointStore::new());
+    store
+        .create(StoredCheckpoint::new("game", serde_json::json!({ "turn": 3 })).id("cp-3"))
+        .unwrap();
+    let server = McplServer::builder()
+        .handler(Board {
+            state: Mutex::new(serde_json::json!({ "turn": 7 })),
+        })
+        .capabilities(McplCapabilities::new("0.4"))
+        .checkpoints(Arc::clone(&store))
+        .build();
+    let (server_conn, mut client) = duplex_pair();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(server_conn).await });
+
+    let init: McplInitializeResult = client
+        .send_request_typed(method::INITIALIZE, &init_params())
+        .await
+        .unwrap();
+    assert!(init.capabilities.mcpl().unwrap().has_rollback());
+
+    let rollback = |feature_set: &str, checkpoint: &str| StateRollbackParams {
+        feature_set: feature_set.into(),
+        checkpoint: checkpoint.into(),
+    };
+    let result: StateRollbackResult = client
+        .send_request_typed(method::STATE_ROLLBACK, &rollback("game", "cp-3"))
+        .await
+        .unwrap();
+    assert!(result.success);
+    assert_eq!(
+        *server.handler().state.lock().unwrap(),
+        serde_json::json!({ "turn": 3 })
+    );
+
+    // Unknown checkpoints, and those of another feature set, are refused
+    for (feature_set, checkpoint) in [("game", "cp-9"), ("lobby", "cp-3")] {
+        let err = client
+            .send_request_typed::<_, StateRollbackResult>(
+                method::STATE_ROLLBACK,
+                &rollback(feature_set, checkpoint),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConnectionError::Rpc {
+                code: ERR_CHECKPOINT_NOT_FOUND,
+                ..
+            }
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_server_lists_and_creates_checkpoints() {
+    let store = Arc::new(MemoryCheckpointStore::new());
+    store
+        .create(StoredCheckpoint::new("lobby", serde_json::json!({})).id("lobby-0"))
+        .unwrap();
+    let server = McplServer::builder()
+        .handler(Board {
+            state: Mutex::new(serde_json::json!({ "turn": 1 })),
+        })
+        .capabilities(McplCapabilities {
+            feature_sets: Some(vec![
+                FeatureSetDeclaration::named("game").rollback(true),
+                FeatureSetDeclaration::named("lobby"),
+            ]),
+            ..McplCapabilities::new("0.4")
+        })
+        .checkpoints(Arc::clone(&store))
+        .build();
+    let (server_conn, client_conn) = duplex_pair();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(server_conn).await });
+    let client = McplClient::new(client_conn.handle());
+    client.initialize(&init_params()).await.unwrap();
+
+    let mut parent = None;
+    for turn in 1..=3 {
+        *server.handler().state.lock().unwrap() = serde_json::json!({ "turn": turn });
+        let created = client
+            .state_checkpoint_create(&StateCheckpointCreateParams {
+                feature_set: "game".into(),
+                parent: parent.clone(),
+                label: Some(format!("turn {}", turn)),
+                metadata: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(created.checkpoint.feature_set, "game");
+        let saved = store.get(&created.checkpoint.id).unwrap().unwrap();
+        assert_eq!(saved.state, serde_json::json!({ "turn": turn }));
+        assert_eq!(saved.checkpoint.parent, parent);
+        parent = Some(created.checkpoint.id);
+    }
+
+    // Pages of two, filtered by feature set
+    let page = client
+        .state_checkpoints_list(&StateCheckpointsListParams {
+            feature_set: Some("game".into()),
+            limit: Some(2),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.checkpoints.len(), 2);
+    assert_eq!(page.checkpoints[0].label.as_deref(), Some("turn 1"));
+    let rest = client
+        .state_checkpoints_list(&StateCheckpointsListParams {
+            feature_set: Some("game".into()),
+            cursor: page.next_cursor,
+            limit: Some(2),
+        })
+        .await
+        .unwrap();
+    assert_eq!(rest.checkpoints.len(), 1);
+    assert_eq!(rest.checkpoints[0].id, parent.unwrap());
+    assert!(rest.next_cursor.is_none());
+    assert_eq!(
+        client
+            .state_checkpoints_list_all("game")
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+
+    // Without a filter, every declared feature set is listed
+    let all = client
+        .state_checkpoints_list(&StateCheckpointsListParams::default())
+        .await
+        .unwrap();
+    assert_eq!(all.checkpoints.len(), 4);
+
+    // A bad parent is refused, as is a cursor the server did not hand out
+    let err = client
+        .state_checkpoint_create(&StateCheckpointCreateParams {
+            feature_set: "game".into(),
+            parent: Some("cp-9".into()),
+            label: None,
+            metadata: None,
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_CHECKPOINT_NOT_FOUND,
+            ..
+        }
+    ));
+    let err = client
+        .state_checkpoints_list(&StateCheckpointsListParams {
+            cursor: Some("next".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: -32602, .. }));
+
+    // The lobby does not declare rollback
+    let err = client.state_rollback("lobby", "lobby-0").await.unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_ROLLBACK_UNSUPPORTED,
+            ..
+        }
+    ));
+}
@@ -0,0 +1,65 @@
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::{McplClient, Router};
+
+mod common;
+use common::duplex_pair;
+
+#[tokio::test]
+async fn test_typed_client_calls() {
+    let (serving, caller) = duplex_pair();
+    let mut router = Router::new();
+    router
+        .on(method::STATE_ROLLBACK, |_ctx, params: StateRollbackParams| async move {
+            Ok(StateRollbackResult {
+                checkpoint: params.checkpoint,
+                success: params.feature_set == "game",
+                reason: None,
+            })
+        })
+        .on(method::MODEL_INFO, |_ctx, _params: serde_json::Value| async move {
+            Ok(ModelInfoResult {
+                id: "model-1".into(),
+                vendor: "acme".into(),
+                context_window: 200_000,
+                capabilities: vec![],
+            })
+        })
+        .on(method::PUSH_EVENT, |_ctx, params: PushEventParams| async move {
+            Ok(serde_json::json!({ "accepted": params.payload.content.is_empty() }))
+        });
+    tokio::spawn(async move { router.serve(serving).await });
+
+    let client = McplClient::from(&caller);
+    let rollback = client.state_rollback("game", "cp-3").await.unwrap();
+    assert_eq!(rollback.checkpoint, "cp-3");
+    assert!(rollback.success);
+
+    let model = client.model_info().await.unwrap();
+    assert_eq!(model.context_window, 200_000);
+
+    let pushed = client
+        .push_event(&PushEventParams {
+            feature_set: "game".into(),
+            event_id: "e1".into(),
+            timestamp: "2025-01-01T00:00:00Z".into(),
+            origin: None,
+            sequence: None,
+            priority: None,
+            expires_at: None,
+            payload: PushEventPayload { content: vec![] },
+        })
+        .await
+        .unwrap();
+    assert!(pushed.accepted);
+
+    let err = client.channels_close("c1").await.unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_METHOD_NOT_FOUND,
+            ..
+        }
+    ));
+}
@@ -0,0 +1,46 @@
+//! In-memory transports shared by the integration tests.
+#![allow(dead_code)]
+
+use mcpl_core::connection::{ConnectionOptions, McplConnection};
+use tokio::io::{AsyncBufReadExt, BufReader, DuplexStream, Lines};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Two connections talking to each other over in-memory pipes.
+pub fn duplex_pair() -> (McplConnection, McplConnection) {
+    duplex_pair_with_options(ConnectionOptions::default())
+}
+
+/// Like [`duplex_pair`], with `options` applied to the first connection.
+pub fn duplex_pair_with_options(options: ConnectionOptions) -> (McplConnection, McplConnection) {
+    let (a_read, b_write) = tokio::io::duplex(BUFFER_SIZE);
+    let (b_read, a_write) = tokio::io::duplex(BUFFER_SIZE);
+    let a = McplConnection::from_parts_with_options(Box::new(a_read), Box::new(a_write), options);
+    let b = McplConnection::from_parts(Box::new(b_read), Box::new(b_write));
+    (a, b)
+}
+
+/// A connection whose peer is driven by hand, so the exact frames on the
+/// wire can be written and observed.
+pub struct RawPeer {
+    /// Frames written here arrive at the connection.
+    pub writer: DuplexStream,
+    /// Frames the connection sends, one per line.
+    pub lines: Lines<BufReader<DuplexStream>>,
+}
+
+pub fn raw_peer() -> (McplConnection, RawPeer) {
+    raw_peer_with_options(ConnectionOptions::default())
+}
+
+pub fn raw_peer_with_options(options: ConnectionOptions) -> (McplConnection, RawPeer) {
+    let (conn_read, writer) = tokio::io::duplex(BUFFER_SIZE);
+    let (peer_read, conn_write) = tokio::io::duplex(BUFFER_SIZE);
+    let conn =
+        McplConnection::from_parts_with_options(Box::new(conn_read), Box::new(conn_write), options);
+    let peer = RawPeer {
+        writer,
+        lines: BufReader::new(peer_read).lines(),
+    };
+    (conn, peer)
+}
@@ -1,10 +1,17 @@
 use mcpl_core::capabilities::*;
-use mcpl_core::connection::{ConnectionError, McplConnection};
+use mcpl_core::connection::{
+    ConnectionError, ConnectionOptions, McplConnection, OverflowPolicy, UnexpectedResponseKind,
+    UnexpectedResponsePolicy,
+};
+use mcpl_core::id::UuidV7Ids;
 use mcpl_core::methods::*;
 use mcpl_core::types::*;
 
 use tokio::net::TcpListener;
 
+mod common;
+use common::{duplex_pair, raw_peer};
+
 /// Helper: spin up server + client connected over TCP.
 async fn connected_pair() -> (McplConnection, McplConnection) {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -66,7 +73,7 @@ async fn test_capability_negotiation() {
         mcpl_core::connection::IncomingMessage::Request(req) => {
             assert_eq!(req.method, "initialize");
             let params: McplInitializeParams =
-                serde_json::from_value(req.params.unwrap()).unwrap();
+                req.params_as().unwrap();
             assert_eq!(params.client_info.name, "test-client");
 
             let server_caps = McplCapabilities {
@@ -81,6 +88,8 @@ async fn test_capability_negotiation() {
                         uses: vec!["connect".into(), "chat".into()],
                         rollback: false,
                         host_state: false,
+                        version: None,
+                        min_host_version: None,
                     },
                     FeatureSetDeclaration {
                         name: "game".into(),
@@ -88,6 +97,8 @@ async fn test_capability_negotiation() {
                         uses: vec!["commands".into(), "observation".into()],
                         rollback: true,
                         host_state: false,
+                        version: None,
+                        min_host_version: None,
                     },
                 ]),
                 ..Default::default()
@@ -159,7 +170,7 @@ async fn test_notification_roundtrip() {
         mcpl_core::connection::IncomingMessage::Notification(notif) => {
             assert_eq!(notif.method, "featureSets/update");
             let p: FeatureSetsUpdateParams =
-                serde_json::from_value(notif.params.unwrap()).unwrap();
+                notif.params_as().unwrap();
             assert_eq!(p.enabled.unwrap(), vec!["lobby", "game"]);
         }
         _ => panic!("Expected notification"),
@@ -176,6 +187,9 @@ async fn test_push_event_request() {
         event_id: "evt_001".into(),
         timestamp: "2026-02-12T00:00:00Z".into(),
         origin: None,
+        sequence: None,
+        priority: None,
+        expires_at: None,
         payload: PushEventPayload {
             content: vec![ContentBlock::text("User joined lobby")],
         },
@@ -198,7 +212,7 @@ async fn test_push_event_request() {
     match msg {
         mcpl_core::connection::IncomingMessage::Request(req) => {
             assert_eq!(req.method, "push/event");
-            let p: PushEventParams = serde_json::from_value(req.params.unwrap()).unwrap();
+            let p: PushEventParams = req.params_as().unwrap();
             assert_eq!(p.feature_set, "lobby");
             assert_eq!(p.event_id, "evt_001");
 
@@ -253,7 +267,7 @@ async fn test_channel_lifecycle() {
         mcpl_core::connection::IncomingMessage::Request(req) => {
             assert_eq!(req.method, "channels/register");
             let p: ChannelsRegisterParams =
-                serde_json::from_value(req.params.unwrap()).unwrap();
+                req.params_as().unwrap();
             assert_eq!(p.channels.len(), 1);
             assert_eq!(p.channels[0].id, "game");
 
@@ -402,7 +416,7 @@ async fn test_from_parts() {
     match msg {
         mcpl_core::connection::IncomingMessage::Notification(notif) => {
             assert_eq!(notif.method, "test/hello");
-            let p: serde_json::Value = notif.params.unwrap();
+            let p: serde_json::Value = notif.params_as().unwrap();
             assert_eq!(p["from"], "client");
         }
         _ => panic!("Expected notification"),
@@ -461,6 +475,9 @@ async fn test_incoming_messages_buffered_during_send_request() {
         event_id: "tick_42".into(),
         timestamp: "2026-02-12T00:00:00Z".into(),
         origin: None,
+        sequence: None,
+        priority: None,
+        expires_at: None,
         payload: PushEventPayload {
             content: vec![ContentBlock::text("Game tick 42")],
         },
@@ -491,9 +508,1130 @@ async fn test_incoming_messages_buffered_during_send_request() {
         mcpl_core::connection::IncomingMessage::Notification(notif) => {
             assert_eq!(notif.method, method::PUSH_EVENT);
             let p: PushEventParams =
-                serde_json::from_value(notif.params.unwrap()).unwrap();
+                notif.params_as().unwrap();
             assert_eq!(p.event_id, "tick_42");
         }
         _ => panic!("Expected buffered notification, got request"),
     }
 }
+
+#[tokio::test]
+async fn test_cancel_request() {
+    let (mut client, mut server) = connected_pair().await;
+    let canceller = client.cancel_handle();
+
+    let client_handle = tokio::spawn(async move {
+        let err = client
+            .send_request(method::CHANNELS_OPEN, Some(serde_json::json!({"type": "game_instance", "address": {}})))
+            .await
+            .unwrap_err();
+        (client, err)
+    });
+
+    let req = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    let token = server.cancellation_token(&req.id).unwrap();
+    assert!(!token.is_cancelled());
+
+    canceller
+        .cancel_request(req.id.clone(), Some("game aborted".into()))
+        .await
+        .unwrap();
+
+    let (_client, err) = client_handle.await.unwrap();
+    assert!(matches!(err, ConnectionError::Cancelled));
+
+    // Server sees the cancellation both as a notification and on the token
+    match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.method, method::NOTIFICATIONS_CANCELLED);
+            let p: CancelledParams = notif.params_as().unwrap();
+            assert_eq!(p.request_id, req.id);
+            assert_eq!(p.reason.as_deref(), Some("game aborted"));
+        }
+        _ => panic!("Expected notification"),
+    }
+    assert!(token.is_cancelled());
+}
+
+#[tokio::test]
+async fn test_progress_routed_to_caller() {
+    let (mut client, mut server) = connected_pair().await;
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    let client_handle = tokio::spawn(async move {
+        let result = client
+            .send_request_with_progress(
+                method::CHANNELS_OPEN,
+                Some(serde_json::json!({"type": "game_instance", "address": {}})),
+                progress_tx,
+            )
+            .await
+            .unwrap();
+        (client, result)
+    });
+
+    let req = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    let token = req.progress_token().expect("progress token in _meta");
+    server.send_progress(token.clone(), 1.0, Some(2.0)).await.unwrap();
+    server.send_progress(token, 2.0, Some(2.0)).await.unwrap();
+    server
+        .send_response(req.id, serde_json::json!({"ok": true}))
+        .await
+        .unwrap();
+
+    let (_client, result) = client_handle.await.unwrap();
+    assert_eq!(result["ok"], true);
+
+    let first = progress_rx.recv().await.unwrap();
+    assert_eq!(first.progress, 1.0);
+    assert_eq!(first.total, Some(2.0));
+    let second = progress_rx.recv().await.unwrap();
+    assert_eq!(second.progress, 2.0);
+}
+
+#[tokio::test]
+async fn test_unclaimed_request_gets_method_not_found() {
+    let (mut client, mut server) = connected_pair().await;
+    server.claim_methods([method::INITIALIZE]);
+
+    let err = client
+        .send_request("lobby/teleport", None)
+        .await
+        .unwrap_err();
+    match err {
+        ConnectionError::Rpc { code, message } => {
+            assert_eq!(code, ERR_METHOD_NOT_FOUND);
+            assert!(message.contains("lobby/teleport"));
+        }
+        other => panic!("Expected RPC error, got: {:?}", other),
+    }
+
+    // Claimed methods still reach the application
+    let client_handle = tokio::spawn(async move {
+        client.send_request(method::INITIALIZE, None).await.unwrap()
+    });
+    match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => {
+            assert_eq!(req.method, method::INITIALIZE);
+            server.send_response(req.id, serde_json::json!({})).await.unwrap();
+        }
+        _ => panic!("Expected request"),
+    }
+    client_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_uuid_request_ids() {
+    let (client_read, server_write) = tokio::io::duplex(4096);
+    let (server_read, client_write) = tokio::io::duplex(4096);
+
+    let mut client = McplConnection::from_parts_with_options(
+        Box::new(client_read),
+        Box::new(client_write),
+        ConnectionOptions {
+            id_generator: Box::new(UuidV7Ids),
+            ..Default::default()
+        },
+    );
+    let mut server = McplConnection::from_parts(
+        Box::new(server_read),
+        Box::new(server_write),
+    );
+
+    let client_handle = tokio::spawn(async move {
+        client.send_request(method::MODEL_INFO, None).await.unwrap()
+    });
+
+    match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => {
+            match &req.id {
+                JsonRpcId::String(s) => assert_eq!(s.len(), 36),
+                other => panic!("Expected string id, got: {:?}", other),
+            }
+            server.send_response(req.id, serde_json::json!({"id": "m"})).await.unwrap();
+        }
+        _ => panic!("Expected request"),
+    }
+
+    let result = client_handle.await.unwrap();
+    assert_eq!(result["id"], "m");
+}
+
+#[tokio::test]
+async fn test_unexpected_response_policy() {
+    let (client_read, server_write) = tokio::io::duplex(4096);
+    let (server_read, client_write) = tokio::io::duplex(4096);
+
+    let mut client = McplConnection::from_parts_with_options(
+        Box::new(client_read),
+        Box::new(client_write),
+        ConnectionOptions {
+            unexpected_responses: UnexpectedResponsePolicy::Error,
+            ..Default::default()
+        },
+    );
+    let mut server = McplConnection::from_parts(
+        Box::new(server_read),
+        Box::new(server_write),
+    );
+
+    let client_handle = tokio::spawn(async move {
+        client.send_request(method::MODEL_INFO, None).await.unwrap();
+        client
+    });
+
+    let req_id = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req.id,
+        _ => panic!("Expected request"),
+    };
+    server.send_response(req_id.clone(), serde_json::json!({})).await.unwrap();
+    let mut client = client_handle.await.unwrap();
+
+    // Answer the same request twice, then answer a request never sent
+    server.send_response(req_id.clone(), serde_json::json!({})).await.unwrap();
+    server.send_response(JsonRpcId::Number(99), serde_json::json!({})).await.unwrap();
+
+    match client.next_message().await.unwrap_err() {
+        ConnectionError::UnexpectedResponse { id, kind } => {
+            assert_eq!(id, req_id);
+            assert_eq!(kind, UnexpectedResponseKind::Duplicate);
+        }
+        other => panic!("Expected unexpected response, got: {:?}", other),
+    }
+    match client.next_message().await.unwrap_err() {
+        ConnectionError::UnexpectedResponse { id, kind } => {
+            assert_eq!(id, JsonRpcId::Number(99));
+            assert_eq!(kind, UnexpectedResponseKind::Unknown);
+        }
+        other => panic!("Expected unexpected response, got: {:?}", other),
+    }
+}
+
+/// Helper: duplex pair where the client uses custom options.
+fn duplex_pair_with_options(options: ConnectionOptions) -> (McplConnection, McplConnection) {
+    let (client_read, server_write) = tokio::io::duplex(4096);
+    let (server_read, client_write) = tokio::io::duplex(4096);
+    let client = McplConnection::from_parts_with_options(
+        Box::new(client_read),
+        Box::new(client_write),
+        options,
+    );
+    let server = McplConnection::from_parts(Box::new(server_read), Box::new(server_write));
+    (client, server)
+}
+
+/// Server sends `count` notifications, then answers one client request so the
+/// client has read every notification by the time `send_request` returns.
+async fn flood_then_sync(client: &mut McplConnection, server: McplConnection, count: i64) {
+    let server_handle = tokio::spawn(async move {
+        let mut server = server;
+        let req_id = match server.next_message().await.unwrap() {
+            mcpl_core::connection::IncomingMessage::Request(req) => req.id,
+            _ => panic!("Expected request"),
+        };
+        for n in 1..=count {
+            server
+                .send_notification("game/tick", Some(serde_json::json!({"n": n})))
+                .await
+                .unwrap();
+        }
+        server.send_response(req_id, serde_json::json!({})).await.unwrap();
+        server
+    });
+    client.send_request("test/sync", None).await.unwrap();
+    server_handle.await.unwrap();
+}
+
+fn tick_number(msg: mcpl_core::connection::IncomingMessage) -> i64 {
+    match msg {
+        mcpl_core::connection::IncomingMessage::Notification(notif) => {
+            notif.params_as::<serde_json::Value>().unwrap()["n"].as_i64().unwrap()
+        }
+        _ => panic!("Expected notification"),
+    }
+}
+
+#[tokio::test]
+async fn test_incoming_overflow_drop_oldest() {
+    let (mut client, server) = duplex_pair_with_options(ConnectionOptions {
+        incoming_capacity: Some(2),
+        overflow: OverflowPolicy::DropOldest,
+        ..Default::default()
+    });
+
+    flood_then_sync(&mut client, server, 3).await;
+
+    assert_eq!(client.incoming_depth(), 2);
+    assert_eq!(tick_number(client.next_message().await.unwrap()), 2);
+    assert_eq!(tick_number(client.next_message().await.unwrap()), 3);
+    assert_eq!(client.incoming_depth(), 0);
+}
+
+#[tokio::test]
+async fn test_incoming_overflow_error() {
+    let (mut client, server) = duplex_pair_with_options(ConnectionOptions {
+        incoming_capacity: Some(1),
+        overflow: OverflowPolicy::Error,
+        ..Default::default()
+    });
+
+    flood_then_sync(&mut client, server, 3).await;
+
+    match client.next_message().await.unwrap_err() {
+        ConnectionError::QueueFull { dropped } => assert_eq!(dropped, 2),
+        other => panic!("Expected QueueFull, got: {:?}", other),
+    }
+    assert_eq!(tick_number(client.next_message().await.unwrap()), 1);
+}
+
+#[tokio::test]
+async fn test_default_options_do_not_stall_requests_behind_notifications() {
+    let (mut client, server) = duplex_pair();
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        flood_then_sync(&mut client, server, 1100),
+    )
+    .await
+    .expect("the response should be read behind buffered notifications");
+    assert_eq!(client.incoming_depth(), 1100);
+    assert!(!client.is_read_paused());
+}
+
+#[tokio::test]
+async fn test_backpressure_pauses_reading() {
+    let (mut client, mut server) = duplex_pair_with_options(ConnectionOptions {
+        incoming_capacity: Some(2),
+        overflow: OverflowPolicy::Backpressure,
+        ..Default::default()
+    });
+
+    for n in 1..=5 {
+        server
+            .send_notification("game/tick", Some(serde_json::json!({"n": n})))
+            .await
+            .unwrap();
+    }
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while !client.is_read_paused() {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .expect("reader should pause on a full queue");
+    assert_eq!(client.incoming_depth(), 2);
+
+    // Nothing is lost: draining resumes reading in order
+    for n in 1..=5 {
+        assert_eq!(tick_number(client.next_message().await.unwrap()), n);
+    }
+    assert!(!client.is_read_paused());
+}
+
+#[tokio::test]
+async fn test_background_writer_prioritizes_responses() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    // Raw peer so the order of frames on the wire is observable
+    let (client_read, mut peer_write) = tokio::io::duplex(4096);
+    let (peer_read, client_write) = tokio::io::duplex(4096);
+    let mut client = McplConnection::from_parts_with_options(
+        Box::new(client_read),
+        Box::new(client_write),
+        ConnectionOptions {
+            background_writer: true,
+            ..Default::default()
+        },
+    );
+
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":7,\"method\":\"model/info\"}\n")
+        .await
+        .unwrap();
+    let req_id = match client.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req.id,
+        _ => panic!("Expected request"),
+    };
+
+    // Queue a flood of chunks, then the response, without yielding to the writer
+    for index in 0..50 {
+        client
+            .send_notification(
+                method::CHANNELS_OUTGOING_CHUNK,
+                Some(serde_json::json!({"index": index})),
+            )
+            .await
+            .unwrap();
+    }
+    client.send_response(req_id, serde_json::json!({})).await.unwrap();
+
+    let mut lines = tokio::io::BufReader::new(peer_read).lines();
+    let mut response_position = None;
+    for position in 0..51 {
+        let line = lines.next_line().await.unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        if value.get("result").is_some() {
+            response_position = Some(position);
+        }
+    }
+    assert!(response_position.unwrap() < 50);
+}
+
+#[tokio::test]
+async fn test_responder_replies_from_another_task() {
+    let (mut client, mut server) = connected_pair().await;
+
+    let server_handle = tokio::spawn(async move {
+        let mut handlers = Vec::new();
+        for _ in 0..2 {
+            let req = match server.next_message().await.unwrap() {
+                mcpl_core::connection::IncomingMessage::Request(req) => req,
+                _ => panic!("Expected request"),
+            };
+            let responder = server.responder(&req);
+            handlers.push(tokio::spawn(async move {
+                if req.method == method::STATE_ROLLBACK {
+                    responder
+                        .reply_error(ERR_CHECKPOINT_NOT_FOUND, "Checkpoint not found")
+                        .await
+                        .unwrap();
+                } else {
+                    responder.reply(serde_json::json!({"handled": req.method})).await.unwrap();
+                }
+            }));
+        }
+        for handler in handlers {
+            handler.await.unwrap();
+        }
+        server
+    });
+
+    let result = client.send_request(method::MODEL_INFO, None).await.unwrap();
+    assert_eq!(result["handled"], method::MODEL_INFO);
+    let err = client.send_request(method::STATE_ROLLBACK, None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_CHECKPOINT_NOT_FOUND, .. }));
+
+    server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_request_context() {
+    // The side with custom options plays the server here
+    let (mut server, mut client) = duplex_pair_with_options(ConnectionOptions {
+        request_deadline: Some(std::time::Duration::from_secs(30)),
+        ..Default::default()
+    });
+
+    let params = serde_json::json!({
+        "featureSet": "lobby",
+        "eventId": "evt_9",
+        "timestamp": "2026-02-12T00:00:00Z",
+        "payload": {"content": [{"type": "text", "text": "hi"}]},
+        "_meta": {"traceId": "abc"}
+    });
+    let client_handle = tokio::spawn(async move {
+        client.send_request(method::PUSH_EVENT, Some(params)).await.unwrap()
+    });
+
+    let req = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    let ctx = server.request_context(req);
+    assert_eq!(ctx.method(), method::PUSH_EVENT);
+    let p: PushEventParams = ctx.params().unwrap();
+    assert_eq!(p.event_id, "evt_9");
+    assert_eq!(ctx.meta_field("traceId").unwrap(), "abc");
+    let remaining = ctx.remaining().unwrap();
+    assert!(remaining > std::time::Duration::from_secs(25));
+    assert!(!ctx.is_cancelled());
+
+    ctx.reply(serde_json::json!({"accepted": true})).await.unwrap();
+    let result = client_handle.await.unwrap();
+    assert_eq!(result["accepted"], true);
+}
+
+struct GatewayInterceptor;
+
+impl mcpl_core::interceptor::Interceptor for GatewayInterceptor {
+    fn on_outgoing(&self, msg: &mut JsonRpcMessage) -> mcpl_core::interceptor::Intercept {
+        if let JsonRpcMessage::Request(req) = msg {
+            let mut params: serde_json::Value = req.params_as().unwrap_or_default();
+            params["_meta"] = serde_json::json!({"authToken": "secret"});
+            req.params = Some(serde_json::value::to_raw_value(&params).unwrap());
+        }
+        mcpl_core::interceptor::Intercept::Continue
+    }
+
+    fn on_incoming(&self, msg: &mut JsonRpcMessage) -> mcpl_core::interceptor::Intercept {
+        match msg {
+            JsonRpcMessage::Request(req) if req.method.starts_with("admin/") => {
+                mcpl_core::interceptor::Intercept::Reject(JsonRpcError {
+                    code: -32000,
+                    message: "Forbidden".into(),
+                    data: None,
+                })
+            }
+            _ => mcpl_core::interceptor::Intercept::Continue,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_interceptor_rewrites_and_rejects() {
+    let (mut client, mut server) = connected_pair().await;
+    client.add_interceptor(GatewayInterceptor);
+
+    // Outgoing rewrite: the server sees the injected auth token
+    let client_handle = tokio::spawn(async move {
+        client.send_request(method::MODEL_INFO, None).await.unwrap();
+        client
+    });
+    let req = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    assert_eq!(req.meta().unwrap()["authToken"], "secret");
+    server.send_response(req.id, serde_json::json!({})).await.unwrap();
+    let _client = client_handle.await.unwrap();
+
+    // Incoming rejection: answered by the interceptor, never queued
+    let err = server.send_request("admin/shutdown", None).await.unwrap_err();
+    match err {
+        ConnectionError::Rpc { code, message } => {
+            assert_eq!(code, -32000);
+            assert_eq!(message, "Forbidden");
+        }
+        other => panic!("Expected RPC error, got: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_wiretap_sees_raw_frames() {
+    use mcpl_core::wiretap::FrameDirection;
+
+    let (mut client, mut server) = connected_pair().await;
+    let mut frames = client.wiretap_channel();
+
+    client
+        .send_notification("test/hello", Some(serde_json::json!({"from": "client"})))
+        .await
+        .unwrap();
+    server.next_message().await.unwrap();
+    server.send_notification("test/hello", None).await.unwrap();
+    client.next_message().await.unwrap();
+
+    let outgoing = frames.recv().await.unwrap();
+    assert_eq!(outgoing.direction, FrameDirection::Outgoing);
+    assert_eq!(
+        outgoing.raw,
+        r#"{"jsonrpc":"2.0","method":"test/hello","params":{"from":"client"}}"#
+    );
+    let incoming = frames.recv().await.unwrap();
+    assert_eq!(incoming.direction, FrameDirection::Incoming);
+    assert_eq!(incoming.raw, r#"{"jsonrpc":"2.0","method":"test/hello"}"#);
+}
+
+#[tokio::test]
+async fn test_auto_ping_responder() {
+    let (mut server, mut client) = duplex_pair_with_options(ConnectionOptions {
+        answer_pings: true,
+        ..Default::default()
+    });
+
+    let result = client.send_request(method::PING, None).await.unwrap();
+    assert_eq!(result, serde_json::json!({}));
+
+    // Pings never reach the application
+    client.send_notification("test/after", None).await.unwrap();
+    match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.method, "test/after");
+        }
+        _ => panic!("Expected notification"),
+    }
+}
+
+#[tokio::test]
+async fn test_lifecycle_enforcement() {
+    use mcpl_core::connection::ConnectionState;
+
+    let (mut server, mut client) = duplex_pair_with_options(ConnectionOptions {
+        enforce_lifecycle: true,
+        ..Default::default()
+    });
+    assert_eq!(server.state(), ConnectionState::Uninitialized);
+
+    // Before the handshake the server refuses MCPL methods in both directions
+    let err = client.send_request(method::CHANNELS_LIST, None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_NOT_INITIALIZED, .. }));
+    let err = server
+        .send_notification(method::FEATURE_SETS_CHANGED, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::NotReady { state: ConnectionState::Uninitialized, .. }
+    ));
+
+    let client_handle = tokio::spawn(async move {
+        client.send_request(method::INITIALIZE, None).await.unwrap();
+        client
+    });
+    let req = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    assert_eq!(server.state(), ConnectionState::Initializing);
+    server.send_response(req.id, serde_json::json!({})).await.unwrap();
+    assert_eq!(server.state(), ConnectionState::Ready);
+    let mut client = client_handle.await.unwrap();
+    assert_eq!(client.state(), ConnectionState::Ready);
+
+    server
+        .send_notification(method::FEATURE_SETS_CHANGED, None)
+        .await
+        .unwrap();
+    client.next_message().await.unwrap();
+
+    server.close().await.unwrap();
+    assert_eq!(server.state(), ConnectionState::Closed);
+}
+
+#[tokio::test]
+async fn test_capability_enforcement() {
+    let (mut gated, mut peer) = duplex_pair_with_options(ConnectionOptions {
+        enforce_capabilities: true,
+        ..Default::default()
+    });
+    let caps = |caps: McplCapabilities| InitializeCapabilities {
+        experimental: Some(ExperimentalCapabilities { mcpl: Some(caps) }),
+        other: Default::default(),
+    };
+
+    // The peer offers rollback and push events; only rollback is accepted
+    let params = McplInitializeParams {
+        protocol_version: "2024-11-05".into(),
+        capabilities: caps(McplCapabilities {
+            rollback: Some(true),
+            push_events: Some(true),
+            ..McplCapabilities::new("0.4")
+        }),
+        client_info: ImplementationInfo { name: "host".into(), version: "1.0".into() },
+    };
+    let peer_handle = tokio::spawn(async move {
+        peer.send_request_typed::<_, serde_json::Value>(method::INITIALIZE, &params)
+            .await
+            .unwrap();
+        peer
+    });
+    let req = match gated.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    let result = McplInitializeResult {
+        protocol_version: "2024-11-05".into(),
+        capabilities: caps(McplCapabilities {
+            rollback: Some(true),
+            ..McplCapabilities::new("0.4")
+        }),
+        server_info: ImplementationInfo { name: "server".into(), version: "1.0".into() },
+    };
+    gated
+        .send_response(req.id, serde_json::to_value(result).unwrap())
+        .await
+        .unwrap();
+    let mut peer = peer_handle.await.unwrap();
+
+    // Outgoing: refused locally without reaching the peer
+    let err = gated.send_request(method::PUSH_EVENT, None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::NotNegotiated { ref method } if method == "push/event"));
+
+    // Incoming: answered on the application's behalf
+    let err = peer.send_request(method::PUSH_EVENT, None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_FEATURE_SET_NOT_ENABLED, .. }));
+
+    // Negotiated and ungated methods pass through
+    peer.send_notification(method::STATE_ROLLBACK, None).await.unwrap();
+    peer.send_notification("game/tick", None).await.unwrap();
+    for expected in [method::STATE_ROLLBACK, "game/tick"] {
+        match gated.next_message().await.unwrap() {
+            mcpl_core::connection::IncomingMessage::Notification(notif) => {
+                assert_eq!(notif.method, expected);
+            }
+            _ => panic!("Expected notification"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_strict_mode_answers_invalid_requests() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (conn_read, mut peer_write) = tokio::io::duplex(4096);
+    let (peer_read, conn_write) = tokio::io::duplex(4096);
+    let mut conn = McplConnection::from_parts_with_options(
+        Box::new(conn_read),
+        Box::new(conn_write),
+        ConnectionOptions {
+            strict: true,
+            ..Default::default()
+        },
+    );
+    let mut peer_lines = tokio::io::BufReader::new(peer_read).lines();
+
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"1.0\",\"id\":3,\"method\":\"model/info\"}\n")
+        .await
+        .unwrap();
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":4,\"method\":\"model/info\",\"params\":5}\n")
+        .await
+        .unwrap();
+    for expected_id in [3, 4] {
+        let line = peer_lines.next_line().await.unwrap().unwrap();
+        let resp: JsonRpcResponse = serde_json::from_str(&line).unwrap();
+        assert_eq!(resp.id, JsonRpcId::Number(expected_id));
+        assert_eq!(resp.error.unwrap().code, ERR_INVALID_REQUEST);
+    }
+
+    // A response carrying both result and error fails the waiting caller
+    let conn_handle = tokio::spawn(async move {
+        let err = conn.send_request(method::MODEL_INFO, None).await.unwrap_err();
+        (conn, err)
+    });
+    let line = peer_lines.next_line().await.unwrap().unwrap();
+    let req: JsonRpcRequest = serde_json::from_str(&line).unwrap();
+    let bad = serde_json::json!({
+        "jsonrpc": "2.0", "id": req.id, "result": {}, "error": {"code": 1, "message": "x"}
+    });
+    peer_write
+        .write_all(format!("{}\n", bad).as_bytes())
+        .await
+        .unwrap();
+    let (mut conn, err) = conn_handle.await.unwrap();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_INVALID_REQUEST, .. }));
+
+    // The session survives
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"test/alive\"}\n")
+        .await
+        .unwrap();
+    match conn.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.method, "test/alive");
+        }
+        _ => panic!("Expected notification"),
+    }
+}
+
+#[tokio::test]
+async fn test_recovers_from_parse_errors() {
+    use mcpl_core::connection::ParseErrorEvent;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let events: Arc<Mutex<Vec<ParseErrorEvent>>> = Arc::default();
+    let sink = events.clone();
+    let (conn_read, mut peer_write) = tokio::io::duplex(4096);
+    let (peer_read, conn_write) = tokio::io::duplex(4096);
+    let mut conn = McplConnection::from_parts_with_options(
+        Box::new(conn_read),
+        Box::new(conn_write),
+        ConnectionOptions {
+            recover_parse_errors: true,
+            on_parse_error: Some(Arc::new(move |event: &ParseErrorEvent| {
+                sink.lock().unwrap().push(event.clone());
+            })),
+            ..Default::default()
+        },
+    );
+    let mut peer_lines = tokio::io::BufReader::new(peer_read).lines();
+
+    // Truncated frame with a salvageable id gets a parse error response
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":7,\"method\":\"model/info\",\n")
+        .await
+        .unwrap();
+    let line = peer_lines.next_line().await.unwrap().unwrap();
+    let resp: JsonRpcResponse = serde_json::from_str(&line).unwrap();
+    assert_eq!(resp.id, JsonRpcId::Number(7));
+    assert_eq!(resp.error.unwrap().code, ERR_PARSE_ERROR);
+
+    // Garbage without an id is skipped silently
+    peer_write.write_all(b"not json\n").await.unwrap();
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"test/alive\"}\n")
+        .await
+        .unwrap();
+    match conn.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.method, "test/alive");
+        }
+        _ => panic!("Expected notification"),
+    }
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].id, Some(JsonRpcId::Number(7)));
+    assert_eq!(events[1].raw, "not json");
+    assert_eq!(events[1].id, None);
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    use mcpl_core::connection::{ConnectionState, IdlePolicy};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // Activity timestamps track both directions
+    let (mut server, mut client) = duplex_pair_with_options(ConnectionOptions::default());
+    assert!(client.last_sent_at().is_none());
+    client.send_notification("test/hello", None).await.unwrap();
+    server.next_message().await.unwrap();
+    assert!(client.last_sent_at().is_some());
+    assert!(server.last_received_at().is_some());
+    assert!(server.last_sent_at().is_none());
+
+    // The callback fires once per idle period
+    let fired = Arc::new(AtomicUsize::new(0));
+    let counter = fired.clone();
+    let (_server, _client) = duplex_pair_with_options(ConnectionOptions {
+        idle_timeout: Some(Duration::from_millis(30)),
+        on_idle: IdlePolicy::Callback(Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })),
+        ..Default::default()
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+    // The default policy closes the connection
+    let (mut server, _client) = duplex_pair_with_options(ConnectionOptions {
+        idle_timeout: Some(Duration::from_millis(30)),
+        ..Default::default()
+    });
+    let result = tokio::time::timeout(Duration::from_secs(1), server.next_message())
+        .await
+        .expect("idle connection was not closed");
+    assert!(matches!(result, Err(ConnectionError::Closed)));
+    assert_eq!(server.state(), ConnectionState::Closed);
+}
+
+#[tokio::test]
+async fn test_deferred_requests_resolve_independently() {
+    let (mut server, mut client) = connected_pair().await;
+
+    let first = client
+        .send_request_deferred("test/first", None)
+        .await
+        .unwrap();
+    let second = client
+        .send_request_deferred("test/second", None)
+        .await
+        .unwrap();
+    assert_ne!(first.id(), second.id());
+
+    // Answer in reverse order
+    let mut requests = Vec::new();
+    for _ in 0..2 {
+        match server.next_message().await.unwrap() {
+            mcpl_core::connection::IncomingMessage::Request(req) => requests.push(req),
+            _ => panic!("Expected request"),
+        }
+    }
+    for req in requests.into_iter().rev() {
+        let method = req.method.clone();
+        server
+            .send_response(req.id, serde_json::json!({ "method": method }))
+            .await
+            .unwrap();
+    }
+
+    let (first, second) = tokio::join!(first, second);
+    assert_eq!(first.unwrap()["method"], "test/first");
+    assert_eq!(second.unwrap()["method"], "test/second");
+}
+
+#[tokio::test]
+async fn test_typed_send_helpers() {
+    let (mut server, mut client) = connected_pair().await;
+
+    let server_handle = tokio::spawn(async move {
+        for reply in [
+            serde_json::json!({ "id": "m1", "vendor": "acme", "contextWindow": 8192 }),
+            serde_json::json!({ "id": "m1" }),
+        ] {
+            match server.next_message().await.unwrap() {
+                mcpl_core::connection::IncomingMessage::Request(req) => {
+                    assert_eq!(req.params_as::<serde_json::Value>().unwrap()["verbose"], true);
+                    server.send_response(req.id, reply).await.unwrap();
+                }
+                _ => panic!("Expected request"),
+            }
+        }
+        match server.next_message().await.unwrap() {
+            mcpl_core::connection::IncomingMessage::Notification(notif) => notif,
+            _ => panic!("Expected notification"),
+        }
+    });
+
+    let params = serde_json::json!({ "verbose": true });
+    let info: ModelInfo = client
+        .send_request_typed(method::MODEL_INFO, &params)
+        .await
+        .unwrap();
+    assert_eq!(info.context_window, 8192);
+
+    let err = client
+        .send_request_typed::<_, ModelInfo>(method::MODEL_INFO, &params)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::InvalidResult { ref method, .. } if method == method::MODEL_INFO
+    ));
+
+    let cancelled = CancelledParams {
+        request_id: JsonRpcId::Number(9),
+        reason: None,
+    };
+    client
+        .send_notification_typed(method::NOTIFICATIONS_CANCELLED, &cancelled)
+        .await
+        .unwrap();
+    let notif = server_handle.await.unwrap();
+    assert_eq!(notif.params_as::<serde_json::Value>().unwrap()["requestId"], 9);
+}
+
+#[tokio::test]
+async fn test_retry_policy_retries_transient_errors() {
+    use mcpl_core::connection::RetryPolicy;
+    use std::time::Duration;
+
+    const ERR_BUSY: i32 = -32050;
+    let (mut client, mut server) = duplex_pair_with_options(ConnectionOptions {
+        retry: Some(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            retryable_codes: [ERR_BUSY].into_iter().collect(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+
+    let server_handle = tokio::spawn(async move {
+        // Busy twice, then succeed; then a non-retryable error
+        let replies = [Some(ERR_BUSY), Some(ERR_BUSY), None, Some(ERR_INTERNAL_ERROR)];
+        for reply in replies {
+            let req = match server.next_message().await.unwrap() {
+                mcpl_core::connection::IncomingMessage::Request(req) => req,
+                _ => panic!("Expected request"),
+            };
+            match reply {
+                Some(code) => server.send_error(req.id, code, "busy").await.unwrap(),
+                None => server
+                    .send_response(req.id, serde_json::json!({ "ok": true }))
+                    .await
+                    .unwrap(),
+            }
+        }
+        server
+    });
+
+    let result = client.send_request("inference/request", None).await.unwrap();
+    assert_eq!(result["ok"], true);
+    let err = client.send_request("inference/request", None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_INTERNAL_ERROR, .. }));
+    server_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_malformed_frames_are_answered() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (conn_read, mut peer_write) = tokio::io::duplex(4096);
+    let (peer_read, conn_write) = tokio::io::duplex(4096);
+    let mut conn = McplConnection::from_parts(Box::new(conn_read), Box::new(conn_write));
+    let mut peer_lines = tokio::io::BufReader::new(peer_read).lines();
+
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":5}\n")
+        .await
+        .unwrap();
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":\"six\",\"method\":\n")
+        .await
+        .unwrap();
+    for (expected_id, expected_code) in [
+        (JsonRpcId::Number(5), ERR_INVALID_REQUEST),
+        (JsonRpcId::String("six".into()), ERR_PARSE_ERROR),
+    ] {
+        let line = peer_lines.next_line().await.unwrap().unwrap();
+        let resp: JsonRpcResponse = serde_json::from_str(&line).unwrap();
+        assert_eq!(resp.id, expected_id);
+        assert_eq!(resp.error.unwrap().code, expected_code);
+    }
+
+    // Both are still reported locally
+    assert!(matches!(
+        conn.next_message().await,
+        Err(ConnectionError::UnrecognizedMessage(_))
+    ));
+    assert!(matches!(conn.next_message().await, Err(ConnectionError::Json(_))));
+}
+
+#[tokio::test]
+async fn test_null_result_is_a_response() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (conn_read, mut peer_write) = tokio::io::duplex(4096);
+    let (peer_read, conn_write) = tokio::io::duplex(4096);
+    let mut conn = McplConnection::from_parts(Box::new(conn_read), Box::new(conn_write));
+    let mut peer_lines = tokio::io::BufReader::new(peer_read).lines();
+
+    let conn_handle =
+        tokio::spawn(async move { conn.send_request("test/nothing", None).await });
+    let line = peer_lines.next_line().await.unwrap().unwrap();
+    let req: JsonRpcRequest = serde_json::from_str(&line).unwrap();
+    let reply = serde_json::json!({ "jsonrpc": "2.0", "id": req.id, "result": null });
+    peer_write
+        .write_all(format!("{}\n", reply).as_bytes())
+        .await
+        .unwrap();
+    assert_eq!(conn_handle.await.unwrap().unwrap(), serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn test_half_close() {
+    use mcpl_core::connection::{ConnectionState, IncomingMessage};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (conn_read, mut peer_write) = tokio::io::duplex(4096);
+    let (peer_read, conn_write) = tokio::io::duplex(4096);
+    let mut conn = McplConnection::from_parts(Box::new(conn_read), Box::new(conn_write));
+    let mut peer_lines = tokio::io::BufReader::new(peer_read).lines();
+
+    // The peer sends a request and then closes its side
+    peer_write
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"test/slow\"}\n")
+        .await
+        .unwrap();
+    drop(peer_write);
+
+    let req = match conn.next_message().await.unwrap() {
+        IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    assert!(matches!(conn.next_message().await, Err(ConnectionError::Closed)));
+    assert!(conn.is_read_closed());
+    assert!(!conn.is_write_closed());
+    assert_eq!(conn.state(), ConnectionState::Closing);
+
+    // The pending response still goes out, but new requests cannot
+    conn.send_response(req.id, serde_json::json!({ "done": true }))
+        .await
+        .unwrap();
+    let line = peer_lines.next_line().await.unwrap().unwrap();
+    let resp: JsonRpcResponse = serde_json::from_str(&line).unwrap();
+    assert_eq!(resp.result_as::<serde_json::Value>().unwrap()["done"], true);
+    assert!(matches!(
+        conn.send_request("test/more", None).await,
+        Err(ConnectionError::Closed)
+    ));
+
+    conn.close().await.unwrap();
+    assert!(conn.is_write_closed());
+    assert_eq!(conn.state(), ConnectionState::Closed);
+    assert!(matches!(
+        conn.send_notification("test/late", None).await,
+        Err(ConnectionError::WriteClosed)
+    ));
+}
+
+#[tokio::test]
+async fn test_duplicate_replies_are_dropped() {
+    let (client_read, server_write) = tokio::io::duplex(4096);
+    let (server_read, client_write) = tokio::io::duplex(4096);
+    let mut client = McplConnection::from_parts_with_options(
+        Box::new(client_read),
+        Box::new(client_write),
+        ConnectionOptions {
+            unexpected_responses: UnexpectedResponsePolicy::Error,
+            ..Default::default()
+        },
+    );
+    let mut server = McplConnection::from_parts(Box::new(server_read), Box::new(server_write));
+
+    let handle = client.handle();
+    let reply = tokio::spawn(async move { handle.send_request("game/state", None).await });
+    let req = match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    let (req, responder) = server.request_context(req).into_parts();
+    server
+        .send_response(req.id, serde_json::json!({ "n": 1 }))
+        .await
+        .unwrap();
+    // The responder's late answer is dropped instead of reaching the peer
+    responder.reply(serde_json::json!({ "n": 2 })).await.unwrap();
+    assert_eq!(reply.await.unwrap().unwrap()["n"], 1);
+
+    server.send_notification("game/tick", None).await.unwrap();
+    match client.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.method, "game/tick")
+        }
+        _ => panic!("Expected notification"),
+    }
+}
+
+#[tokio::test]
+async fn test_dropping_connection_fails_handle_requests() {
+    let (client, mut server) = duplex_pair();
+    let handle = client.handle();
+
+    let reply = tokio::spawn(async move { handle.send_request(method::PING, None).await });
+    match server.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => assert_eq!(req.method, method::PING),
+        _ => panic!("Expected request"),
+    }
+    drop(client);
+
+    let result = tokio::time::timeout(std::time::Duration::from_secs(5), reply)
+        .await
+        .expect("request should resolve once the connection is dropped")
+        .unwrap();
+    assert!(matches!(result, Err(ConnectionError::Closed)));
+}
+
+#[tokio::test]
+async fn test_params_are_parsed_on_demand() {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut conn, mut peer) = raw_peer();
+    // 1e400 is out of range for serde_json::Value
+    let params = r#"{"name": "probe", "big": 1e400}"#;
+    let frame = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"game/probe","params":{params}}}"#);
+    peer.writer
+        .write_all(format!("{}\n", frame).as_bytes())
+        .await
+        .unwrap();
+
+    match conn.next_message().await.unwrap() {
+        mcpl_core::connection::IncomingMessage::Request(req) => {
+            assert_eq!(req.params.as_deref().unwrap().get(), params);
+            assert_eq!(req.param::<String>("name").as_deref(), Some("probe"));
+            assert!(req.params_as::<serde_json::Value>().is_err());
+        }
+        _ => panic!("Expected request"),
+    }
+}
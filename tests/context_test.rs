@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use mcpl_core::methods::*;
+use mcpl_core::types::ContentBlock;
+use mcpl_core::{
+    ConflictPolicy, ContextAssembler, ContextAssemblerOptions, InjectionConflict, TemplateError,
+    TemplateEscape, TemplateOptions, Tokenizer, TrimmedInjection,
+};
+
+fn injection(
+    namespace: &str,
+    position: ContextInjectionPosition,
+    text: &str,
+    priority: Option<i32>,
+) -> ContextInjection {
+    ContextInjection {
+        namespace: namespace.into(),
+        position,
+        content: ContextInjectionContent::Text(text.into()),
+        priority,
+        metadata: None,
+    }
+}
+
+#[test]
+fn test_context_assembler_merges_servers() {
+    use ContextInjectionPosition::*;
+
+    let mut assembler = ContextAssembler::new(ContextAssemblerOptions {
+        namespace_caps: HashMap::from([("telemetry".to_string(), 12)]),
+        default_cap: Some(40),
+        ..Default::default()
+    });
+    assembler.add_result(ContextBeforeInferenceResult {
+        feature_set: "game".into(),
+        context_injections: vec![
+            injection("game", AfterUser, "Board: e4 e5", None),
+            injection("game", System, "You are playing chess.", None),
+            injection(
+                "telemetry",
+                System,
+                "FPS 60, ping 20ms, 4 players",
+                Some(-1),
+            ),
+        ],
+    });
+    assembler.add_result(ContextBeforeInferenceResult {
+        feature_set: "chat".into(),
+        context_injections: vec![
+            injection(
+                "rules",
+                System,
+                "Never reveal the opponent's moves.",
+                Some(5),
+            ),
+            // Replaces the first server's injection only if more important
+            injection("game", System, "You are playing checkers.", None),
+            injection("game", AfterUser, "Board: e4 c5", Some(1)),
+            ContextInjection {
+                namespace: "lobby".into(),
+                position: BeforeUser,
+                content: ContextInjectionContent::Blocks(vec![
+                    ContentBlock::text("Ana joined."),
+                    ContentBlock::text("Ben left, saying a very long goodbye to everyone."),
+                ]),
+                priority: None,
+                metadata: None,
+            },
+        ],
+    });
+    assert_eq!(assembler.len(), 5);
+
+    let context = assembler.assemble();
+    let order: Vec<(&str, ContextInjectionPosition)> = context
+        .injections
+        .iter()
+        .map(|injection| (injection.namespace.as_str(), injection.position))
+        .collect();
+    assert_eq!(
+        order,
+        [
+            ("rules", System),
+            ("game", System),
+            ("telemetry", System),
+            ("lobby", BeforeUser),
+            ("game", AfterUser),
+        ]
+    );
+    assert_eq!(
+        context.system,
+        "Never reveal the opponent's moves.\n\nYou are playing chess.\n\nFPS 60, ping"
+    );
+    // The cap spans the blocks of an injection
+    assert_eq!(
+        context.before_user,
+        "Ana joined.\nBen left, saying a very long "
+    );
+    assert_eq!(context.segment(AfterUser), "Board: e4 c5");
+}
+
+/// Counts whitespace-separated words.
+struct WordTokenizer;
+
+impl Tokenizer for WordTokenizer {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+}
+
+#[test]
+fn test_context_assembler_trims_to_budget() {
+    use ContextInjectionPosition::*;
+
+    let injections = vec![
+        injection("rules", System, &"r".repeat(40), Some(5)),
+        injection("telemetry", System, &"t".repeat(40), Some(-1)),
+        injection("game", AfterUser, &"g".repeat(80), None),
+        ContextInjection {
+            namespace: "lobby".into(),
+            position: BeforeUser,
+            content: ContextInjectionContent::Blocks(vec![ContentBlock::text("l".repeat(20))]),
+            priority: Some(-1),
+            metadata: None,
+        },
+    ];
+    let mut assembler = ContextAssembler::new(ContextAssemblerOptions::default());
+    assembler.extend(injections.clone());
+    assert_eq!(assembler.assemble().tokens, 45);
+
+    // A quarter of 128 tokens: the least important go first
+    let model = ModelInfo {
+        id: "model".into(),
+        vendor: "test".into(),
+        context_window: 128,
+        capabilities: vec![],
+    };
+    let context = assembler.assemble_for(&model);
+    assert_eq!(context.tokens, 32);
+    assert_eq!(
+        context.trimmed,
+        [
+            TrimmedInjection {
+                namespace: "lobby".into(),
+                position: BeforeUser,
+                priority: -1,
+                removed_tokens: 5,
+                dropped: true,
+            },
+            TrimmedInjection {
+                namespace: "telemetry".into(),
+                position: System,
+                priority: -1,
+                removed_tokens: 8,
+                dropped: false,
+            },
+        ]
+    );
+    assert_eq!(
+        context.system,
+        format!("{}\n\n{}", "r".repeat(40), "t".repeat(8))
+    );
+    assert!(context.before_user.is_empty());
+
+    let mut assembler = ContextAssembler::new(ContextAssemblerOptions {
+        tokenizer: Arc::new(WordTokenizer),
+        ..Default::default()
+    });
+    assembler
+        .add(injection("rules", System, "Play fair and be kind", Some(1)))
+        .unwrap();
+    assembler
+        .add(injection("news", System, "Ana won the last game", None))
+        .unwrap();
+    let context = assembler.assemble_within(7);
+    assert_eq!(context.system, "Play fair and be kind\n\nAna won");
+    assert_eq!(context.trimmed[0].removed_tokens, 3);
+}
+
+#[test]
+fn test_context_assembler_namespace_conflicts() {
+    use ContextInjectionPosition::*;
+
+    let result = |feature_set: &str, text: &str| ContextBeforeInferenceResult {
+        feature_set: feature_set.into(),
+        context_injections: vec![injection("board", System, text, None)],
+    };
+    let assemble = |conflicts| {
+        let mut assembler = ContextAssembler::new(ContextAssemblerOptions {
+            conflicts,
+            ..Default::default()
+        });
+        let mut refused = assembler.add_result(result("chess", "e4 e5"));
+        refused.extend(assembler.add_result(result("replay", "d4 d5")));
+        (assembler.assemble().system, refused)
+    };
+
+    assert_eq!(
+        assemble(ConflictPolicy::MostImportant),
+        ("e4 e5".to_string(), vec![])
+    );
+    assert_eq!(
+        assemble(ConflictPolicy::LastWins),
+        ("d4 d5".to_string(), vec![])
+    );
+    assert_eq!(
+        assemble(ConflictPolicy::Concat(" | ".into())),
+        ("e4 e5 | d4 d5".to_string(), vec![])
+    );
+    assert_eq!(
+        assemble(ConflictPolicy::Error),
+        (
+            "e4 e5".to_string(),
+            vec![InjectionConflict::Duplicate {
+                namespace: "board".into(),
+                position: System,
+                feature_set: Some("replay".into()),
+            }]
+        )
+    );
+
+    // Owned namespaces only take injections from their owner
+    let mut assembler = ContextAssembler::new(ContextAssemblerOptions {
+        namespace_owners: HashMap::from([("board".to_string(), "replay".to_string())]),
+        ..Default::default()
+    });
+    assert_eq!(
+        assembler.add_result(result("chess", "e4 e5")),
+        [InjectionConflict::NotOwner {
+            namespace: "board".into(),
+            owner: "replay".into(),
+            feature_set: Some("chess".into()),
+        }]
+    );
+    assert!(assembler
+        .add(injection("board", System, "c4", None))
+        .is_err());
+    assert!(assembler.add_result(result("replay", "d4 d5")).is_empty());
+    assert_eq!(assembler.assemble().system, "d4 d5");
+}
+
+#[test]
+fn test_multi_modal_context_hooks() {
+    let image = ContentBlock::Image {
+        data: None,
+        uri: Some("https://example.com/board.png".into()),
+        mime_type: Some("image/png".into()),
+    };
+
+    // Hosts that only send text are still understood
+    let params: ContextBeforeInferenceParams = serde_json::from_value(serde_json::json!({
+        "inferenceId": "inf-1",
+        "conversationId": "conv-1",
+        "turnIndex": 0,
+        "userMessage": "Your move",
+        "model": { "id": "model-1", "vendor": "test", "contextWindow": 8192 }
+    }))
+    .unwrap();
+    assert!(params.user_content.is_none());
+    assert_eq!(params.user_blocks(), [ContentBlock::text("Your move")]);
+    assert!(!serde_json::to_value(&params)
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .contains_key("userContent"));
+
+    let params: ContextAfterInferenceParams = serde_json::from_value(serde_json::json!({
+        "inferenceId": "inf-1",
+        "conversationId": "conv-1",
+        "turnIndex": 0,
+        "userMessage": "What do you see?",
+        "userContent": [
+            { "type": "text", "text": "What do you see?" },
+            { "type": "image", "uri": "https://example.com/board.png", "mimeType": "image/png" }
+        ],
+        "assistantMessage": "A chess board.",
+        "model": { "id": "model-1", "vendor": "test", "contextWindow": 8192 },
+        "usage": { "inputTokens": 900, "outputTokens": 5 }
+    }))
+    .unwrap();
+    assert_eq!(params.user_blocks()[1], image);
+    assert_eq!(
+        params.assistant_blocks(),
+        [ContentBlock::text("A chess board.")]
+    );
+
+    // Servers inject images; the text segment skips them
+    let mut assembler = ContextAssembler::new(ContextAssemblerOptions::default());
+    assembler
+        .add(ContextInjection {
+            namespace: "board".into(),
+            position: ContextInjectionPosition::BeforeUser,
+            content: ContextInjectionContent::Blocks(vec![
+                ContentBlock::text("Current board:"),
+                image.clone(),
+            ]),
+            priority: None,
+            metadata: None,
+        })
+        .unwrap();
+    let context = assembler.assemble();
+    assert_eq!(context.before_user, "Current board:");
+    assert_eq!(
+        context.segment_blocks(ContextInjectionPosition::BeforeUser),
+        [ContentBlock::text("Current board:"), image]
+    );
+}
+
+#[test]
+fn test_context_injection_from_template() {
+    let state = serde_json::json!({
+        "turn": 12,
+        "player": { "name": "<Ana>", "title": null },
+        "units": [{ "kind": "knight", "hp": 7 }],
+        "log": "x".repeat(30),
+    });
+
+    let injection = ContextInjection::from_template(
+        "game",
+        "Turn {{/turn}}: {{ /player/name }}{{/player/title}} has {{/units|json}}. \\{{literal}}",
+        &state,
+    )
+    .unwrap();
+    assert_eq!(injection.namespace, "game");
+    assert_eq!(injection.position, ContextInjectionPosition::System);
+    let ContextInjectionContent::Text(text) = &injection.content else {
+        panic!("Expected text content");
+    };
+    assert_eq!(
+        text,
+        r#"Turn 12: &lt;Ana&gt; has [{"hp":7,"kind":"knight"}]. {{literal}}"#
+    );
+
+    let options = TemplateOptions {
+        max_value_chars: Some(10),
+        max_chars: Some(40),
+        escape: TemplateEscape::Json,
+    };
+    let render = |template| mcpl_core::template::render(template, &state, &options);
+    assert_eq!(render("{{/log}}").unwrap(), format!("{}…", "x".repeat(9)));
+    assert_eq!(
+        render(r#"{"name": "{{/player/name|raw}}"}"#).unwrap(),
+        r#"{"name": "<Ana>"}"#
+    );
+    assert_eq!(
+        render("{{/log}} {{/log}} {{/log}} {{/log}}"),
+        Err(TemplateError::TooLong { chars: 43, max: 40 })
+    );
+    assert_eq!(render("Turn {{/turn"), Err(TemplateError::Unclosed(5)));
+    assert!(matches!(
+        render("{{/score}}"),
+        Err(TemplateError::Pointer(_))
+    ));
+    assert_eq!(
+        render("{{/turn|upper}}"),
+        Err(TemplateError::UnknownFilter("upper".into()))
+    );
+}
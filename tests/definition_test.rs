@@ -0,0 +1,130 @@
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::{FeatureSet, HandlerResult, McplCapabilities, ServerDefinition};
+
+mod common;
+use common::duplex_pair;
+
+fn rolled_back(owner: &str, params: StateRollbackParams) -> HandlerResult<StateRollbackResult> {
+    Ok(StateRollbackResult {
+        checkpoint: format!("{}:{}", owner, params.checkpoint),
+        success: true,
+        reason: None,
+    })
+}
+
+#[tokio::test]
+async fn test_server_definition() {
+    let (capabilities, router) = ServerDefinition::new(McplCapabilities::new("0.4"))
+        .feature_set(
+            FeatureSet::new("game")
+                .description("Turn-based game state")
+                .on(method::STATE_ROLLBACK, |_ctx, params| async move {
+                    rolled_back("game", params)
+                })
+                .on("game/move", |_ctx, _params: serde_json::Value| async move {
+                    Ok(serde_json::json!({ "moved": true }))
+                }),
+        )
+        .feature_set(
+            FeatureSet::new("lobby")
+                .host_state(true)
+                .on(method::STATE_ROLLBACK, |_ctx, params| async move {
+                    rolled_back("lobby", params)
+                }),
+        )
+        .feature_set(FeatureSet::new("chat").on(
+            method::CONTEXT_BEFORE_INFERENCE,
+            |_ctx, _params: ContextBeforeInferenceParams| async move {
+                Ok(ContextBeforeInferenceResult {
+                    feature_set: "chat".into(),
+                    context_injections: vec![],
+                })
+            },
+        ))
+        .on(
+            "server/status",
+            |_ctx, _params: serde_json::Value| async move { Ok(serde_json::json!({ "up": true })) },
+        )
+        .build();
+
+    // Declarations and capabilities follow from the handlers
+    assert!(capabilities.has_rollback());
+    assert!(capabilities.has_before_inference_hook());
+    assert!(!capabilities.has_after_inference_hook());
+    let declared = capabilities.feature_sets.unwrap();
+    let names: Vec<&str> = declared.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, ["game", "lobby", "chat"]);
+    assert!(declared[0].rollback && declared[1].rollback && !declared[2].rollback);
+    assert!(declared[1].host_state);
+
+    let (serving, mut caller) = duplex_pair();
+    tokio::spawn(async move { router.serve(serving).await });
+
+    // Shared methods are routed by feature set
+    let rollback = |feature_set: &str| {
+        Some(serde_json::json!({ "featureSet": feature_set, "checkpoint": "cp-1" }))
+    };
+    let result = caller
+        .send_request(method::STATE_ROLLBACK, rollback("lobby"))
+        .await
+        .unwrap();
+    assert_eq!(result["checkpoint"], "lobby:cp-1");
+    let result = caller
+        .send_request(method::STATE_ROLLBACK, rollback("game"))
+        .await
+        .unwrap();
+    assert_eq!(result["checkpoint"], "game:cp-1");
+
+    let err = caller
+        .send_request(method::STATE_ROLLBACK, rollback("chat"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_UNKNOWN_FEATURE_SET,
+            ..
+        }
+    ));
+    let err = caller
+        .send_request(method::STATE_ROLLBACK, Some(serde_json::json!({ "checkpoint": "cp-1" })))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INVALID_PARAMS,
+            ..
+        }
+    ));
+
+    // Methods only one feature set handles need no featureSet
+    let result = caller.send_request("game/move", None).await.unwrap();
+    assert_eq!(result["moved"], true);
+    let before = ContextBeforeInferenceParams {
+        inference_id: "inf-1".into(),
+        conversation_id: "conv-1".into(),
+        turn_index: 0,
+        user_message: None,
+        user_content: None,
+        model: ModelInfo {
+            id: "model-1".into(),
+            vendor: "test".into(),
+            context_window: 200_000,
+            capabilities: vec![],
+        },
+    };
+    let result = caller
+        .send_request(
+            method::CONTEXT_BEFORE_INFERENCE,
+            Some(serde_json::to_value(before).unwrap()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result["featureSet"], "chat");
+
+    let result = caller.send_request("server/status", None).await.unwrap();
+    assert_eq!(result["up"], true);
+}
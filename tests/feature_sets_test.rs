@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+
+use mcpl_core::capabilities::McplCapabilities;
+use mcpl_core::feature_sets::MAX_DESCRIPTION_LEN;
+use mcpl_core::methods::*;
+use mcpl_core::server::{McplServer, McplServerHandler};
+use mcpl_core::{
+    EmptyWhitelist, FeatureSetRegistry, FeatureSetSnapshot, FeatureSetVersion,
+    UnresolvedDependencies, ValidationError, VersionSupport,
+};
+
+fn feature_set(name: &str) -> FeatureSetDeclaration {
+    FeatureSetDeclaration::named(name)
+}
+
+struct Server;
+
+impl McplServerHandler for Server {}
+
+#[test]
+fn test_feature_set_registry() {
+    let mut registry =
+        FeatureSetRegistry::from_declarations([feature_set("game"), feature_set("lobby")]);
+    assert!(registry.is_declared("game"));
+    assert!(!registry.is_enabled("game"));
+
+    let scope = ScopeConfig {
+        whitelist: Some(vec!["game.*".into()]),
+        blacklist: None,
+    };
+    let unknown = registry.apply_update(&FeatureSetsUpdateParams {
+        enabled: Some(vec!["game".into(), "lobby".into(), "chess".into()]),
+        disabled: None,
+        scopes: Some(HashMap::from([("game".to_string(), scope)])),
+    });
+    assert_eq!(unknown, ["chess"]);
+    assert_eq!(registry.enabled(), ["game", "lobby"]);
+    assert_eq!(
+        registry.scope("game").and_then(|s| s.whitelist.clone()),
+        Some(vec!["game.*".to_string()])
+    );
+
+    // Enabling wins over disabling the same name
+    registry.apply_update(&FeatureSetsUpdateParams {
+        enabled: Some(vec!["game".into()]),
+        disabled: Some(vec!["game".into(), "lobby".into()]),
+        scopes: None,
+    });
+    assert_eq!(registry.enabled(), ["game"]);
+
+    // Removing a feature set disables it and forgets its scope
+    registry.apply_changed(&FeatureSetsChangedParams {
+        added: Some(HashMap::from([("chess".to_string(), feature_set("chess"))])),
+        removed: Some(vec!["game".into()]),
+    });
+    assert!(!registry.is_declared("game"));
+    assert!(!registry.is_enabled("game"));
+    assert!(registry.scope("game").is_none());
+    let names: Vec<&str> = registry.declarations().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, ["chess", "lobby"]);
+}
+
+fn scope(whitelist: Option<&[&str]>, blacklist: Option<&[&str]>) -> ScopeConfig {
+    let patterns = |list: &[&str]| list.iter().map(|p| p.to_string()).collect();
+    ScopeConfig {
+        whitelist: whitelist.map(patterns),
+        blacklist: blacklist.map(patterns),
+    }
+}
+
+#[test]
+fn test_scope_permits() {
+    // No lists: everything is in scope
+    assert!(scope(None, None).permits("anything"));
+
+    // Globs: `*` spans any run of characters, `?` exactly one
+    let files = scope(Some(&["src/*.rs", "README.?d", "docs/*"]), None);
+    assert!(files.permits("src/lib.rs"));
+    assert!(files.permits("src/.rs"));
+    assert!(files.permits("README.md"));
+    assert!(!files.permits("README.txt"));
+    assert!(files.permits("docs/spec/scopes.md"));
+    assert!(!files.permits("tests/lib.rs"));
+
+    // The blacklist wins over the whitelist
+    let guarded = scope(Some(&["*"]), Some(&["*.secret", "private/*"]));
+    assert!(guarded.permits("notes.txt"));
+    assert!(!guarded.permits("keys.secret"));
+    assert!(!guarded.permits("private/notes.txt"));
+    assert!(!scope(None, Some(&["admin"])).permits("admin"));
+
+    // An empty whitelist allows all unless configured to deny all
+    let empty = scope(Some(&[]), Some(&["admin"]));
+    assert!(empty.permits("user"));
+    assert!(empty.permits_with("user", EmptyWhitelist::AllowAll));
+    assert!(!empty.permits_with("user", EmptyWhitelist::DenyAll));
+    assert!(!empty.permits("admin"));
+    assert!(scope(None, None).permits_with("user", EmptyWhitelist::DenyAll));
+}
+
+#[test]
+fn test_feature_set_diff() {
+    let old = FeatureSetRegistry::from_declarations([
+        feature_set("game"),
+        feature_set("lobby"),
+        feature_set("chat"),
+    ]);
+    let reloaded_game = FeatureSetDeclaration {
+        rollback: true,
+        ..feature_set("game")
+    };
+    let new = FeatureSetRegistry::from_declarations([
+        reloaded_game.clone(),
+        feature_set("lobby"),
+        feature_set("chess"),
+    ]);
+
+    let changed = FeatureSetRegistry::diff(&old, &new);
+    let added = changed.added.unwrap();
+    let mut names: Vec<&str> = added.keys().map(String::as_str).collect();
+    names.sort();
+    assert_eq!(names, ["chess", "game"]);
+    assert_eq!(added["game"], reloaded_game);
+    assert_eq!(changed.removed.unwrap(), ["chat"]);
+
+    // Applying the diff reproduces the new declarations
+    let mut applied = old.clone();
+    applied.apply_changed(&FeatureSetRegistry::diff(&old, &new));
+    assert!(applied.declarations().eq(new.declarations()));
+
+    let unchanged = FeatureSetRegistry::diff(&new, &new);
+    assert!(unchanged.added.is_none() && unchanged.removed.is_none());
+}
+
+#[test]
+fn test_feature_set_dependencies() {
+    let uses = |name: &str, uses: &[&str]| FeatureSetDeclaration {
+        uses: uses.iter().map(|u| u.to_string()).collect(),
+        ..feature_set(name)
+    };
+    let mut registry = FeatureSetRegistry::new();
+    registry.provide(["read_file", "chat-channel"]);
+
+    // Dependencies must be provided or declared before registering
+    assert_eq!(
+        registry.register(uses("editor", &["read_file", "write_file", "files"])),
+        Err(UnresolvedDependencies {
+            feature_set: "editor".into(),
+            missing: vec!["write_file".into(), "files".into()],
+        })
+    );
+    assert!(!registry.is_declared("editor"));
+    registry.provide(["write_file"]);
+    registry.register(uses("files", &["read_file"])).unwrap();
+    registry
+        .register(uses("editor", &["read_file", "write_file", "files"]))
+        .unwrap();
+    registry
+        .register(uses("ide", &["editor", "chat-channel"]))
+        .unwrap();
+    registry.register(feature_set("lobby")).unwrap();
+
+    // Enabling a feature set enables what it uses, transitively
+    assert_eq!(registry.dependencies("ide"), ["editor", "files", "ide"]);
+    registry.apply_update(&FeatureSetsUpdateParams {
+        enabled: Some(vec!["ide".into()]),
+        disabled: None,
+        scopes: None,
+    });
+    assert_eq!(registry.enabled(), ["editor", "files", "ide"]);
+}
+
+#[test]
+fn test_feature_set_validation() {
+    assert_eq!(feature_set("game.v2_beta-1").validate(), Ok(()));
+    assert_eq!(feature_set("").validate(), Err(ValidationError::EmptyName));
+    assert_eq!(
+        feature_set("game set").validate(),
+        Err(ValidationError::InvalidName {
+            name: "game set".into(),
+            character: ' ',
+        })
+    );
+    assert!(matches!(
+        feature_set("-game").validate(),
+        Err(ValidationError::InvalidName { character: '-', .. })
+    ));
+
+    let host_state = FeatureSetDeclaration {
+        host_state: true,
+        ..feature_set("game")
+    };
+    assert_eq!(
+        host_state.validate(),
+        Err(ValidationError::HostStateWithoutRollback("game".into()))
+    );
+    let rollback = FeatureSetDeclaration {
+        rollback: true,
+        ..host_state
+    };
+    assert_eq!(rollback.validate(), Ok(()));
+
+    let verbose = FeatureSetDeclaration {
+        description: Some("x".repeat(MAX_DESCRIPTION_LEN + 1)),
+        ..feature_set("game")
+    };
+    assert!(matches!(
+        verbose.validate(),
+        Err(ValidationError::DescriptionTooLong { len, .. }) if len == MAX_DESCRIPTION_LEN + 1
+    ));
+
+    assert_eq!(
+        FeatureSetDeclaration::validate_all(&[
+            feature_set("game"),
+            feature_set("lobby"),
+            feature_set("game"),
+        ]),
+        Err(ValidationError::DuplicateName("game".into()))
+    );
+
+    // Servers can refuse to start with invalid declarations
+    let server = McplServer::builder()
+        .handler(Server)
+        .capabilities(McplCapabilities {
+            feature_sets: Some(vec![feature_set("game"), feature_set("bad name")]),
+            ..McplCapabilities::new("0.4")
+        })
+        .try_build();
+    assert!(matches!(server, Err(ValidationError::InvalidName { .. })));
+}
+
+#[tokio::test]
+async fn test_watch_enabled_feature_sets() {
+    let mut registry =
+        FeatureSetRegistry::from_declarations([feature_set("telemetry"), feature_set("game")]);
+    let update = |enabled: &[&str], disabled: &[&str]| FeatureSetsUpdateParams {
+        enabled: Some(enabled.iter().map(|n| n.to_string()).collect()),
+        disabled: Some(disabled.iter().map(|n| n.to_string()).collect()),
+        scopes: None,
+    };
+    registry.apply_update(&update(&["telemetry", "game"], &[]));
+
+    let mut enabled = registry.watch_enabled();
+    assert!(enabled.borrow_and_update().contains("telemetry"));
+    let pusher = tokio::spawn(async move {
+        enabled
+            .wait_for(|enabled| !enabled.contains("telemetry"))
+            .await
+            .unwrap()
+            .clone()
+    });
+
+    // Updates that change nothing are not seen
+    let mut quiet = registry.watch_enabled();
+    quiet.borrow_and_update();
+    registry.apply_update(&update(&["game"], &["chess"]));
+    assert!(!quiet.has_changed().unwrap());
+
+    registry.apply_update(&update(&[], &["telemetry"]));
+    assert!(quiet.has_changed().unwrap());
+    let seen = pusher.await.unwrap();
+    assert_eq!(seen.into_iter().collect::<Vec<_>>(), ["game"]);
+}
+
+#[test]
+fn test_compiled_scopes() {
+    let chat = scope(
+        Some(&["chat.lobby.*", "chat.dm.??", "chat.general"]),
+        Some(&["chat.lobby.admin*"]),
+    );
+    let matcher = chat.compile();
+    for target in [
+        "chat.lobby.1",
+        "chat.lobby.",
+        "chat.dm.ab",
+        "chat.general",
+        "chat.lobby.admin",
+        "chat.lobby.admins",
+        "chat.generalist",
+        "chat.dm.abc",
+    ] {
+        assert_eq!(matcher.permits(target), chat.permits(target), "{}", target);
+    }
+    assert!(matcher.permits("chat.lobby.42"));
+    assert!(!matcher.permits("chat.lobby.admins"));
+    assert!(!matcher.permits("chat.generalist"));
+
+    // The registry compiles scopes as updates arrive
+    let mut registry = FeatureSetRegistry::from_declarations([feature_set("chat")]);
+    assert!(registry.permits("chat", "chat.anything"));
+    registry.apply_update(&FeatureSetsUpdateParams {
+        enabled: None,
+        disabled: None,
+        scopes: Some(HashMap::from([("chat".to_string(), chat)])),
+    });
+    assert!(registry.permits("chat", "chat.lobby.7"));
+    assert!(!registry.permits("chat", "chat.lobby.admin"));
+    assert!(!registry.permits("chat", "game.move"));
+    assert!(registry
+        .scope_matcher("chat")
+        .is_some_and(|m| m.permits_with("chat.general", EmptyWhitelist::DenyAll)));
+}
+
+#[test]
+fn test_declaration_builder() {
+    let game = FeatureSetDeclaration::named("game")
+        .description("Turn-based game state")
+        .uses(["commands", "board-channel"])
+        .rollback(true);
+    assert_eq!(
+        serde_json::to_value(&game).unwrap(),
+        serde_json::json!({
+            "name": "game",
+            "description": "Turn-based game state",
+            "uses": ["commands", "board-channel"],
+            "rollback": true,
+            "hostState": false,
+        })
+    );
+
+    let lobby = FeatureSetDeclaration::host_managed("lobby");
+    assert!(lobby.host_state && lobby.rollback);
+    assert_eq!(lobby.validate(), Ok(()));
+}
+
+#[test]
+fn test_feature_set_versions() {
+    let v = |s: &str| s.parse::<FeatureSetVersion>().unwrap();
+    assert_eq!(v("2"), FeatureSetVersion::new(2, 0, 0));
+    assert_eq!(v("1.4.2-beta+7"), FeatureSetVersion::new(1, 4, 2));
+    assert!("1.x".parse::<FeatureSetVersion>().is_err());
+    assert!("1.2.3.4".parse::<FeatureSetVersion>().is_err());
+
+    let game = FeatureSetDeclaration::named("game")
+        .version("1.4.0")
+        .min_host_version("0.9");
+    assert_eq!(
+        serde_json::to_value(&game).unwrap()["minHostVersion"],
+        "0.9"
+    );
+    let host = v("1.0.0");
+    assert_eq!(game.version_support(host, None), VersionSupport::Supported);
+    assert_eq!(
+        game.version_support(host, Some(v("1.5"))),
+        VersionSupport::Supported
+    );
+    assert_eq!(
+        game.version_support(host, Some(v("1.2"))),
+        VersionSupport::Downgraded(v("1.2"))
+    );
+    assert!(matches!(
+        game.version_support(host, Some(v("0.8"))),
+        VersionSupport::Refused(_)
+    ));
+    assert!(matches!(
+        game.version_support(v("0.8.5"), None),
+        VersionSupport::Refused(_)
+    ));
+
+    let registry = FeatureSetRegistry::from_declarations([
+        game,
+        FeatureSetDeclaration::named("lobby").version("3.0"),
+        FeatureSetDeclaration::named("chat"),
+    ]);
+    let support = registry.version_support(
+        host,
+        &HashMap::from([
+            ("lobby".to_string(), v("2.1")),
+            ("chat".to_string(), v("1")),
+        ]),
+    );
+    assert_eq!(support["game"], VersionSupport::Supported);
+    assert!(matches!(support["lobby"], VersionSupport::Refused(_)));
+    assert_eq!(support["chat"], VersionSupport::Supported);
+
+    assert!(matches!(
+        FeatureSetDeclaration::named("game")
+            .version("one")
+            .validate(),
+        Err(ValidationError::InvalidVersion { .. })
+    ));
+}
+
+#[test]
+fn test_registry_snapshot() {
+    let mut registry =
+        FeatureSetRegistry::from_declarations([feature_set("chat"), feature_set("game")]);
+    let chat_scope = scope(Some(&["chat.lobby.*"]), None);
+    registry.apply_update(&FeatureSetsUpdateParams {
+        enabled: Some(vec!["chat".into()]),
+        disabled: None,
+        scopes: Some(HashMap::from([("chat".to_string(), chat_scope.clone())])),
+    });
+
+    // Round-trips through JSON, e.g. a file kept across restarts
+    let saved = serde_json::to_string(&registry.snapshot()).unwrap();
+    let snapshot: FeatureSetSnapshot = serde_json::from_str(&saved).unwrap();
+    assert_eq!(snapshot, registry.snapshot());
+    assert_eq!(snapshot.enabled, ["chat"]);
+
+    let mut restored = FeatureSetRegistry::new();
+    let enabled = restored.watch_enabled();
+    restored.restore(snapshot.clone());
+    assert!(enabled.has_changed().unwrap());
+    assert!(restored.is_enabled("chat") && !restored.is_enabled("game"));
+    assert_eq!(restored.scope("chat"), Some(&chat_scope));
+    assert!(restored.permits("chat", "chat.lobby.1"));
+    assert!(!restored.permits("chat", "chat.dm.1"));
+
+    // Re-applied to a server after reconnecting
+    let update = snapshot.update_params();
+    assert_eq!(update.enabled.as_deref(), Some(&["chat".to_string()][..]));
+    assert_eq!(update.scopes.unwrap()["chat"], chat_scope);
+}
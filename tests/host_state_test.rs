@@ -0,0 +1,314 @@
+use mcpl_core::json_pointer::{self, PointerError};
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::{apply_patch, HostStateContainer, HostStateSnapshot, PatchErrorKind, StateError};
+use serde_json::json;
+
+fn op(op: JsonPatchOp, path: &str, value: Option<serde_json::Value>) -> JsonPatchOperation {
+    JsonPatchOperation {
+        op,
+        path: path.into(),
+        value,
+        from: None,
+    }
+}
+
+fn moved(op: JsonPatchOp, from: &str, path: &str) -> JsonPatchOperation {
+    JsonPatchOperation {
+        op,
+        path: path.into(),
+        value: None,
+        from: Some(from.into()),
+    }
+}
+
+#[test]
+fn test_json_pointer() {
+    assert_eq!(json_pointer::escape("a/b~c"), "a~1b~0c");
+    assert_eq!(json_pointer::unescape("a~1b~0c").unwrap(), "a/b~c");
+    assert!(json_pointer::unescape("a~2").is_err());
+    assert_eq!(json_pointer::push("/units", "a/b"), "/units/a~1b");
+    assert_eq!(json_pointer::tokens("/a~1b/0/").unwrap(), ["a/b", "0", ""]);
+    assert_eq!(
+        json_pointer::split_last("/units/a~1b").unwrap(),
+        Some(("/units", "a/b".to_string()))
+    );
+    assert_eq!(json_pointer::split_last("").unwrap(), None);
+    assert_eq!(json_pointer::array_index("10"), Some(10));
+    assert_eq!(json_pointer::array_index("01"), None);
+
+    let mut doc = json!({ "units": [{ "hp": 3 }], "a/b": null });
+    assert_eq!(json_pointer::get(&doc, "/units/0/hp").unwrap(), &json!(3));
+    assert_eq!(json_pointer::get(&doc, "/a~1b").unwrap(), &json!(null));
+    *json_pointer::get_mut(&mut doc, "/units/0/hp").unwrap() = json!(2);
+    assert_eq!(
+        json_pointer::set(&mut doc, "/units/0/hp", json!(1)).unwrap(),
+        Some(json!(2))
+    );
+    assert_eq!(
+        json_pointer::set(&mut doc, "/units/-", json!({ "hp": 9 })).unwrap(),
+        None
+    );
+    assert_eq!(
+        json_pointer::remove(&mut doc, "/units/1").unwrap(),
+        json!({ "hp": 9 })
+    );
+    assert_eq!(doc, json!({ "units": [{ "hp": 1 }], "a/b": null }));
+
+    assert_eq!(
+        json_pointer::get(&doc, "/units/1"),
+        Err(PointerError::NotFound("/units/1".into()))
+    );
+    assert_eq!(
+        json_pointer::set(&mut doc, "/units/5", json!(0)),
+        Err(PointerError::InvalidIndex("/units/5".into()))
+    );
+    assert_eq!(
+        json_pointer::get(&doc, "units"),
+        Err(PointerError::Invalid("units".into()))
+    );
+}
+
+#[test]
+fn test_apply_patch() {
+    let mut doc = json!({ "foo": ["bar", "baz"], "a/b": { "~c": 1 } });
+    apply_patch(
+        &mut doc,
+        &[
+            op(JsonPatchOp::Add, "/foo/1", Some(json!("qux"))),
+            op(JsonPatchOp::Add, "/foo/-", Some(json!("end"))),
+            op(JsonPatchOp::Remove, "/foo/0", None),
+            op(JsonPatchOp::Replace, "/a~1b/~0c", Some(json!(2))),
+            moved(JsonPatchOp::Copy, "/a~1b", "/copy"),
+            moved(JsonPatchOp::Move, "/foo/2", "/last"),
+            op(JsonPatchOp::Test, "/copy", Some(json!({ "~c": 2 }))),
+        ],
+    )
+    .unwrap();
+    assert_eq!(
+        doc,
+        json!({
+            "foo": ["qux", "baz"],
+            "a/b": { "~c": 2 },
+            "copy": { "~c": 2 },
+            "last": "end"
+        })
+    );
+
+    // A failing operation leaves the document as it was
+    let before = doc.clone();
+    let err = apply_patch(
+        &mut doc,
+        &[
+            op(JsonPatchOp::Remove, "/last", None),
+            op(JsonPatchOp::Test, "/copy/~0c", Some(json!(3))),
+        ],
+    )
+    .unwrap_err();
+    assert_eq!(err.index, 1);
+    assert_eq!(err.kind, PatchErrorKind::TestFailed("/copy/~0c".into()));
+    assert_eq!(doc, before);
+    let rpc = JsonRpcError::from(StateError::from(err));
+    assert_eq!(rpc.code, ERR_PATCH_FAILED);
+    assert_eq!(rpc.data.unwrap()["index"], 1);
+
+    for (patch, kind) in [
+        (
+            op(JsonPatchOp::Remove, "/missing", None),
+            PatchErrorKind::PathNotFound("/missing".into()),
+        ),
+        (
+            op(JsonPatchOp::Add, "/foo/5", Some(json!(0))),
+            PatchErrorKind::InvalidIndex("/foo/5".into()),
+        ),
+        (
+            op(JsonPatchOp::Add, "/foo/01", Some(json!(0))),
+            PatchErrorKind::InvalidIndex("/foo/01".into()),
+        ),
+        (
+            op(JsonPatchOp::Replace, "foo", Some(json!(0))),
+            PatchErrorKind::InvalidPointer("foo".into()),
+        ),
+        (
+            op(JsonPatchOp::Add, "/x", None),
+            PatchErrorKind::MissingField("value"),
+        ),
+        (
+            moved(JsonPatchOp::Move, "/a~1b", "/a~1b/inner"),
+            PatchErrorKind::MoveIntoChild {
+                from: "/a~1b".into(),
+                path: "/a~1b/inner".into(),
+            },
+        ),
+    ] {
+        assert_eq!(apply_patch(&mut doc, &[patch]).unwrap_err().kind, kind);
+    }
+}
+
+#[test]
+fn test_host_state_container() {
+    let turn = |checkpoint: &str, score: i64| HostManagedState {
+        checkpoint: checkpoint.into(),
+        patch: Some(vec![op(JsonPatchOp::Replace, "/score", Some(json!(score)))]),
+    };
+    let mut states = HostStateContainer::new().snapshot_interval(2);
+    states.insert("game", json!({ "score": 0 }));
+    for (i, checkpoint) in ["cp-1", "cp-2", "cp-3", "cp-4"].into_iter().enumerate() {
+        let recorded = states
+            .apply("game", &turn(checkpoint, i as i64 + 1))
+            .unwrap();
+        assert_eq!(recorded.feature_set, "game");
+    }
+    assert_eq!(states.state("game"), Some(&json!({ "score": 4 })));
+    assert_eq!(states.head("game"), Some("cp-4"));
+
+    // Rebuilt from a snapshot or by replay, whichever the checkpoint has
+    for (checkpoint, score) in [("cp-1", 1), ("cp-2", 2), ("cp-3", 3)] {
+        let state = states.rollback("game", checkpoint).unwrap();
+        assert_eq!(*state, json!({ "score": score }));
+    }
+
+    // Applying after a rollback branches from the restored checkpoint
+    states.rollback("game", "cp-1").unwrap();
+    let branched = states.apply("game", &turn("cp-5", 50)).unwrap();
+    assert_eq!(branched.parent.as_deref(), Some("cp-1"));
+    assert_eq!(states.checkpoints("game").len(), 5);
+    assert_eq!(
+        *states.rollback("game", "cp-4").unwrap(),
+        json!({ "score": 4 })
+    );
+
+    let injection = states.render("game").unwrap();
+    assert_eq!(injection.namespace, "game");
+    assert!(matches!(
+        injection.content,
+        ContextInjectionContent::Text(text) if text.contains("\"score\": 4")
+    ));
+    assert_eq!(injection.metadata, Some(json!({ "checkpoint": "cp-4" })));
+
+    // Failures change nothing
+    assert!(matches!(
+        states.apply("game", &turn("cp-2", 9)),
+        Err(StateError::DuplicateCheckpoint(_))
+    ));
+    let bad = HostManagedState {
+        checkpoint: "cp-6".into(),
+        patch: Some(vec![op(JsonPatchOp::Remove, "/lives", None)]),
+    };
+    assert!(matches!(
+        states.apply("game", &bad),
+        Err(StateError::Patch(_))
+    ));
+    assert_eq!(states.head("game"), Some("cp-4"));
+    assert!(matches!(
+        states.rollback("game", "cp-9"),
+        Err(StateError::CheckpointNotFound(_))
+    ));
+    assert!(matches!(
+        states.apply("lobby", &turn("cp-1", 1)),
+        Err(StateError::UnknownFeatureSet(_))
+    ));
+}
+
+#[test]
+fn test_host_state_transaction() {
+    let mut states = HostStateContainer::new();
+    states.insert("game", json!({ "gold": 10, "units": [] }));
+    let spend = |gold: i64| {
+        vec![
+            op(JsonPatchOp::Test, "/gold", Some(json!(gold))),
+            op(JsonPatchOp::Replace, "/gold", Some(json!(gold - 5))),
+        ]
+    };
+
+    // Two tools in one turn: both patches land as one checkpoint
+    let mut tx = states.transaction("game").unwrap();
+    tx.stage(&spend(10)).unwrap();
+    tx.stage_state(&HostManagedState {
+        checkpoint: "tool-2".into(),
+        patch: Some(vec![op(
+            JsonPatchOp::Add,
+            "/units/-",
+            Some(json!("archer")),
+        )]),
+    })
+    .unwrap();
+    // Checked against the staged state: gold is 5 by now, not 10
+    let err = tx.stage(&spend(10)).unwrap_err();
+    assert_eq!(err.index, 0);
+    assert_eq!(tx.staged().len(), 3);
+    assert_eq!(*tx.state(), json!({ "gold": 5, "units": ["archer"] }));
+    let checkpoint = tx.commit("turn-1").unwrap();
+    assert_eq!(checkpoint.id, "turn-1");
+    assert_eq!(states.checkpoints("game").len(), 1);
+    assert_eq!(
+        states.state("game"),
+        Some(&json!({ "gold": 5, "units": ["archer"] }))
+    );
+
+    // A discarded transaction leaves no trace
+    let mut tx = states.transaction("game").unwrap();
+    tx.stage(&spend(5)).unwrap();
+    tx.discard();
+    assert_eq!(states.state("game").unwrap()["gold"], json!(5));
+    assert_eq!(states.head("game"), Some("turn-1"));
+
+    // Nor does a commit that fails
+    let mut tx = states.transaction("game").unwrap();
+    tx.stage(&spend(5)).unwrap();
+    assert!(matches!(
+        tx.commit("turn-1"),
+        Err(StateError::DuplicateCheckpoint(_))
+    ));
+    assert_eq!(states.state("game").unwrap()["gold"], json!(5));
+    assert!(matches!(
+        states.transaction("lobby"),
+        Err(StateError::UnknownFeatureSet(_))
+    ));
+}
+
+#[test]
+fn test_host_state_snapshot_export_import() {
+    let turn = |checkpoint: &str, score: i64| HostManagedState {
+        checkpoint: checkpoint.into(),
+        patch: Some(vec![op(JsonPatchOp::Replace, "/score", Some(json!(score)))]),
+    };
+    let mut origin = HostStateContainer::new().snapshot_interval(4);
+    origin.insert("game", json!({ "score": 0 }));
+    for (checkpoint, score) in [("cp-1", 1), ("cp-2", 2), ("cp-3", 3)] {
+        origin.apply("game", &turn(checkpoint, score)).unwrap();
+    }
+    origin.rollback("game", "cp-1").unwrap();
+    origin.apply("game", &turn("cp-4", 40)).unwrap();
+
+    // Through JSON, as when archived or sent to another host
+    let blob = serde_json::to_string(&origin.export_snapshot("game").unwrap()).unwrap();
+    let snapshot: HostStateSnapshot = serde_json::from_str(&blob).unwrap();
+    assert_eq!(snapshot.checkpoints.len(), 4);
+    assert_eq!(snapshot.head.as_deref(), Some("cp-4"));
+
+    let mut migrated = HostStateContainer::new().snapshot_interval(1);
+    migrated.import_snapshot(snapshot.clone()).unwrap();
+    assert_eq!(migrated.state("game"), Some(&json!({ "score": 40 })));
+    assert_eq!(migrated.checkpoints("game"), origin.checkpoints("game"));
+    assert_eq!(
+        *migrated.rollback("game", "cp-3").unwrap(),
+        json!({ "score": 3 })
+    );
+
+    // A snapshot that does not replay to its state is refused
+    let mut tampered = snapshot.clone();
+    tampered.state = json!({ "score": 9000 });
+    assert!(matches!(
+        migrated.import_snapshot(tampered),
+        Err(StateError::InvalidSnapshot(_))
+    ));
+    let mut reordered = snapshot;
+    reordered.checkpoints.swap(0, 1);
+    assert!(matches!(
+        migrated.import_snapshot(reordered),
+        Err(StateError::InvalidSnapshot(_))
+    ));
+    assert_eq!(migrated.head("game"), Some("cp-3"));
+    assert!(origin.export_snapshot("lobby").is_none());
+}
@@ -0,0 +1,218 @@
+use mcpl_core::capabilities::*;
+use mcpl_core::connection::{ConnectionError, IncomingMessage};
+use mcpl_core::host::{HandlerResult, McplHost, McplHostHandler};
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::{McplClient, RequestContext, SequenceCheck};
+
+use tokio::sync::mpsc;
+
+mod common;
+use common::duplex_pair;
+
+struct TestHost {
+    changes: mpsc::UnboundedSender<FeatureSetsChangedParams>,
+}
+
+impl McplHostHandler for TestHost {
+    async fn on_push_event(
+        &self,
+        _ctx: &RequestContext,
+        params: PushEventParams,
+    ) -> HandlerResult<PushEventResult> {
+        if params.event_id.starts_with("spam") {
+            return Ok(PushEventResult {
+                accepted: false,
+                inference_id: None,
+                reason: Some("flood".into()),
+            });
+        }
+        Ok(PushEventResult {
+            accepted: true,
+            inference_id: Some(format!("inf-{}", params.event_id)),
+            reason: None,
+        })
+    }
+
+    async fn on_push_out_of_sequence(
+        &self,
+        _ctx: &RequestContext,
+        _params: &PushEventParams,
+        check: SequenceCheck,
+    ) -> Option<PushEventResult> {
+        matches!(check, SequenceCheck::Stale { .. }).then(|| PushEventResult {
+            accepted: false,
+            inference_id: None,
+            reason: Some("stale".into()),
+        })
+    }
+
+    async fn on_feature_sets_changed(&self, params: FeatureSetsChangedParams) {
+        self.changes.send(params).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_host_initializes_and_dispatches() {
+    let (host_conn, mut server) = duplex_pair();
+
+    // Minimal server: answer initialize, then drive the host
+    let server_task = tokio::spawn(async move {
+        let req = match server.next_message().await.unwrap() {
+            IncomingMessage::Request(req) => req,
+            _ => panic!("Expected initialize"),
+        };
+        assert_eq!(req.method, method::INITIALIZE);
+        let params: McplInitializeParams = req.params_as().unwrap();
+        assert_eq!(params.client_info.name, "test-host");
+        let result = McplInitializeResult {
+            protocol_version: params.protocol_version,
+            capabilities: InitializeCapabilities {
+                experimental: Some(ExperimentalCapabilities {
+                    mcpl: Some(McplCapabilities {
+                        push_events: Some(true),
+                        ..McplCapabilities::new("0.4")
+                    }),
+                }),
+                other: Default::default(),
+            },
+            server_info: ImplementationInfo {
+                name: "game".into(),
+                version: "1.0.0".into(),
+            },
+        };
+        server
+            .send_response(req.id, serde_json::to_value(result).unwrap())
+            .await
+            .unwrap();
+        match server.next_message().await.unwrap() {
+            IncomingMessage::Notification(notif) => {
+                assert_eq!(notif.method, method::NOTIFICATIONS_INITIALIZED)
+            }
+            _ => panic!("Expected notifications/initialized"),
+        }
+        server
+    });
+
+    let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+    let host = McplHost::builder()
+        .handler(TestHost { changes: changes_tx })
+        .host_info(ImplementationInfo {
+            name: "test-host".into(),
+            version: "0.1.0".into(),
+        })
+        .capabilities(McplCapabilities::new("0.4"))
+        .model_info(ModelInfo {
+            id: "model-1".into(),
+            vendor: "test".into(),
+            context_window: 200_000,
+            capabilities: vec![],
+        })
+        .connect(host_conn)
+        .await
+        .unwrap();
+    let mut server = server_task.await.unwrap();
+
+    assert_eq!(host.server_info().name, "game");
+    assert!(host.server_capabilities().unwrap().has_push_events());
+
+    // Server → host requests reach the handler
+    let event = PushEventParams {
+        feature_set: "game".into(),
+        event_id: "e1".into(),
+        timestamp: "2025-01-01T00:00:00Z".into(),
+        origin: None,
+        sequence: None,
+        priority: None,
+        expires_at: None,
+        payload: PushEventPayload {
+            content: vec![ContentBlock::text("turn started")],
+        },
+    };
+    let result: PushEventResult = server
+        .send_request_typed(method::PUSH_EVENT, &event)
+        .await
+        .unwrap();
+    assert!(result.accepted);
+    assert_eq!(result.inference_id.as_deref(), Some("inf-e1"));
+
+    // Sequenced events: a repeat is answered by the out-of-sequence hook,
+    // a gap still reaches the handler
+    let sequenced = |id: &str, sequence| PushEventParams {
+        event_id: id.into(),
+        sequence: Some(sequence),
+        ..event.clone()
+    };
+    let client = McplClient::from(&server);
+    let first = client.push_event(&sequenced("s1", 1)).await.unwrap();
+    assert!(first.accepted);
+    let repeat = client.push_event(&sequenced("s1-again", 1)).await.unwrap();
+    assert_eq!(repeat.reason.as_deref(), Some("stale"));
+    let after_gap = client.push_event(&sequenced("s3", 3)).await.unwrap();
+    assert!(after_gap.accepted);
+
+    // Batched events are answered one by one, in order
+    let batch = client
+        .push_event_batch(vec![
+            PushEventParams {
+                event_id: "spam-1".into(),
+                ..event.clone()
+            },
+            PushEventParams {
+                event_id: "e2".into(),
+                ..event.clone()
+            },
+        ])
+        .await
+        .unwrap();
+    let ids: Vec<&str> = batch.results.iter().map(|r| r.event_id.as_str()).collect();
+    assert_eq!(ids, ["spam-1", "e2"]);
+    assert!(!batch.all_accepted());
+    assert_eq!(batch.get("spam-1").unwrap().reason.as_deref(), Some("flood"));
+    assert_eq!(batch.get("e2").unwrap().inference_id.as_deref(), Some("inf-e2"));
+    assert_eq!(batch.rejected().count(), 1);
+
+    // Baseline methods have built-in answers
+    let model: ModelInfoResult = server
+        .send_request_typed(method::MODEL_INFO, &serde_json::json!({}))
+        .await
+        .unwrap();
+    assert_eq!(model.id, "model-1");
+    let channels: ChannelsListResult = server
+        .send_request_typed(method::CHANNELS_LIST, &serde_json::json!({}))
+        .await
+        .unwrap();
+    assert!(channels.channels.is_empty());
+
+    let err = server.send_request("game/unknown", None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_METHOD_NOT_FOUND, .. }));
+
+    server
+        .send_notification_typed(
+            method::FEATURE_SETS_CHANGED,
+            &FeatureSetsChangedParams {
+                added: None,
+                removed: Some(vec!["lobby".into()]),
+            },
+        )
+        .await
+        .unwrap();
+    let change = changes_rx.recv().await.unwrap();
+    assert_eq!(change.removed.unwrap(), vec!["lobby".to_string()]);
+
+    // The host can still send while dispatching
+    let sender = host.connection().clone();
+    let reply = tokio::spawn(async move { sender.send_request("game/state", None).await });
+    let req = match server.next_message().await.unwrap() {
+        IncomingMessage::Request(req) => req,
+        _ => panic!("Expected request"),
+    };
+    server
+        .send_response(req.id, serde_json::json!({ "turn": 3 }))
+        .await
+        .unwrap();
+    assert_eq!(reply.await.unwrap().unwrap()["turn"], 3);
+
+    drop((client, server));
+    host.closed().await.unwrap();
+}
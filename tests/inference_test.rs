@@ -0,0 +1,999 @@
+use std::time::Duration;
+
+use mcpl_core::capabilities::*;
+use mcpl_core::connection::{ConnectionError, ConnectionOptions, IncomingMessage};
+use mcpl_core::host::{HandlerResult, McplHost, McplHostHandler};
+use mcpl_core::id::UuidV7Ids;
+use mcpl_core::methods::*;
+use mcpl_core::server::{McplServer, McplServerHandler};
+use mcpl_core::types::{
+    ContentBlock, JsonRpcId, ERR_BUDGET_EXCEEDED, ERR_BUSY, ERR_INVALID_RESPONSE,
+};
+use mcpl_core::{
+    EmitOptions, HandlerError, InferenceBudget, InferenceBudgets, InferenceChunks, InferencePrompt,
+    InferenceProvider, InferenceQueue, InferenceQueueOptions, McplClient, ModelEvent,
+    ModelResolver, RequestContext, UsageTotals,
+};
+
+use tokio::sync::mpsc;
+
+mod common;
+use common::{duplex_pair, duplex_pair_with_options};
+
+fn capabilities() -> McplCapabilities {
+    McplCapabilities {
+        inference_request: Some(InferenceRequestCap::Detailed(InferenceRequestDetail {
+            streaming: true,
+        })),
+        stream_observer: Some(true),
+        ..McplCapabilities::new("0.4")
+    }
+}
+
+fn request(text: &str) -> InferenceRequestParams {
+    InferenceRequestParams {
+        feature_set: "game".into(),
+        conversation_id: None,
+        stream: None,
+        messages: vec![InferenceMessage {
+            role: "user".into(),
+            content: text.into(),
+        }],
+        preferences: None,
+        tools: None,
+        tool_choice: None,
+        response_schema: None,
+    }
+}
+
+/// Answers inference requests by echoing the last message, word by word
+/// when asked to stream.
+struct EchoHost;
+
+impl McplHostHandler for EchoHost {
+    async fn on_inference_request(
+        &self,
+        ctx: &RequestContext,
+        params: InferenceRequestParams,
+    ) -> HandlerResult<InferenceRequestResult> {
+        let content = params.messages.last().unwrap().content.text();
+        if params.stream == Some(true) {
+            let request_id = ctx.id().clone();
+            let conn = ctx.session().unwrap().connection().clone();
+            for (index, word) in content.split_inclusive(' ').enumerate() {
+                let chunk = InferenceChunkParams {
+                    request_id: request_id.clone(),
+                    index: index as u32,
+                    delta: word.into(),
+                };
+                conn.send_notification_typed(method::INFERENCE_CHUNK, &chunk)
+                    .await
+                    .unwrap();
+            }
+        }
+        Ok(InferenceRequestResult {
+            content,
+            model: "echo".into(),
+            finish_reason: "end_turn".into(),
+            usage: InferenceUsage {
+                input_tokens: 3,
+                output_tokens: 3,
+            },
+            tool_calls: None,
+        })
+    }
+}
+
+struct Game;
+
+impl McplServerHandler for Game {}
+
+async fn connect() -> (McplHost<EchoHost>, McplClient) {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .connect(host_conn)
+        .await
+        .unwrap();
+    (host, client)
+}
+
+#[tokio::test]
+async fn test_request_inference_streaming() {
+    let (_host, client) = connect().await;
+
+    let mut stream = client
+        .request_inference_streaming(&request("Knight to f3"))
+        .await
+        .unwrap();
+    let mut chunks = Vec::new();
+    while let Some(chunk) = stream.next_chunk().await {
+        assert_eq!(chunk.index as usize, chunks.len());
+        chunks.push(chunk.delta);
+    }
+    assert_eq!(chunks, ["Knight ", "to ", "f3"]);
+    let result = stream.final_result().await.unwrap();
+    assert_eq!(result.content, "Knight to f3");
+
+    // The result can be awaited without reading the chunks
+    let stream = client
+        .request_inference_streaming(&request("Castle"))
+        .await
+        .unwrap();
+    assert_eq!(stream.final_result().await.unwrap().content, "Castle");
+}
+
+/// Answers with the system prompt, then the last message, streaming them
+/// as two chunks.
+struct ScriptedProvider;
+
+impl InferenceProvider for ScriptedProvider {
+    async fn generate(
+        &self,
+        prompt: InferencePrompt,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let system = prompt.system.unwrap_or_default();
+        let last = prompt.messages.last().unwrap().content.text();
+        Ok(InferenceRequestResult {
+            content: format!("{system}|{last}"),
+            model: "scripted".into(),
+            finish_reason: "end_turn".into(),
+            usage: InferenceUsage {
+                input_tokens: prompt.messages.len() as u32,
+                output_tokens: 2,
+            },
+            tool_calls: None,
+        })
+    }
+
+    async fn generate_streaming(
+        &self,
+        prompt: InferencePrompt,
+        chunks: &mut InferenceChunks,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let result = self.generate(prompt).await?;
+        let (system, last) = result.content.split_once('|').unwrap();
+        chunks.send(system).await?;
+        chunks.send("").await?;
+        chunks.send(last).await?;
+        assert_eq!(chunks.sent(), 2);
+        Ok(result)
+    }
+}
+
+/// Reports the usage of every answered request.
+struct MeteredHost {
+    usage: mpsc::UnboundedSender<(String, InferenceUsage)>,
+}
+
+impl McplHostHandler for MeteredHost {
+    async fn on_inference_answered(
+        &self,
+        params: &InferenceRequestParams,
+        result: &InferenceRequestResult,
+    ) {
+        let _ = self
+            .usage
+            .send((params.feature_set.clone(), result.usage.clone()));
+    }
+}
+
+#[tokio::test]
+async fn test_host_answers_with_inference_provider() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let (usage, mut reported) = mpsc::unbounded_channel();
+    let _host = McplHost::builder()
+        .handler(MeteredHost { usage })
+        .capabilities(capabilities())
+        .inference_provider(ScriptedProvider)
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut params = request("Your move");
+    params.messages.insert(
+        0,
+        InferenceMessage {
+            role: "system".into(),
+            content: "You play chess".into(),
+        },
+    );
+    let result = client.inference_request(&params).await.unwrap();
+    assert_eq!(result.content, "You play chess|Your move");
+    let (feature_set, usage) = reported.recv().await.unwrap();
+    assert_eq!(feature_set, "game");
+    assert_eq!(usage.input_tokens, 1);
+
+    let mut stream = client.request_inference_streaming(&params).await.unwrap();
+    let mut chunks = Vec::new();
+    while let Some(chunk) = stream.next_chunk().await {
+        chunks.push((chunk.index, chunk.delta));
+    }
+    assert_eq!(
+        chunks,
+        [
+            (0, "You play chess".to_string()),
+            (1, "Your move".to_string())
+        ]
+    );
+    assert_eq!(stream.final_result().await.unwrap().model, "scripted");
+    assert!(reported.recv().await.is_some());
+}
+
+/// A model backend's streamed answer.
+struct Events(mpsc::UnboundedReceiver<Result<ModelEvent, HandlerError>>);
+
+impl futures_core::Stream for Events {
+    type Item = Result<ModelEvent, HandlerError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Streams the alphabet one letter every few milliseconds.
+struct AlphabetProvider;
+
+impl InferenceProvider for AlphabetProvider {
+    async fn generate(
+        &self,
+        _prompt: InferencePrompt,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        Err(HandlerError::internal("Only streams"))
+    }
+
+    async fn generate_streaming(
+        &self,
+        _prompt: InferencePrompt,
+        chunks: &mut InferenceChunks,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for letter in 'a'..='z' {
+                let _ = tx.send(Ok(ModelEvent::Text(letter.to_string())));
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            let _ = tx.send(Ok(ModelEvent::Done {
+                model: "alphabet".into(),
+                finish_reason: "end_turn".into(),
+                usage: InferenceUsage {
+                    input_tokens: 1,
+                    output_tokens: 26,
+                },
+            }));
+        });
+        let options = EmitOptions {
+            interval: Duration::from_millis(20),
+            max_chunk_chars: Some(5),
+        };
+        chunks.emit(Events(rx), &options).await
+    }
+}
+
+#[tokio::test]
+async fn test_emit_coalesces_model_output() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(AlphabetProvider)
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut stream = client
+        .request_inference_streaming(&request("Recite"))
+        .await
+        .unwrap();
+    let mut chunks = Vec::new();
+    while let Some(chunk) = stream.next_chunk().await {
+        assert_eq!(chunk.index as usize, chunks.len());
+        assert!(chunk.delta.len() <= 5);
+        chunks.push(chunk.delta);
+    }
+    assert_eq!(chunks[0], "a");
+    assert!(chunks.len() < 26, "{} chunks", chunks.len());
+    assert_eq!(chunks.concat(), "abcdefghijklmnopqrstuvwxyz");
+    let result = stream.final_result().await.unwrap();
+    assert_eq!(result.content, chunks.concat());
+    assert_eq!(result.model, "alphabet");
+}
+
+#[tokio::test]
+async fn test_emit_with_zero_chunk_limit() {
+    let (host, mut server) = duplex_pair();
+    let (tx, rx) = mpsc::unbounded_channel();
+    for text in ["ab", "", "c"] {
+        tx.send(Ok(ModelEvent::Text(text.into()))).unwrap();
+    }
+    tx.send(Ok(ModelEvent::Done {
+        model: "alphabet".into(),
+        finish_reason: "end_turn".into(),
+        usage: InferenceUsage {
+            input_tokens: 1,
+            output_tokens: 3,
+        },
+    }))
+    .unwrap();
+
+    let mut chunks = InferenceChunks::new(host.handle(), JsonRpcId::Number(1));
+    let options = EmitOptions {
+        interval: Duration::ZERO,
+        max_chunk_chars: Some(0),
+    };
+    let result = tokio::time::timeout(Duration::from_secs(5), chunks.emit(Events(rx), &options))
+        .await
+        .expect("emit should finish")
+        .unwrap();
+    assert_eq!(result.content, "abc");
+    assert_eq!(chunks.sent(), 3);
+
+    for expected in ["a", "b", "c"] {
+        match server.next_message().await.unwrap() {
+            IncomingMessage::Notification(notif) => {
+                let chunk: InferenceChunkParams = notif.params_as().unwrap();
+                assert_eq!(chunk.delta, expected);
+            }
+            other => panic!("Expected a chunk, got {other:?}"),
+        }
+    }
+}
+
+/// Starts answering, then thinks forever; reports when it starts a request
+/// that does not stream, and when it is stopped.
+struct StallingProvider {
+    started: mpsc::UnboundedSender<()>,
+    stopped: mpsc::UnboundedSender<()>,
+}
+
+/// Reports being dropped.
+struct Stopped(mpsc::UnboundedSender<()>);
+
+impl Drop for Stopped {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+impl InferenceProvider for StallingProvider {
+    async fn generate(
+        &self,
+        _prompt: InferencePrompt,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let _stopped = Stopped(self.stopped.clone());
+        let _ = self.started.send(());
+        std::future::pending().await
+    }
+
+    async fn generate_streaming(
+        &self,
+        _prompt: InferencePrompt,
+        chunks: &mut InferenceChunks,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let _stopped = Stopped(self.stopped.clone());
+        chunks.send("Let me think").await?;
+        std::future::pending().await
+    }
+}
+
+#[tokio::test]
+async fn test_inference_cancel() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let (started, mut running) = mpsc::unbounded_channel();
+    let (stopped, mut seen) = mpsc::unbounded_channel();
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(StallingProvider { started, stopped })
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut stream = client
+        .request_inference_streaming(&request("Your move"))
+        .await
+        .unwrap();
+    assert_eq!(stream.next_chunk().await.unwrap().delta, "Let me think");
+    stream.cancel(Some("Game over".into())).await.unwrap();
+    assert!(stream.next_chunk().await.is_none());
+    let result = stream.final_result().await.unwrap();
+    assert!(result.is_cancelled());
+    assert_eq!(result.finish_reason, FINISH_REASON_CANCELLED);
+    assert!(seen.recv().await.is_some());
+
+    // Requests that do not stream are cancelled by id
+    let pending = client
+        .connection()
+        .send_request_deferred(
+            method::INFERENCE_REQUEST,
+            Some(serde_json::to_value(request("Your move")).unwrap()),
+        )
+        .await
+        .unwrap();
+    let request_id = pending.id().clone();
+    // Cancel only once the provider is working on it
+    running.recv().await.unwrap();
+    client.inference_cancel(request_id, None).await.unwrap();
+    let result: InferenceRequestResult = serde_json::from_value(pending.await.unwrap()).unwrap();
+    assert!(result.is_cancelled());
+    assert!(seen.recv().await.is_some());
+}
+
+#[tokio::test]
+async fn test_inference_streaming_with_string_ids() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (server_conn, host_conn) = duplex_pair_with_options(ConnectionOptions {
+        id_generator: Box::new(UuidV7Ids),
+        ..Default::default()
+    });
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let (started, _running) = mpsc::unbounded_channel();
+    let (stopped, mut seen) = mpsc::unbounded_channel();
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(StallingProvider { started, stopped })
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut stream = client
+        .request_inference_streaming(&request("Your move"))
+        .await
+        .unwrap();
+    assert_eq!(stream.next_chunk().await.unwrap().delta, "Let me think");
+    stream.cancel(None).await.unwrap();
+    let result = stream.final_result().await.unwrap();
+    assert!(result.is_cancelled());
+    assert!(seen.recv().await.is_some());
+}
+
+/// Calls the tool it is told to, moving the knight to f3.
+struct ToolProvider;
+
+impl InferenceProvider for ToolProvider {
+    async fn generate(
+        &self,
+        _prompt: InferencePrompt,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        Err(HandlerError::internal("Only streams"))
+    }
+
+    async fn generate_streaming(
+        &self,
+        prompt: InferencePrompt,
+        chunks: &mut InferenceChunks,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let Some(InferenceToolChoice::Tool { name }) = prompt.tool_choice else {
+            return Err(HandlerError::invalid_params("Expected a tool choice"));
+        };
+        assert!(prompt.tools.iter().any(|tool| tool.name == name));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(Ok(ModelEvent::Text("Developing the knight".into())));
+        let _ = tx.send(Ok(ModelEvent::ToolCall(InferenceToolCall {
+            id: "call-1".into(),
+            name,
+            input: serde_json::json!({ "from": "g1", "to": "f3" }),
+        })));
+        let _ = tx.send(Ok(ModelEvent::Done {
+            model: "tools".into(),
+            finish_reason: FINISH_REASON_TOOL_USE.into(),
+            usage: InferenceUsage::default(),
+        }));
+        chunks.emit(Events(rx), &EmitOptions::default()).await
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
+struct Move {
+    from: String,
+    to: String,
+}
+
+#[tokio::test]
+async fn test_inference_tool_calls() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(ToolProvider)
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut params = request("Your move");
+    params.tools = Some(vec![InferenceTool {
+        name: "move".into(),
+        description: Some("Move a piece".into()),
+        input_schema: serde_json::json!({
+            "type": "object",
+            "properties": {
+                "from": { "type": "string" },
+                "to": { "type": "string" }
+            },
+            "required": ["from", "to"]
+        }),
+    }]);
+    params.tool_choice = Some(InferenceToolChoice::Tool {
+        name: "move".into(),
+    });
+    assert_eq!(
+        serde_json::to_value(&params).unwrap()["toolChoice"],
+        serde_json::json!({ "type": "tool", "name": "move" })
+    );
+
+    let mut stream = client.request_inference_streaming(&params).await.unwrap();
+    // Only the text is streamed
+    assert_eq!(
+        stream.next_chunk().await.unwrap().delta,
+        "Developing the knight"
+    );
+    assert!(stream.next_chunk().await.is_none());
+    let result = stream.final_result().await.unwrap();
+    assert_eq!(result.content, "Developing the knight");
+    assert_eq!(result.finish_reason, FINISH_REASON_TOOL_USE);
+    let [call] = result.tool_calls() else {
+        panic!("Expected one tool call, got {:?}", result.tool_calls);
+    };
+    assert_eq!(call.name, "move");
+    assert_eq!(
+        call.input_as::<Move>().unwrap(),
+        Move {
+            from: "g1".into(),
+            to: "f3".into()
+        }
+    );
+
+    // Answers without tool calls leave them out
+    let value = serde_json::to_value(InferenceRequestResult {
+        content: "gg".into(),
+        model: "tools".into(),
+        finish_reason: "end_turn".into(),
+        usage: InferenceUsage::default(),
+        tool_calls: None,
+    })
+    .unwrap();
+    assert!(value.get("toolCalls").is_none());
+}
+
+/// Answers with the id of the model it was given.
+struct ModelNameProvider;
+
+impl InferenceProvider for ModelNameProvider {
+    async fn generate(
+        &self,
+        prompt: InferencePrompt,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let model = prompt.model.map(|model| model.id).unwrap_or_default();
+        Ok(InferenceRequestResult {
+            content: format!("seed {:?}", prompt.preferences.seed),
+            model,
+            finish_reason: "end_turn".into(),
+            usage: InferenceUsage::default(),
+            tool_calls: None,
+        })
+    }
+}
+
+fn model(id: &str) -> ModelInfo {
+    ModelInfo {
+        id: id.into(),
+        vendor: "test".into(),
+        context_window: 200_000,
+        capabilities: Vec::new(),
+    }
+}
+
+#[tokio::test]
+async fn test_model_hints_resolve_to_host_models() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(ModelNameProvider)
+        .model_resolver(ModelResolver::new(vec![
+            model("opus-large"),
+            model("Haiku-fast"),
+            model("haiku"),
+        ]))
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut params = request("Your move");
+    params.preferences = Some(InferencePreferences {
+        top_p: Some(0.9),
+        stop: Some(vec!["\n\n".into()]),
+        seed: Some(7),
+        frequency_penalty: Some(0.5),
+        model_hints: vec!["sonnet".into(), "fast".into()],
+        ..Default::default()
+    });
+    let value = serde_json::to_value(&params).unwrap();
+    assert_eq!(value["preferences"]["topP"], 0.9);
+    assert_eq!(value["preferences"]["frequencyPenalty"], 0.5);
+    assert_eq!(
+        value["preferences"]["modelHints"],
+        serde_json::json!(["sonnet", "fast"])
+    );
+
+    // The first hint matching a model wins
+    let result = client.inference_request(&params).await.unwrap();
+    assert_eq!(result.model, "Haiku-fast");
+    assert_eq!(result.content, "seed Some(7)");
+
+    // Exact ids are preferred over partial matches
+    params.preferences.as_mut().unwrap().model_hints = vec!["HAIKU".into()];
+    let result = client.inference_request(&params).await.unwrap();
+    assert_eq!(result.model, "haiku");
+
+    // Without a matching hint the first model is the default
+    let result = client
+        .inference_request(&request("Your move"))
+        .await
+        .unwrap();
+    assert_eq!(result.model, "opus-large");
+}
+
+/// Answers with the last message, wrapped in a code fence if asked to.
+struct VerbatimProvider;
+
+impl InferenceProvider for VerbatimProvider {
+    async fn generate(
+        &self,
+        prompt: InferencePrompt,
+    ) -> Result<InferenceRequestResult, HandlerError> {
+        let text = prompt.messages.last().unwrap().content.text();
+        let content = match text.strip_prefix("fenced:") {
+            Some(json) => format!("```json\n{json}\n```"),
+            None => text,
+        };
+        Ok(InferenceRequestResult {
+            content,
+            model: "verbatim".into(),
+            finish_reason: "end_turn".into(),
+            usage: InferenceUsage::default(),
+            tool_calls: None,
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_response_schema_is_checked() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(VerbatimProvider)
+        .connect(host_conn)
+        .await
+        .unwrap();
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "from": { "type": "string", "minLength": 2, "maxLength": 2 },
+            "to": { "type": "string", "minLength": 2, "maxLength": 2 }
+        },
+        "required": ["from", "to"],
+        "additionalProperties": false
+    });
+    let ask = |answer: &str| {
+        let mut params = request(answer);
+        params.response_schema = Some(schema.clone());
+        params
+    };
+
+    let result = client
+        .inference_request(&ask(r#"fenced:{ "from": "e2", "to": "e4" }"#))
+        .await
+        .unwrap();
+    assert_eq!(result.content, r#"{"from":"e2","to":"e4"}"#);
+    #[derive(serde::Deserialize)]
+    struct Move {
+        to: String,
+    }
+    assert_eq!(result.parse_content::<Move>().unwrap().to, "e4");
+
+    let err = client
+        .inference_request(&ask("I resign"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INVALID_RESPONSE,
+            ..
+        }
+    ));
+
+    let err = client
+        .inference_request(&ask(r#"{ "from": "e2", "to": "e4", "piece": "pawn" }"#))
+        .await
+        .unwrap_err();
+    let ConnectionError::Rpc { code, message } = err else {
+        panic!("Expected an RPC error, got {:?}", err);
+    };
+    assert_eq!(code, ERR_INVALID_RESPONSE);
+    assert!(message.contains("\"/piece\""), "{}", message);
+
+    // Without a schema, any content goes
+    let result = client
+        .inference_request(&request("I resign"))
+        .await
+        .unwrap();
+    assert_eq!(result.content, "I resign");
+}
+
+#[tokio::test]
+async fn test_inference_budgets() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_budgets(InferenceBudgets {
+            feature_sets: [("game".to_string(), InferenceBudget::requests(3))].into(),
+            conversation: Some(InferenceBudget::tokens(10)),
+            ..Default::default()
+        })
+        .connect(host_conn)
+        .await
+        .unwrap();
+    let in_conversation = |text: &str| InferenceRequestParams {
+        conversation_id: Some("match-1".into()),
+        ..request(text)
+    };
+    let rejected = |err: ConnectionError| match err {
+        ConnectionError::Rpc { code, message } => {
+            assert_eq!(code, ERR_BUDGET_EXCEEDED);
+            message
+        }
+        err => panic!("Expected a budget error, got {:?}", err),
+    };
+
+    // Each answer uses 6 tokens; the second one goes over 10
+    client
+        .inference_request(&in_conversation("e4"))
+        .await
+        .unwrap();
+    client
+        .inference_request(&in_conversation("Nf3"))
+        .await
+        .unwrap();
+    let err = client
+        .inference_request(&in_conversation("Bc4"))
+        .await
+        .unwrap_err();
+    assert!(rejected(err).contains("match-1"));
+
+    // The third request of the feature set is its last
+    client.inference_request(&request("Bc4")).await.unwrap();
+    let err = client.inference_request(&request("O-O")).await.unwrap_err();
+    assert!(rejected(err).contains("game"));
+
+    let session = host.session();
+    let answered = UsageTotals {
+        requests: 3,
+        input_tokens: 9,
+        output_tokens: 9,
+    };
+    assert_eq!(session.feature_set_metrics("game").inference, answered);
+    assert_eq!(session.usage().server_initiated, answered);
+    assert_eq!(
+        session
+            .conversation_usage("match-1")
+            .server_initiated
+            .requests,
+        2
+    );
+
+    // The host's own inference counts too, but not against the budgets
+    let after = ContextAfterInferenceParams {
+        inference_id: "inf-1".into(),
+        conversation_id: "match-1".into(),
+        turn_index: 0,
+        user_message: "Who is winning?".into(),
+        assistant_message: "White".into(),
+        user_content: None,
+        assistant_content: None,
+        model: ModelInfo {
+            id: "opus".into(),
+            vendor: "test".into(),
+            context_window: 200_000,
+            capabilities: Vec::new(),
+        },
+        usage: InferenceUsage {
+            input_tokens: 100,
+            output_tokens: 1,
+        },
+        channels: None,
+    };
+    session
+        .connection()
+        .send_notification_typed(method::CONTEXT_AFTER_INFERENCE, &after)
+        .await
+        .unwrap();
+    let usage = session.conversation_usage("match-1");
+    assert_eq!(usage.host_initiated.total_tokens(), 101);
+    assert_eq!(usage.combined().requests, 3);
+}
+
+/// The future's output if it is ready without waiting.
+async fn ready_now<F: std::future::Future + Unpin>(future: &mut F) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = std::future::ready(()) => None,
+    }
+}
+
+#[tokio::test]
+async fn test_inference_queue_takes_turns() {
+    let queue = InferenceQueue::new(InferenceQueueOptions {
+        concurrency: 2,
+        reserved_for_host: 1,
+        max_queued_per_server: 2,
+    });
+
+    // Servers share one slot; the other is the host's
+    let chatty = queue.acquire_for_server("chatty").await.unwrap();
+    let mut chatty_2 = Box::pin(queue.acquire_for_server("chatty"));
+    let mut chatty_3 = Box::pin(queue.acquire_for_server("chatty"));
+    let mut quiet = Box::pin(queue.acquire_for_server("quiet"));
+    assert!(ready_now(&mut chatty_2).await.is_none());
+    assert!(ready_now(&mut chatty_3).await.is_none());
+    assert!(ready_now(&mut quiet).await.is_none());
+    let full = queue.acquire_for_server("chatty").await.err().unwrap();
+    assert_eq!(full.queued, 2);
+    let user = queue.acquire_for_host().await;
+    assert_eq!(queue.running(), 2);
+
+    // The quiet server goes before the chatty one's second request
+    drop(chatty);
+    let quiet = ready_now(&mut quiet).await.unwrap().unwrap();
+    assert!(ready_now(&mut chatty_2).await.is_none());
+    drop(quiet);
+    let chatty_2 = ready_now(&mut chatty_2).await.unwrap().unwrap();
+
+    // Waiting host turns go first
+    let mut user_2 = Box::pin(queue.acquire_for_host());
+    assert!(ready_now(&mut user_2).await.is_none());
+    drop(chatty_2);
+    let _user_2 = ready_now(&mut user_2).await.unwrap();
+    assert!(ready_now(&mut chatty_3).await.is_none());
+    drop(user);
+    assert!(ready_now(&mut chatty_3).await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_host_queues_inference_requests() {
+    let server = McplServer::builder()
+        .handler(Game)
+        .capabilities(capabilities())
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let client = McplClient::new(server_conn.handle());
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let (started, _running) = mpsc::unbounded_channel();
+    let (stopped, _seen) = mpsc::unbounded_channel();
+    let queue = InferenceQueue::new(InferenceQueueOptions {
+        concurrency: 1,
+        reserved_for_host: 0,
+        max_queued_per_server: 0,
+    });
+    let _host = McplHost::builder()
+        .handler(EchoHost)
+        .capabilities(capabilities())
+        .inference_provider(StallingProvider { started, stopped })
+        .inference_queue(queue.clone())
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let mut stream = client
+        .request_inference_streaming(&request("Your move"))
+        .await
+        .unwrap();
+    assert_eq!(stream.next_chunk().await.unwrap().delta, "Let me think");
+    let err = client
+        .inference_request(&request("Your move"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_BUSY, .. }));
+
+    // A cancelled request frees its slot
+    stream.cancel(None).await.unwrap();
+    assert!(stream.final_result().await.unwrap().is_cancelled());
+    assert_eq!(queue.running(), 0);
+}
+
+#[test]
+fn test_inference_message_content_blocks() {
+    let screenshot = ContentBlock::Image {
+        data: Some("iVBORw0KGgo=".into()),
+        uri: None,
+        mime_type: Some("image/png".into()),
+    };
+    let message: InferenceMessage = serde_json::from_value(serde_json::json!({
+        "role": "user",
+        "content": [
+            { "type": "text", "text": "Where should I move?" },
+            { "type": "image", "data": "iVBORw0KGgo=", "mimeType": "image/png" }
+        ]
+    }))
+    .unwrap();
+    assert_eq!(
+        message.content,
+        InferenceContent::Blocks(vec![
+            ContentBlock::text("Where should I move?"),
+            screenshot.clone()
+        ])
+    );
+    assert_eq!(message.content.text(), "Where should I move?");
+    assert!(!message.content.is_text());
+
+    // Plain strings still work both ways
+    let message: InferenceMessage =
+        serde_json::from_value(serde_json::json!({ "role": "user", "content": "e4" })).unwrap();
+    assert_eq!(message.content, InferenceContent::from("e4"));
+    assert_eq!(message.content.blocks(), [ContentBlock::text("e4")]);
+    assert_eq!(
+        serde_json::to_value(&message).unwrap(),
+        serde_json::json!({ "role": "user", "content": "e4" })
+    );
+}
@@ -0,0 +1,125 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::handler::HandlerResult;
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::{mcpl_router, HandlerError, RequestContext};
+
+use tower::ServiceExt;
+
+mod common;
+use common::duplex_pair;
+
+#[derive(Default)]
+struct GameServer {
+    rollbacks: AtomicU32,
+}
+
+#[mcpl_router]
+impl GameServer {
+    #[mcpl_handler("state/rollback")]
+    async fn rollback(&self, params: StateRollbackParams) -> HandlerResult<StateRollbackResult> {
+        self.rollbacks.fetch_add(1, Ordering::SeqCst);
+        Ok(StateRollbackResult {
+            checkpoint: params.checkpoint,
+            success: true,
+            reason: None,
+        })
+    }
+
+    #[mcpl_handler("game/whoami")]
+    async fn whoami(
+        &self,
+        ctx: &RequestContext,
+        _params: serde_json::Value,
+    ) -> HandlerResult<String> {
+        Ok(ctx.method().to_string())
+    }
+
+    #[mcpl_handler("game/load")]
+    async fn load(&self, params: serde_json::Value) -> Result<StateRollbackResult, HandlerError> {
+        let params: StateRollbackParams = serde_json::from_value(params)?;
+        if params.checkpoint != "cp-0" {
+            return Err(HandlerError::checkpoint_not_found(&params.checkpoint));
+        }
+        Ok(StateRollbackResult {
+            checkpoint: params.checkpoint,
+            success: true,
+            reason: None,
+        })
+    }
+
+    #[mcpl_handler("game/count")]
+    async fn count(&self) -> HandlerResult<u32> {
+        Ok(self.rollbacks.load(Ordering::SeqCst))
+    }
+
+    /// Not a handler; left alone by the macro.
+    #[allow(dead_code)]
+    fn helper(&self) {}
+}
+
+#[tokio::test]
+async fn test_generated_router() {
+    let (serving, mut caller) = duplex_pair();
+    let router = Arc::new(GameServer::default()).router();
+    tokio::spawn(async move { router.serve(serving).await });
+
+    let result: StateRollbackResult = caller
+        .send_request_typed(
+            method::STATE_ROLLBACK,
+            &StateRollbackParams {
+                feature_set: "game".into(),
+                checkpoint: "cp-3".into(),
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(result.checkpoint, "cp-3");
+
+    let count = caller.send_request("game/count", None).await.unwrap();
+    assert_eq!(count, 1);
+    let whoami = caller.send_request("game/whoami", None).await.unwrap();
+    assert_eq!(whoami, "game/whoami");
+
+    let err = caller
+        .send_request(method::STATE_ROLLBACK, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INVALID_PARAMS,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_handler_errors() {
+    let service = Arc::new(GameServer::default()).router().into_service();
+    let load = |params: serde_json::Value| {
+        service
+            .clone()
+            .oneshot(JsonRpcRequest::new(1, "game/load", Some(params)))
+    };
+
+    let response = load(serde_json::json!({ "featureSet": "game", "checkpoint": "cp-0" }))
+        .await
+        .unwrap();
+    assert_eq!(response.result_as::<serde_json::Value>().unwrap()["success"], true);
+
+    // Constructors carry a data payload
+    let response = load(serde_json::json!({ "featureSet": "game", "checkpoint": "cp-9" }))
+        .await
+        .unwrap();
+    let error = response.error.unwrap();
+    assert_eq!(error.code, ERR_CHECKPOINT_NOT_FOUND);
+    assert_eq!(error.data.unwrap()["checkpoint"], "cp-9");
+
+    // `?` on a serde error answers invalid params
+    let response = load(serde_json::json!({ "checkpoint": 9 })).await.unwrap();
+    assert_eq!(response.error.unwrap().code, ERR_INVALID_PARAMS);
+}
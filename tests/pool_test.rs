@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use mcpl_core::capabilities::*;
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::host::McplHostHandler;
+use mcpl_core::methods::*;
+use mcpl_core::server::{HandlerResult, McplServer, McplServerHandler};
+use mcpl_core::types::*;
+use mcpl_core::{
+    BeforeInferenceOptions, DegradationPolicy, McplHost, PoolError, RequestContext, ServerPool,
+};
+
+use tokio::sync::mpsc;
+
+mod common;
+use common::duplex_pair;
+
+fn feature_set(name: &str) -> FeatureSetDeclaration {
+    FeatureSetDeclaration {
+        name: name.into(),
+        description: None,
+        uses: vec![],
+        rollback: true,
+        host_state: false,
+        version: None,
+        min_host_version: None,
+    }
+}
+
+/// A server owning one feature set, reporting the updates it receives.
+struct OwningServer {
+    name: &'static str,
+    updates: mpsc::UnboundedSender<(&'static str, FeatureSetsUpdateParams)>,
+}
+
+impl McplServerHandler for OwningServer {
+    async fn on_feature_sets_update(&self, params: FeatureSetsUpdateParams) {
+        self.updates.send((self.name, params)).unwrap();
+    }
+
+    async fn on_state_rollback(
+        &self,
+        _ctx: &RequestContext,
+        params: StateRollbackParams,
+    ) -> HandlerResult<StateRollbackResult> {
+        Ok(StateRollbackResult {
+            checkpoint: format!("{}:{}", self.name, params.checkpoint),
+            success: true,
+            reason: None,
+        })
+    }
+
+    async fn on_context_before_inference(
+        &self,
+        _ctx: &RequestContext,
+        _params: ContextBeforeInferenceParams,
+    ) -> HandlerResult<ContextBeforeInferenceResult> {
+        Ok(ContextBeforeInferenceResult {
+            feature_set: self.name.into(),
+            context_injections: vec![],
+        })
+    }
+}
+
+struct Host;
+
+impl McplHostHandler for Host {}
+
+async fn connect(
+    name: &'static str,
+    context_hooks: bool,
+    updates: &mpsc::UnboundedSender<(&'static str, FeatureSetsUpdateParams)>,
+) -> McplHost<Host> {
+    let hooks = ContextHooksCap {
+        before_inference: true,
+        after_inference: None,
+    };
+    let server = McplServer::builder()
+        .handler(OwningServer {
+            name,
+            updates: updates.clone(),
+        })
+        .capabilities(McplCapabilities {
+            rollback: Some(true),
+            context_hooks: context_hooks.then(|| hooks.clone()),
+            feature_sets: Some(vec![feature_set(name)]),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    tokio::spawn(async move { server.serve(server_conn).await });
+    McplHost::builder()
+        .handler(Host)
+        .capabilities(McplCapabilities {
+            context_hooks: Some(hooks),
+            ..McplCapabilities::new("0.4")
+        })
+        .connect(host_conn)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_server_pool() {
+    let (updates_tx, mut updates_rx) = mpsc::unbounded_channel();
+    let game = connect("game", true, &updates_tx).await;
+    let lobby = connect("lobby", false, &updates_tx).await;
+    let pool = ServerPool::new();
+    pool.insert("game", game.session());
+    pool.insert("lobby", lobby.session());
+    assert_eq!(pool.names(), ["game", "lobby"]);
+
+    let declared: Vec<(String, String)> = pool
+        .feature_sets()
+        .into_iter()
+        .map(|pooled| (pooled.server, pooled.value.name))
+        .collect();
+    assert_eq!(
+        declared,
+        [
+            ("game".to_string(), "game".to_string()),
+            ("lobby".to_string(), "lobby".to_string())
+        ]
+    );
+    assert_eq!(pool.owner("lobby").as_deref(), Some("lobby"));
+
+    // Each server hears only about its own feature sets
+    let results = pool
+        .feature_sets_update(&FeatureSetsUpdateParams {
+            enabled: Some(vec!["game".into()]),
+            disabled: Some(vec!["lobby".into()]),
+            scopes: None,
+        })
+        .await;
+    assert!(results.iter().all(|pooled| pooled.value.is_ok()));
+    let mut updates = [
+        updates_rx.recv().await.unwrap(),
+        updates_rx.recv().await.unwrap(),
+    ];
+    updates.sort_by_key(|(name, _)| *name);
+    assert_eq!(
+        updates[0].1.enabled.as_deref(),
+        Some(&["game".to_string()][..])
+    );
+    assert!(updates[0].1.disabled.is_none());
+    assert!(updates[1].1.enabled.is_none());
+    assert_eq!(
+        updates[1].1.disabled.as_deref(),
+        Some(&["lobby".to_string()][..])
+    );
+
+    // Only servers with the hook are asked
+    let injections = pool
+        .before_inference(&ContextBeforeInferenceParams {
+            inference_id: "inf-1".into(),
+            conversation_id: "conv-1".into(),
+            turn_index: 0,
+            user_message: None,
+            user_content: None,
+            model: ModelInfo {
+                id: "model-1".into(),
+                vendor: "test".into(),
+                context_window: 200_000,
+                capabilities: vec![],
+            },
+        })
+        .await;
+    assert_eq!(injections.len(), 1);
+    assert_eq!(injections[0].server, "game");
+    assert_eq!(injections[0].value.as_ref().unwrap().feature_set, "game");
+
+    // Rollbacks go to the owner
+    let rollback = pool.state_rollback("lobby", "cp-1").await.unwrap();
+    assert_eq!(rollback.server, "lobby");
+    assert_eq!(rollback.value.checkpoint, "lobby:cp-1");
+    assert!(matches!(
+        pool.state_rollback("chess", "cp-1").await,
+        Err(PoolError::UnknownFeatureSet(name)) if name == "chess"
+    ));
+
+    pool.remove("lobby");
+    assert!(pool.owner("lobby").is_none());
+}
+
+/// A server injecting one system line, after `delay`, or failing.
+struct InjectingServer {
+    name: &'static str,
+    delay: Duration,
+    fail: bool,
+}
+
+impl McplServerHandler for InjectingServer {
+    async fn on_context_before_inference(
+        &self,
+        _ctx: &RequestContext,
+        _params: ContextBeforeInferenceParams,
+    ) -> HandlerResult<ContextBeforeInferenceResult> {
+        tokio::time::sleep(self.delay).await;
+        if self.fail {
+            return Err(JsonRpcError::new(
+                ERR_INTERNAL_ERROR,
+                "Game state unavailable",
+            ));
+        }
+        Ok(ContextBeforeInferenceResult {
+            feature_set: self.name.into(),
+            context_injections: vec![ContextInjection {
+                namespace: self.name.into(),
+                position: ContextInjectionPosition::System,
+                content: ContextInjectionContent::Text(format!("From {}", self.name)),
+                priority: None,
+                metadata: None,
+            }],
+        })
+    }
+}
+
+async fn connect_injecting(server: InjectingServer) -> McplHost<Host> {
+    let hooks = ContextHooksCap {
+        before_inference: true,
+        after_inference: None,
+    };
+    let server = McplServer::builder()
+        .handler(server)
+        .capabilities(McplCapabilities {
+            context_hooks: Some(hooks.clone()),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    tokio::spawn(async move { server.serve(server_conn).await });
+    McplHost::builder()
+        .handler(Host)
+        .capabilities(McplCapabilities {
+            context_hooks: Some(hooks),
+            ..McplCapabilities::new("0.4")
+        })
+        .connect(host_conn)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_run_before_inference_merges_and_tolerates_failures() {
+    let pool = ServerPool::new();
+    let mut hosts = Vec::new();
+    for (name, delay, fail) in [
+        ("chess", 0, false),
+        ("lobby", 10, false),
+        ("slow", 200, false),
+        ("broken", 0, true),
+    ] {
+        let host = connect_injecting(InjectingServer {
+            name,
+            delay: Duration::from_millis(delay),
+            fail,
+        })
+        .await;
+        pool.insert(name, host.session());
+        hosts.push(host);
+    }
+
+    let params = ContextBeforeInferenceParams {
+        inference_id: "inf-1".into(),
+        conversation_id: "conv-1".into(),
+        turn_index: 0,
+        user_message: None,
+        user_content: None,
+        model: ModelInfo {
+            id: "model-1".into(),
+            vendor: "test".into(),
+            context_window: 200_000,
+            capabilities: vec![],
+        },
+    };
+    let mut options = BeforeInferenceOptions {
+        timeout: Duration::from_secs(5),
+        timeouts: HashMap::from([("slow".to_string(), Duration::from_millis(50))]),
+        ..Default::default()
+    };
+    let run = pool.run_before_inference(&params, &options).await.unwrap();
+    assert_eq!(run.answered, ["chess", "lobby"]);
+    assert_eq!(run.context.system, "From chess\n\nFrom lobby");
+    let failed: Vec<&str> = run
+        .failed
+        .iter()
+        .map(|pooled| pooled.server.as_str())
+        .collect();
+    assert_eq!(failed, ["broken", "slow"]);
+    assert!(matches!(run.failed[0].value, ConnectionError::Rpc { .. }));
+    assert!(matches!(run.failed[1].value, ConnectionError::Timeout));
+
+    // Once it has answered, the slow server's last injections stand in
+    options.timeouts.clear();
+    let run = pool.run_before_inference(&params, &options).await.unwrap();
+    assert!(run.cached.is_empty());
+    options
+        .timeouts
+        .insert("slow".into(), Duration::from_millis(50));
+    options.degradation = DegradationPolicy::UseCached;
+    let run = pool.run_before_inference(&params, &options).await.unwrap();
+    assert_eq!(run.answered, ["chess", "lobby", "slow"]);
+    assert_eq!(run.cached, ["slow"]);
+    assert_eq!(run.context.system, "From chess\n\nFrom lobby\n\nFrom slow");
+
+    // A server that must answer fails the turn
+    options
+        .degradations
+        .insert("broken".into(), DegradationPolicy::FailTurn);
+    assert!(matches!(
+        pool.run_before_inference(&params, &options).await,
+        Err(PoolError::Connection { server, .. }) if server == "broken"
+    ));
+}
+
+/// A server that prepares context until the host gives up on it.
+struct PreparingServer {
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl McplServerHandler for PreparingServer {
+    async fn on_context_before_inference(
+        &self,
+        ctx: &RequestContext,
+        params: ContextBeforeInferenceParams,
+    ) -> HandlerResult<ContextBeforeInferenceResult> {
+        if params.inference_id == "inf-1" {
+            ctx.cancellation_token().cancelled().await;
+            let _ = self
+                .events
+                .send(format!("abandoned {}", params.inference_id));
+        }
+        Ok(ContextBeforeInferenceResult {
+            feature_set: "game".into(),
+            context_injections: vec![ContextInjection {
+                namespace: "game".into(),
+                position: ContextInjectionPosition::System,
+                content: ContextInjectionContent::Text(format!("For {}", params.inference_id)),
+                priority: None,
+                metadata: None,
+            }],
+        })
+    }
+
+    async fn on_context_cancel(&self, params: ContextCancelParams) {
+        let _ = self.events.send(format!(
+            "cancel {} ({})",
+            params.inference_id,
+            params.reason.unwrap_or_default()
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_run_before_inference_is_superseded() {
+    let hooks = ContextHooksCap {
+        before_inference: true,
+        after_inference: None,
+    };
+    let (events, mut seen) = mpsc::unbounded_channel();
+    let server = McplServer::builder()
+        .handler(PreparingServer { events })
+        .capabilities(McplCapabilities {
+            context_hooks: Some(hooks.clone()),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let host = McplHost::builder()
+        .handler(Host)
+        .capabilities(McplCapabilities {
+            context_hooks: Some(hooks),
+            ..McplCapabilities::new("0.4")
+        })
+        .connect(host_conn)
+        .await
+        .unwrap();
+    let pool = std::sync::Arc::new(ServerPool::new());
+    pool.insert("game", host.session());
+
+    let params = |inference_id: &str| ContextBeforeInferenceParams {
+        inference_id: inference_id.into(),
+        conversation_id: "conv-1".into(),
+        turn_index: 0,
+        user_message: None,
+        user_content: None,
+        model: ModelInfo {
+            id: "model-1".into(),
+            vendor: "test".into(),
+            context_window: 200_000,
+            capabilities: vec![],
+        },
+    };
+    let first = tokio::spawn({
+        let pool = pool.clone();
+        let params = params("inf-1");
+        async move {
+            pool.run_before_inference(&params, &BeforeInferenceOptions::default())
+                .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The user sent another message before the first turn started
+    let run = pool
+        .run_before_inference(&params("inf-2"), &BeforeInferenceOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(run.context.system, "For inf-2");
+    assert!(matches!(
+        first.await.unwrap(),
+        Err(PoolError::Cancelled(id)) if id == "inf-1"
+    ));
+    let mut events = vec![seen.recv().await.unwrap(), seen.recv().await.unwrap()];
+    events.sort();
+    assert_eq!(events, ["abandoned inf-1", "cancel inf-1 (superseded)"]);
+}
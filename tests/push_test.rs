@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mcpl_core::capabilities::McplCapabilities;
+use mcpl_core::connection::McplConnection;
+use mcpl_core::host::McplHostHandler;
+use mcpl_core::methods::*;
+use mcpl_core::server::{HandlerResult, McplServer, McplServerHandler};
+use mcpl_core::types::ContentBlock;
+use mcpl_core::{
+    CorrelationError, DisabledPolicy, DropPolicy, ExcessPolicy, FeatureSetRegistry,
+    FilePushEventStore, InferenceCorrelator, InferenceOutcome, McplClient, McplHost, McplSession,
+    PushError, PushEvent, PushEventQueue, PushEventStore, PushQueueOptions, PushRejection,
+    PushRetryPolicy, PushSequenceTracker, RateLimit, RateLimitStats, RequestContext, Router,
+    SequenceCheck,
+};
+use tokio::sync::mpsc;
+
+mod common;
+use common::duplex_pair;
+
+fn event(id: &str, text: &str) -> PushEventParams {
+    PushEventParams {
+        feature_set: "game".into(),
+        event_id: id.into(),
+        timestamp: String::new(),
+        origin: None,
+        sequence: None,
+        priority: None,
+        expires_at: None,
+        payload: PushEventPayload {
+            content: vec![ContentBlock::text(text)],
+        },
+    }
+}
+
+/// A host that accepts every event and records the ids it saw, in order.
+fn recording_host(conn: McplConnection) -> Arc<Mutex<Vec<String>>> {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    let mut router = Router::new();
+    router.on(method::PUSH_EVENT, move |_ctx, params: PushEventParams| {
+        let recorded = Arc::clone(&recorded);
+        async move {
+            assert!(!params.timestamp.is_empty());
+            recorded.lock().unwrap().push(params.event_id);
+            Ok(PushEventResult {
+                accepted: true,
+                inference_id: None,
+                reason: None,
+            })
+        }
+    });
+    tokio::spawn(async move { router.serve(conn).await });
+    seen
+}
+
+#[tokio::test]
+async fn test_push_queue_buffers_dedups_and_drains_in_order() {
+    let queue = PushEventQueue::new(PushQueueOptions {
+        capacity: 3,
+        drop_policy: DropPolicy::DropOldest,
+        ..Default::default()
+    });
+
+    // Buffered while no host is attached
+    queue.push(event("e1", "one")).unwrap();
+    queue.push(event("e2", "two")).unwrap();
+    assert_eq!(
+        queue.push(event("e1", "again")),
+        Err(PushError::Duplicate("e1".into()))
+    );
+    let generated = queue.push(event("", "three")).unwrap();
+    assert!(!generated.is_empty());
+    // Full: the oldest event makes room
+    queue.push(event("e4", "four")).unwrap();
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue.dropped(), 1);
+
+    let (host, server) = duplex_pair();
+    let seen = recording_host(host);
+    queue.attach(McplClient::from(&server));
+    queue.flush().await;
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec!["e2".to_string(), generated, "e4".into()]
+    );
+
+    // Paused while the host is busy
+    queue.pause();
+    queue.push(event("e5", "five")).unwrap();
+    tokio::task::yield_now().await;
+    assert_eq!(queue.len(), 1);
+    queue.resume();
+    queue.flush().await;
+    assert_eq!(seen.lock().unwrap().last().unwrap(), "e5");
+
+    let strict = PushEventQueue::new(PushQueueOptions {
+        capacity: 1,
+        drop_policy: DropPolicy::DropNewest,
+        ..Default::default()
+    });
+    strict.push(event("a", "a")).unwrap();
+    assert_eq!(strict.push(event("b", "b")), Err(PushError::Full));
+    assert_eq!(strict.len(), 1);
+}
+
+#[tokio::test]
+async fn test_push_queue_rate_limits() {
+    let queue = PushEventQueue::new(PushQueueOptions {
+        rate_limits: HashMap::from([
+            (
+                "telemetry".to_string(),
+                RateLimit::per_second(0.01).burst(2),
+            ),
+            (
+                "scores".to_string(),
+                RateLimit::per_second(20.0).excess(ExcessPolicy::Coalesce),
+            ),
+            (
+                "chat".to_string(),
+                RateLimit::per_second(20.0).excess(ExcessPolicy::Delay),
+            ),
+        ]),
+        ..Default::default()
+    });
+    let (host, server) = duplex_pair();
+    let seen = recording_host(host);
+    for (feature_set, ids) in [
+        ("telemetry", ["t1", "t2", "t3", "t4"]),
+        ("scores", ["s1", "s2", "s3", "s4"]),
+        ("chat", ["c1", "c2", "c3", "c4"]),
+    ] {
+        for id in ids {
+            let mut event = event(id, id);
+            event.feature_set = feature_set.into();
+            queue.push(event).unwrap();
+        }
+    }
+
+    let started = std::time::Instant::now();
+    queue.attach(McplClient::from(&server));
+    queue.flush().await;
+    // Three chat events waited a twentieth of a second each
+    assert!(started.elapsed() >= std::time::Duration::from_millis(140));
+
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(
+        seen,
+        ["t1", "t2", "s1", "s4", "c1", "c2", "c3", "c4"].map(String::from)
+    );
+    let telemetry = queue.rate_limit_stats("telemetry");
+    assert_eq!(telemetry.dropped, 2);
+    let scores = queue.rate_limit_stats("scores");
+    assert_eq!((scores.coalesced, scores.delayed), (2, 1));
+    let chat = queue.rate_limit_stats("chat");
+    assert_eq!((chat.dropped, chat.delayed), (0, 3));
+    assert_eq!(queue.rate_limit_stats("lobby"), RateLimitStats::default());
+}
+
+#[tokio::test]
+async fn test_push_queue_retries_transient_rejections() {
+    let attempts = Arc::new(Mutex::new(HashMap::<String, u32>::new()));
+    let counted = Arc::clone(&attempts);
+    let mut router = Router::new();
+    router.on(method::PUSH_EVENT, move |_ctx, params: PushEventParams| {
+        let counted = Arc::clone(&counted);
+        async move {
+            let mut counted = counted.lock().unwrap();
+            let attempt = counted.entry(params.event_id.clone()).or_default();
+            *attempt += 1;
+            let reason = match params.event_id.as_str() {
+                "settles" if *attempt < 3 => Some("busy"),
+                "invalid" => Some("malformed"),
+                "stuck" => Some("busy"),
+                _ => None,
+            };
+            Ok(PushEventResult {
+                accepted: reason.is_none(),
+                inference_id: None,
+                reason: reason.map(String::from),
+            })
+        }
+    });
+    let (host, server) = duplex_pair();
+    tokio::spawn(async move { router.serve(host).await });
+
+    let rejected = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&rejected);
+    let queue = PushEventQueue::new(PushQueueOptions {
+        retry: Some(PushRetryPolicy {
+            max_attempts: 4,
+            initial_backoff: std::time::Duration::from_millis(5),
+            ..Default::default()
+        }),
+        on_rejected: Some(Arc::new(move |rejection: &PushRejection| {
+            sink.lock().unwrap().push((
+                rejection.event.event_id.clone(),
+                rejection.reason.clone(),
+                rejection.attempts,
+            ));
+        })),
+        ..Default::default()
+    });
+    for id in ["settles", "invalid", "stuck"] {
+        queue.push(event(id, id)).unwrap();
+    }
+    queue.attach(McplClient::from(&server));
+    queue.flush().await;
+
+    let attempts = attempts.lock().unwrap().clone();
+    assert_eq!(attempts["settles"], 3);
+    assert_eq!(attempts["invalid"], 1);
+    assert_eq!(attempts["stuck"], 4);
+    assert_eq!(
+        *rejected.lock().unwrap(),
+        vec![
+            ("invalid".to_string(), Some("malformed".to_string()), 1),
+            ("stuck".to_string(), Some("busy".to_string()), 4),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_push_queue_follows_enabled_feature_sets() {
+    let update = |enabled: &[&str], disabled: &[&str]| FeatureSetsUpdateParams {
+        enabled: Some(enabled.iter().map(|n| n.to_string()).collect()),
+        disabled: Some(disabled.iter().map(|n| n.to_string()).collect()),
+        scopes: None,
+    };
+    let mut registry = FeatureSetRegistry::from_declarations([
+        FeatureSetDeclaration::named("game"),
+        FeatureSetDeclaration::named("telemetry"),
+    ]);
+    registry.apply_update(&update(&["game"], &[]));
+    let on = |feature_set: &str, id: &str| PushEventParams {
+        feature_set: feature_set.into(),
+        ..event(id, id)
+    };
+
+    // Rejected locally, both when pushed and when disabled while queued
+    let rejected = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&rejected);
+    let rejecting = PushEventQueue::new(PushQueueOptions {
+        enabled: Some(registry.watch_enabled()),
+        on_rejected: Some(Arc::new(move |rejection: &PushRejection| {
+            sink.lock()
+                .unwrap()
+                .push((rejection.event.event_id.clone(), rejection.attempts));
+        })),
+        ..Default::default()
+    });
+    assert_eq!(
+        rejecting.push(on("telemetry", "t0")),
+        Err(PushError::NotEnabled("telemetry".into()))
+    );
+    rejecting.push(on("game", "g0")).unwrap();
+    registry.apply_update(&update(&[], &["game"]));
+    let (host, server) = duplex_pair();
+    let seen = recording_host(host);
+    rejecting.attach(McplClient::from(&server));
+    rejecting.flush().await;
+    assert!(seen.lock().unwrap().is_empty());
+    assert_eq!(*rejected.lock().unwrap(), vec![("g0".to_string(), 0)]);
+
+    // Held until re-enabled, without blocking enabled feature sets
+    registry.apply_update(&update(&["game"], &[]));
+    let holding = PushEventQueue::new(PushQueueOptions {
+        enabled: Some(registry.watch_enabled()),
+        disabled: DisabledPolicy::Hold,
+        ..Default::default()
+    });
+    holding.push(on("telemetry", "t1")).unwrap();
+    holding.push(on("game", "g1")).unwrap();
+    holding.attach(McplClient::from(&server));
+    while seen.lock().unwrap().is_empty() {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(holding.len(), 1);
+    registry.apply_update(&update(&["telemetry"], &[]));
+    holding.flush().await;
+    assert_eq!(*seen.lock().unwrap(), ["g1", "t1"].map(String::from));
+}
+
+#[tokio::test]
+async fn test_push_queue_replays_stored_events() {
+    let root = std::env::temp_dir().join(format!("mcpl-push-{}", uuid::Uuid::now_v7()));
+    let options = |store: FilePushEventStore| PushQueueOptions {
+        store: Some(Arc::new(store)),
+        ..Default::default()
+    };
+
+    // Pushed but never delivered before the server went away
+    let queue = PushEventQueue::new(options(FilePushEventStore::open(&root).unwrap()));
+    for id in ["e1", "e2", "e3"] {
+        queue.push(event(id, id)).unwrap();
+    }
+    drop(queue);
+
+    let store = FilePushEventStore::open(&root).unwrap();
+    let saved = store.load().unwrap();
+    assert_eq!(saved.len(), 3);
+    assert!(saved.iter().all(|event| !event.timestamp.is_empty()));
+    let queue = PushEventQueue::new(options(store));
+    assert_eq!(queue.len(), 3);
+    assert_eq!(
+        queue.push(event("e2", "again")),
+        Err(PushError::Duplicate("e2".into()))
+    );
+
+    let timestamps = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&timestamps);
+    let mut router = Router::new();
+    router.on(method::PUSH_EVENT, move |_ctx, params: PushEventParams| {
+        let recorded = Arc::clone(&recorded);
+        async move {
+            recorded
+                .lock()
+                .unwrap()
+                .push((params.event_id, params.timestamp));
+            Ok(serde_json::json!({ "accepted": true }))
+        }
+    });
+    let (host, server) = duplex_pair();
+    tokio::spawn(async move { router.serve(host).await });
+    queue.attach(McplClient::from(&server));
+    queue.flush().await;
+
+    let replayed: Vec<_> = saved
+        .into_iter()
+        .map(|event| (event.event_id, event.timestamp))
+        .collect();
+    assert_eq!(*timestamps.lock().unwrap(), replayed);
+    assert!(FilePushEventStore::open(&root)
+        .unwrap()
+        .load()
+        .unwrap()
+        .is_empty());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_push_queue_numbers_events_per_feature_set() {
+    let sequences = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&sequences);
+    let mut router = Router::new();
+    router.on(method::PUSH_EVENT, move |_ctx, params: PushEventParams| {
+        let recorded = Arc::clone(&recorded);
+        async move {
+            recorded
+                .lock()
+                .unwrap()
+                .push((params.feature_set, params.sequence.unwrap()));
+            Ok(serde_json::json!({ "accepted": true }))
+        }
+    });
+    let (host, server) = duplex_pair();
+    tokio::spawn(async move { router.serve(host).await });
+
+    let queue = PushEventQueue::new(PushQueueOptions {
+        rate_limits: HashMap::from([("chat".to_string(), RateLimit::per_second(0.01))]),
+        ..Default::default()
+    });
+    let on = |feature_set: &str, id: &str| PushEventParams {
+        feature_set: feature_set.into(),
+        ..event(id, id)
+    };
+    // The second chat event is dropped by the rate limit before it is numbered
+    for (feature_set, id) in [
+        ("game", "g1"),
+        ("chat", "c1"),
+        ("chat", "c2"),
+        ("game", "g2"),
+    ] {
+        queue.push(on(feature_set, id)).unwrap();
+    }
+    let client = McplClient::from(&server);
+    queue.attach(client.clone());
+    queue.flush().await;
+    // A new host starts counting again
+    queue.detach();
+    queue.push(on("game", "g3")).unwrap();
+    queue.attach(client);
+    queue.flush().await;
+    let expected = [("game", 1), ("chat", 1), ("game", 2), ("game", 1)];
+    assert_eq!(
+        *sequences.lock().unwrap(),
+        expected.map(|(feature_set, sequence)| (feature_set.to_string(), sequence))
+    );
+
+    let mut tracker = PushSequenceTracker::new();
+    assert_eq!(tracker.check("game", 7), SequenceCheck::InOrder);
+    tracker.record("game", 7);
+    assert!(tracker.check("game", 8).is_in_order());
+    assert_eq!(
+        tracker.check("game", 10),
+        SequenceCheck::Gap {
+            expected: 8,
+            received: 10
+        }
+    );
+    assert_eq!(
+        tracker.check("game", 7),
+        SequenceCheck::Stale {
+            expected: 8,
+            received: 7
+        }
+    );
+    tracker.reset("game");
+    assert_eq!(tracker.last("game"), None);
+}
+
+#[test]
+fn test_push_event_builder() {
+    let event = PushEvent::builder("lobby")
+        .text("User joined")
+        .content(ContentBlock::text("Welcome"))
+        .origin(serde_json::json!({ "user": "ana" }))
+        .build();
+    assert_eq!(event.feature_set, "lobby");
+    assert!(uuid::Uuid::parse_str(&event.event_id).is_ok());
+    // RFC 3339 UTC with milliseconds, e.g. 2024-05-01T12:30:00.250Z
+    assert_eq!(event.timestamp.len(), 24);
+    assert!(event.timestamp.ends_with('Z'));
+    assert_eq!(event.timestamp.as_bytes()[10], b'T');
+    assert_eq!(event.payload.content.len(), 2);
+    assert_eq!(event.origin.unwrap()["user"], "ana");
+    assert_eq!(event.sequence, None);
+
+    let epoch = PushEvent::builder("game")
+        .event_id("e1")
+        .timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_250))
+        .build();
+    assert_eq!(epoch.event_id, "e1");
+    assert_eq!(epoch.timestamp, "1970-01-01T00:00:01.250Z");
+    assert!(epoch.payload.content.is_empty());
+}
+
+/// Hands each connected session to the test.
+struct SessionSink {
+    sessions: mpsc::UnboundedSender<McplSession>,
+}
+
+impl McplServerHandler for SessionSink {
+    async fn on_connected(&self, session: &McplSession) {
+        self.sessions.send(session.clone()).unwrap();
+    }
+}
+
+/// Starts inference `inf-1` for every event except `spam` (rejected) and
+/// `quiet` (accepted without an inference).
+struct InferringHost;
+
+impl McplHostHandler for InferringHost {
+    async fn on_push_event(
+        &self,
+        _ctx: &RequestContext,
+        params: PushEventParams,
+    ) -> HandlerResult<PushEventResult> {
+        let (accepted, inference_id) = match params.event_id.as_str() {
+            "spam" => (false, None),
+            "quiet" => (true, None),
+            _ => (true, Some("inf-1".to_string())),
+        };
+        Ok(PushEventResult {
+            accepted,
+            inference_id,
+            reason: (!accepted).then(|| "flood".into()),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_push_events_correlate_with_inferences() {
+    let (server_conn, host_conn) = duplex_pair();
+    let (sessions_tx, mut sessions_rx) = mpsc::unbounded_channel();
+    let correlator = InferenceCorrelator::new();
+    let server = McplServer::builder()
+        .handler(SessionSink {
+            sessions: sessions_tx,
+        })
+        .correlator(correlator.clone())
+        .build();
+    tokio::spawn(async move { server.serve(server_conn).await });
+    let host = McplHost::builder()
+        .handler(InferringHost)
+        .capabilities(McplCapabilities {
+            push_events: Some(true),
+            ..McplCapabilities::new("0.4")
+        })
+        .connect(host_conn)
+        .await
+        .unwrap();
+    let session = sessions_rx.recv().await.unwrap();
+
+    let queue = PushEventQueue::new(PushQueueOptions {
+        correlator: Some(correlator.clone()),
+        ..Default::default()
+    });
+    for id in ["e1", "e2", "spam", "quiet"] {
+        queue.push(event(id, id)).unwrap();
+    }
+    queue.attach(McplClient::new(session.connection().clone()));
+    queue.flush().await;
+
+    assert_eq!(correlator.inference_for("e1").as_deref(), Some("inf-1"));
+    assert_eq!(host.inference_events("inf-1"), ["e1", "e2"]);
+    assert_eq!(
+        correlator
+            .await_inference_outcome("spam")
+            .await
+            .unwrap_err(),
+        CorrelationError::Rejected {
+            event_id: "spam".into(),
+            reason: "flood".into()
+        }
+    );
+    assert_eq!(
+        correlator
+            .await_inference_outcome("quiet")
+            .await
+            .unwrap_err(),
+        CorrelationError::NoInference("quiet".into())
+    );
+
+    // The host reports the inference once it finishes
+    let waiting = {
+        let correlator = correlator.clone();
+        tokio::spawn(async move { correlator.await_inference_outcome("e2").await })
+    };
+    host.connection()
+        .send_notification_typed(
+            method::CONTEXT_AFTER_INFERENCE,
+            &ContextAfterInferenceParams {
+                inference_id: "inf-1".into(),
+                conversation_id: "conv".into(),
+                turn_index: 1,
+                user_message: String::new(),
+                assistant_message: "Knight to f3".into(),
+                user_content: None,
+                assistant_content: None,
+                model: ModelInfo {
+                    id: "model".into(),
+                    vendor: "test".into(),
+                    context_window: 8192,
+                    capabilities: vec![],
+                },
+                usage: InferenceUsage {
+                    input_tokens: 10,
+                    output_tokens: 3,
+                },
+                channels: None,
+            },
+        )
+        .await
+        .unwrap();
+    match waiting.await.unwrap().unwrap() {
+        InferenceOutcome::AfterInference(params) => {
+            assert_eq!(params.assistant_message, "Knight to f3")
+        }
+        other => panic!("Unexpected outcome {other:?}"),
+    }
+    let outcome = correlator.await_inference_outcome("e1").await.unwrap();
+    assert_eq!(outcome.inference_id(), "inf-1");
+    assert_eq!(correlator.inference_for("e1"), None);
+    assert_eq!(host.finish_inference("inf-1"), ["e1", "e2"]);
+    assert!(host.inference_events("inf-1").is_empty());
+}
+
+#[tokio::test]
+async fn test_push_queue_sends_urgent_events_first() {
+    let queue = PushEventQueue::new(PushQueueOptions {
+        capacity: 4,
+        ..Default::default()
+    });
+    let urgent = |id: &str, priority| PushEventParams {
+        priority: Some(priority),
+        ..event(id, id)
+    };
+    queue.push(urgent("telemetry1", PushPriority::Low)).unwrap();
+    queue.push(event("move", "e4")).unwrap();
+    queue.push(urgent("telemetry2", PushPriority::Low)).unwrap();
+    queue.push(urgent("error", PushPriority::High)).unwrap();
+    // Full: the oldest low priority event makes room
+    queue
+        .push(urgent("game-over", PushPriority::Critical))
+        .unwrap();
+    assert_eq!(queue.dropped(), 1);
+    // Full again: the older of the two low priority events goes
+    queue.push(urgent("telemetry3", PushPriority::Low)).unwrap();
+    assert_eq!(queue.dropped(), 2);
+
+    let (host, server) = duplex_pair();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&seen);
+    let mut router = Router::new();
+    router.on(method::PUSH_EVENT, move |_ctx, params: PushEventParams| {
+        let recorded = Arc::clone(&recorded);
+        async move {
+            recorded
+                .lock()
+                .unwrap()
+                .push((params.event_id, params.priority));
+            Ok(PushEventResult {
+                accepted: true,
+                inference_id: None,
+                reason: None,
+            })
+        }
+    });
+    tokio::spawn(async move { router.serve(host).await });
+    queue.attach(McplClient::from(&server));
+    queue.flush().await;
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            ("game-over".to_string(), Some(PushPriority::Critical)),
+            ("error".into(), Some(PushPriority::High)),
+            ("move".into(), None),
+            ("telemetry3".into(), Some(PushPriority::Low)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_push_queue_drops_expired_events() {
+    let expired = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&expired);
+    let queue = PushEventQueue::new(PushQueueOptions {
+        on_expired: Some(Arc::new(move |event: &PushEventParams| {
+            recorded.lock().unwrap().push(event.event_id.clone());
+        })),
+        ..Default::default()
+    });
+    let stale = PushEventParams {
+        expires_at: Some("2024-05-01T12:30:00.250Z".into()),
+        ..event("stale", "Opponent is thinking")
+    };
+    queue.push(stale).unwrap();
+    queue
+        .push(
+            PushEvent::builder("game")
+                .event_id("brief")
+                .ttl(Duration::from_millis(20))
+                .build(),
+        )
+        .unwrap();
+    queue.push(event("move", "e4")).unwrap();
+    queue
+        .push(
+            PushEvent::builder("game")
+                .event_id("lasting")
+                .ttl(Duration::from_secs(3_600))
+                .build(),
+        )
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Delivered after a reconnect, without the events that went stale
+    let (host, server) = duplex_pair();
+    let seen = recording_host(host);
+    queue.attach(McplClient::from(&server));
+    queue.flush().await;
+    assert_eq!(*seen.lock().unwrap(), ["move", "lasting"]);
+    assert_eq!(*expired.lock().unwrap(), ["stale", "brief"]);
+    assert_eq!(queue.expired(), 2);
+}
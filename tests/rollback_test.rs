@@ -0,0 +1,119 @@
+use std::sync::Mutex;
+
+use mcpl_core::capabilities::*;
+use mcpl_core::host::McplHostHandler;
+use mcpl_core::methods::*;
+use mcpl_core::server::{HandlerResult, McplServer, McplServerHandler};
+use mcpl_core::{McplHost, PoolError, RequestContext, RollbackCoordinator, ServerPool};
+
+mod common;
+use common::duplex_pair;
+
+/// A server that rolls back to any checkpoint except those it refuses.
+struct Rewindable {
+    refuse: &'static str,
+    restored: Mutex<Vec<String>>,
+}
+
+impl McplServerHandler for Rewindable {
+    async fn on_state_rollback(
+        &self,
+        _ctx: &RequestContext,
+        params: StateRollbackParams,
+    ) -> HandlerResult<StateRollbackResult> {
+        let success = params.checkpoint != self.refuse;
+        if success {
+            self.restored
+                .lock()
+                .unwrap()
+                .push(params.checkpoint.clone());
+        }
+        Ok(StateRollbackResult {
+            checkpoint: params.checkpoint,
+            success,
+            reason: (!success).then(|| "corrupt".to_string()),
+        })
+    }
+}
+
+struct Host;
+
+impl McplHostHandler for Host {}
+
+async fn connect(name: &str, refuse: &'static str) -> (McplHost<Host>, McplServer<Rewindable>) {
+    let server = McplServer::builder()
+        .handler(Rewindable {
+            refuse,
+            restored: Mutex::new(Vec::new()),
+        })
+        .capabilities(McplCapabilities {
+            rollback: Some(true),
+            feature_sets: Some(vec![FeatureSetDeclaration::named(name).rollback(true)]),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let (host_conn, server_conn) = duplex_pair();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(server_conn).await });
+    let host = McplHost::builder()
+        .handler(Host)
+        .capabilities(McplCapabilities::new("0.4"))
+        .connect(host_conn)
+        .await
+        .unwrap();
+    (host, server)
+}
+
+#[tokio::test]
+async fn test_rollback_coordinator() {
+    let (game, game_server) = connect("game", "g-2").await;
+    let (lobby, lobby_server) = connect("lobby", "").await;
+    let pool = ServerPool::new();
+    pool.insert("game", game.session());
+    pool.insert("lobby", lobby.session());
+
+    let rollbacks = RollbackCoordinator::new().parallel(true);
+    rollbacks.record(1, "game", "g-1");
+    rollbacks.record(1, "lobby", "l-1");
+    rollbacks.record(2, "game", "g-2");
+    rollbacks.record(3, "lobby", "l-3");
+    rollbacks.record(4, "chess", "c-4");
+    assert_eq!(
+        rollbacks.plan(3).into_iter().collect::<Vec<_>>(),
+        [
+            ("game".to_string(), "g-2".to_string()),
+            ("lobby".to_string(), "l-3".to_string())
+        ]
+    );
+
+    // Each feature set goes back to its last checkpoint at or before turn 1
+    let report = rollbacks.rollback(&pool, 1).await;
+    assert!(!report.is_complete());
+    assert_eq!(report.unavailable, ["chess"]);
+    assert!(report.outcomes.iter().all(|outcome| outcome.succeeded()));
+    assert_eq!(*game_server.handler().restored.lock().unwrap(), ["g-1"]);
+    assert_eq!(*lobby_server.handler().restored.lock().unwrap(), ["l-1"]);
+
+    // A refusal and an unknown feature set fail only their own rollbacks
+    let rollbacks = RollbackCoordinator::new();
+    rollbacks.record(2, "game", "g-2");
+    rollbacks.record(2, "lobby", "l-2");
+    rollbacks.record(2, "chess", "c-2");
+    let report = rollbacks.rollback(&pool, 5).await;
+    let failed: Vec<&str> = report
+        .failures()
+        .map(|outcome| outcome.feature_set.as_str())
+        .collect();
+    assert_eq!(failed, ["chess", "game"]);
+    assert!(matches!(
+        report.outcomes[0].result,
+        Err(PoolError::UnknownFeatureSet(_))
+    ));
+    let game_result = &report.outcomes[1].result.as_ref().unwrap().value;
+    assert_eq!(game_result.reason.as_deref(), Some("corrupt"));
+    assert!(report.outcomes[2].succeeded());
+
+    rollbacks.record(3, "game", "g-3");
+    rollbacks.forget_after(2);
+    assert_eq!(rollbacks.plan(9)["game"], "g-2");
+}
@@ -0,0 +1,423 @@
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::Router;
+
+use tokio::sync::mpsc;
+
+mod common;
+use common::duplex_pair;
+
+#[tokio::test]
+async fn test_router_dispatches_by_method() {
+    let (serving, mut caller) = duplex_pair();
+    let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+
+    let mut router = Router::new();
+    router
+        .on_request(method::PUSH_EVENT, |ctx| async move {
+            let params: PushEventParams = ctx.params().map_err(JsonRpcError::invalid_params)?;
+            Ok(serde_json::json!({ "accepted": true, "inferenceId": params.event_id }))
+        })
+        .on_notification(method::CHANNELS_CHANGED, move |notification| {
+            let seen = seen_tx.clone();
+            async move {
+                seen.send(notification.method).unwrap();
+            }
+        });
+    assert!(router.handles(method::PUSH_EVENT));
+    let serve = tokio::spawn(async move { router.serve(serving).await });
+
+    let result = caller
+        .send_request(
+            method::PUSH_EVENT,
+            Some(serde_json::json!({
+                "featureSet": "game",
+                "eventId": "e7",
+                "timestamp": "2025-01-01T00:00:00Z",
+                "payload": { "content": [] }
+            })),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result["inferenceId"], "e7");
+
+    let err = caller
+        .send_request(method::PUSH_EVENT, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INVALID_PARAMS,
+            ..
+        }
+    ));
+    let err = caller
+        .send_request(method::SCOPE_ELEVATE, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_METHOD_NOT_FOUND,
+            ..
+        }
+    ));
+
+    // Baseline methods are answered without handlers
+    caller.send_request(method::PING, None).await.unwrap();
+    let channels: ChannelsListResult = caller
+        .send_request_typed(method::CHANNELS_LIST, &serde_json::json!({}))
+        .await
+        .unwrap();
+    assert!(channels.channels.is_empty());
+
+    caller
+        .send_notification(method::CHANNELS_CHANGED, None)
+        .await
+        .unwrap();
+    assert_eq!(seen_rx.recv().await.unwrap(), method::CHANNELS_CHANGED);
+
+    drop(caller);
+    serve.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_typed_router_handlers() {
+    let (serving, mut caller) = duplex_pair();
+
+    let mut router = Router::new();
+    router.on(
+        method::STATE_ROLLBACK,
+        |_ctx, params: StateRollbackParams| async move {
+            if params.checkpoint == "missing" {
+                return Err(JsonRpcError::new(ERR_CHECKPOINT_NOT_FOUND, "No such checkpoint"));
+            }
+            Ok(StateRollbackResult {
+                checkpoint: params.checkpoint,
+                success: true,
+                reason: None,
+            })
+        },
+    );
+    tokio::spawn(async move { router.serve(serving).await });
+
+    let result: StateRollbackResult = caller
+        .send_request_typed(
+            method::STATE_ROLLBACK,
+            &StateRollbackParams {
+                feature_set: "game".into(),
+                checkpoint: "cp-2".into(),
+            },
+        )
+        .await
+        .unwrap();
+    assert!(result.success);
+    assert_eq!(result.checkpoint, "cp-2");
+
+    let err = caller
+        .send_request(
+            method::STATE_ROLLBACK,
+            Some(serde_json::json!({ "featureSet": "game", "checkpoint": "missing" })),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_CHECKPOINT_NOT_FOUND,
+            ..
+        }
+    ));
+
+    // Serde failures never reach the handler
+    let err = caller
+        .send_request(
+            method::STATE_ROLLBACK,
+            Some(serde_json::json!({ "checkpoint": 1 })),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INVALID_PARAMS,
+            ..
+        }
+    ));
+}
+
+#[tokio::test]
+async fn test_concurrency_limits() {
+    use mcpl_core::{BusyPolicy, ConcurrencyLimits};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    for when_busy in [BusyPolicy::Reject, BusyPolicy::Queue] {
+        let (serving, caller) = duplex_pair();
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new();
+        let (running_in, peak_in) = (Arc::clone(&running), Arc::clone(&peak));
+        router
+            .on_request("game/slow", move |_ctx| {
+                let (running, peak) = (Arc::clone(&running_in), Arc::clone(&peak_in));
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(serde_json::json!({}))
+                }
+            })
+            .on_request("game/fast", |_ctx| async move { Ok(serde_json::json!({})) })
+            .limit_concurrency(ConcurrencyLimits {
+                per_method: [("game/slow".to_string(), 1)].into(),
+                when_busy,
+                ..Default::default()
+            });
+        tokio::spawn(async move { router.serve(serving).await });
+
+        let handle = caller.handle();
+        let first = handle.send_request_deferred("game/slow", None).await.unwrap();
+        let second = handle.send_request_deferred("game/slow", None).await.unwrap();
+        // Other methods are not held up
+        handle.send_request("game/fast", None).await.unwrap();
+
+        first.await.unwrap();
+        let second = second.await;
+        match when_busy {
+            BusyPolicy::Reject => assert!(matches!(
+                second,
+                Err(ConnectionError::Rpc {
+                    code: ERR_BUSY,
+                    ..
+                })
+            )),
+            BusyPolicy::Queue => assert!(second.is_ok()),
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+    }
+}
+
+#[tokio::test]
+async fn test_authentication() {
+    use mcpl_core::{MetaToken, RequestContext};
+
+    let (serving, mut caller) = duplex_pair();
+    let mut router = Router::new();
+    router
+        .on_request("game/state", |_ctx| async move { Ok(serde_json::json!({ "turn": 3 })) })
+        .authenticate(MetaToken::new("authToken", ["s3cret"]));
+    tokio::spawn(async move { router.serve(serving).await });
+
+    let result = caller
+        .send_request(
+            "game/state",
+            Some(serde_json::json!({ "_meta": { "authToken": "s3cret" } })),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result["turn"], 3);
+    for params in [None, Some(serde_json::json!({ "_meta": { "authToken": "guess" } }))] {
+        let err = caller.send_request("game/state", params).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ConnectionError::Rpc {
+                code: ERR_UNAUTHORIZED,
+                ..
+            }
+        ));
+    }
+
+    // Identity recorded on the session, with a custom rejection code
+    let (serving, mut caller) = duplex_pair();
+    let session = serving.session();
+    let mut router = Router::new();
+    router
+        .on_request("game/state", |_ctx| async move { Ok(serde_json::json!({})) })
+        .authenticate(|ctx: &RequestContext| {
+            match ctx.session().and_then(|session| session.identity()) {
+                Some(identity) if identity == "player-1" => Ok(()),
+                _ => Err("unknown peer".into()),
+            }
+        })
+        .auth_error_code(-32099);
+    tokio::spawn(async move { router.serve(serving).await });
+
+    let err = caller.send_request("game/state", None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: -32099, .. }));
+    session.set_identity(Some("player-1".into()));
+    caller.send_request("game/state", None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_request_tracing() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_max_level(tracing::Level::INFO)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (serving, mut caller) = duplex_pair();
+    let mut router = Router::new();
+    router
+        .on(
+            method::STATE_ROLLBACK,
+            |_ctx, _params: StateRollbackParams| async move {
+                Err::<StateRollbackResult, _>(JsonRpcError::new(
+                    ERR_CHECKPOINT_NOT_FOUND,
+                    "No such checkpoint",
+                ))
+            },
+        )
+        .trace_requests();
+    tokio::spawn(async move { router.serve(serving).await });
+
+    caller.send_request(method::PING, None).await.unwrap();
+    caller
+        .send_request(
+            method::STATE_ROLLBACK,
+            Some(serde_json::json!({ "featureSet": "game", "checkpoint": "cp-9" })),
+        )
+        .await
+        .unwrap_err();
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = logs.lines().filter(|line| line.contains("WARN")).collect();
+    // Only the failure is logged at warn, inside the request's span
+    assert_eq!(lines.len(), 1, "{}", logs);
+    assert!(lines[0].contains("mcpl.request"));
+    assert!(lines[0].contains("method=\"state/rollback\""));
+    assert!(lines[0].contains("feature_set=\"game\""));
+    assert!(lines[0].contains("duration_ms="));
+    assert!(lines[0].contains("No such checkpoint"));
+}
+
+#[tokio::test]
+async fn test_nested_routers() {
+    use mcpl_core::MetaToken;
+
+    let (serving, mut caller) = duplex_pair();
+    let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+
+    let mut lobby = Router::new();
+    lobby
+        .on_request("join", |ctx| async move {
+            Ok(serde_json::json!({ "method": ctx.method() }))
+        })
+        .authenticate(MetaToken::new("authToken", ["s3cret"]));
+    let mut channels = Router::new();
+    channels
+        .on_request("open", |_ctx| async move { Ok(serde_json::json!({ "opened": true })) })
+        .on_notification("publish", move |notification| {
+            let seen = seen_tx.clone();
+            async move {
+                seen.send(notification.method).unwrap();
+            }
+        });
+    let mut router = Router::new();
+    router
+        .on_request("lobby", |_ctx| async move { Ok(serde_json::json!({ "top": true })) })
+        .nest("lobby", lobby)
+        .nest("channels/", channels);
+    assert!(router.handles("lobby/join"));
+    assert!(router.handles(method::CHANNELS_OPEN));
+    assert!(!router.handles("channels/join"));
+    tokio::spawn(async move { router.serve(serving).await });
+
+    // Handlers see the full method name
+    let result = caller
+        .send_request(
+            "lobby/join",
+            Some(serde_json::json!({ "_meta": { "authToken": "s3cret" } })),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result["method"], "lobby/join");
+    assert_eq!(caller.send_request("lobby", None).await.unwrap()["top"], true);
+
+    // The sub-router's authenticator covers only its methods
+    let err = caller.send_request("lobby/join", None).await.unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_UNAUTHORIZED,
+            ..
+        }
+    ));
+    let result = caller.send_request(method::CHANNELS_OPEN, None).await.unwrap();
+    assert_eq!(result["opened"], true);
+
+    // Built-in answers still apply under a prefix
+    let channels: ChannelsListResult = caller
+        .send_request_typed(method::CHANNELS_LIST, &serde_json::json!({}))
+        .await
+        .unwrap();
+    assert!(channels.channels.is_empty());
+    let err = caller
+        .send_request(
+            "lobby/leave",
+            Some(serde_json::json!({ "_meta": { "authToken": "s3cret" } })),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_METHOD_NOT_FOUND,
+            ..
+        }
+    ));
+
+    caller
+        .send_notification(method::CHANNELS_PUBLISH, None)
+        .await
+        .unwrap();
+    assert_eq!(seen_rx.recv().await.unwrap(), method::CHANNELS_PUBLISH);
+}
+
+#[tokio::test]
+async fn test_handler_panics_are_answered() {
+    let (serving, mut caller) = duplex_pair();
+    let mut router = Router::new();
+    router.on_request("game/crash", |ctx| async move {
+        if ctx.method() == "game/crash" {
+            panic!("boom");
+        }
+        Ok(serde_json::json!({}))
+    });
+    tokio::spawn(async move { router.serve(serving).await });
+
+    let err = caller.send_request("game/crash", None).await.unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INTERNAL_ERROR,
+            ..
+        }
+    ));
+    // The connection keeps serving
+    caller.send_request(method::PING, None).await.unwrap();
+}
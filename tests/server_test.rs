@@ -0,0 +1,457 @@
+use mcpl_core::capabilities::*;
+use mcpl_core::connection::{ConnectionError, ConnectionOptions, IncomingMessage, McplConnection};
+use mcpl_core::methods::*;
+use mcpl_core::server::{DisconnectReason, HandlerResult, McplServer, McplServerHandler};
+use mcpl_core::types::*;
+use mcpl_core::host::McplHostHandler;
+use mcpl_core::{McplHost, McplSession, RequestContext};
+
+use tokio::sync::mpsc;
+
+mod common;
+use common::{duplex_pair, duplex_pair_with_options};
+
+fn init_params() -> McplInitializeParams {
+    McplInitializeParams {
+        protocol_version: "2024-11-05".into(),
+        capabilities: InitializeCapabilities::default(),
+        client_info: ImplementationInfo {
+            name: "test-host".into(),
+            version: "0.1.0".into(),
+        },
+    }
+}
+
+struct GameServer {
+    updates: mpsc::UnboundedSender<FeatureSetsUpdateParams>,
+}
+
+impl McplServerHandler for GameServer {
+    async fn on_feature_sets_update(&self, params: FeatureSetsUpdateParams) {
+        self.updates.send(params).unwrap();
+    }
+
+    async fn on_state_rollback(
+        &self,
+        _ctx: &RequestContext,
+        params: StateRollbackParams,
+    ) -> HandlerResult<StateRollbackResult> {
+        if params.checkpoint == "missing" {
+            return Err(JsonRpcError::new(ERR_CHECKPOINT_NOT_FOUND, "No such checkpoint"));
+        }
+        Ok(StateRollbackResult {
+            checkpoint: params.checkpoint,
+            success: true,
+            reason: None,
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_server_dispatches_to_handler() {
+    let (server_conn, mut client) = duplex_pair();
+    let (updates_tx, mut updates_rx) = mpsc::unbounded_channel();
+    let server = McplServer::builder()
+        .handler(GameServer { updates: updates_tx })
+        .server_info(ImplementationInfo {
+            name: "game".into(),
+            version: "1.0.0".into(),
+        })
+        .capabilities(McplCapabilities {
+            rollback: Some(true),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let serve = tokio::spawn(async move { server.serve(server_conn).await });
+
+    // initialize is answered from the configured info and capabilities
+    let init: McplInitializeResult = client
+        .send_request_typed(method::INITIALIZE, &init_params())
+        .await
+        .unwrap();
+    assert_eq!(init.protocol_version, "2024-11-05");
+    assert_eq!(init.server_info.name, "game");
+    let caps = init.capabilities.experimental.unwrap().mcpl.unwrap();
+    assert!(caps.has_rollback());
+
+    // Typed request handlers
+    let rollback: StateRollbackResult = client
+        .send_request_typed(
+            method::STATE_ROLLBACK,
+            &StateRollbackParams {
+                feature_set: "game".into(),
+                checkpoint: "cp-1".into(),
+            },
+        )
+        .await
+        .unwrap();
+    assert!(rollback.success);
+    assert_eq!(rollback.checkpoint, "cp-1");
+
+    let err = client
+        .send_request(
+            method::STATE_ROLLBACK,
+            Some(serde_json::json!({ "featureSet": "game", "checkpoint": "missing" })),
+        )
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_CHECKPOINT_NOT_FOUND, .. }));
+
+    // Bad params and unhandled methods get standard errors
+    let err = client
+        .send_request(method::STATE_ROLLBACK, Some(serde_json::json!({ "checkpoint": 3 })))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_INVALID_PARAMS, .. }));
+    let err = client.send_request("game/unknown", None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_METHOD_NOT_FOUND, .. }));
+
+    // Notifications reach their handler
+    client
+        .send_notification_typed(
+            method::FEATURE_SETS_UPDATE,
+            &FeatureSetsUpdateParams {
+                enabled: Some(vec!["game".into()]),
+                disabled: None,
+                scopes: None,
+            },
+        )
+        .await
+        .unwrap();
+    let update = updates_rx.recv().await.unwrap();
+    assert_eq!(update.enabled.unwrap(), vec!["game".to_string()]);
+
+    // The serve loop ends cleanly when the host disconnects
+    drop(client);
+    serve.await.unwrap().unwrap();
+}
+
+/// Records lifecycle hooks as they run.
+struct LifecycleServer {
+    events: mpsc::UnboundedSender<String>,
+}
+
+impl McplServerHandler for LifecycleServer {
+    async fn on_connected(&self, _session: &McplSession) {
+        self.events.send("connected".into()).unwrap();
+    }
+
+    async fn on_initialized(&self, session: &McplSession) {
+        let host = session.negotiated().unwrap().peer_info.name;
+        self.events.send(format!("initialized {host}")).unwrap();
+    }
+
+    async fn on_shutdown(&self, session: &McplSession) {
+        session
+            .connection()
+            .send_notification("game/goodbye", None)
+            .await
+            .unwrap();
+        self.events.send("shutdown".into()).unwrap();
+    }
+
+    async fn on_disconnect(&self, _session: &McplSession, reason: DisconnectReason) {
+        self.events.send(format!("disconnect {reason:?}")).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_server_lifecycle_hooks() {
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let server = McplServer::builder()
+        .handler(LifecycleServer { events: events_tx })
+        .build();
+
+    // The host hangs up
+    let (server_conn, mut client) = duplex_pair();
+    let serving = server.clone();
+    let serve = tokio::spawn(async move { serving.serve(server_conn).await });
+    client
+        .send_request(method::INITIALIZE, Some(serde_json::to_value(init_params()).unwrap()))
+        .await
+        .unwrap();
+    client
+        .send_notification(method::NOTIFICATIONS_INITIALIZED, None)
+        .await
+        .unwrap();
+    assert_eq!(events_rx.recv().await.unwrap(), "connected");
+    assert_eq!(events_rx.recv().await.unwrap(), "initialized test-host");
+    drop(client);
+    serve.await.unwrap().unwrap();
+    assert_eq!(events_rx.recv().await.unwrap(), "disconnect PeerClosed");
+
+    // The server shuts down, saying goodbye first
+    let (server_conn, mut client) = duplex_pair();
+    let serving = server.clone();
+    let serve = tokio::spawn(async move { serving.serve(server_conn).await });
+    assert_eq!(events_rx.recv().await.unwrap(), "connected");
+    server.shutdown();
+    serve.await.unwrap().unwrap();
+    assert_eq!(events_rx.recv().await.unwrap(), "shutdown");
+    assert_eq!(events_rx.recv().await.unwrap(), "disconnect Shutdown");
+    match client.next_message().await.unwrap() {
+        IncomingMessage::Notification(notification) => {
+            assert_eq!(notification.method, "game/goodbye")
+        }
+        _ => panic!("Expected notification"),
+    }
+    assert!(matches!(client.next_message().await, Err(ConnectionError::Closed)));
+}
+
+/// Hands each connected session to the test.
+struct SessionSink {
+    sessions: mpsc::UnboundedSender<McplSession>,
+}
+
+impl McplServerHandler for SessionSink {
+    async fn on_connected(&self, session: &McplSession) {
+        self.sessions.send(session.clone()).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_serve_tcp_accepts_hosts() {
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sessions_tx, mut sessions_rx) = mpsc::unbounded_channel();
+    let server = McplServer::builder()
+        .handler(SessionSink {
+            sessions: sessions_tx,
+        })
+        .max_connections(1)
+        .build();
+    let serving = server.clone();
+    let accept = tokio::spawn(async move { serving.serve_tcp(listener).await });
+
+    let mut first = McplConnection::from_tcp(TcpStream::connect(addr).await.unwrap());
+    first.send_request(method::PING, None).await.unwrap();
+    let first_session = sessions_rx.recv().await.unwrap();
+
+    // The second host waits for the only slot
+    let mut second = McplConnection::from_tcp(TcpStream::connect(addr).await.unwrap());
+    let ping = tokio::time::timeout(
+        Duration::from_millis(100),
+        second.send_request(method::PING, None),
+    );
+    assert!(ping.await.is_err());
+
+    // Shutting down one session frees the slot for the next host
+    first_session.shutdown();
+    assert!(matches!(first.next_message().await, Err(ConnectionError::Closed)));
+    second.send_request(method::PING, None).await.unwrap();
+    sessions_rx.recv().await.unwrap();
+
+    server.shutdown();
+    accept.await.unwrap().unwrap();
+    assert!(matches!(second.next_message().await, Err(ConnectionError::Closed)));
+}
+
+#[tokio::test]
+async fn test_dynamic_feature_sets() {
+    let (server_conn, mut client) = duplex_pair();
+    let (updates_tx, _updates_rx) = mpsc::unbounded_channel();
+    let server = McplServer::builder()
+        .handler(GameServer { updates: updates_tx })
+        .capabilities(McplCapabilities {
+            rollback: Some(true),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(server_conn).await });
+
+    let init: McplInitializeResult = client
+        .send_request_typed(
+            method::INITIALIZE,
+            &McplInitializeParams {
+                capabilities: InitializeCapabilities::with_mcpl(McplCapabilities::new("0.4")),
+                ..init_params()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(init.capabilities.mcpl().unwrap().feature_sets.is_none());
+    let rollback = |feature_set: &str| {
+        Some(serde_json::json!({ "featureSet": feature_set, "checkpoint": "cp-1" }))
+    };
+    // Without declarations, requests are not checked against feature sets
+    client
+        .send_request(method::STATE_ROLLBACK, rollback("lobby"))
+        .await
+        .unwrap();
+
+    server
+        .add_feature_set(FeatureSetDeclaration {
+            name: "lobby".into(),
+            description: None,
+            uses: vec![],
+            rollback: true,
+            host_state: false,
+            version: None,
+            min_host_version: None,
+        })
+        .await;
+    let changed = match client.next_message().await.unwrap() {
+        IncomingMessage::Notification(notif) => notif,
+        _ => panic!("Expected featureSets/changed"),
+    };
+    assert_eq!(changed.method, method::FEATURE_SETS_CHANGED);
+    assert_eq!(changed.params_as::<serde_json::Value>().unwrap()["added"]["lobby"]["rollback"], true);
+    let peer = client.session().negotiated().unwrap().peer_capabilities.unwrap();
+    assert_eq!(peer.feature_sets.unwrap()[0].name, "lobby");
+
+    client
+        .send_request(method::STATE_ROLLBACK, rollback("lobby"))
+        .await
+        .unwrap();
+    let err = client
+        .send_request(method::STATE_ROLLBACK, rollback("game"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_UNKNOWN_FEATURE_SET,
+            ..
+        }
+    ));
+
+    assert!(server.remove_feature_set("lobby").await);
+    assert!(!server.remove_feature_set("lobby").await);
+    assert!(server.feature_sets().is_empty());
+    match client.next_message().await.unwrap() {
+        IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.params_as::<serde_json::Value>().unwrap()["removed"][0], "lobby")
+        }
+        _ => panic!("Expected featureSets/changed"),
+    }
+    let err = client
+        .send_request(method::STATE_ROLLBACK, rollback("lobby"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_UNKNOWN_FEATURE_SET,
+            ..
+        }
+    ));
+    let peer = client.session().negotiated().unwrap().peer_capabilities.unwrap();
+    assert!(peer.feature_sets.unwrap().is_empty());
+}
+
+/// Takes a while to answer `game/slow`.
+struct SlowServer;
+
+impl McplServerHandler for SlowServer {
+    async fn on_request(&self, ctx: &RequestContext) -> HandlerResult<serde_json::Value> {
+        match ctx.method() {
+            "game/slow" => {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                Ok(serde_json::json!({ "done": true }))
+            }
+            _ => Err(JsonRpcError::method_not_found(ctx.method())),
+        }
+    }
+}
+
+struct Host;
+
+impl McplHostHandler for Host {}
+
+#[tokio::test]
+async fn test_feature_sets_list() {
+    let (server_conn, host_conn) = duplex_pair();
+    let (updates_tx, mut updates_rx) = mpsc::unbounded_channel();
+    let declare = |name: &str| FeatureSetDeclaration {
+        name: name.into(),
+        description: None,
+        uses: vec![],
+        rollback: true,
+        host_state: false,
+        version: None,
+        min_host_version: None,
+    };
+    let server = McplServer::builder()
+        .handler(GameServer { updates: updates_tx })
+        .capabilities(McplCapabilities {
+            rollback: Some(true),
+            feature_sets: Some(vec![declare("game"), declare("lobby")]),
+            ..McplCapabilities::new("0.4")
+        })
+        .build();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(server_conn).await });
+    let host = McplHost::builder()
+        .handler(Host)
+        .capabilities(McplCapabilities::new("0.4"))
+        .connect(host_conn)
+        .await
+        .unwrap();
+
+    let client = host.client();
+    client
+        .feature_sets_update(&FeatureSetsUpdateParams {
+            enabled: Some(vec!["lobby".into()]),
+            disabled: None,
+            scopes: None,
+        })
+        .await
+        .unwrap();
+    updates_rx.recv().await.unwrap();
+    server.add_feature_set(declare("chess")).await;
+
+    let listed = client.feature_sets_list().await.unwrap();
+    let states: Vec<(&str, bool)> = listed
+        .feature_sets
+        .iter()
+        .map(|fs| (fs.declaration.name.as_str(), fs.enabled))
+        .collect();
+    assert_eq!(states, [("game", false), ("lobby", true), ("chess", false)]);
+}
+
+#[tokio::test]
+async fn test_server_drain() {
+    use std::time::Duration;
+
+    let server = McplServer::builder().handler(SlowServer).build();
+    let (server_conn, mut client) = duplex_pair_with_options(ConnectionOptions {
+        background_writer: true,
+        ..Default::default()
+    });
+    let serving = server.clone();
+    let serve = tokio::spawn(async move { serving.serve(server_conn).await });
+
+    let handle = client.handle();
+    let slow = handle.send_request_deferred("game/slow", None).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let draining = server.clone();
+    let drain = tokio::spawn(async move { draining.drain().await });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(server.is_draining());
+
+    // New requests are refused, pings still answered
+    let err = handle.send_request("game/slow", None).await.unwrap_err();
+    assert!(matches!(err, ConnectionError::Rpc { code: ERR_BUSY, .. }));
+    handle.send_request(method::PING, None).await.unwrap();
+    assert!(!drain.is_finished());
+
+    // The in-flight request is answered before the connection closes
+    assert_eq!(slow.await.unwrap()["done"], true);
+    drain.await.unwrap();
+    serve.await.unwrap().unwrap();
+
+    // The host is told before the connection closes
+    match client.next_message().await.unwrap() {
+        IncomingMessage::Notification(notif) => {
+            assert_eq!(notif.method, method::NOTIFICATIONS_SHUTDOWN);
+            let params: ShutdownParams = notif.params_as().unwrap();
+            assert_eq!(params.reason.as_deref(), Some("draining"));
+        }
+        other => panic!("Expected shutdown notification, got {other:?}"),
+    }
+    assert!(matches!(client.next_message().await, Err(ConnectionError::Closed)));
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::types::*;
+use mcpl_core::{serve_service, Router};
+
+use tower::{ServiceBuilder, ServiceExt};
+
+mod common;
+use common::duplex_pair;
+
+fn router() -> Router {
+    let mut router = Router::new();
+    router
+        .on_request("game/echo", |ctx| async move {
+            Ok(ctx.params::<serde_json::Value>().unwrap_or_default())
+        })
+        .on_request("game/slow", |_ctx| async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(serde_json::json!({}))
+        });
+    router
+}
+
+#[tokio::test]
+async fn test_router_as_service() {
+    let service = router().into_service();
+
+    let request = JsonRpcRequest::new(1, "game/echo", Some(serde_json::json!({ "n": 1 })));
+    let response = service.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.id, JsonRpcId::Number(1));
+    assert_eq!(response.result_as::<serde_json::Value>().unwrap()["n"], 1);
+
+    let request = JsonRpcRequest::new(2, "game/unknown", None);
+    let response = service.oneshot(request).await.unwrap();
+    assert_eq!(response.error.unwrap().code, ERR_METHOD_NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_serve_through_middleware() {
+    let (serving, mut caller) = duplex_pair();
+    let service = ServiceBuilder::new()
+        .timeout(Duration::from_millis(50))
+        .service(router().into_service());
+    let serve = tokio::spawn(serve_service(serving, service));
+
+    let result = caller
+        .send_request("game/echo", Some(serde_json::json!([1, 2])))
+        .await
+        .unwrap();
+    assert_eq!(result, serde_json::json!([1, 2]));
+
+    let err = caller.send_request("game/slow", None).await.unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INTERNAL_ERROR,
+            ..
+        }
+    ));
+
+    drop(caller);
+    serve.await.unwrap().unwrap();
+}
@@ -0,0 +1,307 @@
+use mcpl_core::capabilities::*;
+use mcpl_core::connection::ConnectionError;
+use mcpl_core::methods::*;
+use mcpl_core::types::*;
+use mcpl_core::{
+    accept_initialize, negotiate, FeatureSetMetrics, McplClient, ProtocolVersion, Router,
+    UsageTotals,
+};
+
+mod common;
+use common::{duplex_pair, raw_peer};
+use tokio::io::AsyncWriteExt;
+
+fn info(name: &str) -> ImplementationInfo {
+    ImplementationInfo {
+        name: name.into(),
+        version: "1.0".into(),
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_helpers() {
+    let (mut server, mut client) = duplex_pair();
+
+    let accepting = tokio::spawn(async move {
+        let caps = McplCapabilities {
+            rollback: Some(true),
+            push_events: Some(true),
+            ..McplCapabilities::new("0.4")
+        };
+        let session = accept_initialize(&mut server, caps, info("game")).await;
+        (server, session)
+    });
+
+    // Requests before the handshake are refused; ping is always answered
+    let err = client.send_request(method::CHANNELS_LIST, None).await.unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_NOT_INITIALIZED,
+            ..
+        }
+    ));
+    client.send_request(method::PING, None).await.unwrap();
+
+    let caps = McplCapabilities {
+        rollback: Some(true),
+        ..McplCapabilities::new("0.4")
+    };
+    let host_session = negotiate(&mut client, caps, info("host")).await.unwrap();
+    let (_server, server_session) = accepting.await.unwrap();
+    let server_session = server_session.unwrap();
+
+    assert_eq!(host_session.peer_info.name, "game");
+    assert_eq!(server_session.peer_info.name, "host");
+    assert_eq!(host_session.protocol_version, server_session.protocol_version);
+    assert!(host_session.supports_method(method::STATE_ROLLBACK));
+    assert!(server_session.supports_method(method::STATE_ROLLBACK));
+    assert!(!host_session.supports_method(method::PUSH_EVENT));
+    assert!(!server_session.supports_method(method::PUSH_EVENT));
+    assert!(server_session.supports_method(method::PING));
+}
+
+#[tokio::test]
+async fn test_version_negotiation() {
+    assert_eq!("0.4".parse::<ProtocolVersion>().unwrap(), ProtocolVersion::V0_4);
+    assert!("four".parse::<ProtocolVersion>().is_err());
+
+    // The host also offers a newer version the server does not know
+    let (mut server, mut client) = duplex_pair();
+    let accepting = tokio::spawn(async move {
+        let caps = McplCapabilities {
+            channels: Some(true),
+            ..McplCapabilities::new("0.4")
+        };
+        accept_initialize(&mut server, caps, info("game")).await
+    });
+    let caps = McplCapabilities {
+        channels: Some(true),
+        supported_versions: Some(vec!["0.4".into(), "0.5".into()]),
+        ..McplCapabilities::new("0.5")
+    };
+    let host_session = negotiate(&mut client, caps, info("host")).await.unwrap();
+    let server_session = accepting.await.unwrap().unwrap();
+    assert_eq!(host_session.version, Some(ProtocolVersion::V0_4));
+    assert_eq!(server_session.version, Some(ProtocolVersion::V0_4));
+    assert!(host_session.supports_method(method::CHANNELS_LIST));
+    assert!(host_session.supports_method(method::PING));
+
+    // No overlap: initialize is refused and both sides report the mismatch
+    let (mut server, mut client) = duplex_pair();
+    let accepting = tokio::spawn(async move {
+        accept_initialize(&mut server, McplCapabilities::new("0.4"), info("game")).await
+    });
+    let err = negotiate(&mut client, McplCapabilities::new("0.5"), info("host"))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ConnectionError::Rpc {
+            code: ERR_INVALID_PARAMS,
+            ..
+        }
+    ));
+    match accepting.await.unwrap().unwrap_err() {
+        ConnectionError::VersionMismatch(mismatch) => {
+            assert_eq!(mismatch.ours, vec![ProtocolVersion::V0_4]);
+            assert_eq!(mismatch.theirs, vec![ProtocolVersion::new(0, 5)]);
+        }
+        other => panic!("Expected version mismatch, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn test_session_tracks_traffic() {
+    let (mut server, mut client) = duplex_pair();
+    let accepting = tokio::spawn(async move {
+        let caps = McplCapabilities {
+            scoped_access: Some(true),
+            channels: Some(true),
+            ..McplCapabilities::new("0.4")
+        };
+        accept_initialize(&mut server, caps, info("game")).await.unwrap();
+        server
+    });
+    let caps = McplCapabilities {
+        scoped_access: Some(true),
+        channels: Some(true),
+        ..McplCapabilities::new("0.4")
+    };
+    negotiate(&mut client, caps, info("host")).await.unwrap();
+    let server = accepting.await.unwrap();
+
+    let server_session = server.session();
+    let negotiated = server_session.negotiated().unwrap();
+    assert_eq!(negotiated.peer_info.name, "host");
+    assert_eq!(negotiated.local_info.name, "game");
+    assert!(server_session.supports_method(method::SCOPE_ELEVATE));
+
+    // The host answers scope/elevate from a router that reads its session
+    let host_session = client.session();
+    let mut router = Router::new();
+    router.on(method::SCOPE_ELEVATE, |ctx, params: ScopeElevateParams| async move {
+        let session = ctx.session().unwrap();
+        Ok(ScopeElevateResult {
+            approved: session.is_feature_set_enabled(&params.feature_set),
+            payload: None,
+            reason: None,
+        })
+    });
+    tokio::spawn(async move { router.serve(client).await });
+
+    host_session
+        .connection()
+        .send_notification_typed(
+            method::FEATURE_SETS_UPDATE,
+            &FeatureSetsUpdateParams {
+                enabled: Some(vec!["game".into(), "chat".into()]),
+                disabled: Some(vec!["chat".into()]),
+                scopes: None,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(host_session.enabled_feature_sets(), vec!["game".to_string()]);
+
+    let elevate = |feature_set: &str| ScopeElevateParams {
+        feature_set: feature_set.into(),
+        scope: ScopeElevateScope {
+            label: "admin".into(),
+            payload: None,
+        },
+    };
+    let client = McplClient::new(server_session.connection().clone());
+    assert!(client.scope_elevate(&elevate("game")).await.unwrap().approved);
+    assert!(!client.scope_elevate(&elevate("lobby")).await.unwrap().approved);
+    assert!(server_session.has_scope("game", "admin"));
+    assert!(!server_session.has_scope("lobby", "admin"));
+    assert_eq!(host_session.granted_scopes("game"), vec!["admin".to_string()]);
+
+    let channel = ChannelDescriptor {
+        id: "irc:1".into(),
+        channel_type: "irc".into(),
+        label: "#general".into(),
+        direction: ChannelDirection::Bidirectional,
+        address: None,
+        metadata: None,
+    };
+    server_session
+        .connection()
+        .send_notification_typed(
+            method::CHANNELS_CHANGED,
+            &ChannelsChangedParams {
+                added: Some(vec![channel]),
+                removed: None,
+                updated: None,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(server_session.channels().len(), 1);
+    assert!(server_session.channel("irc:1").is_some());
+}
+
+#[tokio::test]
+async fn test_feature_set_metrics() {
+    let (mut server, mut client) = duplex_pair();
+    let caps = || McplCapabilities {
+        rollback: Some(true),
+        push_events: Some(true),
+        ..McplCapabilities::new("0.4")
+    };
+    let accepting = tokio::spawn(async move {
+        accept_initialize(&mut server, caps(), info("game")).await.unwrap();
+        server
+    });
+    negotiate(&mut client, caps(), info("host")).await.unwrap();
+    let server = accepting.await.unwrap();
+    let server_session = server.session();
+    let host_session = client.session();
+
+    let mut server_router = Router::new();
+    server_router.on(method::STATE_ROLLBACK, |_ctx, params: StateRollbackParams| async move {
+        Ok(StateRollbackResult {
+            success: params.checkpoint != "missing",
+            checkpoint: params.checkpoint,
+            reason: None,
+        })
+    });
+    tokio::spawn(async move { server_router.serve(server).await });
+    let mut host_router = Router::new();
+    host_router.on(method::PUSH_EVENT, |_ctx, params: PushEventParams| async move {
+        Ok(PushEventResult {
+            accepted: params.event_id != "spam",
+            inference_id: None,
+            reason: None,
+        })
+    });
+    tokio::spawn(async move { host_router.serve(client).await });
+
+    let host = McplClient::new(host_session.connection().clone());
+    assert!(host.state_rollback("game", "cp-1").await.unwrap().success);
+    assert!(!host.state_rollback("game", "missing").await.unwrap().success);
+    let push = |event_id: &str| PushEventParams {
+        feature_set: "game".into(),
+        event_id: event_id.into(),
+        timestamp: "2024-01-01T00:00:00Z".into(),
+        origin: None,
+        sequence: None,
+        priority: None,
+        expires_at: None,
+        payload: PushEventPayload {
+            content: vec![ContentBlock::text("Your move")],
+        },
+    };
+    let game = McplClient::new(server_session.connection().clone());
+    assert!(game.push_event(&push("move-1")).await.unwrap().accepted);
+    assert!(!game.push_event(&push("spam")).await.unwrap().accepted);
+
+    // Each side counts what it answered; both see the outcomes
+    let expected = |requests_handled| FeatureSetMetrics {
+        requests_handled,
+        push_events_accepted: 1,
+        push_events_rejected: 1,
+        rollbacks: 1,
+        inference: UsageTotals::default(),
+    };
+    assert_eq!(server_session.feature_set_metrics("game"), expected(2));
+    assert_eq!(host_session.feature_set_metrics("game"), expected(2));
+    assert_eq!(host_session.feature_set_metrics("lobby"), FeatureSetMetrics::default());
+    assert_eq!(server_session.metrics().keys().collect::<Vec<_>>(), ["game"]);
+}
+
+#[tokio::test]
+async fn test_unanswered_requests_are_not_metered() {
+    let (mut conn, mut peer) = raw_peer();
+    let session = conn.session();
+    let rollback = serde_json::json!({"featureSet": "game", "checkpoint": "cp-1"});
+    let cancelled = conn
+        .send_request_deferred(method::STATE_ROLLBACK, Some(rollback.clone()))
+        .await
+        .unwrap();
+    conn.cancel_request(cancelled.id().clone(), None).await.unwrap();
+    let dropped = conn
+        .send_request_deferred(method::STATE_ROLLBACK, Some(rollback.clone()))
+        .await
+        .unwrap();
+    let dropped_id = dropped.id().clone();
+    drop(dropped);
+    let answered = conn
+        .send_request_deferred(method::STATE_ROLLBACK, Some(rollback))
+        .await
+        .unwrap();
+
+    // Late answers to the cancelled and dropped requests are not counted
+    for id in [cancelled.id(), &dropped_id, answered.id()] {
+        let response = JsonRpcResponse::success(
+            id.clone(),
+            serde_json::json!({"success": true, "checkpoint": "cp-1"}),
+        );
+        let mut frame = serde_json::to_vec(&response).unwrap();
+        frame.push(b'\n');
+        peer.writer.write_all(&frame).await.unwrap();
+    }
+    answered.await.unwrap();
+    assert_eq!(session.feature_set_metrics("game").rollbacks, 1);
+}